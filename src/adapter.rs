@@ -0,0 +1,281 @@
+//! Adapter trimming
+//!
+//! [`AdapterTrimmer`] removes 3' adapters: a read is cut where an adapter
+//! starts, whether the whole adapter is in the read or only its beginning at
+//! the read's end. Matches allow mismatches in proportion to the matched
+//! length (no indels), and an `N` in an adapter matches any base.
+//!
+//! For pairs, the trimmer additionally looks for the overlap of R1 with the
+//! reverse complement of R2. When the insert is shorter than the reads, the
+//! mates overlap completely and both read into the adapter, so they are cut
+//! to the insert length even if the adapter is too short or too damaged to
+//! be found on its own.
+use anyhow::Result;
+
+use crate::mapper::{PairedMapper, ParallelMapper};
+use crate::record::Record;
+
+/// Default fraction of mismatching bases in a match, as cutadapt's `-e`
+pub const DEFAULT_ERROR_RATE: f64 = 0.1;
+
+/// Default number of adapter bases that must match at the end of a read
+pub const DEFAULT_MIN_OVERLAP: usize = 3;
+
+/// Default number of bases by which mates must overlap to be cut to their insert
+pub const DEFAULT_MIN_INSERT_OVERLAP: usize = 30;
+
+/// 3' adapter trimmer for single reads and pairs
+///
+/// Wrapped FASTA records are kept as they are.
+#[derive(Debug, Clone)]
+pub struct AdapterTrimmer {
+    adapters1: Vec<Vec<u8>>,
+    adapters2: Vec<Vec<u8>>,
+    error_rate: f64,
+    min_overlap: usize,
+    min_insert_overlap: Option<usize>,
+    min_len: usize,
+}
+
+impl AdapterTrimmer {
+    /// Trims `adapters` from single reads and from both mates of pairs
+    pub fn new<A: AsRef<[u8]>>(adapters: &[A]) -> Self {
+        let adapters: Vec<Vec<u8>> = adapters
+            .iter()
+            .map(|adapter| adapter.as_ref().to_ascii_uppercase())
+            .filter(|adapter| !adapter.is_empty())
+            .collect();
+        Self {
+            adapters2: adapters.clone(),
+            adapters1: adapters,
+            error_rate: DEFAULT_ERROR_RATE,
+            min_overlap: DEFAULT_MIN_OVERLAP,
+            min_insert_overlap: Some(DEFAULT_MIN_INSERT_OVERLAP),
+            min_len: 0,
+        }
+    }
+
+    /// Trims `adapters` from R2 instead of the R1 adapters
+    pub fn with_r2_adapters<A: AsRef<[u8]>>(mut self, adapters: &[A]) -> Self {
+        self.adapters2 = Self::new(adapters).adapters1;
+        self
+    }
+
+    /// Allows `error_rate` mismatches per matched base
+    pub fn with_error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Requires at least `min_overlap` adapter bases to match at the end of a read
+    pub fn with_min_overlap(mut self, min_overlap: usize) -> Self {
+        self.min_overlap = min_overlap.max(1);
+        self
+    }
+
+    /// Requires mates to overlap by `min_overlap` bases to be cut to their insert, or `None` to not use the overlap
+    pub fn with_min_insert_overlap(mut self, min_overlap: Option<usize>) -> Self {
+        self.min_insert_overlap = min_overlap.map(|overlap| overlap.max(1));
+        self
+    }
+
+    /// Drops reads (or pairs with a mate) shorter than `min_len` after trimming
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+
+    fn max_mismatches(&self, len: usize) -> usize {
+        (len as f64 * self.error_rate) as usize
+    }
+
+    /// Length of `seq` before the first of `adapters`
+    fn adapter_start(&self, seq: &[u8], adapters: &[Vec<u8>]) -> usize {
+        adapters
+            .iter()
+            .filter_map(|adapter| self.find_adapter(seq, adapter))
+            .min()
+            .unwrap_or(seq.len())
+    }
+
+    /// Leftmost start of `adapter`, complete or cut off by the end of `seq`
+    fn find_adapter(&self, seq: &[u8], adapter: &[u8]) -> Option<usize> {
+        (0..seq.len()).find(|&start| {
+            let overlap = adapter.len().min(seq.len() - start);
+            overlap >= self.min_overlap.min(adapter.len())
+                && within_mismatches(
+                    &seq[start..start + overlap],
+                    &adapter[..overlap],
+                    self.max_mismatches(overlap),
+                    |base, adapter_base| {
+                        adapter_base == b'N' || base.eq_ignore_ascii_case(&adapter_base)
+                    },
+                )
+        })
+    }
+
+    /// Insert length of mates that overlap completely, if shorter than both
+    fn insert_len(&self, seq1: &[u8], seq2: &[u8]) -> Option<usize> {
+        let min_overlap = self.min_insert_overlap?;
+        let longest = seq1.len().min(seq2.len()).checked_sub(1)?;
+        // Mate 2 read back from its start is the reverse complement of the insert
+        (min_overlap..=longest).rev().find(|&insert| {
+            let mate2 = seq2[..insert].iter().rev().map(|&base| complement(base));
+            let mut mismatches = seq1[..insert]
+                .iter()
+                .zip(mate2)
+                .filter(|(a, b)| !a.eq_ignore_ascii_case(b));
+            mismatches.nth(self.max_mismatches(insert)).is_none()
+        })
+    }
+}
+
+/// Whether `seq` and `pattern` differ at most at `max_mismatches` positions
+fn within_mismatches<F>(seq: &[u8], pattern: &[u8], max_mismatches: usize, matches: F) -> bool
+where
+    F: Fn(u8, u8) -> bool,
+{
+    let mut mismatches = 0;
+    for (&base, &pattern_base) in seq.iter().zip(pattern) {
+        if !matches(base, pattern_base) {
+            mismatches += 1;
+            if mismatches > max_mismatches {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+pub(crate) fn complement(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+/// Sequence of a record, unless it is wrapped FASTA
+pub(crate) fn single_line_seq<'r>(record: &Record<'r>) -> Option<&'r [u8]> {
+    match *record {
+        Record::Fastq { seq, .. } => Some(seq),
+        Record::Fasta { seq, .. } => memchr::memchr(b'\n', seq).is_none().then_some(seq),
+    }
+}
+
+/// Keeps the first `len` bases of a record
+fn truncate(record: Record<'_>, len: usize) -> Record<'_> {
+    match record {
+        Record::Fastq { head, seq, qual } => Record::Fastq {
+            head,
+            seq: &seq[..len.min(seq.len())],
+            qual: &qual[..len.min(qual.len())],
+        },
+        Record::Fasta { head, seq } => Record::Fasta {
+            head,
+            seq: &seq[..len.min(seq.len())],
+        },
+    }
+}
+
+impl ParallelMapper for AdapterTrimmer {
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+        let Some(seq) = single_line_seq(&record) else {
+            return Ok(Some(record));
+        };
+        let len = self.adapter_start(seq, &self.adapters1);
+        Ok((len >= self.min_len).then(|| truncate(record, len)))
+    }
+}
+
+impl PairedMapper for AdapterTrimmer {
+    fn map_pair<'r>(
+        &mut self,
+        record1: Record<'r>,
+        record2: Record<'r>,
+    ) -> Result<Option<(Record<'r>, Record<'r>)>> {
+        let (Some(seq1), Some(seq2)) = (single_line_seq(&record1), single_line_seq(&record2))
+        else {
+            return Ok(Some((record1, record2)));
+        };
+        let mut len1 = self.adapter_start(seq1, &self.adapters1);
+        let mut len2 = self.adapter_start(seq2, &self.adapters2);
+        if let Some(insert) = self.insert_len(seq1, seq2) {
+            len1 = len1.min(insert);
+            len2 = len2.min(insert);
+        }
+        if len1 < self.min_len || len2 < self.min_len {
+            return Ok(None);
+        }
+        Ok(Some((truncate(record1, len1), truncate(record2, len2))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MinimalRefRecord;
+
+    const ADAPTER: &str = "AGATCGGAAGAGC";
+
+    /// Insert shorter than the reads, so both mates read through into the adapter
+    const INSERT: &str = "ACGTTGCATGCAAGTCCGATTAGCGGATCCATGACTTGCA";
+
+    fn reverse_complement(seq: &str) -> String {
+        seq.bytes().rev().map(|base| complement(base) as char).collect()
+    }
+
+    /// Lengths of the trimmed sequence and qualities of a read, `None` if dropped
+    fn trimmed(trimmer: &mut AdapterTrimmer, seq: &str) -> Option<(usize, usize)> {
+        let qual = vec![b'I'; seq.len()];
+        let record = Record::Fastq {
+            head: b"r",
+            seq: seq.as_bytes(),
+            qual: &qual,
+        };
+        let trimmed = trimmer.map_record(record).unwrap()?;
+        Some((trimmed.ref_seq().len(), trimmed.ref_qual().len()))
+    }
+
+    fn trimmed_pair(trimmer: &mut AdapterTrimmer, seq1: &str, seq2: &str) -> (usize, usize) {
+        let qual = vec![b'I'; seq1.len().max(seq2.len())];
+        let record1 = Record::Fastq {
+            head: b"r",
+            seq: seq1.as_bytes(),
+            qual: &qual[..seq1.len()],
+        };
+        let record2 = Record::Fastq {
+            head: b"r",
+            seq: seq2.as_bytes(),
+            qual: &qual[..seq2.len()],
+        };
+        let (record1, record2) = trimmer.map_pair(record1, record2).unwrap().unwrap();
+        (record1.ref_seq().len(), record2.ref_seq().len())
+    }
+
+    #[test]
+    fn adapter_start_at_the_read_end_is_trimmed() {
+        let mut trimmer = AdapterTrimmer::new(&[ADAPTER]);
+        // Complete adapter inside the read
+        assert_eq!(trimmed(&mut trimmer, "CCCCCCAGATCGGAAGAGCTTTT"), Some((6, 6)));
+        // Only the first four adapter bases before the read ends
+        assert_eq!(trimmed(&mut trimmer, "CCCCCCCCCCCCAGAT"), Some((12, 12)));
+        let mut strict = AdapterTrimmer::new(&[ADAPTER]).with_min_overlap(5);
+        assert_eq!(trimmed(&mut strict, "CCCCCCCCCCCCAGAT"), Some((16, 16)));
+        let mut min_len = AdapterTrimmer::new(&[ADAPTER]).with_min_len(13);
+        assert_eq!(trimmed(&mut min_len, "CCCCCCCCCCCCAGAT"), None);
+    }
+
+    #[test]
+    fn overlapping_mates_are_cut_to_the_insert() {
+        // Neither mate contains the adapter, only poly-G past the insert
+        let read1 = format!("{}GGGGGGGGGG", INSERT);
+        let read2 = format!("{}GGGGGGGGGG", reverse_complement(INSERT));
+        let mut trimmer = AdapterTrimmer::new(&[ADAPTER]);
+        assert_eq!(trimmed_pair(&mut trimmer, &read1, &read2), (40, 40));
+        let mut adapters_only = AdapterTrimmer::new(&[ADAPTER]).with_min_insert_overlap(None);
+        assert_eq!(trimmed_pair(&mut adapters_only, &read1, &read2), (50, 50));
+    }
+}
@@ -0,0 +1,93 @@
+//! CPU affinity and priority of the reader and worker threads
+//!
+//! On a loaded machine the reader thread getting descheduled stalls every
+//! worker. The reader can be pinned to a core that the workers then avoid,
+//! and the workers can be niced below the reader. Lowering the workers
+//! instead of raising the reader needs no privileges. Only implemented on
+//! Linux; elsewhere these settings have no effect.
+use anyhow::Result;
+
+/// Niceness added to worker threads when the reader has priority
+#[cfg(target_os = "linux")]
+const WORKER_NICE: libc::c_int = 5;
+
+/// Pins the calling thread to `core`
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_to_core(core: usize) -> Result<()> {
+    use anyhow::{bail, Context};
+    if core >= libc::CPU_SETSIZE as usize {
+        bail!("Core {} is out of range", core);
+    }
+    // SAFETY: cpu_set_t is plain data and only accessed through the libc macros
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to pin the reader thread to core {}", core));
+    }
+    Ok(())
+}
+
+/// Removes `core` from the cores the calling thread may run on
+///
+/// Keeps the affinity if `core` is the only allowed core.
+#[cfg(target_os = "linux")]
+pub(crate) fn avoid_core(core: usize) -> Result<()> {
+    use anyhow::Context;
+    // SAFETY: cpu_set_t is plain data and only accessed through the libc macros
+    let result = unsafe {
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, size, &mut set) != 0 {
+            -1
+        } else if core >= libc::CPU_SETSIZE as usize
+            || !libc::CPU_ISSET(core, &set)
+            || libc::CPU_COUNT(&set) == 1
+        {
+            0
+        } else {
+            libc::CPU_CLR(core, &mut set);
+            libc::sched_setaffinity(0, size, &set)
+        }
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to keep a worker thread off core {}", core));
+    }
+    Ok(())
+}
+
+/// Lowers the scheduling priority of the calling thread below the reader's
+#[cfg(target_os = "linux")]
+pub(crate) fn lower_priority() -> Result<()> {
+    use anyhow::Context;
+    // SAFETY: plain syscalls on the calling thread
+    let result = unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        let nice = libc::getpriority(libc::PRIO_PROCESS, tid);
+        libc::setpriority(libc::PRIO_PROCESS, tid, (nice + WORKER_NICE).min(19))
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("Failed to lower the priority of a worker thread");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_to_core(_core: usize) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn avoid_core(_core: usize) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn lower_priority() -> Result<()> {
+    Ok(())
+}
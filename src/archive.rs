@@ -0,0 +1,391 @@
+//! Reading FASTA/FASTQ members of tar archives
+//!
+//! Sequencing bundles (e.g. from SRA-like archives) often ship as
+//! `reads.tar` or `reads.tar.gz` holding one FASTQ per run or lane.
+//! [`TarReader`] streams the archive once and reads its members one after
+//! the other as a single chained input, each member possibly compressed on
+//! its own. With a [`MemberProcessor`] every record arrives with the name of
+//! the member it was read from.
+use anyhow::{bail, Context, Result};
+use flate2::Crc;
+use parking_lot::Mutex;
+use seq_io::policy;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::batch::BatchSize;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_reader, BoxedReader, FastxReader, FastxRecordSet};
+use crate::macro_impl::{run_parallel, MemberWorker};
+use crate::processor::{apply_error_action, MemberProcessor};
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::{ParallelProcessor, ParallelReader};
+
+/// Size of tar headers and of the blocks member data is padded to
+const BLOCK_SIZE: u64 = 512;
+
+/// Archive stream shared between the reader and the member being read
+struct TarStream {
+    inner: BoxedReader,
+    /// Data bytes of the current member not yet read
+    remaining: u64,
+    /// Padding after the current member's data
+    padding: u64,
+}
+
+impl TarStream {
+    /// Skips the rest of the current member and its padding
+    fn skip_member(&mut self) -> io::Result<()> {
+        let skip = self.remaining + self.padding;
+        let skipped = io::copy(&mut (&mut self.inner).take(skip), &mut io::sink())?;
+        if skipped < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining = 0;
+        self.padding = 0;
+        Ok(())
+    }
+
+    /// Starts a member of `size` bytes
+    fn start_member(&mut self, size: u64) {
+        self.remaining = size;
+        self.padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+    }
+
+    /// Reads the whole current member, for names stored as members
+    fn read_member(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.remaining as usize);
+        MemberData(self).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// `io::Read` over the data of the current member
+struct MemberData<'a>(&'a mut TarStream);
+
+impl Read for MemberData<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(self.0.remaining.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self.0.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.0.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// `io::Read` over the current member, owned by its record reader
+struct MemberReader(Arc<Mutex<TarStream>>);
+
+impl Read for MemberReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        MemberData(&mut self.0.lock()).read(buf)
+    }
+}
+
+/// Batch of records from a single member
+#[derive(Default)]
+struct MemberRecordSet {
+    member: String,
+    records: FastxRecordSet,
+}
+
+impl BatchSize for MemberRecordSet {
+    fn num_records(&self) -> usize {
+        self.records.num_records()
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.records.num_bytes()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        self.records.checksum(crc);
+    }
+}
+
+type MemberFilter = Box<dyn Fn(&str) -> bool + Send>;
+
+/// Reader over the FASTA/FASTQ members of a (possibly compressed) tar archive
+///
+/// Members are read in archive order and may use different formats and
+/// compressions. Directories, links and empty members are skipped; other
+/// files can be skipped with [`with_member_filter`](Self::with_member_filter).
+pub struct TarReader {
+    stream: Arc<Mutex<TarStream>>,
+    name: String,
+    filter: Option<MemberFilter>,
+    current: Option<(String, FastxReader)>,
+    finished: bool,
+}
+
+impl TarReader {
+    /// Opens a tar archive, which may itself be compressed
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::new(BufReader::new(file), &path.display().to_string())
+    }
+
+    /// Reads a (possibly compressed) tar archive, naming it `name` in errors
+    pub fn new<R: Read + Send + 'static>(reader: R, name: &str) -> Result<Self> {
+        let (inner, _compression) = niffler::send::get_reader(Box::new(reader))
+            .with_context(|| format!("Failed to open {}", name))?;
+        let stream = TarStream {
+            inner: Box::new(BufReader::new(inner)),
+            remaining: 0,
+            padding: 0,
+        };
+        Ok(Self {
+            stream: Arc::new(Mutex::new(stream)),
+            name: name.to_string(),
+            filter: None,
+            current: None,
+            finished: false,
+        })
+    }
+
+    /// Only reads the members whose name (path within the archive) passes `filter`
+    ///
+    /// Useful to skip checksums or metadata shipped next to the reads, e.g.
+    /// `|name| name.contains(".fastq")`.
+    pub fn with_member_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Advances to the next member to read and returns its name
+    fn next_member(&mut self) -> Result<Option<String>> {
+        let mut stream = self.stream.lock();
+        let mut long_name = None;
+        loop {
+            stream
+                .skip_member()
+                .with_context(|| format!("{} is truncated", self.name))?;
+            let Some(header) = read_header(&mut stream.inner)
+                .with_context(|| format!("Failed to read {}", self.name))?
+            else {
+                return Ok(None);
+            };
+            if !checksum_matches(&header) {
+                bail!("{} is not a tar archive", self.name);
+            }
+            stream.start_member(header_size(&header)?);
+            match header[156] {
+                // GNU long name of the next member
+                b'L' => {
+                    let data = stream.read_member()?;
+                    long_name = Some(String::from_utf8_lossy(until_nul(&data)).into_owned());
+                }
+                // PAX extended header of the next member
+                b'x' => {
+                    let data = stream.read_member()?;
+                    if let Some(path) = pax_path(&data) {
+                        long_name = Some(path);
+                    }
+                }
+                b'0' | b'\0' | b'7' => {
+                    let name = long_name.take().unwrap_or_else(|| header_name(&header));
+                    let wanted = self.filter.as_ref().is_none_or(|filter| filter(&name));
+                    if stream.remaining > 0 && wanted {
+                        return Ok(Some(name));
+                    }
+                }
+                _ => long_name = None,
+            }
+        }
+    }
+
+    /// Reads the next record set, moving on to the next member as each one ends
+    fn read_record_set(&mut self, record_set: &mut MemberRecordSet) -> Option<Result<()>> {
+        loop {
+            if self.finished {
+                return None;
+            }
+            if self.current.is_none() {
+                let member = match self.next_member() {
+                    Ok(Some(member)) => member,
+                    Ok(None) => {
+                        self.finished = true;
+                        return None;
+                    }
+                    Err(e) => return Some(Err(e)),
+                };
+                let handle = Box::new(MemberReader(Arc::clone(&self.stream)));
+                match open_reader(handle, &format!("{}:{}", self.name, member)) {
+                    Ok((handle, format)) => {
+                        self.current = Some((member, FastxReader::new(handle, format)))
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let (member, reader) = self.current.as_mut()?;
+            match reader.read_record_set(&mut record_set.records) {
+                Some(result) => {
+                    record_set.member.clone_from(member);
+                    return Some(result);
+                }
+                None => self.current = None,
+            }
+        }
+    }
+
+    /// Processes the records of all members, passing each record's member name
+    pub fn process_parallel_members<T: MemberProcessor>(
+        self,
+        processor: T,
+        num_threads: usize,
+    ) -> Result<()> {
+        self.process_parallel_members_with_config(processor, ParallelConfig::new(num_threads))
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel_members` but with a full run configuration
+    pub fn process_parallel_members_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: MemberProcessor,
+    {
+        run_parallel::<D, _, MemberRecordSet, _, _, _>(
+            self,
+            MemberWorker(processor),
+            &config,
+            |reader, record_set| reader.read_record_set(record_set),
+            |record_set, worker, global_idx| {
+                for (record_idx, record) in record_set.records.records().enumerate() {
+                    let result = worker.0.process_member_record(
+                        &record_set.member,
+                        record,
+                        global_idx,
+                        record_idx,
+                    );
+                    if let Err(err) = result {
+                        let action = worker.0.on_record_error(&err, record_idx);
+                        apply_error_action(err, action)?;
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+impl ParallelReader<BoxedReader, policy::StdPolicy> for TarReader {
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        run_parallel::<D, _, MemberRecordSet, _, _, _>(
+            self,
+            processor,
+            &config,
+            |reader, record_set| reader.read_record_set(record_set),
+            |record_set, processor, global_idx| record_set.records.process(processor, global_idx),
+        )
+    }
+}
+
+/// Reads the next header, or `None` at the end of the archive
+fn read_header(reader: &mut impl Read) -> io::Result<Option<[u8; 512]>> {
+    let mut header = [0; BLOCK_SIZE as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    // The archive ends with zero blocks
+    if header.iter().all(|&byte| byte == 0) {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// Whether the header checksum matches, which also rejects non-tar inputs
+fn checksum_matches(header: &[u8; 512]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(idx, &byte)| if (148..156).contains(&idx) { b' ' } else { byte } as u64)
+        .sum();
+    parse_octal(&header[148..156]) == Some(sum)
+}
+
+/// Member data size, in octal or GNU base-256
+fn header_size(header: &[u8; 512]) -> Result<u64> {
+    let field = &header[124..136];
+    if field[0] & 0x80 != 0 {
+        let size = field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |size, &byte| {
+                (size << 8) | u64::from(byte)
+            });
+        return Ok(size);
+    }
+    parse_octal(field).context("Invalid member size in tar header")
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(until_nul(field)).ok()?.trim();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Member name, joined with the ustar prefix if there is one
+fn header_name(header: &[u8; 512]) -> String {
+    let name = String::from_utf8_lossy(until_nul(&header[..100]));
+    let prefix = until_nul(&header[345..500]);
+    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+        format!("{}/{}", String::from_utf8_lossy(prefix), name)
+    } else {
+        name.into_owned()
+    }
+}
+
+/// Path from PAX records of the form `<length> <key>=<value>\n`
+fn pax_path(data: &[u8]) -> Option<String> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = memchr::memchr(b' ', rest)?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(path) = record.strip_prefix(b"path=") {
+            return Some(String::from_utf8_lossy(path).into_owned());
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    let end = memchr::memchr(0, field).unwrap_or(field.len());
+    &field[..end]
+}
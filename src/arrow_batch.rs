@@ -0,0 +1,80 @@
+//! Conversion of record batches to Arrow
+//!
+//! Hands batches to in-process query engines such as DataFusion or Polars as
+//! [`RecordBatch`]es with a `head`, `seq` and, for FASTQ, `qual` column, all
+//! `LargeBinary`. A [`SoaBatch`] already stores every column as one buffer
+//! with offsets, which is the Arrow layout, so [`into_arrow`] moves its
+//! buffers into the arrays without copying the bytes. Converting a record set
+//! goes through a `SoaBatch`, copying each record once.
+use anyhow::Result;
+use arrow::array::{ArrayRef, LargeBinaryArray};
+use arrow::buffer::{Buffer, OffsetBuffer, ScalarBuffer};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use std::sync::Arc;
+
+use crate::record::RecordSetLike;
+use crate::soa::SoaBatch;
+
+/// Schema of converted batches, with a `qual` column if `with_quals`
+pub fn sequence_schema(with_quals: bool) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("head", DataType::LargeBinary, false),
+        Field::new("seq", DataType::LargeBinary, false),
+    ];
+    if with_quals {
+        fields.push(Field::new("qual", DataType::LargeBinary, false));
+    }
+    Arc::new(Schema::new(fields))
+}
+
+/// Converts the records of a record set
+pub fn record_set_to_arrow<S: RecordSetLike>(record_set: &S) -> Result<RecordBatch> {
+    let mut batch = SoaBatch::new();
+    batch.fill(record_set);
+    into_arrow(batch)
+}
+
+/// Converts a batch, moving its buffers into the arrays
+pub fn into_arrow(batch: SoaBatch) -> Result<RecordBatch> {
+    let schema = sequence_schema(batch.has_quals);
+    // Sequences and qualities share their offsets
+    let seq_offsets = offset_buffer(batch.offsets);
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(binary_array(
+            offset_buffer(batch.head_offsets),
+            batch.heads,
+        )?),
+        Arc::new(binary_array(seq_offsets.clone(), batch.seqs)?),
+    ];
+    if batch.has_quals {
+        columns.push(Arc::new(binary_array(seq_offsets, batch.quals)?));
+    }
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+fn offset_buffer(offsets: Vec<usize>) -> OffsetBuffer<i64> {
+    // Same-sized element types, so this converts in place and keeps the allocation
+    let offsets: Vec<i64> = offsets.into_iter().map(|offset| offset as i64).collect();
+    OffsetBuffer::new(ScalarBuffer::from(offsets))
+}
+
+fn binary_array(offsets: OffsetBuffer<i64>, values: Vec<u8>) -> Result<LargeBinaryArray> {
+    Ok(LargeBinaryArray::try_new(
+        offsets,
+        Buffer::from_vec(values),
+        None,
+    )?)
+}
+
+impl SoaBatch {
+    /// Converts to an Arrow batch without copying, see [`into_arrow`]
+    pub fn into_arrow(self) -> Result<RecordBatch> {
+        into_arrow(self)
+    }
+
+    /// Converts a copy of the batch, for processors that keep reusing it
+    pub fn to_arrow(&self) -> Result<RecordBatch> {
+        into_arrow(self.clone())
+    }
+}
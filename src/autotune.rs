@@ -0,0 +1,99 @@
+//! Picking the number of worker threads for a file and processor
+//!
+//! The best thread count depends on the compression of the input (a gzip
+//! reader thread saturates long before the workers do) and on the cost of
+//! the processor, so it is measured rather than guessed: [`autotune`] runs
+//! the processor over the first batches of the file at increasing thread
+//! counts and keeps the fastest.
+use anyhow::Result;
+use std::path::Path;
+
+use crate::config::ParallelConfig;
+use crate::fastx::{FastxReader, FastxRecordSet};
+use crate::macro_impl::run_parallel;
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::ParallelProcessor;
+
+/// Number of record sets read by every trial
+pub const DEFAULT_TRIAL_BATCHES: usize = 256;
+
+/// Relative throughput within which fewer threads are preferred
+const TOLERANCE: f64 = 0.05;
+
+/// Outcome of [`autotune`]
+#[derive(Debug, Clone)]
+pub struct Autotune {
+    /// Fewest threads within 5% of the best measured throughput
+    pub num_threads: usize,
+    /// Statistics of every trial, by increasing thread count
+    pub trials: Vec<RunStats>,
+}
+
+impl Autotune {
+    /// Run configuration with the chosen number of threads
+    pub fn config(&self) -> ParallelConfig {
+        ParallelConfig::new(self.num_threads)
+    }
+}
+
+/// Measures `processor` on a prefix of `path` at 1, 2, 4, ... up to `max_threads` threads
+///
+/// Every trial opens the file again and processes its first
+/// [`DEFAULT_TRIAL_BATCHES`] record sets, so decompression is part of the
+/// measurement. The processor is cloned for every trial; state it shares
+/// across clones accumulates over the trials.
+pub fn autotune<P, T>(path: P, processor: T, max_threads: usize) -> Result<Autotune>
+where
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+{
+    autotune_with_batches(path, processor, max_threads, DEFAULT_TRIAL_BATCHES)
+}
+
+/// Same as [`autotune`] but with `trial_batches` record sets per trial
+pub fn autotune_with_batches<P, T>(
+    path: P,
+    processor: T,
+    max_threads: usize,
+    trial_batches: usize,
+) -> Result<Autotune>
+where
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+{
+    let path = path.as_ref();
+    let max_threads = max_threads.max(1);
+    let thread_counts = std::iter::successors(Some(1), |&n| Some(n * 2))
+        .take_while(|&n| n < max_threads)
+        .chain([max_threads]);
+
+    let mut trials = Vec::new();
+    for num_threads in thread_counts {
+        let reader = (FastxReader::from_path(path)?, trial_batches);
+        let stats = run_parallel::<_, _, FastxRecordSet, _, _, _>(
+            reader,
+            processor.clone(),
+            &ParallelConfig::new(num_threads),
+            |(reader, remaining), record_set| {
+                *remaining = remaining.checked_sub(1)?;
+                reader.read_record_set(record_set)
+            },
+            |record_set, processor, global_idx| record_set.process(processor, global_idx),
+        )?;
+        trials.push(stats);
+    }
+
+    let best = trials
+        .iter()
+        .map(RunStats::records_per_sec)
+        .fold(0.0, f64::max);
+    let num_threads = trials
+        .iter()
+        .find(|stats| stats.records_per_sec() >= best * (1.0 - TOLERANCE))
+        .map_or(1, |stats| stats.num_threads);
+    Ok(Autotune {
+        num_threads,
+        trials,
+    })
+}
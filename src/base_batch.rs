@@ -0,0 +1,124 @@
+//! Batches holding a fixed number of bases
+//!
+//! seq_io closes a record set once its buffer is full, so the number of bases
+//! per batch varies with record and header lengths. For processors whose cost
+//! scales with the bases (alignment, k-mer counting) that makes the work per
+//! batch unpredictable. [`BaseBatchReader`] instead closes a batch once its
+//! sequences reach a base threshold.
+use anyhow::Result;
+use seq_io::{fasta, fastq, policy};
+use std::io;
+use std::path::Path;
+
+use crate::batch::RecordBuffer;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, BoxedReader, Format};
+use crate::macro_impl::run_parallel;
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Default number of bases after which a batch is closed
+pub const DEFAULT_BATCH_BASES: usize = 1 << 20;
+
+enum BaseBatchInner<R: io::Read> {
+    Fasta(fasta::Reader<R>),
+    Fastq(fastq::Reader<R>),
+}
+
+/// Reader dispatching batches of about the same number of bases
+pub struct BaseBatchReader<R: io::Read = BoxedReader> {
+    inner: BaseBatchInner<R>,
+    batch_bases: usize,
+}
+
+impl BaseBatchReader<BoxedReader> {
+    /// Opens a (possibly compressed) file and detects its format
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let (handle, format) = open_path(path)?;
+        Ok(Self::new(handle, format))
+    }
+}
+
+impl<R: io::Read> BaseBatchReader<R> {
+    pub fn new(reader: R, format: Format) -> Self {
+        let inner = match format {
+            Format::Fasta => BaseBatchInner::Fasta(fasta::Reader::new(reader)),
+            Format::Fastq => BaseBatchInner::Fastq(fastq::Reader::new(reader)),
+        };
+        Self {
+            inner,
+            batch_bases: DEFAULT_BATCH_BASES,
+        }
+    }
+
+    /// Closes a batch once its sequences hold at least `batch_bases` bases
+    ///
+    /// A record longer than the threshold forms a batch of its own.
+    pub fn with_batch_bases(mut self, batch_bases: usize) -> Self {
+        self.batch_bases = batch_bases.max(1);
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        match self.inner {
+            BaseBatchInner::Fasta(_) => Format::Fasta,
+            BaseBatchInner::Fastq(_) => Format::Fastq,
+        }
+    }
+
+    /// Fills `records` up to the base threshold, copying joined sequences
+    fn read_records(&mut self, records: &mut RecordBuffer) -> Option<Result<()>> {
+        records.clear();
+        let mut bases = 0;
+
+        macro_rules! fill {
+            ($reader:expr) => {
+                while bases < self.batch_bases {
+                    match $reader.next() {
+                        Some(Ok(record)) => {
+                            let seq = record.ref_full_seq();
+                            bases += seq.len();
+                            records.push(record.ref_head(), &seq, record.ref_qual());
+                        }
+                        Some(Err(e)) => return Some(Err(e.into())),
+                        None => break,
+                    }
+                }
+            };
+        }
+
+        match &mut self.inner {
+            BaseBatchInner::Fasta(reader) => fill!(reader),
+            BaseBatchInner::Fastq(reader) => fill!(reader),
+        }
+        if records.is_empty() {
+            return None;
+        }
+        Some(Ok(()))
+    }
+}
+
+impl<R> ParallelReader<R, policy::StdPolicy> for BaseBatchReader<R>
+where
+    R: io::Read + Send,
+{
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        run_parallel::<D, _, RecordBuffer, _, _, _>(
+            self,
+            processor,
+            &config,
+            |reader, records| reader.read_records(records),
+            |records, processor, global_idx| records.process(processor, global_idx),
+        )
+    }
+}
@@ -0,0 +1,128 @@
+//! Size accounting of dispatched batches
+use flate2::Crc;
+use std::ops::Range;
+
+use crate::record::{RecordSetLike, SliceRecord};
+use crate::MinimalRefRecord;
+
+/// Number of records and bytes held by a batch
+pub(crate) trait BatchSize {
+    fn num_records(&self) -> usize;
+
+    /// Bytes of headers, sequences and qualities
+    fn num_bytes(&self) -> usize;
+
+    /// Whether the batch holds no records, which keeps it from being dispatched
+    fn is_empty_batch(&self) -> bool {
+        self.num_records() == 0
+    }
+
+    /// Feeds the content of the batch to `crc`, for batch checksums
+    ///
+    /// Batches whose content is opaque feed nothing, leaving their checksum
+    /// to the record count.
+    #[allow(unused_variables)]
+    fn checksum(&self, crc: &mut Crc) {}
+}
+
+impl<S: RecordSetLike> BatchSize for S {
+    fn num_records(&self) -> usize {
+        self.len()
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.records()
+            .map(|r| r.ref_head().len() + r.ref_seq().len() + r.ref_qual().len())
+            .sum()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        for record in self.records() {
+            crc.update(record.ref_head());
+            crc.update(record.ref_seq());
+            crc.update(record.ref_qual());
+            crc.update(b"\n");
+        }
+    }
+}
+
+/// Location of a record's fields in a [`RecordBuffer`]
+#[derive(Debug, Clone)]
+struct RecordRanges {
+    head: Range<usize>,
+    seq: Range<usize>,
+    qual: Range<usize>,
+}
+
+/// Records copied into one contiguous buffer
+#[derive(Debug, Default)]
+pub(crate) struct RecordBuffer {
+    data: Vec<u8>,
+    records: Vec<RecordRanges>,
+}
+
+impl RecordBuffer {
+    pub(crate) fn clear(&mut self) {
+        self.data.clear();
+        self.records.clear();
+    }
+
+    pub(crate) fn push(&mut self, head: &[u8], seq: &[u8], qual: &[u8]) {
+        let mut append = |field: &[u8]| {
+            let start = self.data.len();
+            self.data.extend_from_slice(field);
+            start..self.data.len()
+        };
+        let record = RecordRanges {
+            head: append(head),
+            seq: append(seq),
+            qual: append(qual),
+        };
+        self.records.push(record);
+    }
+
+    pub(crate) fn record(&self, idx: usize) -> SliceRecord<'_> {
+        let ranges = &self.records[idx];
+        SliceRecord {
+            head: &self.data[ranges.head.clone()],
+            seq: &self.data[ranges.seq.clone()],
+            qual: &self.data[ranges.qual.clone()],
+        }
+    }
+}
+
+/// Iterator over the records of a [`RecordBuffer`]
+pub(crate) struct RecordBufferIter<'a> {
+    buffer: &'a RecordBuffer,
+    idx: usize,
+}
+
+impl<'a> Iterator for RecordBufferIter<'a> {
+    type Item = SliceRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = (self.idx < self.buffer.records.len()).then(|| self.buffer.record(self.idx));
+        self.idx += 1;
+        record
+    }
+}
+
+impl RecordSetLike for RecordBuffer {
+    type Record<'a> = SliceRecord<'a>;
+    type Iter<'a> = RecordBufferIter<'a>;
+
+    fn records(&self) -> Self::Iter<'_> {
+        RecordBufferIter {
+            buffer: self,
+            idx: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
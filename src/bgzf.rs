@@ -0,0 +1,143 @@
+//! Reading BGZF files with their virtual offsets
+//!
+//! BGZF files (as written by bgzip or htslib) are a series of gzip members of
+//! at most 64 KiB of data each. Indexes address them by virtual offset: the
+//! compressed offset of a block shifted left by 16 bits, plus an offset into
+//! its uncompressed data. [`BgzfReader`] decompresses the blocks one by one
+//! and keeps their offsets in a [`BgzfBlocks`] table, which maps positions in
+//! the uncompressed stream back to virtual offsets.
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+/// Offsets of the blocks read so far, shared with the reader
+#[derive(Debug, Clone, Default)]
+pub struct BgzfBlocks(Arc<Mutex<VecDeque<BlockOffset>>>);
+
+#[derive(Debug, Clone, Copy)]
+struct BlockOffset {
+    compressed: u64,
+    uncompressed: u64,
+}
+
+impl BgzfBlocks {
+    /// Virtual offset of the uncompressed position `offset`
+    ///
+    /// `None` if the block holding it was not read yet or was released.
+    pub fn virtual_offset(&self, offset: u64) -> Option<u64> {
+        let blocks = self.0.lock();
+        let idx = blocks
+            .partition_point(|block| block.uncompressed <= offset)
+            .checked_sub(1)?;
+        let block = blocks[idx];
+        let within = offset - block.uncompressed;
+        (within < 1 << 16).then_some(block.compressed << 16 | within)
+    }
+
+    /// Forgets the blocks before the one holding `offset`
+    pub(crate) fn release(&self, offset: u64) {
+        let mut blocks = self.0.lock();
+        while blocks.len() > 1 && blocks[1].uncompressed <= offset {
+            blocks.pop_front();
+        }
+    }
+
+    fn push(&self, compressed: u64, uncompressed: u64) {
+        self.0.lock().push_back(BlockOffset {
+            compressed,
+            uncompressed,
+        });
+    }
+}
+
+/// Decompresses a BGZF stream block by block, recording the block offsets
+pub struct BgzfReader<R> {
+    inner: R,
+    block: Vec<u8>,
+    data: Vec<u8>,
+    pos: usize,
+    compressed: u64,
+    uncompressed: u64,
+    blocks: BgzfBlocks,
+    eof: bool,
+}
+
+impl<R: Read> BgzfReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            block: Vec::new(),
+            data: Vec::new(),
+            pos: 0,
+            compressed: 0,
+            uncompressed: 0,
+            blocks: BgzfBlocks::default(),
+            eof: false,
+        }
+    }
+
+    /// Table of the blocks read, to look up virtual offsets from another thread or reader
+    pub fn blocks(&self) -> BgzfBlocks {
+        self.blocks.clone()
+    }
+
+    /// Reads and decompresses the next block, `false` at the end of the input
+    fn read_block(&mut self) -> io::Result<bool> {
+        let mut header = [0u8; 18];
+        if !read_header(&mut self.inner, &mut header)? {
+            // Lets the end of the input map to the offset after the last block
+            self.blocks.push(self.compressed, self.uncompressed);
+            self.eof = true;
+            return Ok(false);
+        }
+        if header[..4] != [0x1f, 0x8b, 8, 4] || &header[12..14] != b"BC" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "input is not BGZF compressed",
+            ));
+        }
+        let size = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+        self.block.clear();
+        self.block.extend_from_slice(&header);
+        self.block.resize(size.max(header.len()), 0);
+        self.inner.read_exact(&mut self.block[header.len()..])?;
+
+        self.data.clear();
+        flate2::read::GzDecoder::new(&self.block[..]).read_to_end(&mut self.data)?;
+        self.pos = 0;
+        self.blocks.push(self.compressed, self.uncompressed);
+        self.compressed += size as u64;
+        self.uncompressed += self.data.len() as u64;
+        Ok(true)
+    }
+}
+
+/// Fills `header`, `false` if the input ended before its first byte
+fn read_header<R: Read>(reader: &mut R, header: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.data.len() {
+            if self.eof || !self.read_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
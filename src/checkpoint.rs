@@ -0,0 +1,190 @@
+//! Checkpointing and resuming of long runs
+//!
+//! With [`ParallelConfig::with_checkpoint`](crate::ParallelConfig::with_checkpoint)
+//! a checkpoint file is rewritten every N batches. Batches finish out of
+//! order, so a checkpoint only covers the longest prefix of batches that are
+//! all done. After a crash,
+//! [`ParallelConfig::resume_from_checkpoint`](crate::ParallelConfig::resume_from_checkpoint)
+//! truncates the registered outputs to their checkpointed length and the next
+//! run skips the covered batches without processing them.
+//!
+//! Resuming relies on the batches being cut exactly as before, so the input,
+//! the reader and its batch settings must not change between runs, and only
+//! single-reader runs can be checkpointed. Output lengths are only exact if
+//! the outputs are written in batch order and flushed after every batch.
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Progress covered by a checkpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Number of leading batches that are fully processed
+    pub batches: u64,
+    pub records: u64,
+    /// Input bytes of those batches, which is the exact file offset for raw chunk dispatch
+    pub input_offset: u64,
+    /// Length of every registered output when the checkpoint was taken
+    pub outputs: Vec<(PathBuf, u64)>,
+}
+
+impl Checkpoint {
+    /// Loads a checkpoint file, returning `None` if it does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read checkpoint {}", path.display()))
+            }
+        };
+        Self::parse(&text)
+            .map(Some)
+            .with_context(|| format!("Invalid checkpoint {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut checkpoint = Self::default();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "batches" => checkpoint.batches = value.parse()?,
+                "records" => checkpoint.records = value.parse()?,
+                "input_offset" => checkpoint.input_offset = value.parse()?,
+                "output" => {
+                    let Some((len, path)) = value.split_once(' ') else {
+                        bail!("Malformed output line: {}", line);
+                    };
+                    checkpoint.outputs.push((PathBuf::from(path), len.parse()?));
+                }
+                _ => bail!("Unknown checkpoint field: {}", key),
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "batches {}", self.batches);
+        let _ = writeln!(out, "records {}", self.records);
+        let _ = writeln!(out, "input_offset {}", self.input_offset);
+        for (path, len) in &self.outputs {
+            let _ = writeln!(out, "output {} {}", len, path.display());
+        }
+        out
+    }
+
+    /// Writes the checkpoint to a temporary file and renames it over `path`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.render())
+            .and_then(|_| fs::rename(&tmp, path))
+            .with_context(|| format!("Failed to write checkpoint {}", path.display()))
+    }
+
+    /// Truncates every output to its checkpointed length
+    pub fn truncate_outputs(&self) -> Result<()> {
+        for (path, len) in &self.outputs {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .with_context(|| format!("Failed to open output {}", path.display()))?;
+            file.set_len(*len)
+                .with_context(|| format!("Failed to truncate output {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checkpoint settings of a run
+#[derive(Debug, Clone, Default)]
+pub struct CheckpointOptions {
+    pub path: PathBuf,
+    /// Number of completed batches between two checkpoints
+    pub every_batches: usize,
+    pub outputs: Vec<PathBuf>,
+    /// Checkpoint to continue from
+    pub resume: Option<Checkpoint>,
+}
+
+/// Progress of the batches finished past the checkpointed prefix
+struct TrackerState {
+    checkpoint: Checkpoint,
+    /// Finished batches that do not extend the prefix yet, with their records and bytes
+    pending: BTreeMap<u64, (u64, u64)>,
+    batches_since_save: usize,
+}
+
+/// Tracks completed batches of a run and writes the checkpoints
+pub(crate) struct CheckpointTracker {
+    options: CheckpointOptions,
+    state: Mutex<TrackerState>,
+}
+
+impl CheckpointTracker {
+    pub(crate) fn new(options: CheckpointOptions) -> Self {
+        let checkpoint = options.resume.clone().unwrap_or_default();
+        Self {
+            options,
+            state: Mutex::new(TrackerState {
+                checkpoint,
+                pending: BTreeMap::new(),
+                batches_since_save: 0,
+            }),
+        }
+    }
+
+    /// Number of leading batches to skip because a previous run covered them
+    pub(crate) fn resume_batches(&self) -> usize {
+        self.options.resume.as_ref().map_or(0, |c| c.batches as usize)
+    }
+
+    /// Records a finished batch and writes a checkpoint if one is due
+    pub(crate) fn on_batch_done(&self, batch_idx: usize, records: usize, bytes: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        state
+            .pending
+            .insert(batch_idx as u64, (records as u64, bytes as u64));
+        loop {
+            let next = state.checkpoint.batches;
+            let Some((records, bytes)) = state.pending.remove(&next) else {
+                break;
+            };
+            state.checkpoint.batches += 1;
+            state.checkpoint.records += records;
+            state.checkpoint.input_offset += bytes;
+            state.batches_since_save += 1;
+        }
+        if state.batches_since_save >= self.options.every_batches.max(1) {
+            state.batches_since_save = 0;
+            self.write(&mut state.checkpoint)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the final checkpoint of a run
+    pub(crate) fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        self.write(&mut state.checkpoint)
+    }
+
+    fn write(&self, checkpoint: &mut Checkpoint) -> Result<()> {
+        checkpoint.outputs = self
+            .options
+            .outputs
+            .iter()
+            .map(|path| {
+                let len = fs::metadata(path).map_or(0, |m| m.len());
+                (path.clone(), len)
+            })
+            .collect();
+        checkpoint.save(&self.options.path)
+    }
+}
@@ -0,0 +1,248 @@
+//! Streaming checksums of the inputs
+//!
+//! Pipelines record MD5 or SHA-256 sums of their inputs for provenance,
+//! which usually takes a second pass over files that were just read. A
+//! [`Checksums`] collector hashes the bytes of every input opened through it
+//! while the reader thread reads them, either as stored (usually compressed)
+//! or after decompression. Registered with
+//! [`ParallelConfig::with_checksums`](crate::ParallelConfig::with_checksums),
+//! the sums are returned in [`RunStats::checksums`](crate::RunStats::checksums)
+//! and written to the run report.
+//!
+//! A sum covers the bytes read so far, which for a completed run is the
+//! whole input.
+use anyhow::{bail, Context, Result};
+use md5::{Digest, Md5};
+use parking_lot::Mutex;
+use sha2::Sha256;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::fastx::{open_reader_with, BoxedReader, FastxReader, Format};
+use crate::paired::PairedReader;
+
+/// Hash function of a checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Bytes of an input a checksum is computed over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumLayer {
+    /// The bytes as stored, i.e. compressed for compressed inputs, as `md5sum` computes them
+    Stored,
+    /// The bytes after decompression, as parsed
+    Decompressed,
+}
+
+impl ChecksumLayer {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stored => "stored",
+            Self::Decompressed => "decompressed",
+        }
+    }
+}
+
+/// Checksum of one input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputChecksum {
+    pub input: String,
+    pub algorithm: ChecksumAlgorithm,
+    pub layer: ChecksumLayer,
+    /// Lowercase hexadecimal digest
+    pub value: String,
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Self::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(bytes),
+            Self::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// Digest of the bytes so far, leaving the hasher usable
+    fn hex(&self) -> String {
+        let digest = match self {
+            Self::Md5(hasher) => hasher.clone().finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.clone().finalize().to_vec(),
+        };
+        let mut hex = String::with_capacity(digest.len() * 2);
+        for byte in digest {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        hex
+    }
+}
+
+/// Running checksum of one input
+struct InputDigest {
+    input: String,
+    algorithm: ChecksumAlgorithm,
+    layer: ChecksumLayer,
+    hasher: Mutex<Hasher>,
+}
+
+impl InputDigest {
+    fn value(&self) -> InputChecksum {
+        InputChecksum {
+            input: self.input.clone(),
+            algorithm: self.algorithm,
+            layer: self.layer,
+            value: self.hasher.lock().hex(),
+        }
+    }
+}
+
+/// Collects the checksums of the inputs opened through it
+///
+/// Clones share the collected checksums, so the collector can be handed to
+/// the run configuration after opening the inputs.
+#[derive(Clone, Default)]
+pub struct Checksums {
+    kinds: Vec<(ChecksumAlgorithm, ChecksumLayer)>,
+    digests: Arc<Mutex<Vec<Arc<InputDigest>>>>,
+}
+
+impl Checksums {
+    /// Collector computing no checksum, to be configured with [`with_checksum`](Self::with_checksum)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Computes `algorithm` over the `layer` bytes of every input
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm, layer: ChecksumLayer) -> Self {
+        if !self.kinds.contains(&(algorithm, layer)) {
+            self.kinds.push((algorithm, layer));
+        }
+        self
+    }
+
+    /// Checksums of the inputs opened so far, in the order they were opened
+    pub fn values(&self) -> Vec<InputChecksum> {
+        self.digests
+            .lock()
+            .iter()
+            .map(|digest| digest.value())
+            .collect()
+    }
+
+    /// Opens a (possibly compressed) file and detects its format, hashing it while it is read
+    pub fn open_path<P: AsRef<Path>>(&self, path: P) -> Result<(BoxedReader, Format)> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let file = File::open(path).with_context(|| format!("Failed to open {}", name))?;
+        let handle = self.wrap(&name, ChecksumLayer::Stored, Box::new(file));
+        open_reader_with(Box::new(BufReader::new(handle)), &name, |handle| {
+            self.wrap(&name, ChecksumLayer::Decompressed, handle)
+        })
+    }
+
+    /// Hashes the bytes read from `handle` with every checksum of `layer`
+    fn wrap(&self, input: &str, layer: ChecksumLayer, handle: BoxedReader) -> BoxedReader {
+        let digests: Vec<Arc<InputDigest>> = self
+            .kinds
+            .iter()
+            .filter(|&&(_, kind_layer)| kind_layer == layer)
+            .map(|&(algorithm, layer)| {
+                Arc::new(InputDigest {
+                    input: input.to_string(),
+                    algorithm,
+                    layer,
+                    hasher: Mutex::new(Hasher::new(algorithm)),
+                })
+            })
+            .collect();
+        if digests.is_empty() {
+            return handle;
+        }
+        self.digests.lock().extend(digests.iter().cloned());
+        Box::new(ChecksumReader {
+            inner: handle,
+            digests,
+        })
+    }
+}
+
+impl std::fmt::Debug for Checksums {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Checksums")
+            .field("kinds", &self.kinds)
+            .field("inputs", &self.digests.lock().len())
+            .finish()
+    }
+}
+
+/// Reader feeding the bytes it reads to running checksums
+struct ChecksumReader<R> {
+    inner: R,
+    digests: Vec<Arc<InputDigest>>,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for digest in &self.digests {
+            digest.hasher.lock().update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl FastxReader<BoxedReader> {
+    /// Opens a (possibly compressed) file like [`from_path`](Self::from_path), computing the checksums of `checksums` while it is read
+    pub fn from_path_with_checksums<P: AsRef<Path>>(
+        path: P,
+        checksums: &Checksums,
+    ) -> Result<Self> {
+        let (handle, format) = checksums.open_path(path)?;
+        Ok(Self::new(handle, format))
+    }
+}
+
+impl PairedReader<BoxedReader, BoxedReader> {
+    /// Opens both mate files like [`from_paths`](Self::from_paths), computing the checksums of `checksums` while they are read
+    pub fn from_paths_with_checksums<P1: AsRef<Path>, P2: AsRef<Path>>(
+        path1: P1,
+        path2: P2,
+        checksums: &Checksums,
+    ) -> Result<Self> {
+        let (handle1, format1) = checksums.open_path(path1)?;
+        let (handle2, format2) = checksums.open_path(path2)?;
+        if format1 != format2 {
+            bail!(
+                "Mate files have different formats: {:?} and {:?}",
+                format1,
+                format2
+            );
+        }
+        Ok(Self::new(handle1, handle2, format1))
+    }
+}
@@ -0,0 +1,555 @@
+//! Raw-chunk dispatch mode
+//!
+//! In the default mode the reader thread fully parses every record set, which
+//! caps throughput at the speed of a single parser. Here the reader thread only
+//! reads raw bytes and finds record boundaries; the field parsing is done by the
+//! workers on their own chunk.
+//!
+//! FASTQ boundaries are found by counting lines, so the input must use the
+//! standard four-line layout (no wrapped sequence or quality lines). Every
+//! record is checked to start with `@` and to have its `+` line in place, so
+//! input in another layout fails with the byte offset of the first
+//! misaligned record instead of being split in the wrong places.
+use anyhow::{bail, Result};
+use flate2::Crc;
+use seq_io::policy;
+use std::io;
+
+use crate::batch::BatchSize;
+use crate::bgzf::{BgzfBlocks, BgzfReader};
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::stats::RunStats;
+use crate::fastx::Format;
+use crate::index::IndexBuilder;
+use crate::macro_impl::{run_parallel, RangeWorker};
+use crate::processor::apply_error_action;
+use crate::{ParallelProcessor, ParallelReader};
+
+/// Default number of bytes read per chunk
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Raw bytes of a run of complete records
+#[derive(Debug, Default, Clone)]
+pub struct RawChunk {
+    pub data: Vec<u8>,
+    pub num_records: usize,
+    /// Position of `data` in the input
+    pub range: ByteRange,
+}
+
+/// Bytes of the input covered by a batch
+///
+/// `start` and `end` count uncompressed bytes from the start of the input.
+/// For BGZF inputs read through [`RawChunkReader::bgzf`], the virtual
+/// offsets of both ends are filled in as well.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+    pub virtual_start: Option<u64>,
+    pub virtual_end: Option<u64>,
+}
+
+/// Counts the records of a chunk holding complete records, starting at byte `base` of the input
+///
+/// FASTQ records are counted by their boundaries, so trailing blank lines are
+/// not a record and records out of the four-line layout are an error.
+pub(crate) fn count_records(format: Format, data: &[u8], base: u64) -> Result<usize> {
+    match format {
+        Format::Fastq => {
+            fastq_record_ends(data, base, true).try_fold(0, |n, end| end.map(|_| n + 1))
+        }
+        Format::Fasta => {
+            Ok(memchr::memmem::find_iter(data, b"\n>").count() + data.starts_with(b">") as usize)
+        }
+    }
+}
+
+/// Start of every record in a chunk holding complete records
+pub(crate) fn record_starts(format: Format, data: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let (marker, starts): (u8, Box<dyn Iterator<Item = usize>>) = match format {
+        Format::Fastq => (
+            b'@',
+            Box::new(fastq_record_ends(data, 0, true).map_while(Result::ok)),
+        ),
+        Format::Fasta => (
+            b'>',
+            Box::new(memchr::memmem::find_iter(data, b"\n>").map(|pos| pos + 1)),
+        ),
+    };
+    std::iter::once(0)
+        .chain(starts)
+        .filter(move |&start| data.get(start) == Some(&marker))
+}
+
+/// Ends of the complete FASTQ records of `data`, in order
+///
+/// Stops at an incomplete last record or at trailing blank lines. With
+/// `at_eof`, `data` runs to the end of the input, so the last record may lack
+/// its final newline and an incomplete one is an error. Errors name the byte
+/// offset of the record, counting from `base`.
+fn fastq_record_ends(
+    data: &[u8],
+    base: u64,
+    at_eof: bool,
+) -> impl Iterator<Item = Result<usize>> + '_ {
+    let mut start = Some(0);
+    std::iter::from_fn(move || {
+        let end = fastq_record_end(data, start?, base, at_eof).transpose()?;
+        start = end.as_ref().ok().copied();
+        Some(end)
+    })
+}
+
+/// End of the FASTQ record at `start`, checking that it has the four-line layout
+fn fastq_record_end(data: &[u8], start: usize, base: u64, at_eof: bool) -> Result<Option<usize>> {
+    let rest = &data[start..];
+    if rest.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let offset = base + start as u64;
+    if rest[0] != b'@' {
+        bail!("Expected a FASTQ record starting with '@' at byte {}", offset);
+    }
+    let mut newlines = memchr::memchr_iter(b'\n', rest);
+    let seq_end = newlines.nth(1);
+    if let Some(&first) = seq_end.and_then(|pos| rest.get(pos + 1)) {
+        if first != b'+' {
+            bail!("Expected the '+' line of the FASTQ record at byte {}", offset);
+        }
+    }
+    let plus_end = newlines.next();
+    match (plus_end, newlines.next()) {
+        (_, Some(qual_end)) => Ok(Some(start + qual_end + 1)),
+        (Some(_), None) if at_eof => Ok(Some(data.len())),
+        _ if at_eof => bail!("Truncated FASTQ record at byte {}", offset),
+        _ => Ok(None),
+    }
+}
+
+/// Reader that dispatches raw byte chunks holding whole records
+pub struct RawChunkReader<R: io::Read> {
+    inner: R,
+    format: Format,
+    chunk_size: usize,
+    /// Bytes read past the last record boundary of the previous chunk
+    carry: Vec<u8>,
+    eof: bool,
+    /// Uncompressed bytes handed out in chunks so far
+    offset: u64,
+    blocks: Option<BgzfBlocks>,
+    index: Option<IndexBuilder>,
+}
+
+impl<R: io::Read> RawChunkReader<R> {
+    pub fn new(inner: R, format: Format) -> Self {
+        Self {
+            inner,
+            format,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            carry: Vec::new(),
+            eof: false,
+            offset: 0,
+            blocks: None,
+            index: None,
+        }
+    }
+
+    pub fn fasta(inner: R) -> Self {
+        Self::new(inner, Format::Fasta)
+    }
+
+    pub fn fastq(inner: R) -> Self {
+        Self::new(inner, Format::Fastq)
+    }
+
+    /// Sets the number of bytes read per chunk
+    ///
+    /// Chunks may grow past this size to fit at least one complete record
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Adds virtual offsets to the byte ranges of the chunks
+    ///
+    /// `blocks` must come from the [`BgzfReader`] this reader reads from.
+    pub fn with_bgzf_blocks(mut self, blocks: BgzfBlocks) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    /// Records the ID and offset of every record in `index` while processing
+    ///
+    /// The workers find the records of their chunks, so this adds no work
+    /// to the reader thread. Take the index with [`IndexBuilder::finish`]
+    /// once the run is done.
+    pub fn with_index(mut self, index: &IndexBuilder) -> Self {
+        self.index = Some(index.clone());
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Fills `chunk` with the next run of complete records
+    pub fn read_chunk(&mut self, chunk: &mut RawChunk) -> Option<Result<()>> {
+        let result = self.read_chunk_data(chunk)?;
+        Some(result.and_then(|()| self.count(chunk)))
+    }
+
+    /// Fills `chunk` with exactly the next `num_records` records
+    ///
+    /// Fewer records are returned only at the end of the input.
+    pub fn read_records(&mut self, chunk: &mut RawChunk, num_records: usize) -> Option<Result<()>> {
+        let result = self.read_records_data(&mut chunk.data, num_records)?;
+        chunk.range = self.byte_range(self.offset - chunk.data.len() as u64);
+        Some(result.and_then(|()| self.count(chunk)))
+    }
+
+    fn count(&self, chunk: &mut RawChunk) -> Result<()> {
+        chunk.num_records = count_records(self.format, &chunk.data, chunk.range.start)?;
+        Ok(())
+    }
+
+    /// Range from `start` to the end of the chunk just read
+    fn byte_range(&self, start: u64) -> ByteRange {
+        let end = self.offset;
+        let mut range = ByteRange {
+            start,
+            end,
+            ..Default::default()
+        };
+        if let Some(blocks) = &self.blocks {
+            range.virtual_start = blocks.virtual_offset(start);
+            range.virtual_end = blocks.virtual_offset(end);
+            blocks.release(end);
+        }
+        range
+    }
+
+    /// Same as `read_chunk` but leaves counting the records to the caller
+    pub(crate) fn read_chunk_data(&mut self, chunk: &mut RawChunk) -> Option<Result<()>> {
+        let result = self.skip_blank_chunks(&mut chunk.data, Self::fill_chunk)?;
+        chunk.range = self.byte_range(self.offset - chunk.data.len() as u64);
+        Some(result)
+    }
+
+    fn read_records_data(&mut self, chunk: &mut Vec<u8>, num_records: usize) -> Option<Result<()>> {
+        self.skip_blank_chunks(chunk, |reader, chunk| reader.fill_records(chunk, num_records))
+    }
+
+    /// Reads chunks with `read` until one holds more than blank lines
+    ///
+    /// A run of blank lines inside the input is skipped rather than handed
+    /// out as an empty batch, and one at the end of the input ends it.
+    fn skip_blank_chunks<F>(&mut self, chunk: &mut Vec<u8>, mut read: F) -> Option<Result<()>>
+    where
+        F: FnMut(&mut Self, &mut Vec<u8>) -> Result<()>,
+    {
+        loop {
+            if let Err(e) = read(self, chunk) {
+                return Some(Err(e));
+            }
+            if !chunk.iter().all(u8::is_ascii_whitespace) {
+                return Some(Ok(()));
+            }
+            if self.eof && self.carry.is_empty() {
+                return None;
+            }
+        }
+    }
+
+    /// Fills `chunk` with the records of about one chunk size
+    fn fill_chunk(&mut self, chunk: &mut Vec<u8>) -> Result<()> {
+        chunk.clear();
+        chunk.append(&mut self.carry);
+
+        let mut target = self.chunk_size;
+        loop {
+            self.fill(chunk, target)?;
+            if self.eof {
+                self.check_last_chunk(chunk)?;
+                break;
+            }
+            match self.last_boundary(chunk)? {
+                Some(cut) => {
+                    self.carry.extend_from_slice(&chunk[cut..]);
+                    chunk.truncate(cut);
+                    break;
+                }
+                // A single record is larger than the chunk, keep reading
+                None => target = chunk.len() + self.chunk_size,
+            }
+        }
+
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Fills `chunk` with the next `num_records` records
+    fn fill_records(&mut self, chunk: &mut Vec<u8>, num_records: usize) -> Result<()> {
+        chunk.clear();
+        chunk.append(&mut self.carry);
+
+        let mut target = self.chunk_size;
+        loop {
+            if let Some(cut) = self.nth_boundary(chunk, num_records.max(1))? {
+                self.carry.extend_from_slice(&chunk[cut..]);
+                chunk.truncate(cut);
+                break;
+            }
+            if self.eof {
+                self.check_last_chunk(chunk)?;
+                break;
+            }
+            target = target.max(chunk.len() * 2);
+            self.fill(chunk, target)?;
+        }
+
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Reads from the inner reader until `chunk` holds `target` bytes or EOF
+    fn fill(&mut self, chunk: &mut Vec<u8>, target: usize) -> io::Result<()> {
+        while chunk.len() < target && !self.eof {
+            let start = chunk.len();
+            chunk.resize(target, 0);
+            match self.inner.read(&mut chunk[start..]) {
+                Ok(0) => {
+                    chunk.truncate(start);
+                    self.eof = true;
+                }
+                Ok(n) => chunk.truncate(start + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => chunk.truncate(start),
+                Err(e) => {
+                    chunk.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Position right after the last complete record in `chunk`
+    fn last_boundary(&self, chunk: &[u8]) -> Result<Option<usize>> {
+        match self.format {
+            Format::Fastq => fastq_record_ends(chunk, self.offset, false)
+                .try_fold(None, |_, end| end.map(Some)),
+            Format::Fasta => Ok(memchr::memmem::rfind(chunk, b"\n>").map(|pos| pos + 1)),
+        }
+    }
+
+    /// Position right after the `n`-th complete record in `chunk`
+    fn nth_boundary(&self, chunk: &[u8], n: usize) -> Result<Option<usize>> {
+        match self.format {
+            Format::Fastq => fastq_record_ends(chunk, self.offset, self.eof)
+                .nth(n - 1)
+                .transpose(),
+            Format::Fasta => Ok(memchr::memmem::find_iter(chunk, b"\n>")
+                .nth(n - 1)
+                .map(|pos| pos + 1)),
+        }
+    }
+
+    /// Checks the layout of the records of the chunk that ends the input
+    fn check_last_chunk(&self, chunk: &[u8]) -> Result<()> {
+        if self.format == Format::Fastq {
+            fastq_record_ends(chunk, self.offset, true).try_for_each(|end| end.map(|_| ()))?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: io::Read> RawChunkReader<BgzfReader<R>> {
+    /// Reads a BGZF stream, with virtual offsets in the byte ranges of the chunks
+    pub fn bgzf(inner: R, format: Format) -> Self {
+        let reader = BgzfReader::new(inner);
+        let blocks = reader.blocks();
+        Self::new(reader, format).with_bgzf_blocks(blocks)
+    }
+}
+
+/// Parses a raw chunk on a worker thread and hands each record to the processor
+pub(crate) fn process_chunk<T: ParallelProcessor>(
+    format: Format,
+    chunk: &RawChunk,
+    processor: &mut T,
+    global_idx: usize,
+) -> Result<()> {
+    match format {
+        Format::Fastq => {
+            let mut parser = seq_io::fastq::Reader::new(&chunk.data[..]);
+            let mut record_idx = 0;
+            while let Some(record) = parser.next() {
+                if let Err(err) = processor.process_record(record?, global_idx, record_idx) {
+                    let action = processor.on_record_error(&err, record_idx);
+                    apply_error_action(err, action)?;
+                }
+                record_idx += 1;
+            }
+        }
+        Format::Fasta => {
+            let mut parser = seq_io::fasta::Reader::new(&chunk.data[..]);
+            let mut record_idx = 0;
+            while let Some(record) = parser.next() {
+                if let Err(err) = processor.process_record(record?, global_idx, record_idx) {
+                    let action = processor.on_record_error(&err, record_idx);
+                    apply_error_action(err, action)?;
+                }
+                record_idx += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl BatchSize for RawChunk {
+    fn num_records(&self) -> usize {
+        self.num_records
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Chunks read without counting their records are empty only without data
+    fn is_empty_batch(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        crc.update(&self.data);
+    }
+}
+
+impl<R> ParallelReader<R, policy::StdPolicy> for RawChunkReader<R>
+where
+    R: io::Read + Send,
+{
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        let format = self.format;
+        let index = self.index.clone();
+        run_parallel::<D, _, RawChunk, _, _, _>(
+            self,
+            RangeWorker::new(processor),
+            &config,
+            |reader, chunk| reader.read_chunk(chunk),
+            |chunk, worker, global_idx| {
+                worker.range = Some(chunk.range);
+                process_chunk(format, chunk, &mut worker.processor, global_idx)?;
+                // Only once the batch succeeded, so a retried batch is indexed once
+                if let Some(index) = &index {
+                    index.add_chunk(format, chunk);
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EmptyBatches;
+    use crate::testutil::{assert_parallel_consistent, LengthDist, SyntheticReads};
+    use crate::MinimalRefRecord;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl ParallelProcessor for Counter {
+        fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+            &mut self,
+            _record: Rf,
+            _record_set_idx: usize,
+            _record_idx: usize,
+        ) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fastq_count_ignores_trailing_blank_lines() {
+        let data = b"@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nIIII\n";
+        let count = |data: &[u8]| count_records(Format::Fastq, data, 0).unwrap();
+        assert_eq!(count(data), 2);
+        assert_eq!(count(&data[..data.len() - 1]), 2);
+        assert_eq!(count(b"@r1\nACGT\n+\nIIII\n\n"), 1);
+        assert_eq!(count(b"@r1\r\nACGT\r\n+\r\nIIII\r\n\r\n"), 1);
+        assert_eq!(count(b""), 0);
+        let misaligned = count_records(Format::Fastq, b"@r1\nACGT\n+\nIIII\n\n@r2\n", 100);
+        assert!(misaligned.unwrap_err().to_string().contains("at byte 116"));
+    }
+
+    #[test]
+    fn chunks_count_every_synthetic_record() {
+        let inputs = [
+            SyntheticReads::fastq(1000).with_lengths(LengthDist::Uniform { min: 1, max: 300 }),
+            SyntheticReads::fasta(1000).with_line_width(60),
+        ];
+        for reads in &inputs {
+            let mut reader =
+                RawChunkReader::new(reads.reader(), reads.format()).with_chunk_size(4096);
+            let mut chunk = RawChunk::default();
+            let mut num_records = 0;
+            while let Some(result) = reader.read_chunk(&mut chunk) {
+                result.unwrap();
+                num_records += chunk.num_records;
+            }
+            assert_eq!(num_records, 1000);
+            // Parsed in parallel, every record is still seen exactly once
+            assert_parallel_consistent(reads, 4, Counter::default, |counter| {
+                counter.0.load(Ordering::Relaxed)
+            });
+        }
+    }
+
+    #[test]
+    fn blank_lines_inside_the_input_never_end_it() {
+        let data: String = (0..200)
+            .map(|i| format!(">r{}\nACGT\n{}", i, "\n".repeat(100)))
+            .collect();
+        let counter = Counter::default();
+        let config = ParallelConfig::new(2).with_empty_batches(EmptyBatches::Stop);
+        RawChunkReader::fasta(io::Cursor::new(data.into_bytes()))
+            .with_chunk_size(64)
+            .process_parallel_with_config(counter.clone(), config)
+            .unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 200);
+    }
+
+    #[test]
+    fn misaligned_fastq_fails_with_the_byte_offset() {
+        let record = "@r\nACGT\n+\nIIII\n";
+        let inputs = [
+            // A blank line between records
+            format!("{0}\n{0}", record),
+            // A wrapped sequence
+            format!("{0}@r\nACGT\nACGT\n+\nIIII\n{0}", record),
+        ];
+        for data in inputs {
+            let mut reader = RawChunkReader::fastq(io::Cursor::new(data.into_bytes()))
+                .with_chunk_size(8);
+            let mut chunk = RawChunk::default();
+            let err = std::iter::from_fn(|| reader.read_chunk(&mut chunk))
+                .find_map(Result::err)
+                .expect("a misaligned record is an error");
+            assert!(err.to_string().contains("at byte 15"), "{}", err);
+        }
+    }
+}
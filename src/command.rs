@@ -0,0 +1,89 @@
+//! Subprocess outputs of a [`ParallelWriter`](crate::ParallelWriter)
+//!
+//! The child reads the records on its stdin. Its stderr is passed through to
+//! ours and the end of it is kept, so that a failing child (e.g. an aligner
+//! rejecting its input) is reported with its own error message. Without the
+//! `parallel` feature, which the forwarding thread needs, the child writes to
+//! our stderr directly and failures are reported with their exit status only.
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// Bytes of the child's stderr kept for error messages
+const STDERR_TAIL: usize = 4096;
+
+/// Running child process fed by a writer
+pub(crate) struct CommandSink {
+    child: Child,
+    program: String,
+    /// Thread forwarding stderr, returning its tail
+    stderr: Option<JoinHandle<Vec<u8>>>,
+}
+
+impl CommandSink {
+    /// Spawns `command` with piped stdin and stderr
+    pub(crate) fn spawn(mut command: Command) -> Result<(Self, ChildStdin)> {
+        let program = command.get_program().to_string_lossy().into_owned();
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stderr(if cfg!(feature = "parallel") {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .spawn()
+            .with_context(|| format!("Failed to run {}", program))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stderr = child.stderr.take().map(|stderr| thread::spawn(|| forward_stderr(stderr)));
+        let sink = Self {
+            child,
+            program,
+            stderr,
+        };
+        Ok((sink, stdin))
+    }
+
+    /// Waits for the child, which must have its stdin closed, and fails unless it succeeded
+    pub(crate) fn wait(mut self) -> Result<()> {
+        let status = self
+            .child
+            .wait()
+            .with_context(|| format!("Failed to wait for {}", self.program))?;
+        let tail = self
+            .stderr
+            .take()
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+        if !status.success() {
+            let tail = String::from_utf8_lossy(&tail);
+            bail!("{} failed ({}): {}", self.program, status, tail.trim_end());
+        }
+        Ok(())
+    }
+
+    /// Stops the child without waiting for it to consume its input
+    pub(crate) fn kill(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Copies the child's stderr to ours and returns the last [`STDERR_TAIL`] bytes
+fn forward_stderr(mut stderr: impl Read) -> Vec<u8> {
+    let mut tail = VecDeque::with_capacity(STDERR_TAIL);
+    let mut buffer = [0; 8192];
+    loop {
+        let n = match stderr.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let _ = io::stderr().write_all(&buffer[..n]);
+        tail.extend(&buffer[..n]);
+        if tail.len() > STDERR_TAIL {
+            tail.drain(..tail.len() - STDERR_TAIL);
+        }
+    }
+    tail.into()
+}
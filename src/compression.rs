@@ -0,0 +1,177 @@
+//! Block-wise output compression
+//!
+//! Every batch is compressed on its own into one or more self-contained
+//! streams (gzip members, BGZF blocks or zstd frames). Concatenated, they form
+//! a valid compressed file, so the batches can be compressed on the workers
+//! and written in any grouping.
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+
+/// Largest uncompressed size of a BGZF block, as used by htslib
+pub(crate) const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Empty BGZF block that marks the end of a BGZF file
+pub(crate) const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compression format of an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    /// Blocked gzip, readable by any gzip reader and indexable by htslib
+    Bgzf,
+    Zstd,
+}
+
+impl Compression {
+    /// Picks the compression from the file extension (`.gz`, `.bgz`/`.bgzf`, `.zst`/`.zstd`)
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let extension = path.as_ref().extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some("gz") => Self::Gzip,
+            Some("bgz" | "bgzf") => Self::Bgzf,
+            Some("zst" | "zstd") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// Default compression level of the format
+    pub fn default_level(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Gzip | Self::Bgzf => 6,
+            Self::Zstd => 3,
+        }
+    }
+}
+
+/// Compression settings of a writer
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CompressionOptions {
+    pub(crate) compression: Compression,
+    /// Level, or the format's default if unset
+    pub(crate) level: Option<u32>,
+    /// Threads compressing each batch
+    pub(crate) threads: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            level: None,
+            threads: 1,
+        }
+    }
+}
+
+impl CompressionOptions {
+    fn level(&self) -> u32 {
+        self.level.unwrap_or(self.compression.default_level())
+    }
+
+    /// Compresses `data`, split across `threads` threads
+    ///
+    /// Without the `parallel` feature `data` is compressed on the calling thread.
+    pub(crate) fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let num_parts = self.threads.min(data.len() / BGZF_BLOCK_SIZE).max(1);
+        if num_parts == 1 || !cfg!(feature = "parallel") {
+            return self.compress_part(data);
+        }
+        // BGZF parts must end on a block boundary
+        let part_size = data
+            .len()
+            .div_ceil(num_parts)
+            .next_multiple_of(BGZF_BLOCK_SIZE);
+        let parts = thread::scope(|scope| {
+            let handles: Vec<_> = data
+                .chunks(part_size)
+                .map(|part| scope.spawn(move || self.compress_part(part)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(parts.concat())
+    }
+
+    fn compress_part(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let level = self.level();
+        match self.compression {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Bgzf => {
+                let mut out = Vec::with_capacity(data.len() / 3);
+                for block in data.chunks(BGZF_BLOCK_SIZE) {
+                    out.extend_from_slice(&bgzf_block(block, level)?);
+                }
+                Ok(out)
+            }
+            Compression::Zstd => {
+                let level = match level {
+                    0 | 1 => niffler::Level::One,
+                    2 => niffler::Level::Two,
+                    3 => niffler::Level::Three,
+                    4 => niffler::Level::Four,
+                    5 => niffler::Level::Five,
+                    6 => niffler::Level::Six,
+                    7 => niffler::Level::Seven,
+                    8 => niffler::Level::Eight,
+                    _ => niffler::Level::Nine,
+                };
+                let mut out = Vec::with_capacity(data.len() / 3);
+                {
+                    let mut writer = niffler::send::get_writer(
+                        Box::new(&mut out),
+                        niffler::Format::Zstd,
+                        level,
+                    )?;
+                    writer.write_all(data)?;
+                    writer.flush()?;
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Compresses at most [`BGZF_BLOCK_SIZE`] bytes into one BGZF block
+fn bgzf_block(data: &[u8], level: u32) -> Result<Vec<u8>> {
+    // The BC extra field holds the block size, patched in once it is known
+    let mut encoder = flate2::GzBuilder::new()
+        .extra(vec![b'B', b'C', 2, 0, 0, 0])
+        .write(Vec::new(), flate2::Compression::new(level));
+    encoder.write_all(data)?;
+    let mut block = encoder.finish()?;
+    let Ok(block_size) = u16::try_from(block.len() - 1) else {
+        bail!(
+            "BGZF block of {} bytes exceeds the format limit",
+            block.len()
+        );
+    };
+    block[16..18].copy_from_slice(&block_size.to_le_bytes());
+    Ok(block)
+}
+
+/// Compressed sizes of the BGZF blocks in `data`, read from their BC fields
+pub(crate) fn bgzf_block_sizes(mut data: &[u8]) -> Vec<usize> {
+    let mut sizes = Vec::new();
+    while data.len() >= 18 {
+        let size = u16::from_le_bytes([data[16], data[17]]) as usize + 1;
+        sizes.push(size);
+        data = &data[size.min(data.len())..];
+    }
+    sizes
+}
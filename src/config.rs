@@ -0,0 +1,546 @@
+//! Run configuration
+use anyhow::{bail, Context, Result};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::checkpoint::{Checkpoint, CheckpointOptions};
+#[cfg(feature = "checksum")]
+use crate::checksum::Checksums;
+use crate::dispatch::{DefaultDispatcher, Dispatcher};
+#[cfg(feature = "parallel")]
+use crate::handle::RunControl;
+use crate::metrics::Metrics;
+use crate::progress::InputProgress;
+use crate::report::ReportOptions;
+use crate::retry::RetryPolicy;
+use crate::schedule::Scheduler;
+use crate::stats::RunStats;
+
+/// Configuration of a parallel run
+///
+/// The type parameter selects the channel backend used between the reader
+/// and the workers (see [`crate::dispatch`]).
+pub struct ParallelConfig<D: Dispatcher = DefaultDispatcher> {
+    pub(crate) options: Options,
+    dispatcher: PhantomData<D>,
+}
+
+/// Settings of a run, independent of the channel backend
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    pub(crate) num_threads: usize,
+    pub(crate) max_records_per_sec: Option<u64>,
+    pub(crate) max_bytes_per_sec: Option<u64>,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+    pub(crate) report: Option<ReportOptions>,
+    pub(crate) scheduler: Option<SchedulerFactory>,
+    pub(crate) checkpoint: Option<CheckpointOptions>,
+    pub(crate) progress: Option<ProgressHook>,
+    /// Fewest active workers when scaling with the queue occupancy
+    pub(crate) min_threads: Option<usize>,
+    /// Most batches dispatched past the oldest unfinished one
+    pub(crate) reorder_window: Option<usize>,
+    /// Core reserved for the reader threads
+    pub(crate) reader_core: Option<usize>,
+    /// Runs the workers at a lower priority than the reader
+    pub(crate) high_priority_reader: bool,
+    pub(crate) backpressure: Backpressure,
+    pub(crate) empty_batches: EmptyBatches,
+    /// Processing errors tolerated before the run aborts
+    pub(crate) max_errors: u64,
+    /// Retries of batches failing with a retryable error
+    pub(crate) batch_retry: Option<RetryPolicy>,
+    /// Records the input must hold for the run to succeed
+    pub(crate) expected_records: Option<u64>,
+    /// Computes a checksum of every dispatched batch
+    pub(crate) batch_checksums: bool,
+    pub(crate) input_progress: Option<InputProgress>,
+    /// Set when the run was spawned with a handle
+    #[cfg(feature = "parallel")]
+    pub(crate) control: Option<Arc<RunControl>>,
+    #[cfg(feature = "checksum")]
+    pub(crate) checksums: Option<Checksums>,
+    /// Prefix of the names of the threads of the run
+    pub(crate) thread_name: String,
+    /// Stack size of the worker threads, the standard library default if unset
+    pub(crate) worker_stack_size: Option<usize>,
+    /// Record sets buffered per worker, on top of one per reader
+    pub(crate) record_sets_per_thread: usize,
+}
+
+/// Default prefix of thread names, giving e.g. `seqio-worker-3` and `seqio-reader`
+pub const DEFAULT_THREAD_NAME: &str = "seqio";
+
+/// Default number of record sets buffered per worker, for double buffering
+pub const DEFAULT_RECORD_SETS_PER_THREAD: usize = 2;
+
+#[cfg(feature = "parallel")]
+impl Options {
+    /// Builder of a thread named `<prefix>-<role>`
+    pub(crate) fn thread_builder(&self, role: &str) -> thread::Builder {
+        thread::Builder::new().name(format!("{}-{}", self.thread_name, role))
+    }
+
+    /// Builder of worker thread `thread_id`, with the configured stack size
+    pub(crate) fn worker_builder(&self, thread_id: usize) -> thread::Builder {
+        let builder = self.thread_builder(&format!("worker-{}", thread_id));
+        match self.worker_stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
+    }
+}
+
+/// What the reader does with a batch that holds no records
+///
+/// Empty batches are never dispatched, so processors and batch hooks only
+/// ever see batches with records, and they take no batch index. They occur
+/// e.g. when a record set is read from trailing blank lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyBatches {
+    /// Reads on, so the rest of the input is still processed
+    #[default]
+    Skip,
+    /// Treats the empty batch as the end of the input, for sources that signal the end of a stream this way
+    Stop,
+}
+
+/// What the reader does when the workers fall behind
+///
+/// The workers fall behind once every record set is either queued or being
+/// processed. Discarded records are counted in
+/// [`RunStats::dropped_records`](crate::RunStats::dropped_records).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Blocks the reader until a worker frees a record set
+    #[default]
+    Block,
+    /// Reads on and discards batches until a record set is free
+    Drop,
+    /// Reads on and keeps one in every `n` batches, blocking for a free record set for it
+    Sample(usize),
+}
+
+/// User hook called periodically with the progress of a run
+#[derive(Clone)]
+pub(crate) struct ProgressHook {
+    pub(crate) interval: Duration,
+    pub(crate) callback: Arc<dyn Fn(&RunStats) + Send + Sync>,
+}
+
+impl std::fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressHook")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Creates the scheduler of a run from the number of workers
+#[derive(Clone)]
+pub(crate) struct SchedulerFactory(Arc<dyn Fn(usize) -> Box<dyn Scheduler> + Send + Sync>);
+
+impl SchedulerFactory {
+    pub(crate) fn create(&self, num_workers: usize) -> Box<dyn Scheduler> {
+        (self.0)(num_workers)
+    }
+}
+
+impl std::fmt::Debug for SchedulerFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SchedulerFactory")
+    }
+}
+
+impl ParallelConfig {
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            options: Options {
+                num_threads: num_threads.max(1),
+                max_records_per_sec: None,
+                max_bytes_per_sec: None,
+                metrics: None,
+                report: None,
+                scheduler: None,
+                checkpoint: None,
+                progress: None,
+                min_threads: None,
+                reorder_window: None,
+                reader_core: None,
+                high_priority_reader: false,
+                backpressure: Backpressure::Block,
+                empty_batches: EmptyBatches::Skip,
+                max_errors: 0,
+                batch_retry: None,
+                expected_records: None,
+                batch_checksums: false,
+                input_progress: None,
+                #[cfg(feature = "parallel")]
+                control: None,
+                #[cfg(feature = "checksum")]
+                checksums: None,
+                thread_name: DEFAULT_THREAD_NAME.to_string(),
+                worker_stack_size: None,
+                record_sets_per_thread: DEFAULT_RECORD_SETS_PER_THREAD,
+            },
+            dispatcher: PhantomData,
+        }
+    }
+}
+
+impl<D: Dispatcher> ParallelConfig<D> {
+    /// Switches the channel backend
+    pub fn with_dispatcher<E: Dispatcher>(self) -> ParallelConfig<E> {
+        ParallelConfig {
+            options: self.options,
+            dispatcher: PhantomData,
+        }
+    }
+
+    /// Limits the rate at which the reader dispatches records
+    pub fn with_max_records_per_sec(mut self, max_records_per_sec: u64) -> Self {
+        self.options.max_records_per_sec = Some(max_records_per_sec.max(1));
+        self
+    }
+
+    /// Limits the rate at which the reader dispatches sequence data
+    ///
+    /// Bytes are counted over headers, sequences and qualities.
+    pub fn with_max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.options.max_bytes_per_sec = Some(max_bytes_per_sec.max(1));
+        self
+    }
+
+    /// Updates the given counters while the run progresses
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.options.metrics = Some(metrics);
+        self
+    }
+
+    /// Writes a JSON report of the run to `path` once it finishes
+    pub fn with_report<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.options.report.get_or_insert_with(Default::default).path = path.into();
+        self
+    }
+
+    /// Lists the given inputs in the JSON report
+    pub fn with_report_inputs<I, S>(mut self, inputs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let report = self.options.report.get_or_insert_with(Default::default);
+        report.inputs.extend(inputs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Assigns batches to workers through a custom [`Scheduler`] instead of the dispatcher channel
+    ///
+    /// `factory` is called with the number of workers at the start of every run.
+    pub fn with_scheduler<F, S>(mut self, factory: F) -> Self
+    where
+        F: Fn(usize) -> S + Send + Sync + 'static,
+        S: Scheduler + 'static,
+    {
+        let factory = move |num_workers| Box::new(factory(num_workers)) as Box<dyn Scheduler>;
+        self.options.scheduler = Some(SchedulerFactory(Arc::new(factory)));
+        self
+    }
+
+    /// Calls `callback` every `interval` with the progress so far
+    ///
+    /// The callback runs on a separate monitor thread, independent of batch
+    /// boundaries, so it keeps firing while a long batch is processed.
+    /// Without the `parallel` feature it runs between batches instead.
+    pub fn with_progress<F>(mut self, interval: Duration, callback: F) -> Self
+    where
+        F: Fn(&RunStats) + Send + Sync + 'static,
+    {
+        self.options.progress = Some(ProgressHook {
+            interval: interval.max(Duration::from_millis(1)),
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    /// Reports the input position counted by `progress` in the run's statistics
+    ///
+    /// Lets progress callbacks estimate the fraction complete and the time
+    /// left, see [`RunStats::eta`](crate::RunStats::eta).
+    pub fn with_input_progress(mut self, progress: &InputProgress) -> Self {
+        self.options.input_progress = Some(progress.clone());
+        self
+    }
+
+    /// Parks idle workers when the reader cannot keep up, keeping at least `min_threads` active
+    ///
+    /// The queue of read batches is sampled every few milliseconds: while it
+    /// stays empty the run is reader-bound and workers are parked one by one,
+    /// and once batches pile up parked workers are woken again. Requires the
+    /// default queue, not a custom scheduler.
+    pub fn with_worker_scaling(mut self, min_threads: usize) -> Self {
+        self.options.min_threads = Some(min_threads.max(1));
+        self
+    }
+
+    /// Lets workers run at most `batches` batches ahead of the oldest unfinished batch
+    ///
+    /// Ordered outputs such as [`ParallelWriter`](crate::ParallelWriter) hold
+    /// every batch finished ahead of a slow one until it is done. Without a
+    /// window the reader keeps dispatching batches behind a straggler, so the
+    /// held output is only bounded by the straggler's duration; with one the
+    /// reader waits once batch `oldest + batches` is due, bounding the held
+    /// output to `batches` batches. Larger windows tolerate more skew in
+    /// batch times, smaller ones use less memory. A window below the number
+    /// of threads leaves workers idle.
+    pub fn with_reorder_window(mut self, batches: usize) -> Self {
+        self.options.reorder_window = Some(batches.max(1));
+        self
+    }
+
+    /// Pins the reader thread to `core` and keeps the workers off it
+    ///
+    /// On a loaded machine a descheduled reader stalls every worker; a
+    /// reserved core prevents that. With several readers all of them share
+    /// the core. Fails the run if the reader cannot be pinned. Only has an
+    /// effect on Linux.
+    pub fn with_reader_core(mut self, core: usize) -> Self {
+        self.options.reader_core = Some(core);
+        self
+    }
+
+    /// Gives the reader thread priority over the workers
+    ///
+    /// Raising a thread's priority requires privileges, so the workers are
+    /// niced below the reader instead. Only has an effect on Linux.
+    pub fn with_high_priority_reader(mut self) -> Self {
+        self.options.high_priority_reader = true;
+        self
+    }
+
+    /// Names the threads of the run `<prefix>-worker-<id>`, `<prefix>-reader` and so on
+    ///
+    /// Threads are named `seqio-*` by default; a prefix per run tells the
+    /// runs of a pipeline apart in `perf`, `top -H` and debuggers. Thread
+    /// names longer than 15 bytes are truncated by Linux.
+    pub fn with_thread_name<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.options.thread_name = prefix.into();
+        self
+    }
+
+    /// Sets the stack size of the worker threads in bytes
+    ///
+    /// For processors recursing deeper than the default stack of 2 MiB
+    /// allows. The reader and helper threads keep the default.
+    pub fn with_worker_stack_size(mut self, bytes: usize) -> Self {
+        self.options.worker_stack_size = Some(bytes);
+        self
+    }
+
+    /// Buffers `record_sets` record sets per worker instead of two
+    ///
+    /// The pool of record sets bounds both the batches read ahead of the
+    /// workers and the memory they take. More sets absorb bursty processors
+    /// and uneven batch times, a single one halves the memory of the pool
+    /// at the cost of workers waiting for the reader more often. Every
+    /// reader gets one more set to read into.
+    pub fn with_record_sets_per_thread(mut self, record_sets: usize) -> Self {
+        self.options.record_sets_per_thread = record_sets.max(1);
+        self
+    }
+
+    /// Sets what the reader does when the workers fall behind
+    ///
+    /// Dropping or sampling keeps the reader at the pace of its input, e.g.
+    /// for live QC of a sequencer's output, at the cost of completeness.
+    /// Cannot be combined with checkpoints.
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.options.backpressure = match backpressure {
+            Backpressure::Sample(n) => Backpressure::Sample(n.max(1)),
+            backpressure => backpressure,
+        };
+        self
+    }
+
+    /// Sets what the reader does with a batch that holds no records, skipping it by default
+    pub fn with_empty_batches(mut self, empty_batches: EmptyBatches) -> Self {
+        self.options.empty_batches = empty_batches;
+        self
+    }
+
+    /// Tolerates up to `max_errors` processing errors, counted across threads, before aborting
+    ///
+    /// Covers errors returned for records (when `on_record_error` aborts) and
+    /// errors parsing batches on the workers; the rest of a batch that failed
+    /// to parse is skipped. Errors of the reader thread, whose input cannot be
+    /// resynchronized, and of the batch and thread hooks always abort. The
+    /// tolerated errors are listed in
+    /// [`RunStats::error_messages`](crate::RunStats::error_messages) and the
+    /// run report.
+    pub fn with_max_errors(mut self, max_errors: u64) -> Self {
+        self.options.max_errors = max_errors;
+        self
+    }
+
+    /// Processes a batch again when it fails with a [`Retryable`](crate::Retryable) error
+    ///
+    /// The worker that failed the batch waits for the backoff of `policy`
+    /// and hands the whole batch to its processor again, up to
+    /// `max_retries` times, before the error counts against
+    /// [`with_max_errors`](Self::with_max_errors) or aborts the run. A
+    /// retryable record error fails its batch even while the error budget
+    /// lasts, unless `on_record_error` skips or counts it. Before every retry
+    /// the processor's `on_batch_retry` hook undoes the failed attempt (the
+    /// built-in writers drop the records they buffered), and errors the
+    /// attempt tolerated go back to the budget. Retried batches are counted
+    /// in [`RunStats::retried_batches`](crate::RunStats::retried_batches).
+    pub fn with_batch_retry(mut self, policy: RetryPolicy) -> Self {
+        self.options.batch_retry = Some(policy);
+        self
+    }
+
+    /// Includes the checksums of the inputs opened through `checksums` in the run's statistics and report
+    #[cfg(feature = "checksum")]
+    pub fn with_checksums(mut self, checksums: &Checksums) -> Self {
+        self.options.checksums = Some(checksums.clone());
+        self
+    }
+
+    /// Computes a CRC-32 of every dispatched batch, returned in [`RunStats::batch_checksums`] and the run report
+    ///
+    /// Two runs over the same input yield the same sequence of checksums
+    /// exactly when they cut the input into the same batches, whatever
+    /// their thread counts, which rules out the partitioning when chasing
+    /// nondeterministic results. Costs one pass over each batch on the
+    /// reader thread.
+    pub fn with_batch_checksums(mut self) -> Self {
+        self.options.batch_checksums = true;
+        self
+    }
+
+    /// Fails the run unless it reads exactly `records` records
+    ///
+    /// Catches inputs that end early without a parse error, such as gzip
+    /// files truncated at a member boundary. Records dropped by the
+    /// backpressure policy and records covered by a resumed checkpoint count
+    /// as read.
+    pub fn with_expected_records(mut self, records: u64) -> Self {
+        self.options.expected_records = Some(records);
+        self
+    }
+
+    /// Expects as many records as the `samtools faidx`/`fqidx` index at `path` lists, see [`with_expected_records`](Self::with_expected_records)
+    pub fn with_expected_records_from_index<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let index = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read index {}", path.display()))?;
+        let records = index.lines().filter(|line| !line.trim().is_empty()).count();
+        Ok(self.with_expected_records(records as u64))
+    }
+
+    /// Rewrites a checkpoint file at `path` every `every_batches` completed batches
+    pub fn with_checkpoint<P: Into<PathBuf>>(mut self, path: P, every_batches: usize) -> Self {
+        let checkpoint = self.options.checkpoint.get_or_insert_with(Default::default);
+        checkpoint.path = path.into();
+        checkpoint.every_batches = every_batches.max(1);
+        self
+    }
+
+    /// Records the length of the given output files in every checkpoint
+    pub fn with_checkpoint_outputs<I, P>(mut self, outputs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        let checkpoint = self.options.checkpoint.get_or_insert_with(Default::default);
+        checkpoint.outputs.extend(outputs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Continues from the checkpoint file set with `with_checkpoint`, if it exists
+    ///
+    /// Truncates the checkpointed outputs to their recorded length, so they
+    /// must be opened for appending afterwards. The run then skips the batches
+    /// covered by the checkpoint.
+    pub fn resume_from_checkpoint(mut self) -> Result<Self> {
+        let Some(options) = self.options.checkpoint.as_mut() else {
+            bail!("resume_from_checkpoint requires with_checkpoint");
+        };
+        let checkpoint = Checkpoint::load(&options.path)?;
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.truncate_outputs()?;
+        }
+        options.resume = checkpoint;
+        Ok(self)
+    }
+
+    /// Checkpoint the run continues from, if any
+    pub fn resumed_checkpoint(&self) -> Option<&Checkpoint> {
+        self.options.checkpoint.as_ref()?.resume.as_ref()
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.options.num_threads
+    }
+
+    /// Whether any rate limit is set
+    pub(crate) fn is_throttled(&self) -> bool {
+        self.options.max_records_per_sec.is_some() || self.options.max_bytes_per_sec.is_some()
+    }
+}
+
+impl<D: Dispatcher> Clone for ParallelConfig<D> {
+    fn clone(&self) -> Self {
+        Self {
+            options: self.options.clone(),
+            dispatcher: PhantomData,
+        }
+    }
+}
+
+impl<D: Dispatcher> std::fmt::Debug for ParallelConfig<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParallelConfig")
+            .field("options", &self.options)
+            .field("dispatcher", &std::any::type_name::<D>())
+            .finish()
+    }
+}
+
+/// Rate limiter shared by the reader threads of a run
+pub(crate) struct Throttle {
+    start: Instant,
+    records: u64,
+    bytes: u64,
+    max_records_per_sec: Option<u64>,
+    max_bytes_per_sec: Option<u64>,
+}
+
+impl Throttle {
+    pub(crate) fn new<D: Dispatcher>(config: &ParallelConfig<D>) -> Self {
+        Self {
+            start: Instant::now(),
+            records: 0,
+            bytes: 0,
+            max_records_per_sec: config.options.max_records_per_sec,
+            max_bytes_per_sec: config.options.max_bytes_per_sec,
+        }
+    }
+
+    /// Accounts for a batch and returns how long to wait before dispatching it
+    pub(crate) fn delay(&mut self, records: usize, bytes: usize) -> Duration {
+        self.records += records as u64;
+        self.bytes += bytes as u64;
+        let by_records = self
+            .max_records_per_sec
+            .map_or(0.0, |rate| self.records as f64 / rate as f64);
+        let by_bytes = self
+            .max_bytes_per_sec
+            .map_or(0.0, |rate| self.bytes as f64 / rate as f64);
+        Duration::from_secs_f64(by_records.max(by_bytes)).saturating_sub(self.start.elapsed())
+    }
+}
@@ -0,0 +1,77 @@
+//! Record counting without parsing
+//!
+//! Counting only needs record boundaries, so the input is split into raw
+//! chunks as in [`RawChunkReader`] and the workers count the records of each
+//! chunk by scanning for line breaks (FASTQ) or headers (FASTA). No record is
+//! parsed, but FASTQ input out of the four-line layout fails with the byte
+//! offset of the first misaligned record rather than giving a wrong count.
+use anyhow::Result;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::chunk::{count_records, RawChunk, RawChunkReader};
+use crate::config::ParallelConfig;
+use crate::fastx::open_path;
+use crate::macro_impl::{run_parallel, WorkerHooks};
+
+/// Worker that adds the record count of every chunk to a shared total
+#[derive(Clone, Default)]
+struct RecordCounter {
+    total: Arc<AtomicU64>,
+}
+
+impl WorkerHooks for RecordCounter {
+    fn set_thread_id(&mut self, _thread_id: usize) {}
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Counts the records of a (possibly compressed) FASTA/FASTQ file
+///
+/// FASTQ input must use the standard four-line layout.
+pub fn count_records_parallel<P: AsRef<Path>>(path: P, num_threads: usize) -> Result<u64> {
+    let (handle, format) = open_path(path)?;
+    let reader = RawChunkReader::new(handle, format);
+    let counter = RecordCounter::default();
+    run_parallel::<_, _, RawChunk, _, _, _>(
+        reader,
+        counter.clone(),
+        &ParallelConfig::new(num_threads),
+        |reader, chunk| reader.read_chunk_data(chunk),
+        |chunk, counter, _| {
+            let num_records = count_records(format, &chunk.data, chunk.range.start)?;
+            counter.total.fetch_add(num_records as u64, Ordering::Relaxed);
+            Ok(())
+        },
+    )?;
+    Ok(counter.total.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misaligned_fastq_is_an_error_not_a_count() {
+        let path =
+            std::env::temp_dir().join(format!("count_misaligned_{}.fq", std::process::id()));
+        std::fs::write(&path, "@r1\nACGT\n+\nIIII\n@r2\nACGT\nACGT\n+\nIIII\n").unwrap();
+        let result = count_records_parallel(&path, 2);
+        std::fs::remove_file(&path).unwrap();
+        let err = result.unwrap_err();
+        assert!(format!("{:#}", err).contains("at byte 16"), "{:#}", err);
+    }
+}
@@ -0,0 +1,689 @@
+//! Exact-duplicate detection
+//!
+//! Every record (or pair) is reduced to a 128-bit hash of its sequence, of
+//! its sequence and qualities, or of its ID, and inserted into a set shared
+//! by all workers. The set is split into shards with their own locks, so
+//! workers rarely wait on each other. A record is a duplicate if an equal
+//! record was inserted before it; which copy counts as the first one depends
+//! on thread timing, but exactly one copy of every distinct record is kept.
+//!
+//! In [exact](DuplicateSet::exact) mode the set keeps the keys themselves
+//! instead of their hashes, so two distinct records can never collide, and a
+//! [memory limit](DuplicateSet::with_memory_limit) bounds the size of the
+//! set. [`DedupWriter`] and [`PairedDedupWriter`] write the first copies to
+//! an output from any run.
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::fastx::FastxReader;
+use crate::header::split_id;
+use crate::paired::PairedReader;
+use crate::processor::{PairedParallelProcessor, ParallelProcessor};
+use crate::reader::{PairedParallelReader, ParallelReader};
+use crate::writer::ParallelWriter;
+use crate::MinimalRefRecord;
+
+/// Number of independently locked shards of a [`DuplicateSet`]
+const NUM_SHARDS: usize = 64;
+
+/// Estimated memory of a hash in a [`DuplicateSet`], table overhead included
+const HASH_ENTRY_BYTES: u64 = 24;
+
+/// Estimated memory of an exact key on top of its bytes
+const KEY_ENTRY_BYTES: u64 = 24;
+
+/// Part of a record that decides whether two records are duplicates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupKey {
+    /// The (joined) sequence
+    #[default]
+    Sequence,
+    /// The sequence and the qualities
+    SequenceQuality,
+    /// The ID, the header up to the first whitespace
+    Id,
+}
+
+/// Counts of a duplicate detection run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DuplicateStats {
+    /// Records (or pairs) seen
+    pub records: u64,
+    /// Records (or pairs) equal to an earlier one
+    pub duplicates: u64,
+    /// Records (or pairs) kept without being added to the set, as it was at its memory limit
+    pub untracked: u64,
+}
+
+impl DuplicateStats {
+    pub fn unique(&self) -> u64 {
+        self.records - self.duplicates
+    }
+
+    /// Fraction of the records that are duplicates
+    pub fn duplicate_rate(&self) -> f64 {
+        if self.records == 0 {
+            return 0.0;
+        }
+        self.duplicates as f64 / self.records as f64
+    }
+}
+
+/// Hashes are already uniform, so the shards use their low bits as is
+#[derive(Default)]
+struct PassThroughHasher(u64);
+
+impl Hasher for PassThroughHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8) | u64::from(byte);
+        }
+    }
+
+    fn write_u128(&mut self, value: u128) {
+        self.0 = value as u64;
+    }
+}
+
+/// Hashes, or keys in exact mode, of one shard
+#[derive(Default)]
+struct ShardSet {
+    hashes: HashSet<u128, BuildHasherDefault<PassThroughHasher>>,
+    keys: HashSet<Box<[u8]>>,
+}
+
+type Shard = Mutex<ShardSet>;
+
+/// Set of record hashes shared by all workers of a run
+///
+/// Can also be used from custom processors through
+/// [`insert_record`](Self::insert_record) and [`insert_pair`](Self::insert_pair).
+/// Processors run with
+/// [`ParallelConfig::with_batch_retry`](crate::ParallelConfig::with_batch_retry)
+/// insert through [`insert_record_tracked`](Self::insert_record_tracked) instead
+/// and [`undo`](Self::undo) the batch in `on_batch_retry`, or the records of a
+/// retried batch are found as duplicates of themselves.
+pub struct DuplicateSet {
+    key: DedupKey,
+    exact: bool,
+    memory_limit: u64,
+    shards: Vec<Shard>,
+    /// Estimated memory of the hashes or keys inserted so far
+    memory: AtomicU64,
+    records: AtomicU64,
+    duplicates: AtomicU64,
+    untracked: AtomicU64,
+}
+
+impl DuplicateSet {
+    pub fn new(key: DedupKey) -> Self {
+        Self {
+            key,
+            exact: false,
+            memory_limit: u64::MAX,
+            shards: (0..NUM_SHARDS).map(|_| Shard::default()).collect(),
+            memory: AtomicU64::new(0),
+            records: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            untracked: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps the keys themselves instead of their 128-bit hashes
+    ///
+    /// Distinct records are then never taken for duplicates, at the cost of
+    /// memory: every key takes its full length, e.g. the whole sequence with
+    /// [`DedupKey::Sequence`].
+    pub fn exact(mut self) -> Self {
+        self.exact = true;
+        self
+    }
+
+    /// Stops adding to the set once it takes about `bytes` of memory
+    ///
+    /// Records are still checked against the set, but new ones are kept
+    /// without being added and counted in [`DuplicateStats::untracked`], so
+    /// their later copies are kept too. No record is dropped by mistake.
+    pub fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Estimated memory of the set, in bytes
+    pub fn memory_usage(&self) -> u64 {
+        self.memory.load(Ordering::Relaxed)
+    }
+
+    /// Inserts a record and returns whether it is the first of its kind
+    pub fn insert_record<'a, Rf: MinimalRefRecord<'a>>(&self, record: &Rf) -> bool {
+        let (hash, key) = self.record_key(&[record]);
+        self.insert(hash, key, None)
+    }
+
+    /// Inserts a pair and returns whether it is the first of its kind
+    ///
+    /// Pairs are duplicates only if both mates are.
+    pub fn insert_pair<'a, Rf: MinimalRefRecord<'a>>(&self, record1: &Rf, record2: &Rf) -> bool {
+        let (hash, key) = self.record_key(&[record1, record2]);
+        self.insert(hash, key, None)
+    }
+
+    /// Inserts a record like [`insert_record`](Self::insert_record), keeping the insertion in `batch`
+    pub fn insert_record_tracked<'a, Rf: MinimalRefRecord<'a>>(
+        &self,
+        record: &Rf,
+        batch: &mut BatchInsertions,
+    ) -> bool {
+        let (hash, key) = self.record_key(&[record]);
+        self.insert(hash, key, Some(batch))
+    }
+
+    /// Inserts a pair like [`insert_pair`](Self::insert_pair), keeping the insertion in `batch`
+    pub fn insert_pair_tracked<'a, Rf: MinimalRefRecord<'a>>(
+        &self,
+        record1: &Rf,
+        record2: &Rf,
+        batch: &mut BatchInsertions,
+    ) -> bool {
+        let (hash, key) = self.record_key(&[record1, record2]);
+        self.insert(hash, key, Some(batch))
+    }
+
+    /// Takes the insertions of `batch` back out of the set and its counts
+    pub fn undo(&self, batch: &mut BatchInsertions) {
+        for insertion in batch.0.drain(..) {
+            self.records.fetch_sub(1, Ordering::Relaxed);
+            match insertion.outcome {
+                Outcome::Duplicate => {
+                    self.duplicates.fetch_sub(1, Ordering::Relaxed);
+                }
+                Outcome::Untracked => {
+                    self.untracked.fetch_sub(1, Ordering::Relaxed);
+                }
+                Outcome::Added { cost } => {
+                    let mut shard = self.shards[shard_of(insertion.hash)].lock();
+                    if self.exact {
+                        shard.keys.remove(&insertion.key[..]);
+                    } else {
+                        shard.hashes.remove(&insertion.hash);
+                    }
+                    self.memory.fetch_sub(cost, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Hash of `records` under the set's key, and the key itself in exact mode
+    fn record_key<'a, Rf: MinimalRefRecord<'a>>(&self, records: &[&Rf]) -> (u128, Vec<u8>) {
+        let mut hasher = RecordHasher::new();
+        let mut key = Vec::new();
+        for record in records {
+            hasher.add(self.key, *record);
+            if self.exact {
+                append_key(self.key, *record, &mut key);
+            }
+        }
+        (hasher.finish(), key)
+    }
+
+    /// Inserts `hash`, or `key` in exact mode, unless the memory limit is reached
+    fn insert(&self, hash: u128, mut key: Vec<u8>, batch: Option<&mut BatchInsertions>) -> bool {
+        let mut shard = self.shards[shard_of(hash)].lock();
+        self.records.fetch_add(1, Ordering::Relaxed);
+        let present = if self.exact {
+            shard.keys.contains(&key[..])
+        } else {
+            shard.hashes.contains(&hash)
+        };
+        let cost = if self.exact {
+            key.len() as u64 + KEY_ENTRY_BYTES
+        } else {
+            HASH_ENTRY_BYTES
+        };
+        let outcome = if present {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            Outcome::Duplicate
+        } else if self.memory.load(Ordering::Relaxed).saturating_add(cost) > self.memory_limit {
+            self.untracked.fetch_add(1, Ordering::Relaxed);
+            Outcome::Untracked
+        } else {
+            self.memory.fetch_add(cost, Ordering::Relaxed);
+            if self.exact {
+                // The batch keeps its own copy to undo the insertion
+                let stored = match batch {
+                    Some(_) => key.clone(),
+                    None => std::mem::take(&mut key),
+                };
+                shard.keys.insert(stored.into_boxed_slice());
+            } else {
+                shard.hashes.insert(hash);
+            }
+            Outcome::Added { cost }
+        };
+        let first = outcome != Outcome::Duplicate;
+        if let Some(batch) = batch {
+            batch.0.push(Insertion { hash, key, outcome });
+        }
+        first
+    }
+
+    pub fn stats(&self) -> DuplicateStats {
+        DuplicateStats {
+            records: self.records.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            untracked: self.untracked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Shard holding `hash`
+fn shard_of(hash: u128) -> usize {
+    (hash >> 64) as usize % NUM_SHARDS
+}
+
+/// What inserting a record did to a [`DuplicateSet`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Duplicate,
+    Untracked,
+    /// Added to the set, taking `cost` bytes
+    Added { cost: u64 },
+}
+
+#[derive(Debug, Clone)]
+struct Insertion {
+    hash: u128,
+    /// Empty unless the set is exact
+    key: Vec<u8>,
+    outcome: Outcome,
+}
+
+/// Insertions into a [`DuplicateSet`] during the current batch
+///
+/// Cleared once the batch is complete, or taken back out of the set with
+/// [`DuplicateSet::undo`] before the batch is processed again.
+#[derive(Debug, Clone, Default)]
+pub struct BatchInsertions(Vec<Insertion>);
+
+impl BatchInsertions {
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Appends the parts of `record` compared under `key`, each prefixed by its length
+fn append_key<'a, Rf: MinimalRefRecord<'a>>(key: DedupKey, record: &Rf, out: &mut Vec<u8>) {
+    let mut push = |part: &[u8]| {
+        out.extend_from_slice(&(part.len() as u64).to_le_bytes());
+        out.extend_from_slice(part);
+    };
+    match key {
+        DedupKey::Sequence => push(&record.ref_full_seq()),
+        DedupKey::SequenceQuality => {
+            push(&record.ref_full_seq());
+            push(record.ref_qual());
+        }
+        DedupKey::Id => push(split_id(record.ref_head()).0),
+    }
+}
+
+/// 128-bit hash from two differently seeded 64-bit hashers
+struct RecordHasher([DefaultHasher; 2]);
+
+impl RecordHasher {
+    fn new() -> Self {
+        let mut hashers: [DefaultHasher; 2] = Default::default();
+        for (seed, hasher) in hashers.iter_mut().enumerate() {
+            hasher.write_usize(seed);
+        }
+        Self(hashers)
+    }
+
+    fn add<'a, Rf: MinimalRefRecord<'a>>(&mut self, key: DedupKey, record: &Rf) {
+        if key == DedupKey::Id {
+            let (id, _) = split_id(record.ref_head());
+            for hasher in &mut self.0 {
+                id.hash(hasher);
+            }
+            return;
+        }
+        let seq = record.ref_full_seq();
+        for hasher in &mut self.0 {
+            seq.hash(hasher);
+            if key == DedupKey::SequenceQuality {
+                record.ref_qual().hash(hasher);
+            }
+        }
+    }
+
+    fn finish(&self) -> u128 {
+        (u128::from(self.0[0].finish()) << 64) | u128::from(self.0[1].finish())
+    }
+}
+
+/// Writer that drops the records already in a [`DuplicateSet`]
+///
+/// Every record is inserted into the set, which is shared by all clones, and
+/// only the first copies are written to the output. Run a clone and call
+/// [`finish`](Self::finish) once the run is done.
+#[derive(Clone)]
+pub struct DedupWriter {
+    set: Arc<DuplicateSet>,
+    out: Option<ParallelWriter>,
+    batch: BatchInsertions,
+}
+
+impl DedupWriter {
+    pub fn new(out: ParallelWriter, set: DuplicateSet) -> Self {
+        Self {
+            set: Arc::new(set),
+            out: Some(out),
+            batch: BatchInsertions::default(),
+        }
+    }
+
+    /// Only counts the duplicates
+    fn counter(set: DuplicateSet) -> Self {
+        Self {
+            set: Arc::new(set),
+            out: None,
+            batch: BatchInsertions::default(),
+        }
+    }
+
+    pub fn stats(&self) -> DuplicateStats {
+        self.set.stats()
+    }
+
+    /// Finishes the output and returns the counts of the run
+    pub fn finish(self) -> Result<DuplicateStats> {
+        if let Some(out) = self.out {
+            out.finish()?;
+        }
+        Ok(self.set.stats())
+    }
+}
+
+impl ParallelProcessor for DedupWriter {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        let first = self.set.insert_record_tracked(&record, &mut self.batch);
+        if let Some(out) = &mut self.out {
+            out.skip(record_set_idx);
+            if first {
+                out.write_record(record_set_idx, &record)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.batch.clear();
+        match &mut self.out {
+            Some(out) => out.finish_batch(),
+            None => Ok(()),
+        }
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.set.undo(&mut self.batch);
+        if let Some(out) = &mut self.out {
+            out.discard_batch();
+        }
+        Ok(())
+    }
+}
+
+/// Pair-aware [`DedupWriter`], which drops a pair only if both mates equal an earlier pair
+#[derive(Clone)]
+pub struct PairedDedupWriter {
+    set: Arc<DuplicateSet>,
+    out: Option<(ParallelWriter, ParallelWriter)>,
+    batch: BatchInsertions,
+}
+
+impl PairedDedupWriter {
+    pub fn new(out1: ParallelWriter, out2: ParallelWriter, set: DuplicateSet) -> Self {
+        Self {
+            set: Arc::new(set),
+            out: Some((out1, out2)),
+            batch: BatchInsertions::default(),
+        }
+    }
+
+    /// Only counts the duplicates
+    fn counter(set: DuplicateSet) -> Self {
+        Self {
+            set: Arc::new(set),
+            out: None,
+            batch: BatchInsertions::default(),
+        }
+    }
+
+    pub fn stats(&self) -> DuplicateStats {
+        self.set.stats()
+    }
+
+    /// Finishes both outputs and returns the counts of the run
+    pub fn finish(self) -> Result<DuplicateStats> {
+        if let Some((out1, out2)) = self.out {
+            out1.finish()?;
+            out2.finish()?;
+        }
+        Ok(self.set.stats())
+    }
+}
+
+impl PairedParallelProcessor for PairedDedupWriter {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        let first = self.set.insert_pair_tracked(&record1, &record2, &mut self.batch);
+        if let Some((out1, out2)) = &mut self.out {
+            out1.skip(record_set_idx);
+            out2.skip(record_set_idx);
+            if first {
+                out1.write_record(record_set_idx, &record1)?;
+                out2.write_record(record_set_idx, &record2)?;
+            }
+        }
+        Ok((record1, record2))
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.batch.clear();
+        match &mut self.out {
+            Some((out1, out2)) => {
+                out1.finish_batch()?;
+                out2.finish_batch()
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.set.undo(&mut self.batch);
+        if let Some((out1, out2)) = &mut self.out {
+            out1.discard_batch();
+            out2.discard_batch();
+        }
+        Ok(())
+    }
+}
+
+/// Counts the exact duplicates of a (possibly compressed) FASTA/FASTQ file
+pub fn duplicate_stats_parallel<P: AsRef<Path>>(
+    path: P,
+    key: DedupKey,
+    num_threads: usize,
+) -> Result<DuplicateStats> {
+    let counter = DedupWriter::counter(DuplicateSet::new(key));
+    FastxReader::from_path(path)?.process_parallel(counter.clone(), num_threads)?;
+    Ok(counter.stats())
+}
+
+/// Counts the exact duplicate pairs of (possibly compressed) R1/R2 files
+pub fn duplicate_stats_paired_parallel<P1, P2>(
+    r1: P1,
+    r2: P2,
+    key: DedupKey,
+    num_threads: usize,
+) -> Result<DuplicateStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let counter = PairedDedupWriter::counter(DuplicateSet::new(key));
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(counter.clone(), num_threads)?;
+    Ok(counter.stats())
+}
+
+/// Writes the first copy of every distinct record of `input` to `output`
+///
+/// Records are written in input order. Outputs are compressed according to
+/// their extension.
+pub fn dedup_parallel<P1, P2>(
+    input: P1,
+    output: P2,
+    key: DedupKey,
+    num_threads: usize,
+) -> Result<DuplicateStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let writer = DedupWriter::new(ParallelWriter::from_path(output)?, DuplicateSet::new(key));
+    FastxReader::from_path(input)?.process_parallel(writer.clone(), num_threads)?;
+    writer.finish()
+}
+
+/// Writes the first copy of every distinct pair of R1/R2 files to `out1`/`out2`
+///
+/// Pairs are written in input order and kept unless both mates equal an
+/// earlier pair.
+pub fn dedup_paired_parallel<P1, P2, P3, P4>(
+    r1: P1,
+    r2: P2,
+    out1: P3,
+    out2: P4,
+    key: DedupKey,
+    num_threads: usize,
+) -> Result<DuplicateStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    P4: AsRef<Path>,
+{
+    let writer = PairedDedupWriter::new(
+        ParallelWriter::from_path(out1)?,
+        ParallelWriter::from_path(out2)?,
+        DuplicateSet::new(key),
+    );
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(writer.clone(), num_threads)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::SliceRecord;
+
+    fn record<'a>(head: &'a str, seq: &'a str, qual: &'a str) -> SliceRecord<'a> {
+        SliceRecord {
+            head: head.as_bytes(),
+            seq: seq.as_bytes(),
+            qual: qual.as_bytes(),
+        }
+    }
+
+    fn inserted(set: &DuplicateSet, records: &[SliceRecord]) -> Vec<bool> {
+        records.iter().map(|r| set.insert_record(r)).collect()
+    }
+
+    #[test]
+    fn exact_and_hashed_sets_find_the_same_duplicates() {
+        let records = [
+            record("r1", "ACGT", "IIII"),
+            record("r2", "ACGT", "IIII"),
+            record("r3", "ACGA", "IIII"),
+            record("r4", "ACGA", "IIII"),
+        ];
+        let hashed = DuplicateSet::new(DedupKey::Sequence);
+        let exact = DuplicateSet::new(DedupKey::Sequence).exact();
+        assert_eq!(inserted(&hashed, &records), [true, false, true, false]);
+        assert_eq!(inserted(&exact, &records), [true, false, true, false]);
+        assert_eq!(hashed.stats(), exact.stats());
+        // Exact keys take their length prefix and bytes on top of the entry
+        assert_eq!(hashed.memory_usage(), 2 * HASH_ENTRY_BYTES);
+        assert_eq!(exact.memory_usage(), 2 * (8 + 4 + KEY_ENTRY_BYTES));
+    }
+
+    #[test]
+    fn key_decides_what_is_a_duplicate() {
+        let records = [
+            record("r1 lane=1", "ACGT", "IIII"),
+            record("r1 lane=2", "ACGT", "####"),
+            record("r2", "TTTT", "IIII"),
+        ];
+        let first = |key| inserted(&DuplicateSet::new(key), &records);
+        assert_eq!(first(DedupKey::Sequence), [true, false, true]);
+        assert_eq!(first(DedupKey::SequenceQuality), [true, true, true]);
+        assert_eq!(first(DedupKey::Id), [true, false, true]);
+    }
+
+    #[test]
+    fn records_past_the_memory_limit_are_kept_untracked() {
+        let set = DuplicateSet::new(DedupKey::Sequence).with_memory_limit(2 * HASH_ENTRY_BYTES);
+        let records = [
+            record("r1", "AAAA", "IIII"),
+            record("r2", "CCCC", "IIII"),
+            record("r3", "GGGG", "IIII"),
+            record("r4", "GGGG", "IIII"),
+            record("r5", "AAAA", "IIII"),
+        ];
+        // The copies of untracked records are kept, those of tracked ones still dropped
+        assert_eq!(inserted(&set, &records), [true, true, true, true, false]);
+        let stats = set.stats();
+        assert_eq!((stats.records, stats.duplicates, stats.untracked), (5, 1, 2));
+        assert_eq!(set.memory_usage(), 2 * HASH_ENTRY_BYTES);
+    }
+
+    #[test]
+    fn pairs_are_duplicates_only_if_both_mates_are() {
+        for set in [
+            DuplicateSet::new(DedupKey::Sequence),
+            DuplicateSet::new(DedupKey::Sequence).exact(),
+        ] {
+            let (ac, gt) = (record("a", "AC", "II"), record("a", "GT", "II"));
+            let (acg, t) = (record("b", "ACG", "III"), record("b", "T", "I"));
+            let tt = record("c", "TT", "II");
+            assert!(set.insert_pair(&ac, &gt));
+            assert!(!set.insert_pair(&ac, &gt));
+            assert!(set.insert_pair(&ac, &tt));
+            assert!(set.insert_pair(&gt, &ac));
+            // The mates are not simply joined
+            assert!(set.insert_pair(&acg, &t));
+        }
+    }
+}
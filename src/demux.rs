@@ -0,0 +1,564 @@
+//! Sample-sheet driven demultiplexing
+//!
+//! Every record (or pair) is assigned to the sample whose barcode matches the
+//! record's barcode within a number of mismatches, and written to that
+//! sample's output file(s). Records without a unique match go to the
+//! `Undetermined` output. Workers collect records per sample and append them
+//! to the shared output files at the end of each batch, so the order of
+//! records within an output follows batch completion, not input order. In
+//! paired mode both mates of a batch are appended under one lock, which keeps
+//! R1 and R2 outputs in sync.
+//!
+//! Barcodes can also come from separate index read files (I1, I2), which are
+//! read in lockstep with the read files.
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::fastx::{append_record, FastxReader, Format};
+use crate::header::parse_illumina;
+use crate::config::ParallelConfig;
+use crate::paired::{MateGroupProcessor, MateGroupReader, PairedReader};
+use crate::processor::{PairedParallelProcessor, ParallelProcessor};
+use crate::reader::{PairedParallelReader, ParallelReader};
+use crate::MinimalRefRecord;
+
+/// Name of the output holding records without a matching sample
+pub const UNDETERMINED: &str = "Undetermined";
+
+/// Bytes buffered per output by a worker before it appends them to the file
+const FLUSH_THRESHOLD: usize = 1 << 20;
+
+/// A sample and its barcode, with one sequence per index (i7, then i5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub name: String,
+    pub barcodes: Vec<Vec<u8>>,
+}
+
+/// Samples to demultiplex into
+#[derive(Debug, Clone, Default)]
+pub struct SampleSheet {
+    samples: Vec<Sample>,
+}
+
+impl SampleSheet {
+    /// Reads a CSV sample sheet of `sample,barcode[,barcode2]` lines
+    ///
+    /// Blank lines and lines starting with `#` are skipped, as is a header
+    /// line whose barcode column is not a nucleotide sequence.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open sample sheet {}", path.display()))?;
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("Invalid sample sheet {}", path.display()))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut sheet = Self::default();
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut columns = line.split(',').map(str::trim);
+            let name = columns.next().unwrap_or_default();
+            let barcodes: Vec<Vec<u8>> = columns
+                .filter(|c| !c.is_empty())
+                .map(|c| c.to_ascii_uppercase().into_bytes())
+                .collect();
+            let is_sequence = |b: &Vec<u8>| b.iter().all(|c| b"ACGTN".contains(c));
+            if barcodes.is_empty() || !barcodes.iter().all(is_sequence) {
+                if sheet.samples.is_empty() {
+                    continue;
+                }
+                bail!("Line {}: expected sample,barcode[,barcode2]", line_idx + 1);
+            }
+            sheet.add(name, barcodes)?;
+        }
+        if sheet.samples.is_empty() {
+            bail!("Sample sheet lists no samples");
+        }
+        Ok(sheet)
+    }
+
+    /// Adds a sample with one barcode per index
+    pub fn add<S: Into<String>>(&mut self, name: S, barcodes: Vec<Vec<u8>>) -> Result<()> {
+        let name = name.into();
+        if name.is_empty() || name.contains(['/', '\\']) || name == UNDETERMINED {
+            bail!("Invalid sample name {:?}", name);
+        }
+        if self.samples.iter().any(|s| s.name == name) {
+            bail!("Duplicate sample name {:?}", name);
+        }
+        if let Some(first) = self.samples.first() {
+            if first.barcodes.len() != barcodes.len() {
+                bail!("Sample {:?} has a different number of barcodes than {:?}", name, first.name);
+            }
+        }
+        self.samples.push(Sample { name, barcodes });
+        Ok(())
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+}
+
+/// Where the barcode of a record is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeSource {
+    /// The index field of an Illumina header (`... 1:N:0:ACGT+TTGA`)
+    Header,
+    /// The first `n` bases of the (first) read, which are not trimmed
+    Inline(usize),
+}
+
+/// Per-output record counts of a demultiplexing run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DemuxStats {
+    /// Records (or pairs) per sample, in sample sheet order
+    pub samples: Vec<(String, u64)>,
+    pub undetermined: u64,
+}
+
+/// Splits reads into per-sample outputs
+#[derive(Debug, Clone)]
+pub struct Demultiplexer {
+    sheet: SampleSheet,
+    out_dir: PathBuf,
+    max_mismatches: usize,
+    source: BarcodeSource,
+}
+
+impl Demultiplexer {
+    pub fn new<P: Into<PathBuf>>(sheet: SampleSheet, out_dir: P) -> Self {
+        Self {
+            sheet,
+            out_dir: out_dir.into(),
+            max_mismatches: 0,
+            source: BarcodeSource::Header,
+        }
+    }
+
+    /// Sets the number of mismatches allowed per index
+    pub fn with_max_mismatches(mut self, max_mismatches: usize) -> Self {
+        self.max_mismatches = max_mismatches;
+        self
+    }
+
+    pub fn with_barcode_source(mut self, source: BarcodeSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Demultiplexes a single (possibly compressed) file into `<sample>.<ext>` outputs
+    pub fn run<P: AsRef<Path>>(&self, path: P, num_threads: usize) -> Result<DemuxStats> {
+        let reader = FastxReader::from_path(path)?;
+        let router = self.router(reader.format(), &[""])?;
+        reader.process_parallel(router.worker(), num_threads)?;
+        router.finish()
+    }
+
+    /// Demultiplexes two mate files into `<sample>_R1.<ext>` and `<sample>_R2.<ext>` outputs
+    pub fn run_paired<P1, P2>(&self, path1: P1, path2: P2, num_threads: usize) -> Result<DemuxStats>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let reader = PairedReader::from_paths(path1, path2)?;
+        let router = self.router(reader.format(), &["_R1", "_R2"])?;
+        reader.process_parallel_paired(router.worker(), num_threads)?;
+        router.finish()
+    }
+
+    /// Demultiplexes one or two read files by the barcodes in one or two index read files
+    ///
+    /// The index reads (I1, then I2) are matched against the sample barcodes
+    /// in order and all files are read in lockstep. The reads are written to
+    /// `<sample>.<ext>` outputs, or `<sample>_R1.<ext>` and `<sample>_R2.<ext>`
+    /// when two read files are given; index reads are not written.
+    pub fn run_with_index_reads<P: AsRef<Path>>(
+        &self,
+        reads: &[P],
+        index_reads: &[P],
+        num_threads: usize,
+    ) -> Result<DemuxStats> {
+        let (read_names, suffixes): (Vec<_>, &[&str]) = match reads.len() {
+            1 => (vec!["R1"], &[""]),
+            2 => (vec!["R1", "R2"], &["_R1", "_R2"]),
+            n => bail!("Expected one or two read files, got {}", n),
+        };
+        let index_names = match index_reads.len() {
+            1 => vec!["I1"],
+            2 => vec!["I1", "I2"],
+            n => bail!("Expected one or two index read files, got {}", n),
+        };
+        if let Some(sample) = self.sheet.samples.first() {
+            if sample.barcodes.len() != index_reads.len() {
+                bail!(
+                    "Sample sheet lists {} barcodes per sample but {} index read files were given",
+                    sample.barcodes.len(),
+                    index_reads.len()
+                );
+            }
+        }
+
+        let paths: Vec<&Path> = reads.iter().chain(index_reads).map(AsRef::as_ref).collect();
+        let names = read_names.into_iter().chain(index_names).collect();
+        let reader = MateGroupReader::from_paths(&paths, names)?;
+        let router = self.router(reader.format(), suffixes)?;
+        reader.process(router.worker(), &ParallelConfig::new(num_threads))?;
+        router.finish()
+    }
+
+    /// Creates the output files, one per sample and mate suffix
+    fn router(&self, format: Format, suffixes: &[&str]) -> Result<Arc<Router>> {
+        std::fs::create_dir_all(&self.out_dir).with_context(|| {
+            format!("Failed to create output directory {}", self.out_dir.display())
+        })?;
+        let names = self.sheet.samples.iter().map(|s| s.name.as_str());
+        let outputs = names
+            .chain([UNDETERMINED])
+            .map(|name| {
+                let writers = suffixes
+                    .iter()
+                    .map(|suffix| {
+                        let path = self
+                            .out_dir
+                            .join(format!("{}{}.{}", name, suffix, format.extension()));
+                        File::create(&path)
+                            .map(BufWriter::new)
+                            .with_context(|| format!("Failed to create {}", path.display()))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Mutex::new(writers))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let exact = self
+            .sheet
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(idx, s)| (s.barcodes.clone(), idx))
+            .collect();
+
+        Ok(Arc::new(Router {
+            samples: self.sheet.samples.clone(),
+            exact,
+            max_mismatches: self.max_mismatches,
+            source: self.source,
+            format,
+            num_mates: suffixes.len(),
+            counts: outputs.iter().map(|_| AtomicU64::new(0)).collect(),
+            outputs,
+        }))
+    }
+}
+
+/// Barcode matching and output files shared by all workers
+struct Router {
+    samples: Vec<Sample>,
+    exact: HashMap<Vec<Vec<u8>>, usize>,
+    max_mismatches: usize,
+    source: BarcodeSource,
+    format: Format,
+    num_mates: usize,
+    /// One entry per sample, then one for undetermined records
+    outputs: Vec<Mutex<Vec<BufWriter<File>>>>,
+    counts: Vec<AtomicU64>,
+}
+
+impl Router {
+    fn undetermined(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Output index of a record, taking the barcode from the configured source
+    fn route<'a, Rf: MinimalRefRecord<'a>>(&self, record: &Rf) -> usize {
+        match self.source {
+            BarcodeSource::Header => {
+                let barcode = parse_illumina(record.ref_head()).and_then(|h| h.barcode);
+                match barcode {
+                    Some(barcode) => {
+                        let parts: Vec<&[u8]> = barcode.split('+').map(str::as_bytes).collect();
+                        self.assign(&parts)
+                    }
+                    None => self.undetermined(),
+                }
+            }
+            BarcodeSource::Inline(len) => match record.ref_seq().get(..len) {
+                Some(barcode) => self.assign(&[barcode]),
+                None => self.undetermined(),
+            },
+        }
+    }
+
+    /// Output index of the unique sample within `max_mismatches` per index
+    fn assign(&self, parts: &[&[u8]]) -> usize {
+        let key: Vec<Vec<u8>> = parts.iter().map(|p| p.to_ascii_uppercase()).collect();
+        if let Some(&idx) = self.exact.get(&key) {
+            return idx;
+        }
+        if self.max_mismatches == 0 {
+            return self.undetermined();
+        }
+
+        let mut best = None;
+        let mut best_distance = usize::MAX;
+        let mut tied = false;
+        for (idx, sample) in self.samples.iter().enumerate() {
+            let Some(distance) = self.distance(&sample.barcodes, &key) else {
+                continue;
+            };
+            if distance < best_distance {
+                (best, best_distance, tied) = (Some(idx), distance, false);
+            } else if distance == best_distance {
+                tied = true;
+            }
+        }
+        match best {
+            Some(idx) if !tied => idx,
+            _ => self.undetermined(),
+        }
+    }
+
+    /// Total mismatches, or `None` if any index exceeds the limit
+    fn distance(&self, expected: &[Vec<u8>], observed: &[Vec<u8>]) -> Option<usize> {
+        if expected.len() != observed.len() {
+            return None;
+        }
+        let mut total = 0;
+        for (expected, observed) in expected.iter().zip(observed) {
+            if expected.len() != observed.len() {
+                return None;
+            }
+            let mismatches = expected
+                .iter()
+                .zip(observed)
+                .filter(|(e, o)| e != o && **e != b'N' && **o != b'N')
+                .count();
+            if mismatches > self.max_mismatches {
+                return None;
+            }
+            total += mismatches;
+        }
+        Some(total)
+    }
+
+    /// Output index of a group of index reads
+    fn route_index_reads<'a, Rf: MinimalRefRecord<'a>>(&self, index_reads: &[Rf]) -> usize {
+        let parts: Vec<&[u8]> = index_reads.iter().map(|r| r.ref_seq()).collect();
+        self.assign(&parts)
+    }
+
+    fn worker(self: &Arc<Self>) -> DemuxWorker {
+        DemuxWorker {
+            router: Arc::clone(self),
+            buffers: Vec::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    /// Flushes the outputs once all workers are done and collects the counts
+    fn finish(self: Arc<Self>) -> Result<DemuxStats> {
+        for output in &self.outputs {
+            for writer in output.lock().iter_mut() {
+                writer.flush()?;
+            }
+        }
+        let count = |idx: usize| self.counts[idx].load(Ordering::Relaxed);
+        Ok(DemuxStats {
+            samples: self
+                .samples
+                .iter()
+                .enumerate()
+                .map(|(idx, s)| (s.name.clone(), count(idx)))
+                .collect(),
+            undetermined: count(self.undetermined()),
+        })
+    }
+}
+
+/// Worker-side buffers of records per output and mate
+struct DemuxWorker {
+    router: Arc<Router>,
+    buffers: Vec<Vec<Vec<u8>>>,
+    counts: Vec<u64>,
+}
+
+impl Clone for DemuxWorker {
+    fn clone(&self) -> Self {
+        self.router.worker()
+    }
+}
+
+impl DemuxWorker {
+    fn buffer(&mut self, output: usize, mate: usize) -> &mut Vec<u8> {
+        if self.buffers.is_empty() {
+            let num_mates = self.router.num_mates;
+            self.buffers = vec![vec![Vec::new(); num_mates]; self.router.outputs.len()];
+            self.counts = vec![0; self.router.outputs.len()];
+        }
+        &mut self.buffers[output][mate]
+    }
+
+    fn push<'a, Rf: MinimalRefRecord<'a>>(&mut self, output: usize, mate: usize, record: &Rf) {
+        let format = self.router.format;
+        let buffer = self.buffer(output, mate);
+        append_record(buffer, format, record.ref_head(), record.ref_seq(), record.ref_qual());
+    }
+
+    /// Appends the buffered records of one output to its file(s)
+    fn flush_output(&mut self, output: usize) -> Result<()> {
+        let buffers = &mut self.buffers[output];
+        if buffers.iter().all(Vec::is_empty) {
+            return Ok(());
+        }
+        let mut writers = self.router.outputs[output].lock();
+        for (writer, buffer) in writers.iter_mut().zip(buffers.iter_mut()) {
+            writer.write_all(buffer)?;
+            buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn flush_all(&mut self) -> Result<()> {
+        for output in 0..self.buffers.len() {
+            self.flush_output(output)?;
+        }
+        Ok(())
+    }
+
+    fn flush_if_full(&mut self, output: usize) -> Result<()> {
+        if self.buffers[output].iter().any(|b| b.len() >= FLUSH_THRESHOLD) {
+            self.flush_output(output)?;
+        }
+        Ok(())
+    }
+}
+
+impl ParallelProcessor for DemuxWorker {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        let output = self.router.route(&record);
+        self.push(output, 0, &record);
+        self.counts[output] += 1;
+        self.flush_if_full(output)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.flush_all()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.flush_all()?;
+        for (total, count) in self.router.counts.iter().zip(self.counts.iter_mut()) {
+            total.fetch_add(std::mem::take(count), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl PairedParallelProcessor for DemuxWorker {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        let output = self.router.route(&record1);
+        self.push(output, 0, &record1);
+        self.push(output, 1, &record2);
+        self.counts[output] += 1;
+        self.flush_if_full(output)?;
+        Ok((record1, record2))
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.flush_all()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        ParallelProcessor::on_thread_complete(self)
+    }
+}
+
+impl MateGroupProcessor for DemuxWorker {
+    fn process_mates<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        mates: &[Rf],
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        let (reads, index_reads) = mates.split_at(self.router.num_mates);
+        let output = self.router.route_index_reads(index_reads);
+        for (mate, read) in reads.iter().enumerate() {
+            self.push(output, mate, read);
+        }
+        self.counts[output] += 1;
+        self.flush_if_full(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_sheet_skips_header_comments_and_blank_lines() {
+        let sheet = "Sample_ID,index\n# lane 1\n\nA,AAAAAA\nB , aaaaac\n";
+        let sheet = SampleSheet::from_reader(sheet.as_bytes()).unwrap();
+        let barcodes: Vec<_> = sheet.samples().iter().map(|s| s.barcodes.clone()).collect();
+        assert_eq!(barcodes, [[b"AAAAAA".to_vec()], [b"AAAAAC".to_vec()]]);
+        assert_eq!(sheet.samples()[1].name, "B");
+        // Only a line before the first sample is taken for a header
+        let err = SampleSheet::from_reader("A,AAAAAA\nSample_ID,index\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("Line 2"), "{}", err);
+    }
+
+    #[test]
+    fn barcodes_as_close_to_two_samples_are_undetermined() {
+        let dir = std::env::temp_dir().join(format!("demux_ties_{}", std::process::id()));
+        let input = std::env::temp_dir().join(format!("demux_ties_{}.fq", std::process::id()));
+        let reads = [
+            ("exact", "AAAAAA"),
+            ("one_off_a", "CAAAAA"),
+            ("one_off_b", "CAAAAC"),
+            ("tied", "AAAAAG"),
+        ];
+        let data: String = reads
+            .iter()
+            .map(|(id, barcode)| format!("@{}\n{}TTTT\n+\nIIIIIIIIII\n", id, barcode))
+            .collect();
+        std::fs::write(&input, data).unwrap();
+        let mut sheet = SampleSheet::default();
+        sheet.add("A", vec![b"AAAAAA".to_vec()]).unwrap();
+        sheet.add("B", vec![b"AAAAAC".to_vec()]).unwrap();
+        let stats = Demultiplexer::new(sheet, &dir)
+            .with_max_mismatches(1)
+            .with_barcode_source(BarcodeSource::Inline(6))
+            .run(&input, 2);
+        let undetermined = std::fs::read_to_string(dir.join("Undetermined.fastq"));
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let stats = stats.unwrap();
+        assert_eq!(stats.samples, [("A".to_owned(), 2), ("B".to_owned(), 1)]);
+        assert_eq!(stats.undetermined, 1);
+        assert!(undetermined.unwrap().starts_with("@tied\n"));
+    }
+}
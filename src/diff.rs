@@ -0,0 +1,208 @@
+//! Record-by-record comparison of two files
+//!
+//! [`diff_parallel`] reads two files like mate files of a pair, so the
+//! records at the same position are compared on the workers. It counts the
+//! records whose IDs, sequences or qualities differ and reports the first
+//! difference, which makes it a quick regression check of a pipeline's output
+//! against a known-good run.
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::paired::{MateCountMismatch, PairedReader};
+use crate::processor::PairedParallelProcessor;
+use crate::reader::PairedParallelReader;
+use crate::MinimalRefRecord;
+
+/// Parts of the records to compare, all by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Compare the IDs, the headers up to the first space
+    pub ids: bool,
+    /// Compare the qualities of FASTQ records
+    pub qualities: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            ids: true,
+            qualities: true,
+        }
+    }
+}
+
+/// Outcome of a comparison
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffReport {
+    /// Records compared, one from each file
+    pub records: u64,
+    /// Compared records that differ in any compared part
+    pub differing: u64,
+    pub id_diffs: u64,
+    pub seq_diffs: u64,
+    pub qual_diffs: u64,
+    pub first_difference: Option<Difference>,
+    /// Records in each file, if the files differ in length
+    pub record_counts: Option<(u64, u64)>,
+}
+
+impl DiffReport {
+    /// Whether the files hold the same records
+    pub fn is_identical(&self) -> bool {
+        self.differing == 0 && self.record_counts.is_none()
+    }
+}
+
+/// First pair of records that differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub record_set_idx: usize,
+    pub record_idx: usize,
+    pub id_a: String,
+    pub id_b: String,
+    /// Differing parts, among `id`, `seq` and `qual`
+    pub fields: Vec<&'static str>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record set {}, record {} ({} vs {}): {} differ",
+            self.record_set_idx,
+            self.record_idx,
+            self.id_a,
+            self.id_b,
+            self.fields.join(", ")
+        )
+    }
+}
+
+/// Counters and first difference shared by all differ clones
+#[derive(Default)]
+struct DiffState {
+    records: AtomicU64,
+    differing: AtomicU64,
+    id_diffs: AtomicU64,
+    seq_diffs: AtomicU64,
+    qual_diffs: AtomicU64,
+    first_difference: Mutex<Option<Difference>>,
+}
+
+impl DiffState {
+    /// Keeps the difference with the lowest indices, so the result does not depend on thread timing
+    fn record_difference(&self, difference: Difference) {
+        let mut first = self.first_difference.lock();
+        let key = |d: &Difference| (d.record_set_idx, d.record_idx);
+        if first.as_ref().is_none_or(|f| key(&difference) < key(f)) {
+            *first = Some(difference);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Differ {
+    options: DiffOptions,
+    state: Arc<DiffState>,
+}
+
+impl PairedParallelProcessor for Differ {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        let state = &self.state;
+        state.records.fetch_add(1, Ordering::Relaxed);
+        let (id1, id2) = (
+            record1.ref_id().unwrap_or_default(),
+            record2.ref_id().unwrap_or_default(),
+        );
+        let mut fields = Vec::new();
+        if self.options.ids && id1 != id2 {
+            state.id_diffs.fetch_add(1, Ordering::Relaxed);
+            fields.push("id");
+        }
+        if record1.ref_full_seq() != record2.ref_full_seq() {
+            state.seq_diffs.fetch_add(1, Ordering::Relaxed);
+            fields.push("seq");
+        }
+        if self.options.qualities && record1.ref_qual() != record2.ref_qual() {
+            state.qual_diffs.fetch_add(1, Ordering::Relaxed);
+            fields.push("qual");
+        }
+        if !fields.is_empty() {
+            state.differing.fetch_add(1, Ordering::Relaxed);
+            state.record_difference(Difference {
+                record_set_idx,
+                record_idx,
+                id_a: id1.to_string(),
+                id_b: id2.to_string(),
+                fields,
+            });
+        }
+        Ok((record1, record2))
+    }
+}
+
+/// Compares two (possibly compressed) FASTA/FASTQ files record by record
+pub fn diff_parallel<P1, P2>(a: P1, b: P2, num_threads: usize) -> Result<DiffReport>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    diff_parallel_with_options(a, b, DiffOptions::default(), num_threads)
+}
+
+/// Compares two files like [`diff_parallel`], only on the parts set in `options`
+///
+/// Files with different numbers of records are reported in
+/// [`DiffReport::record_counts`] rather than as an error; the batch in which
+/// the shorter file ends is then not compared.
+pub fn diff_parallel_with_options<P1, P2>(
+    a: P1,
+    b: P2,
+    options: DiffOptions,
+    num_threads: usize,
+) -> Result<DiffReport>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let state = Arc::new(DiffState::default());
+    let differ = Differ {
+        options,
+        state: Arc::clone(&state),
+    };
+    let result = PairedReader::from_paths(a, b)?.process_parallel_paired(differ, num_threads);
+    let record_counts = match result {
+        Ok(()) => None,
+        Err(err) => match err.downcast_ref::<MateCountMismatch>() {
+            Some(mismatch) => {
+                let longer =
+                    mismatch.records + mismatch.leftover.iter().map(|&(_, n)| n).sum::<u64>();
+                Some(match mismatch.ended {
+                    "R1" => (mismatch.records, longer),
+                    _ => (longer, mismatch.records),
+                })
+            }
+            None => return Err(err),
+        },
+    };
+    let state = Arc::into_inner(state).expect("workers hold no differ after the run");
+    Ok(DiffReport {
+        records: state.records.into_inner(),
+        differing: state.differing.into_inner(),
+        id_diffs: state.id_diffs.into_inner(),
+        seq_diffs: state.seq_diffs.into_inner(),
+        qual_diffs: state.qual_diffs.into_inner(),
+        first_difference: state.first_difference.into_inner(),
+        record_counts,
+    })
+}
@@ -0,0 +1,246 @@
+//! Channel layer between the reader thread and the worker threads
+//!
+//! The reader thread only ever sends small messages (indices into the shared
+//! record sets), so the choice of channel mostly matters at high thread counts
+//! with tiny records. The backend is selected through the [`Dispatcher`] trait:
+//!
+//! - [`CrossbeamDispatcher`]: the default, backed by `crossbeam-channel`
+//! - `FlumeDispatcher`: backed by `flume` (requires the `flume` feature)
+//! - `RingDispatcher`: a lock-free bounded ring with spinning receivers
+//!   (requires the `ring` feature)
+//!
+//! Custom backends can be plugged in by implementing [`Dispatcher`].
+use anyhow::{anyhow, Result};
+
+/// Sending half of a dispatch channel
+pub trait BatchSender<T>: Send + Clone {
+    /// Sends a message, blocking while the channel is full
+    fn send(&self, msg: T) -> Result<()>;
+}
+
+/// Receiving half of a dispatch channel
+pub trait BatchReceiver<T>: Send + Clone {
+    /// Receives a message, blocking until one is available
+    ///
+    /// Returns `None` once all senders are dropped and the channel is drained
+    fn recv(&self) -> Option<T>;
+
+    /// Receives a message if one is available right away, without blocking
+    ///
+    /// The `Drop` and `Sample` backpressure policies rely on this returning
+    /// `None` when the channel is empty.
+    fn try_recv(&self) -> Option<T>;
+}
+
+/// Factory for bounded multi-consumer channels
+pub trait Dispatcher {
+    type Sender<T: Send>: BatchSender<T>;
+    type Receiver<T: Send>: BatchReceiver<T>;
+
+    /// Creates a bounded channel holding at most `capacity` messages
+    fn bounded<T: Send>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>);
+}
+
+/// Dispatcher used by [`ParallelReader::process_parallel`](crate::ParallelReader::process_parallel)
+///
+/// Selected by feature flags, preferring `ring` over `flume` over `crossbeam-channel`
+#[cfg(feature = "ring")]
+pub type DefaultDispatcher = RingDispatcher;
+#[cfg(all(feature = "flume", not(feature = "ring")))]
+pub type DefaultDispatcher = FlumeDispatcher;
+#[cfg(not(any(feature = "flume", feature = "ring")))]
+pub type DefaultDispatcher = CrossbeamDispatcher;
+
+/// Dispatcher backed by `crossbeam-channel`
+pub struct CrossbeamDispatcher;
+
+impl<T: Send> BatchSender<T> for crossbeam_channel::Sender<T> {
+    fn send(&self, msg: T) -> Result<()> {
+        crossbeam_channel::Sender::send(self, msg).map_err(|_| anyhow!("Dispatch channel disconnected"))
+    }
+}
+
+impl<T: Send> BatchReceiver<T> for crossbeam_channel::Receiver<T> {
+    fn recv(&self) -> Option<T> {
+        crossbeam_channel::Receiver::recv(self).ok()
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        crossbeam_channel::Receiver::try_recv(self).ok()
+    }
+}
+
+impl Dispatcher for CrossbeamDispatcher {
+    type Sender<T: Send> = crossbeam_channel::Sender<T>;
+    type Receiver<T: Send> = crossbeam_channel::Receiver<T>;
+
+    fn bounded<T: Send>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        crossbeam_channel::bounded(capacity)
+    }
+}
+
+#[cfg(feature = "flume")]
+pub use self::flume_impl::FlumeDispatcher;
+
+#[cfg(feature = "flume")]
+mod flume_impl {
+    use super::{BatchReceiver, BatchSender, Dispatcher};
+    use anyhow::{anyhow, Result};
+
+    /// Dispatcher backed by `flume`
+    pub struct FlumeDispatcher;
+
+    impl<T: Send> BatchSender<T> for flume::Sender<T> {
+        fn send(&self, msg: T) -> Result<()> {
+            flume::Sender::send(self, msg).map_err(|_| anyhow!("Dispatch channel disconnected"))
+        }
+    }
+
+    impl<T: Send> BatchReceiver<T> for flume::Receiver<T> {
+        fn recv(&self) -> Option<T> {
+            flume::Receiver::recv(self).ok()
+        }
+
+        fn try_recv(&self) -> Option<T> {
+            flume::Receiver::try_recv(self).ok()
+        }
+    }
+
+    impl Dispatcher for FlumeDispatcher {
+        type Sender<T: Send> = flume::Sender<T>;
+        type Receiver<T: Send> = flume::Receiver<T>;
+
+        fn bounded<T: Send>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+            flume::bounded(capacity)
+        }
+    }
+}
+
+#[cfg(feature = "ring")]
+pub use self::ring_impl::{RingDispatcher, RingReceiver, RingSender};
+
+#[cfg(feature = "ring")]
+mod ring_impl {
+    use super::{BatchReceiver, BatchSender, Dispatcher};
+    use anyhow::{bail, Result};
+    use crossbeam_queue::ArrayQueue;
+    use crossbeam_utils::Backoff;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct Ring<T> {
+        queue: ArrayQueue<T>,
+        senders: AtomicUsize,
+        receivers: AtomicUsize,
+    }
+
+    /// Dispatcher backed by a lock-free bounded ring
+    ///
+    /// Both ends spin (with backoff) instead of parking, which trades idle CPU
+    /// for lower latency when batches are small and workers are many.
+    pub struct RingDispatcher;
+
+    /// Sending half of a [`RingDispatcher`] channel
+    pub struct RingSender<T> {
+        ring: Arc<Ring<T>>,
+    }
+
+    /// Receiving half of a [`RingDispatcher`] channel
+    pub struct RingReceiver<T> {
+        ring: Arc<Ring<T>>,
+    }
+
+    impl<T> Clone for RingSender<T> {
+        fn clone(&self) -> Self {
+            self.ring.senders.fetch_add(1, Ordering::SeqCst);
+            Self {
+                ring: Arc::clone(&self.ring),
+            }
+        }
+    }
+
+    impl<T> Drop for RingSender<T> {
+        fn drop(&mut self) {
+            self.ring.senders.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl<T> Clone for RingReceiver<T> {
+        fn clone(&self) -> Self {
+            self.ring.receivers.fetch_add(1, Ordering::SeqCst);
+            Self {
+                ring: Arc::clone(&self.ring),
+            }
+        }
+    }
+
+    impl<T> Drop for RingReceiver<T> {
+        fn drop(&mut self) {
+            self.ring.receivers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl<T: Send> BatchSender<T> for RingSender<T> {
+        fn send(&self, msg: T) -> Result<()> {
+            let backoff = Backoff::new();
+            let mut msg = msg;
+            loop {
+                if self.ring.receivers.load(Ordering::SeqCst) == 0 {
+                    bail!("Dispatch channel disconnected");
+                }
+                match self.ring.queue.push(msg) {
+                    Ok(()) => return Ok(()),
+                    Err(rejected) => {
+                        msg = rejected;
+                        backoff.snooze();
+                    }
+                }
+            }
+        }
+    }
+
+    impl<T: Send> BatchReceiver<T> for RingReceiver<T> {
+        fn recv(&self) -> Option<T> {
+            let backoff = Backoff::new();
+            loop {
+                if let Some(msg) = self.ring.queue.pop() {
+                    return Some(msg);
+                }
+                if self.ring.senders.load(Ordering::SeqCst) == 0 {
+                    // Drain anything pushed right before the last sender left
+                    return self.ring.queue.pop();
+                }
+                if backoff.is_completed() {
+                    std::thread::yield_now();
+                } else {
+                    backoff.snooze();
+                }
+            }
+        }
+
+        fn try_recv(&self) -> Option<T> {
+            self.ring.queue.pop()
+        }
+    }
+
+    impl Dispatcher for RingDispatcher {
+        type Sender<T: Send> = RingSender<T>;
+        type Receiver<T: Send> = RingReceiver<T>;
+
+        fn bounded<T: Send>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+            let ring = Arc::new(Ring {
+                queue: ArrayQueue::new(capacity.max(1)),
+                senders: AtomicUsize::new(1),
+                receivers: AtomicUsize::new(1),
+            });
+            (
+                RingSender {
+                    ring: Arc::clone(&ring),
+                },
+                RingReceiver { ring },
+            )
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! FASTA files with a separate `.qual` file (454 and Sanger legacy data)
+//!
+//! A `.qual` file mirrors its FASTA file record by record, with the
+//! sequence replaced by whitespace-separated numeric Phred scores. Both files
+//! are read like the mates of paired-end input, and the
+//! workers synthesize records whose `ref_qual()` holds the scores encoded as
+//! Phred+33, like a FASTQ quality line.
+use anyhow::{anyhow, bail, Result};
+use seq_io::{fasta, policy};
+use std::io;
+use std::path::Path;
+
+use crate::chunk::RawChunkReader;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, BoxedReader, Format};
+use crate::macro_impl::run_parallel;
+use crate::paired::{join_mates, mate_reader, PairedChunk, DEFAULT_PAIRED_BATCH_SIZE};
+use crate::record::SliceRecord;
+use crate::stats::RunStats;
+use crate::processor::apply_error_action;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Highest Phred score that fits into a printable Phred+33 character
+const MAX_PHRED: u8 = 93;
+
+/// Reader over a FASTA file and its matching `.qual` file
+pub struct FastaQualReader<R1: io::Read, R2: io::Read> {
+    fasta: RawChunkReader<R1>,
+    qual: RawChunkReader<R2>,
+    batch_size: usize,
+}
+
+impl FastaQualReader<BoxedReader, BoxedReader> {
+    /// Opens a (possibly compressed) FASTA file and its `.qual` file
+    pub fn from_paths<P1: AsRef<Path>, P2: AsRef<Path>>(fasta: P1, qual: P2) -> Result<Self> {
+        let (fasta_handle, fasta_format) = open_path(fasta.as_ref())?;
+        let (qual_handle, qual_format) = open_path(qual.as_ref())?;
+        if fasta_format != Format::Fasta {
+            bail!("{} is not a FASTA file", fasta.as_ref().display());
+        }
+        if qual_format != Format::Fasta {
+            bail!("{} is not a .qual file", qual.as_ref().display());
+        }
+        Ok(Self::new(fasta_handle, qual_handle))
+    }
+}
+
+impl<R1: io::Read, R2: io::Read> FastaQualReader<R1, R2> {
+    pub fn new(fasta: R1, qual: R2) -> Self {
+        Self {
+            fasta: RawChunkReader::fasta(fasta),
+            qual: RawChunkReader::fasta(qual),
+            batch_size: DEFAULT_PAIRED_BATCH_SIZE,
+        }
+    }
+
+    /// Sets the number of records per batch
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+/// Parses whitespace-separated Phred scores into Phred+33 characters
+fn parse_qual_scores(scores: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    out.clear();
+    for token in scores
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|token| !token.is_empty())
+    {
+        let score = std::str::from_utf8(token)
+            .ok()
+            .and_then(|token| token.parse::<u8>().ok())
+            .filter(|&score| score <= MAX_PHRED)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid quality score '{}'",
+                    String::from_utf8_lossy(token)
+                )
+            })?;
+        out.push(score + 33);
+    }
+    Ok(())
+}
+
+/// Pairs the sequence and quality records of a chunk and hands the merged records to the processor
+fn process_fasta_qual_chunk<T: ParallelProcessor>(
+    chunk: &PairedChunk,
+    processor: &mut T,
+    global_idx: usize,
+) -> Result<()> {
+    let mut seqs = fasta::Reader::new(&chunk.r1.data[..]);
+    let mut quals = fasta::Reader::new(&chunk.r2.data[..]);
+    let mut qual = Vec::new();
+    let mut record_idx = 0;
+    loop {
+        let (seq_record, qual_record) = match (seqs.next(), quals.next()) {
+            (Some(seq_record), Some(qual_record)) => (seq_record?, qual_record?),
+            (None, None) => break,
+            _ => bail!(
+                "FASTA and .qual have a different number of records in batch {}",
+                global_idx
+            ),
+        };
+        let (seq_id, qual_id) = (seq_record.ref_id()?, qual_record.ref_id()?);
+        if seq_id != qual_id {
+            bail!("Sequence {} is paired with quality record {}", seq_id, qual_id);
+        }
+        let seq = seq_record.ref_full_seq();
+        parse_qual_scores(qual_record.ref_seq(), &mut qual)?;
+        if qual.len() != seq.len() {
+            bail!(
+                "Sequence {} has {} bases but {} quality scores",
+                seq_id,
+                seq.len(),
+                qual.len()
+            );
+        }
+        let record = SliceRecord {
+            head: seq_record.ref_head(),
+            seq: &seq,
+            qual: &qual,
+        };
+        if let Err(err) = processor.process_record(record, global_idx, record_idx) {
+            let action = processor.on_record_error(&err, record_idx);
+            apply_error_action(err, action)?;
+        }
+        record_idx += 1;
+    }
+    Ok(())
+}
+
+impl<R1, R2> ParallelReader<R1, policy::StdPolicy> for FastaQualReader<R1, R2>
+where
+    R1: io::Read + Send,
+    R2: io::Read + Send,
+{
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        let Self {
+            fasta,
+            qual,
+            batch_size,
+        } = self;
+        let mates = vec![mate_reader(fasta, batch_size), mate_reader(qual, batch_size)];
+        join_mates(&config.options, vec!["FASTA", ".qual"], mates, |joiner| {
+            run_parallel::<D, _, PairedChunk, _, _, _>(
+                joiner,
+                processor,
+                &config,
+                |joiner, chunk| joiner.read_mates(&mut [&mut chunk.r1, &mut chunk.r2]),
+                process_fasta_qual_chunk,
+            )
+        })
+    }
+}
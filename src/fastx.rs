@@ -0,0 +1,354 @@
+//! Format detection and a reader that handles both FASTA and FASTQ
+use anyhow::{Context, Result};
+use seq_io::{fasta, fastq, policy};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::stats::RunStats;
+use crate::macro_impl::run_parallel;
+use crate::record::{Record, RecordSetLike};
+use crate::retry::{RetryPolicy, RetryReader};
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Boxed (possibly decompressing) input stream
+pub type BoxedReader = Box<dyn io::Read + Send>;
+
+/// Sequence record format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Fasta,
+    Fastq,
+}
+
+impl Format {
+    /// Detects the format from the first non-whitespace byte of the input
+    ///
+    /// Returns `None` if the input is empty or starts with neither `>` nor `@`
+    pub fn detect<R: BufRead>(reader: &mut R) -> io::Result<Option<Self>> {
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(pos) => {
+                    return Ok(match buf[pos] {
+                        b'>' => Some(Self::Fasta),
+                        b'@' => Some(Self::Fastq),
+                        _ => None,
+                    })
+                }
+                None => {
+                    let len = buf.len();
+                    reader.consume(len);
+                }
+            }
+        }
+    }
+
+    /// Conventional file extension of the format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Fasta => "fasta",
+            Self::Fastq => "fastq",
+        }
+    }
+}
+
+/// Appends a record in the given format to `out`
+///
+/// FASTA sequences are written as they are, keeping any line breaks.
+pub(crate) fn append_record(out: &mut Vec<u8>, format: Format, head: &[u8], seq: &[u8], qual: &[u8]) {
+    match format {
+        Format::Fasta => {
+            out.push(b'>');
+            out.extend_from_slice(head);
+            out.push(b'\n');
+            out.extend_from_slice(seq);
+            out.push(b'\n');
+        }
+        Format::Fastq => {
+            out.push(b'@');
+            out.extend_from_slice(head);
+            out.push(b'\n');
+            out.extend_from_slice(seq);
+            out.extend_from_slice(b"\n+\n");
+            out.extend_from_slice(qual);
+            out.push(b'\n');
+        }
+    }
+}
+
+/// Opens a (possibly compressed) file and detects its record format
+///
+/// Empty inputs are reported as FASTQ and yield no records.
+pub fn open_path<P: AsRef<Path>>(path: P) -> Result<(BoxedReader, Format)> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    open_reader(Box::new(BufReader::new(file)), &path.display().to_string())
+}
+
+/// Like [`open_path`], repeating reads of the file that fail with a transient error
+pub fn open_path_with_retry<P: AsRef<Path>>(
+    path: P,
+    policy: RetryPolicy,
+) -> Result<(BoxedReader, Format)> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let handle = BufReader::new(RetryReader::new(file, policy));
+    open_reader(Box::new(handle), &path.display().to_string())
+}
+
+/// Decompresses `handle` if needed and detects its record format, naming it `name` in errors
+pub(crate) fn open_reader(handle: BoxedReader, name: &str) -> Result<(BoxedReader, Format)> {
+    open_reader_with(handle, name, |handle| handle)
+}
+
+/// Like [`open_reader`], passing the decompressed stream through `wrap` before it is parsed
+pub(crate) fn open_reader_with<W>(handle: BoxedReader, name: &str, wrap: W) -> Result<(BoxedReader, Format)>
+where
+    W: FnOnce(BoxedReader) -> BoxedReader,
+{
+    let (handle, _compression) =
+        niffler::send::get_reader(handle).with_context(|| format!("Failed to open {}", name))?;
+    let mut handle = BufReader::new(wrap(handle));
+    let format = match Format::detect(&mut handle)? {
+        Some(format) => format,
+        None if handle.fill_buf()?.is_empty() => Format::Fastq,
+        None => anyhow::bail!("{} is neither FASTA nor FASTQ", name),
+    };
+    Ok((Box::new(handle), format))
+}
+
+/// Record set of either format
+#[derive(Default)]
+pub enum FastxRecordSet {
+    #[default]
+    Empty,
+    Fasta(fasta::RecordSet),
+    Fastq(fastq::RecordSet),
+}
+
+/// Record of either format, as yielded by a [`FastxRecordSet`]
+///
+/// Lets a single processor handle both formats through
+/// [`MinimalRefRecord`], while the underlying seq_io record stays available
+/// for format-specific access.
+#[derive(Debug, Clone)]
+pub enum FastxRecord<'a> {
+    Fasta(fasta::RefRecord<'a>),
+    Fastq(fastq::RefRecord<'a>),
+}
+
+impl FastxRecord<'_> {
+    pub fn format(&self) -> Format {
+        match self {
+            Self::Fasta(_) => Format::Fasta,
+            Self::Fastq(_) => Format::Fastq,
+        }
+    }
+}
+
+impl MinimalRefRecord<'_> for FastxRecord<'_> {
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        match self {
+            Self::Fasta(record) => record.ref_id(),
+            Self::Fastq(record) => record.ref_id(),
+        }
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        match self {
+            Self::Fasta(record) => record.ref_head(),
+            Self::Fastq(record) => record.ref_head(),
+        }
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        match self {
+            Self::Fasta(record) => record.ref_seq(),
+            Self::Fastq(record) => record.ref_seq(),
+        }
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        match self {
+            Self::Fasta(record) => record.ref_full_seq(),
+            Self::Fastq(record) => record.ref_full_seq(),
+        }
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        match self {
+            Self::Fasta(record) => record.ref_qual(),
+            Self::Fastq(record) => record.ref_qual(),
+        }
+    }
+
+    fn to_record(&self) -> Record<'_> {
+        match self {
+            Self::Fasta(record) => record.to_record(),
+            Self::Fastq(record) => record.to_record(),
+        }
+    }
+}
+
+/// Iterator over the records of a [`FastxRecordSet`]
+pub enum FastxRecordSetIter<'a> {
+    Empty,
+    Fasta(fasta::RecordSetIter<'a>),
+    Fastq(fastq::RecordSetIter<'a>),
+}
+
+impl<'a> Iterator for FastxRecordSetIter<'a> {
+    type Item = FastxRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Empty => None,
+            Self::Fasta(records) => records.next().map(FastxRecord::Fasta),
+            Self::Fastq(records) => records.next().map(FastxRecord::Fastq),
+        }
+    }
+}
+
+impl RecordSetLike for FastxRecordSet {
+    type Record<'a> = FastxRecord<'a>;
+    type Iter<'a> = FastxRecordSetIter<'a>;
+
+    fn records(&self) -> Self::Iter<'_> {
+        match self {
+            Self::Empty => FastxRecordSetIter::Empty,
+            Self::Fasta(set) => FastxRecordSetIter::Fasta(set.into_iter()),
+            Self::Fastq(set) => FastxRecordSetIter::Fastq(set.into_iter()),
+        }
+    }
+}
+
+/// Reader over either format, chosen at runtime
+pub enum FastxReader<R: io::Read = BoxedReader> {
+    Fasta(fasta::Reader<R>),
+    Fastq(fastq::Reader<R>),
+}
+
+impl FastxReader<BoxedReader> {
+    /// Opens a (possibly compressed) file and detects its format
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let (handle, format) = open_path(path)?;
+        Ok(Self::new(handle, format))
+    }
+
+    /// Opens a file like [`from_path`](Self::from_path), retrying transient read errors
+    pub fn from_path_with_retry<P: AsRef<Path>>(path: P, policy: RetryPolicy) -> Result<Self> {
+        let (handle, format) = open_path_with_retry(path, policy)?;
+        Ok(Self::new(handle, format))
+    }
+}
+
+impl<R: io::Read> FastxReader<R> {
+    pub fn new(reader: R, format: Format) -> Self {
+        match format {
+            Format::Fasta => Self::Fasta(fasta::Reader::new(reader)),
+            Format::Fastq => Self::Fastq(fastq::Reader::new(reader)),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        match self {
+            Self::Fasta(_) => Format::Fasta,
+            Self::Fastq(_) => Format::Fastq,
+        }
+    }
+
+    /// Reads the next record set, switching the set's variant if needed
+    pub fn read_record_set(&mut self, record_set: &mut FastxRecordSet) -> Option<Result<()>> {
+        match self {
+            Self::Fasta(reader) => {
+                if !matches!(record_set, FastxRecordSet::Fasta(_)) {
+                    *record_set = FastxRecordSet::Fasta(fasta::RecordSet::default());
+                }
+                let FastxRecordSet::Fasta(set) = record_set else {
+                    unreachable!()
+                };
+                reader
+                    .read_record_set(set)
+                    .map(|result| result.map_err(Into::into))
+            }
+            Self::Fastq(reader) => {
+                if !matches!(record_set, FastxRecordSet::Fastq(_)) {
+                    *record_set = FastxRecordSet::Fastq(fastq::RecordSet::default());
+                }
+                let FastxRecordSet::Fastq(set) = record_set else {
+                    unreachable!()
+                };
+                reader
+                    .read_record_set(set)
+                    .map(|result| result.map_err(Into::into))
+            }
+        }
+    }
+}
+
+impl<R: io::Read + Send> FastxReader<R> {
+    /// Processes whole batches until at least `max_records` records were read, then returns the reader
+    ///
+    /// The returned reader continues right after the last batch, so a first
+    /// pass over the start of a file (e.g. to detect the quality encoding)
+    /// can be followed by a run over the rest with different settings,
+    /// without reopening the file. Batches are not split, so up to a batch
+    /// more than `max_records` records may be processed; `RunStats::records`
+    /// tells how many were.
+    pub fn process_parallel_take<D, T>(
+        mut self,
+        processor: T,
+        max_records: u64,
+        config: ParallelConfig<D>,
+    ) -> Result<(Self, RunStats)>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        let stats = run_parallel::<D, _, FastxRecordSet, _, _, _>(
+            (&mut self, max_records),
+            processor,
+            &config,
+            |(reader, remaining), record_set| {
+                if *remaining == 0 {
+                    return None;
+                }
+                let result = reader.read_record_set(record_set)?;
+                *remaining = remaining.saturating_sub(record_set.len() as u64);
+                Some(result)
+            },
+            |record_set, processor, global_idx| record_set.process(processor, global_idx),
+        )?;
+        Ok((self, stats))
+    }
+}
+
+impl<R> ParallelReader<R, policy::StdPolicy> for FastxReader<R>
+where
+    R: io::Read + Send,
+{
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        run_parallel::<D, _, FastxRecordSet, _, _, _>(
+            self,
+            processor,
+            &config,
+            |reader, record_set| reader.read_record_set(record_set),
+            |record_set, processor, global_idx| record_set.process(processor, global_idx),
+        )
+    }
+}
@@ -0,0 +1,126 @@
+//! Parallel extraction of records by ID
+//!
+//! Pulling a few thousand reads out of a file of billions should not mean
+//! parsing all of it. [`fetch_records`] looks the requested IDs up in an
+//! [`Index`], either built during an earlier run or read from a `.fai`,
+//! and has the workers seek to and parse only those records. Every worker
+//! opens the file on its own, and the records are visited in file order so
+//! that nearby records are read from nearby positions.
+use anyhow::Result;
+use std::fs::File;
+use std::path::Path;
+
+use crate::batch::BatchSize;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::index::Index;
+use crate::macro_impl::{run_parallel, FetchWorker};
+use crate::processor::apply_error_action;
+use crate::stats::RunStats;
+use crate::ParallelProcessor;
+
+/// Number of records fetched per batch
+pub const FETCH_BATCH_SIZE: usize = 1024;
+
+/// Positions in the index of the records of a batch
+#[derive(Debug, Default)]
+struct FetchBatch {
+    entries: Vec<usize>,
+}
+
+impl BatchSize for FetchBatch {
+    fn num_records(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Not known before the records are read
+    fn num_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Hands the records with the given IDs in the file at `path` to the processor
+///
+/// IDs missing from the index are skipped, and each record is processed
+/// once however often its ID is listed. Batches hold records in file order,
+/// so their indices follow the file.
+pub fn fetch_records<P, I, T>(
+    path: P,
+    index: &Index,
+    ids: I,
+    num_threads: usize,
+    processor: T,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+    T: ParallelProcessor,
+{
+    fetch_records_with_config(
+        path,
+        index,
+        ids,
+        processor,
+        ParallelConfig::new(num_threads),
+    )
+    .map(|_| ())
+}
+
+/// Same as [`fetch_records`] but with a full run configuration
+///
+/// `RunStats::records` counts the records found.
+pub fn fetch_records_with_config<P, I, T, D>(
+    path: P,
+    index: &Index,
+    ids: I,
+    processor: T,
+    config: ParallelConfig<D>,
+) -> Result<RunStats>
+where
+    P: AsRef<Path>,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+    T: ParallelProcessor,
+    D: Dispatcher,
+{
+    let path = path.as_ref();
+    let mut entries: Vec<usize> = ids
+        .into_iter()
+        .filter_map(|id| index.position(id.as_ref()))
+        .collect();
+    // Index positions are in file order
+    entries.sort_unstable();
+    entries.dedup();
+
+    run_parallel::<D, _, FetchBatch, _, _, _>(
+        entries.chunks(FETCH_BATCH_SIZE),
+        FetchWorker::new(processor),
+        &config,
+        |chunks, batch| {
+            let chunk = chunks.next()?;
+            batch.entries.clear();
+            batch.entries.extend_from_slice(chunk);
+            Some(Ok(()))
+        },
+        |batch, worker, global_idx| {
+            let file = match &mut worker.file {
+                Some(file) => file,
+                None => worker.file.insert(File::open(path)?),
+            };
+            for (record_idx, &entry) in batch.entries.iter().enumerate() {
+                index.read_entry(file, entry, |record| {
+                    if let Err(err) = worker
+                        .processor
+                        .process_record(record, global_idx, record_idx)
+                    {
+                        let action = worker.processor.on_record_error(&err, record_idx);
+                        apply_error_action(err, action)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        },
+    )
+}
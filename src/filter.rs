@@ -0,0 +1,103 @@
+//! Read filtering
+//!
+//! [`RecordFilter`] is a [`ParallelMapper`] keeping or dropping records as
+//! they are, for the usual read-cleaning checks: length bounds, the fraction
+//! of `N` bases and the mean quality. Written through a
+//! [`MapWriter`](crate::MapWriter) it passes the records that pass through
+//! unchanged; [`Mates`](crate::Mates) applies it to pairs, which are dropped
+//! when either mate fails.
+use anyhow::Result;
+
+use crate::mapper::ParallelMapper;
+use crate::quality::QualityEncoding;
+use crate::record::Record;
+
+/// Length, N-content and mean-quality filter
+///
+/// All checks are off by default. Line breaks of wrapped FASTA sequences are
+/// not counted as bases, and FASTA records pass the quality check.
+#[derive(Debug, Clone, Default)]
+pub struct RecordFilter {
+    min_len: usize,
+    max_len: Option<usize>,
+    max_n_fraction: Option<f64>,
+    min_mean_qual: Option<f64>,
+    encoding: QualityEncoding,
+}
+
+impl RecordFilter {
+    /// Filter keeping every record, to be configured with the `with_*` methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drops records shorter than `min_len`
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+
+    /// Drops records longer than `max_len`
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Drops records in which more than `max_fraction` of the bases are `N`
+    pub fn with_max_n_fraction(mut self, max_fraction: f64) -> Self {
+        self.max_n_fraction = Some(max_fraction.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Drops FASTQ records with a mean quality score below `min_qual`
+    pub fn with_min_mean_quality(mut self, min_qual: f64) -> Self {
+        self.min_mean_qual = Some(min_qual);
+        self
+    }
+
+    /// Sets the encoding of the quality characters, Phred+33 by default
+    pub fn with_encoding(mut self, encoding: QualityEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Whether a record passes all checks
+    pub fn passes(&self, record: &Record<'_>) -> bool {
+        let (len, n_count) = match *record {
+            Record::Fastq { seq, .. } => (seq.len(), count_n(seq)),
+            Record::Fasta { seq, .. } => {
+                let line_breaks = memchr::memchr2_iter(b'\n', b'\r', seq).count();
+                (seq.len() - line_breaks, count_n(seq))
+            }
+        };
+        if len < self.min_len || self.max_len.is_some_and(|max_len| len > max_len) {
+            return false;
+        }
+        if let Some(max_fraction) = self.max_n_fraction {
+            if len > 0 && n_count as f64 > max_fraction * len as f64 {
+                return false;
+            }
+        }
+        match (self.min_mean_qual, record) {
+            (Some(min_qual), Record::Fastq { qual, .. }) if !qual.is_empty() => {
+                let offset = self.encoding.offset();
+                let total: u64 = qual
+                    .iter()
+                    .map(|&q| u64::from(q.saturating_sub(offset)))
+                    .sum();
+                total as f64 >= min_qual * qual.len() as f64
+            }
+            _ => true,
+        }
+    }
+}
+
+fn count_n(seq: &[u8]) -> usize {
+    memchr::memchr2_iter(b'N', b'n', seq).count()
+}
+
+impl ParallelMapper for RecordFilter {
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+        Ok(self.passes(&record).then_some(record))
+    }
+}
@@ -0,0 +1,200 @@
+//! Group-by-key processing of sorted inputs
+//!
+//! Consecutive records whose headers map to the same key (a UMI, a read name
+//! prefix, ...) form a group, and every group is handed to a
+//! [`GroupProcessor`] as a whole. The reader thread extracts the keys and only
+//! ends a batch at a key change, so a group is never split across batches or
+//! workers. Records are copied into the batch buffer with their sequence
+//! lines joined.
+use anyhow::Result;
+use flate2::Crc;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::batch::{BatchSize, RecordBuffer};
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{BoxedReader, FastxReader};
+use crate::macro_impl::{run_parallel, GroupWorker};
+use crate::processor::{apply_error_action, GroupProcessor};
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::MinimalRefRecord;
+
+/// Default minimum number of records per batch
+pub const DEFAULT_GROUP_BATCH_SIZE: usize = 1024;
+
+/// Records of whole groups
+#[derive(Debug, Default)]
+struct GroupedChunk {
+    records: RecordBuffer,
+    /// End index (in `records`) of every group
+    group_ends: Vec<usize>,
+}
+
+impl GroupedChunk {
+    fn clear(&mut self) {
+        self.records.clear();
+        self.group_ends.clear();
+    }
+
+    /// Record index ranges of the groups
+    fn groups(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let starts = std::iter::once(0).chain(self.group_ends.iter().copied());
+        starts.zip(self.group_ends.iter().copied()).map(|(start, end)| start..end)
+    }
+}
+
+impl BatchSize for GroupedChunk {
+    fn num_records(&self) -> usize {
+        self.records.num_records()
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.records.num_bytes()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        self.records.checksum(crc);
+    }
+}
+
+/// Reader that batches records by whole groups of a key taken from the header
+pub struct GroupedReader<R: io::Read, F> {
+    inner: FastxReader<R>,
+    key_fn: F,
+    batch_size: usize,
+    /// First record of the next batch, read when the previous batch ended
+    carry: GroupedChunk,
+    last_key: Vec<u8>,
+}
+
+impl<F> GroupedReader<BoxedReader, F>
+where
+    F: for<'h> Fn(&'h [u8]) -> &'h [u8],
+{
+    /// Opens a (possibly compressed) file and detects its format
+    pub fn from_path<P: AsRef<Path>>(path: P, key_fn: F) -> Result<Self> {
+        Ok(Self::new(FastxReader::from_path(path)?, key_fn))
+    }
+}
+
+impl<R: io::Read, F> GroupedReader<R, F>
+where
+    F: for<'h> Fn(&'h [u8]) -> &'h [u8],
+{
+    /// Groups the records of `inner` by `key_fn` applied to their header
+    pub fn new(inner: FastxReader<R>, key_fn: F) -> Self {
+        Self {
+            inner,
+            key_fn,
+            batch_size: DEFAULT_GROUP_BATCH_SIZE,
+            carry: GroupedChunk::default(),
+            last_key: Vec::new(),
+        }
+    }
+
+    /// Sets the minimum number of records per batch
+    ///
+    /// A batch extends past this size until the current group ends.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Fills `chunk` with whole groups
+    fn read_groups(&mut self, chunk: &mut GroupedChunk) -> Option<Result<()>> {
+        chunk.clear();
+        std::mem::swap(chunk, &mut self.carry);
+
+        macro_rules! fill {
+            ($reader:expr) => {
+                while let Some(record) = $reader.next() {
+                    let record = match record {
+                        Ok(record) => record,
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    let key = (self.key_fn)(record.ref_head());
+                    let new_group = !chunk.records.is_empty() && key != &self.last_key[..];
+                    if new_group {
+                        self.last_key.clear();
+                        self.last_key.extend_from_slice(key);
+                        if chunk.records.len() >= self.batch_size {
+                            let seq = record.ref_full_seq();
+                            self.carry.records.push(record.ref_head(), &seq, record.ref_qual());
+                            break;
+                        }
+                        chunk.group_ends.push(chunk.records.len());
+                    } else if chunk.records.is_empty() {
+                        self.last_key.clear();
+                        self.last_key.extend_from_slice(key);
+                    }
+                    let seq = record.ref_full_seq();
+                    chunk.records.push(record.ref_head(), &seq, record.ref_qual());
+                }
+            };
+        }
+
+        match &mut self.inner {
+            FastxReader::Fasta(reader) => fill!(reader),
+            FastxReader::Fastq(reader) => fill!(reader),
+        }
+
+        if chunk.records.is_empty() {
+            return None;
+        }
+        chunk.group_ends.push(chunk.records.len());
+        Some(Ok(()))
+    }
+
+    /// Processes the groups in parallel
+    pub fn process_parallel_grouped<T>(self, processor: T, num_threads: usize) -> Result<()>
+    where
+        R: Send,
+        F: Send,
+        T: GroupProcessor,
+    {
+        self.process_parallel_grouped_with_config(processor, ParallelConfig::new(num_threads))
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel_grouped` but with a full run configuration
+    pub fn process_parallel_grouped_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        R: Send,
+        F: Send,
+        D: Dispatcher,
+        T: GroupProcessor,
+    {
+        run_parallel::<D, _, GroupedChunk, _, _, _>(
+            self,
+            GroupWorker(processor),
+            &config,
+            |reader, chunk| reader.read_groups(chunk),
+            |chunk, worker, global_idx| process_grouped_chunk(chunk, &mut worker.0, global_idx),
+        )
+    }
+}
+
+/// Hands every group of a chunk to the processor
+fn process_grouped_chunk<T: GroupProcessor>(
+    chunk: &GroupedChunk,
+    processor: &mut T,
+    global_idx: usize,
+) -> Result<()> {
+    let mut group = Vec::new();
+    for (group_idx, range) in chunk.groups().enumerate() {
+        group.clear();
+        group.extend(range.map(|idx| chunk.records.record(idx)));
+        if let Err(err) = processor.process_group(&group, global_idx, group_idx) {
+            let action = processor.on_record_error(&err, group_idx);
+            apply_error_action(err, action)?;
+        }
+    }
+    Ok(())
+}
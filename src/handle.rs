@@ -0,0 +1,85 @@
+//! Runs in the background
+//!
+//! The `spawn_*` methods of the reader traits start a run on a new thread
+//! and return a [`RunHandle`] right away, so GUIs and servers can start a
+//! run without dedicating the calling thread to it. The handle reports the
+//! progress of the run, cancels it and waits for its result. Requires the
+//! `parallel` feature.
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::stats::RunStats;
+
+/// How often a spawned run refreshes the statistics returned by [`RunHandle::progress`]
+pub(crate) const CONTROL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// State shared between a spawned run and its handle
+#[derive(Debug, Default)]
+pub(crate) struct RunControl {
+    cancelled: AtomicBool,
+    stats: Mutex<RunStats>,
+}
+
+impl RunControl {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn update(&self, stats: RunStats) {
+        *self.stats.lock() = stats;
+    }
+}
+
+/// Handle to a run started in the background
+pub struct RunHandle {
+    control: Arc<RunControl>,
+    thread: JoinHandle<Result<RunStats>>,
+}
+
+impl RunHandle {
+    /// Starts `run` with `config` on a new thread
+    pub(crate) fn spawn<D, F>(mut config: ParallelConfig<D>, run: F) -> Result<Self>
+    where
+        D: Dispatcher + Send + 'static,
+        F: FnOnce(ParallelConfig<D>) -> Result<RunStats> + Send + 'static,
+    {
+        let control = Arc::new(RunControl::default());
+        config.options.control = Some(Arc::clone(&control));
+        let thread = config
+            .options
+            .thread_builder("run")
+            .spawn(move || run(config))
+            .context("Failed to spawn the run thread")?;
+        Ok(Self { control, thread })
+    }
+
+    /// Statistics of the run so far, refreshed every 100 ms and final once the run is done
+    pub fn progress(&self) -> RunStats {
+        self.control.stats.lock().clone()
+    }
+
+    /// Stops reading new batches; the run then fails with a cancellation error
+    ///
+    /// Batches already read are still processed, so processors see complete
+    /// batches and their hooks run as usual.
+    pub fn cancel(&self) {
+        self.control.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the run to end and returns its result
+    pub fn join(self) -> Result<RunStats> {
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("Run thread panicked"))?
+    }
+}
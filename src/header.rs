@@ -0,0 +1,182 @@
+//! Zero-copy parsing of common read header layouts
+//!
+//! All parsers borrow from the header returned by
+//! [`MinimalRefRecord::ref_head`](crate::MinimalRefRecord::ref_head) and return
+//! `None` if it does not follow the expected layout. Supported layouts:
+//!
+//! - Illumina CASAVA 1.8+:
+//!   `instrument:run:flowcell:lane:tile:x:y[:umi] read:filtered:control:barcode`
+//! - Illumina before CASAVA 1.8: `instrument:lane:tile:x:y#barcode/read`
+//! - SRA: `accession.spot [original header] [length=N]`
+//!
+//! [`fields`] splits a header on an arbitrary delimiter for custom layouts.
+use std::str;
+
+/// Fields of an Illumina read header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IlluminaHeader<'a> {
+    pub instrument: &'a str,
+    /// Run number (CASAVA 1.8+ only)
+    pub run: Option<u32>,
+    /// Flowcell ID (CASAVA 1.8+ only)
+    pub flowcell: Option<&'a str>,
+    pub lane: u32,
+    pub tile: u32,
+    pub x: u32,
+    pub y: u32,
+    /// UMI appended as an eighth ID field (CASAVA 1.8+ only)
+    pub umi: Option<&'a str>,
+    /// Read number, 1 or 2 for paired reads
+    pub read: Option<u8>,
+    /// Whether the read failed the chastity filter (CASAVA 1.8+ only)
+    pub filtered: Option<bool>,
+    /// Control number (CASAVA 1.8+ only)
+    pub control: Option<u16>,
+    /// Index sequence or sample number
+    pub barcode: Option<&'a str>,
+}
+
+/// Fields of an SRA read header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SraHeader<'a> {
+    /// Run accession, e.g. `SRR001666`
+    pub accession: &'a str,
+    pub spot: u64,
+    /// Original header of the submitted read, if kept
+    pub original: Option<&'a str>,
+    pub length: Option<usize>,
+}
+
+impl<'a> SraHeader<'a> {
+    /// Parses the original header as an Illumina header
+    pub fn illumina(&self) -> Option<IlluminaHeader<'a>> {
+        self.original.and_then(|original| parse_illumina(original.as_bytes()))
+    }
+}
+
+/// Splits a header on `delim`
+pub fn fields(head: &[u8], delim: u8) -> impl Iterator<Item = &[u8]> {
+    head.split(move |&b| b == delim)
+}
+
+/// Splits a header into its ID and optional description at the first space
+pub fn split_id(head: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match memchr::memchr(b' ', head) {
+        Some(pos) => (&head[..pos], Some(&head[pos + 1..])),
+        None => (head, None),
+    }
+}
+
+/// Parses an Illumina header of either the CASAVA 1.8+ or the older layout
+pub fn parse_illumina(head: &[u8]) -> Option<IlluminaHeader<'_>> {
+    let head = str::from_utf8(head).ok()?;
+    parse_casava18(head).or_else(|| parse_casava_legacy(head))
+}
+
+fn parse_casava18(head: &str) -> Option<IlluminaHeader<'_>> {
+    let (id, description) = match head.split_once(' ') {
+        Some((id, description)) => (id, Some(description)),
+        None => (head, None),
+    };
+    let mut parts = id.split(':');
+    let instrument = parts.next()?;
+    let run = parts.next()?.parse().ok()?;
+    let flowcell = parts.next()?;
+    let lane = parts.next()?.parse().ok()?;
+    let tile = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+    // Some pipelines append the UMI as an eighth field
+    let umi = parts.next();
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let mut header = IlluminaHeader {
+        instrument,
+        run: Some(run),
+        flowcell: Some(flowcell),
+        lane,
+        tile,
+        x,
+        y,
+        umi,
+        read: None,
+        filtered: None,
+        control: None,
+        barcode: None,
+    };
+    if let Some(description) = description {
+        let description = description.split(' ').next().unwrap_or_default();
+        let mut parts = description.split(':');
+        header.read = parts.next().and_then(|r| r.parse().ok());
+        header.filtered = parts.next().and_then(|f| match f {
+            "Y" => Some(true),
+            "N" => Some(false),
+            _ => None,
+        });
+        header.control = parts.next().and_then(|c| c.parse().ok());
+        header.barcode = parts.next().filter(|b| !b.is_empty());
+    }
+    Some(header)
+}
+
+fn parse_casava_legacy(head: &str) -> Option<IlluminaHeader<'_>> {
+    let id = head.split(' ').next()?;
+    let (id, read) = match id.rsplit_once('/') {
+        Some((id, read)) => (id, Some(read.parse().ok()?)),
+        None => (id, None),
+    };
+    let (id, barcode) = match id.split_once('#') {
+        Some((id, barcode)) => (id, Some(barcode)),
+        None => (id, None),
+    };
+    let mut parts = id.split(':');
+    let header = IlluminaHeader {
+        instrument: parts.next()?,
+        run: None,
+        flowcell: None,
+        lane: parts.next()?.parse().ok()?,
+        tile: parts.next()?.parse().ok()?,
+        x: parts.next()?.parse().ok()?,
+        y: parts.next()?.parse().ok()?,
+        umi: None,
+        read,
+        filtered: None,
+        control: None,
+        barcode,
+    };
+    parts.next().is_none().then_some(header)
+}
+
+/// Parses an SRA header
+pub fn parse_sra(head: &[u8]) -> Option<SraHeader<'_>> {
+    let head = str::from_utf8(head).ok()?;
+    let (id, rest) = match head.split_once(' ') {
+        Some((id, rest)) => (id, Some(rest)),
+        None => (head, None),
+    };
+    let (accession, spot) = id.split_once('.')?;
+    let is_accession = accession.len() > 3
+        && accession[..3].bytes().all(|b| b.is_ascii_uppercase())
+        && accession[3..].bytes().all(|b| b.is_ascii_digit());
+    if !is_accession {
+        return None;
+    }
+    // The spot may carry a read suffix (`SRR001666.1.1`)
+    let spot = spot.split('.').next()?.parse().ok()?;
+
+    let (original, length) = match rest {
+        Some(rest) => match rest.rsplit_once("length=") {
+            Some((original, length)) => (original.trim_end(), length.trim().parse().ok()),
+            None => (rest, None),
+        },
+        None => ("", None),
+    };
+    Some(SraHeader {
+        accession,
+        spot,
+        original: (!original.is_empty()).then_some(original),
+        length,
+    })
+}
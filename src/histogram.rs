@@ -0,0 +1,116 @@
+//! Read-length distribution of a file
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::fastx::FastxReader;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Exact distribution of sequence lengths
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LengthHistogram {
+    /// Number of records of every observed length
+    pub counts: BTreeMap<usize, u64>,
+}
+
+impl LengthHistogram {
+    pub fn num_records(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn total_bases(&self) -> u64 {
+        self.counts
+            .iter()
+            .map(|(&len, &count)| len as u64 * count)
+            .sum()
+    }
+
+    pub fn min(&self) -> Option<usize> {
+        self.counts.keys().next().copied()
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.counts.keys().next_back().copied()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        let num_records = self.num_records();
+        (num_records > 0).then(|| self.total_bases() as f64 / num_records as f64)
+    }
+
+    /// Counts per bin of `bin_width` lengths, keyed by the lower bound of the bin
+    ///
+    /// Empty bins are left out.
+    pub fn binned(&self, bin_width: usize) -> Vec<(usize, u64)> {
+        let bin_width = bin_width.max(1);
+        let mut bins: Vec<(usize, u64)> = Vec::new();
+        for (&len, &count) in &self.counts {
+            let bin = len / bin_width * bin_width;
+            match bins.last_mut() {
+                Some((last, total)) if *last == bin => *total += count,
+                _ => bins.push((bin, count)),
+            }
+        }
+        bins
+    }
+
+    /// Length such that records at least this long hold half of all bases
+    pub fn n50(&self) -> Option<usize> {
+        let half = self.total_bases().div_ceil(2);
+        let mut covered = 0;
+        for (&len, &count) in self.counts.iter().rev() {
+            covered += len as u64 * count;
+            if covered >= half && covered > 0 {
+                return Some(len);
+            }
+        }
+        None
+    }
+
+    fn merge(&mut self, other: &BTreeMap<usize, u64>) {
+        for (&len, &count) in other {
+            *self.counts.entry(len).or_default() += count;
+        }
+    }
+}
+
+/// Collects lengths per thread and merges them once the thread is done
+#[derive(Clone, Default)]
+struct LengthCollector {
+    local: BTreeMap<usize, u64>,
+    shared: Arc<Mutex<LengthHistogram>>,
+}
+
+impl ParallelProcessor for LengthCollector {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        // Multi-line FASTA sequences still hold their line breaks
+        let seq = record.ref_seq();
+        let len = seq.len() - memchr::memchr2_iter(b'\n', b'\r', seq).count();
+        *self.local.entry(len).or_default() += 1;
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.shared.lock().merge(&self.local);
+        self.local.clear();
+        Ok(())
+    }
+}
+
+/// Computes the read-length distribution of a (possibly compressed) FASTA/FASTQ file
+pub fn length_histogram_parallel<P: AsRef<Path>>(
+    path: P,
+    num_threads: usize,
+) -> Result<LengthHistogram> {
+    let collector = LengthCollector::default();
+    FastxReader::from_path(path)?.process_parallel(collector.clone(), num_threads)?;
+    let histogram = std::mem::take(&mut *collector.shared.lock());
+    Ok(histogram)
+}
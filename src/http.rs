@@ -0,0 +1,202 @@
+//! Reading FASTA/FASTQ directly from HTTP(S) URLs
+//!
+//! [`HttpReader`] streams an object with range requests, fetching the next
+//! ranges on a background thread while the current one is parsed, so that
+//! cloud-hosted data needs no download first. Servers without range support
+//! are read with a single streaming request, with the same readahead.
+//!
+//! Uncompressed objects can also be read by several reader threads at once:
+//! [`process_url_sharded`] splits the object into byte ranges that start at
+//! record boundaries and reads each with its own requests.
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read};
+
+use crate::chunk::{process_chunk, RawChunk, RawChunkReader};
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_reader, BoxedReader, FastxReader, Format};
+use crate::macro_impl::run_parallel_multi;
+use crate::prefetch::{Block, PrefetchReader};
+use crate::stats::RunStats;
+use crate::ParallelProcessor;
+
+/// Bytes fetched per range request
+pub const RANGE_SIZE: u64 = 8 << 20;
+
+/// Ranges fetched ahead of the reader
+const READAHEAD: usize = 4;
+
+/// Bytes searched for a record start at a shard boundary
+const PROBE_SIZE: u64 = 1 << 20;
+
+/// `io::Read` over an HTTP(S) object, fetched ahead on a background thread
+pub struct HttpReader(PrefetchReader);
+
+impl HttpReader {
+    /// Opens the whole object at `url`
+    pub fn open(url: &str) -> Result<Self> {
+        if let Some(size) = object_size(url)? {
+            return Ok(Self::range(url, 0, size));
+        }
+        let response = ureq::get(url)
+            .call()
+            .with_context(|| format!("Failed to request {}", url))?;
+        let mut body = response.into_reader();
+        Ok(Self(PrefetchReader::spawn(READAHEAD, move |tx| loop {
+            let mut block = Vec::new();
+            match (&mut body).take(RANGE_SIZE).read_to_end(&mut block) {
+                Ok(0) => return,
+                Ok(_) => {
+                    if tx.send(Ok(block)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            }
+        })))
+    }
+
+    /// Reads bytes `start..end` of an object served with range support
+    pub fn range(url: &str, start: u64, end: u64) -> Self {
+        let url = url.to_string();
+        Self(PrefetchReader::spawn(READAHEAD, move |tx| {
+            let mut pos = start;
+            while pos < end {
+                let next = (pos + RANGE_SIZE).min(end);
+                let block = fetch_range(&url, pos, next);
+                let failed = block.is_err();
+                if tx.send(block).is_err() || failed {
+                    return;
+                }
+                pos = next;
+            }
+        }))
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// Size of the object at `url`, if the server supports range requests
+fn object_size(url: &str) -> Result<Option<u64>> {
+    let response = ureq::head(url)
+        .call()
+        .with_context(|| format!("Failed to request {}", url))?;
+    let ranges = response
+        .header("Accept-Ranges")
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"));
+    let size = response
+        .header("Content-Length")
+        .and_then(|value| value.parse().ok());
+    Ok(size.filter(|_| ranges))
+}
+
+/// Fetches bytes `start..end` of the object at `url`
+fn fetch_range(url: &str, start: u64, end: u64) -> Block {
+    let response = ureq::get(url)
+        .set("Range", &format!("bytes={}-{}", start, end - 1))
+        .call()
+        .map_err(io::Error::other)?;
+    if response.status() != 206 {
+        return Err(io::Error::other(format!("{} ignored a range request", url)));
+    }
+    let mut data = Vec::with_capacity((end - start) as usize);
+    response.into_reader().read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Opens a (possibly compressed) FASTA/FASTQ object and detects its format
+pub fn open_url(url: &str) -> Result<(BoxedReader, Format)> {
+    open_reader(Box::new(HttpReader::open(url)?), url)
+}
+
+impl FastxReader<BoxedReader> {
+    /// Opens a (possibly compressed) object over HTTP(S) and detects its format
+    pub fn from_url(url: &str) -> Result<Self> {
+        let (handle, format) = open_url(url)?;
+        Ok(Self::new(handle, format))
+    }
+}
+
+/// Offset of the first record starting in `data` after its first line break
+fn record_start(format: Format, data: &[u8]) -> Option<usize> {
+    let line_starts: Vec<usize> = memchr::memchr_iter(b'\n', data).map(|pos| pos + 1).collect();
+    let starts_with = |pos: usize, byte: u8| data.get(pos) == Some(&byte);
+    match format {
+        Format::Fasta => line_starts.into_iter().find(|&pos| starts_with(pos, b'>')),
+        // A header is the only line starting with `@` two lines before a `+`
+        Format::Fastq => line_starts
+            .windows(3)
+            .find(|lines| starts_with(lines[0], b'@') && starts_with(lines[2], b'+'))
+            .map(|lines| lines[0]),
+    }
+}
+
+/// Processes an uncompressed FASTA/FASTQ object with `num_shards` reader threads
+///
+/// The object is split into `num_shards` byte ranges, each moved forward to
+/// the next record start and read with its own range requests, so the
+/// download is spread over several connections. FASTQ must use the
+/// four-line layout. Batch indices are unique across shards but do not
+/// follow the object order.
+pub fn process_url_sharded<D, T>(
+    url: &str,
+    processor: T,
+    mut config: ParallelConfig<D>,
+    num_shards: usize,
+) -> Result<RunStats>
+where
+    D: Dispatcher,
+    T: ParallelProcessor,
+{
+    let Some(size) = object_size(url)? else {
+        bail!("{} does not support range requests", url);
+    };
+    let head = if size > 0 {
+        fetch_range(url, 0, size.min(PROBE_SIZE))?
+    } else {
+        Vec::new()
+    };
+    let format = match Format::detect(&mut &head[..])? {
+        Some(format) => format,
+        None if head.is_empty() => Format::Fastq,
+        None => bail!("{} is not an uncompressed FASTA or FASTQ object", url),
+    };
+    if let Some(report) = &mut config.options.report {
+        report.inputs.push(url.to_string());
+    }
+
+    let mut bounds = vec![0];
+    let num_shards = num_shards.max(1) as u64;
+    for shard in 1..num_shards {
+        let offset = size * shard / num_shards;
+        if offset <= *bounds.last().unwrap_or(&0) {
+            continue;
+        }
+        let probe = fetch_range(url, offset, size.min(offset + PROBE_SIZE))?;
+        // A record longer than the probe merges this shard into the previous one
+        if let Some(start) = record_start(format, &probe) {
+            bounds.push(offset + start as u64);
+        }
+    }
+    bounds.push(size);
+    bounds.dedup();
+
+    let readers = bounds
+        .windows(2)
+        .map(|range| RawChunkReader::new(HttpReader::range(url, range[0], range[1]), format))
+        .collect();
+    run_parallel_multi::<D, _, RawChunk, _, _, _>(
+        readers,
+        processor,
+        &config,
+        |reader, chunk| reader.read_chunk(chunk),
+        |chunk, processor, global_idx| process_chunk(format, chunk, processor, global_idx),
+    )
+}
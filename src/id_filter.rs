@@ -0,0 +1,163 @@
+//! Filtering by read ID
+//!
+//! [`IdFilter`] keeps the records whose ID is in a list (include mode) or
+//! drops them (exclude mode), like `seqkit grep -f`. The IDs are loaded once
+//! into a hash set shared by all workers. IDs are compared up to a `/1` or
+//! `/2` suffix, so one list selects both mates; as a [`PairedMapper`] a pair
+//! matches if either mate does, and both mates are kept or dropped together.
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::fastx::FastxReader;
+use crate::header::split_id;
+use crate::mapper::{MapWriter, PairedMapWriter, PairedMapper, ParallelMapper};
+use crate::paired::PairedReader;
+use crate::reader::{PairedParallelReader, ParallelReader};
+use crate::record::Record;
+use crate::writer::ParallelWriter;
+use crate::MinimalRefRecord;
+
+/// Whether an [`IdFilter`] keeps or drops the listed records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdFilterMode {
+    /// Keeps only the listed records
+    #[default]
+    Include,
+    /// Keeps all but the listed records
+    Exclude,
+}
+
+/// Keeps or drops records by ID, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct IdFilter {
+    ids: Arc<HashSet<Vec<u8>>>,
+    mode: IdFilterMode,
+}
+
+impl IdFilter {
+    /// Filter on the given IDs, which may carry a `/1` or `/2` suffix
+    pub fn new<I, S>(ids: I, mode: IdFilterMode) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let ids = ids
+            .into_iter()
+            .map(|id| strip_mate(id.as_ref()).to_vec())
+            .collect();
+        Self {
+            ids: Arc::new(ids),
+            mode,
+        }
+    }
+
+    /// Filter on the IDs listed in a file, one per line
+    ///
+    /// Only the first word of a line is used, and a leading `@` or `>` is
+    /// ignored, so a file of headers works as well. Blank lines are skipped.
+    pub fn from_path<P: AsRef<Path>>(path: P, mode: IdFilterMode) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read(path)
+            .with_context(|| format!("Failed to read ID list {}", path.display()))?;
+        let ids = text.split(|&b| b == b'\n').filter_map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let line = line
+                .strip_prefix(b"@")
+                .or_else(|| line.strip_prefix(b">"))
+                .unwrap_or(line);
+            let id = line
+                .split(|b| b.is_ascii_whitespace())
+                .find(|word| !word.is_empty())?;
+            Some(id)
+        });
+        Ok(Self::new(ids, mode))
+    }
+
+    /// Number of distinct IDs in the list
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Whether the ID of the record is in the list
+    pub fn matches(&self, record: &Record<'_>) -> bool {
+        let (id, _) = split_id(record.ref_head());
+        self.ids.contains(strip_mate(id))
+    }
+
+    /// Whether the record is kept
+    pub fn keeps(&self, record: &Record<'_>) -> bool {
+        self.matches(record) == (self.mode == IdFilterMode::Include)
+    }
+}
+
+fn strip_mate(id: &[u8]) -> &[u8] {
+    id.strip_suffix(b"/1")
+        .or_else(|| id.strip_suffix(b"/2"))
+        .unwrap_or(id)
+}
+
+impl ParallelMapper for IdFilter {
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+        Ok(self.keeps(&record).then_some(record))
+    }
+}
+
+impl PairedMapper for IdFilter {
+    fn map_pair<'r>(
+        &mut self,
+        record1: Record<'r>,
+        record2: Record<'r>,
+    ) -> Result<Option<(Record<'r>, Record<'r>)>> {
+        let matches = self.matches(&record1) || self.matches(&record2);
+        let keep = matches == (self.mode == IdFilterMode::Include);
+        Ok(keep.then_some((record1, record2)))
+    }
+}
+
+/// Writes the records of `input` kept by `filter` to `output`, in input order
+///
+/// Outputs are compressed according to their extension.
+pub fn filter_ids_parallel<P1, P2>(
+    input: P1,
+    output: P2,
+    filter: &IdFilter,
+    num_threads: usize,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let out = ParallelWriter::from_path(output)?;
+    let writer = MapWriter::new(filter.clone(), out.clone());
+    FastxReader::from_path(input)?.process_parallel(writer, num_threads)?;
+    out.finish()
+}
+
+/// Writes the pairs of R1/R2 files kept by `filter` to `out1`/`out2`, in input order
+pub fn filter_ids_paired_parallel<P1, P2, P3, P4>(
+    r1: P1,
+    r2: P2,
+    out1: P3,
+    out2: P4,
+    filter: &IdFilter,
+    num_threads: usize,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    P4: AsRef<Path>,
+{
+    let out1 = ParallelWriter::from_path(out1)?;
+    let out2 = ParallelWriter::from_path(out2)?;
+    let writer = PairedMapWriter::new(filter.clone(), out1.clone(), out2.clone());
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(writer, num_threads)?;
+    out1.finish()?;
+    out2.finish()
+}
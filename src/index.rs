@@ -0,0 +1,312 @@
+//! Name-to-offset index built during a run
+//!
+//! Looking up reads by name needs an index, which usually takes a pass of
+//! its own over the input. An [`IndexBuilder`] attached to a
+//! [`RawChunkReader`](crate::RawChunkReader) instead records the ID and byte
+//! offset of every record while a normal run processes it, on the workers.
+//! The resulting [`Index`] can be saved next to the input and used to fetch
+//! single records later.
+//!
+//! Offsets count uncompressed bytes, so fetching needs a reader that seeks
+//! in the uncompressed data: a plain file, or e.g. a
+//! `SeekableZstdReader` with the `zstd` feature.
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use seq_io::{fasta, fastq};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::chunk::{record_starts, RawChunk};
+use crate::fastx::{FastxRecord, Format};
+use crate::header::split_id;
+use crate::record::Record;
+use crate::MinimalRefRecord;
+
+/// Buffer of the parser reading a single record, grown for longer records
+const PARSE_CAPACITY: usize = 1 << 13;
+
+/// Collects the offsets of the records of a run, shared with the workers
+#[derive(Debug, Clone, Default)]
+pub struct IndexBuilder(Arc<Mutex<IndexState>>);
+
+#[derive(Debug, Default)]
+struct IndexState {
+    format: Option<Format>,
+    entries: Vec<(Vec<u8>, u64)>,
+}
+
+impl IndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the IDs and offsets of the records of a chunk
+    pub(crate) fn add_chunk(&self, format: Format, chunk: &RawChunk) {
+        let entries: Vec<_> = record_starts(format, &chunk.data)
+            .map(|start| {
+                let line = &chunk.data[start + 1..];
+                let end = memchr::memchr(b'\n', line).unwrap_or(line.len());
+                let head = line[..end].strip_suffix(b"\r").unwrap_or(&line[..end]);
+                (split_id(head).0.to_vec(), chunk.range.start + start as u64)
+            })
+            .collect();
+        let mut state = self.0.lock();
+        state.format = Some(format);
+        state.entries.extend(entries);
+    }
+
+    /// Index of the records seen so far, normally taken once the run is done
+    pub fn finish(&self) -> Index {
+        let state = self.0.lock();
+        Index::new(state.format.unwrap_or(Format::Fastq), state.entries.clone())
+    }
+}
+
+/// Byte offset of every record by ID
+///
+/// If several records share an ID, the first one is found.
+#[derive(Debug, Clone)]
+pub struct Index {
+    format: Format,
+    /// Sorted by offset
+    entries: Vec<(Vec<u8>, u64)>,
+    by_id: HashMap<Vec<u8>, usize>,
+}
+
+impl Index {
+    fn new(format: Format, mut entries: Vec<(Vec<u8>, u64)>) -> Self {
+        entries.sort_unstable_by_key(|&(_, offset)| offset);
+        let mut by_id = HashMap::with_capacity(entries.len());
+        for (idx, (id, _)) in entries.iter().enumerate() {
+            by_id.entry(id.clone()).or_insert(idx);
+        }
+        Self {
+            format,
+            entries,
+            by_id,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Offset of the record with the given ID
+    pub fn offset(&self, id: &[u8]) -> Option<u64> {
+        self.by_id.get(id).map(|&idx| self.entries[idx].1)
+    }
+
+    /// IDs and offsets in file order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], u64)> + '_ {
+        self.entries
+            .iter()
+            .map(|(id, offset)| (id.as_slice(), *offset))
+    }
+
+    /// Position of the record with the given ID in [`iter`](Self::iter) order
+    pub(crate) fn position(&self, id: &[u8]) -> Option<usize> {
+        self.by_id.get(id).copied()
+    }
+
+    /// Reads the record with the given ID from a reader over the indexed input
+    pub fn fetch<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        id: &[u8],
+    ) -> Result<Option<IndexedRecord>> {
+        let Some(idx) = self.position(id) else {
+            return Ok(None);
+        };
+        self.read_entry(reader, idx, |record| {
+            Ok(Some(match record {
+                FastxRecord::Fasta(record) => IndexedRecord::Fasta(record.to_owned_record()),
+                FastxRecord::Fastq(record) => IndexedRecord::Fastq(record.to_owned_record()),
+            }))
+        })
+    }
+
+    /// Parses the record at position `idx` and hands it to `f`
+    pub(crate) fn read_entry<R, O, F>(&self, reader: &mut R, idx: usize, f: F) -> Result<O>
+    where
+        R: Read + Seek,
+        F: FnOnce(FastxRecord<'_>) -> Result<O>,
+    {
+        let (id, offset) = &self.entries[idx];
+        reader.seek(SeekFrom::Start(*offset))?;
+        let mismatch = || {
+            anyhow!(
+                "No record {} at offset {}, the input does not match the index",
+                String::from_utf8_lossy(id),
+                offset
+            )
+        };
+        match self.format {
+            Format::Fasta => {
+                let mut parser = fasta::Reader::with_capacity(reader, PARSE_CAPACITY);
+                let record = parser.next().transpose()?.ok_or_else(mismatch)?;
+                if split_id(record.ref_head()).0 != id.as_slice() {
+                    return Err(mismatch());
+                }
+                f(FastxRecord::Fasta(record))
+            }
+            Format::Fastq => {
+                let mut parser = fastq::Reader::with_capacity(reader, PARSE_CAPACITY);
+                let record = parser.next().transpose()?.ok_or_else(mismatch)?;
+                if split_id(record.ref_head()).0 != id.as_slice() {
+                    return Err(mismatch());
+                }
+                f(FastxRecord::Fastq(record))
+            }
+        }
+    }
+
+    /// Reads a `samtools faidx` (FASTA) or `samtools fqidx` (FASTQ) index
+    ///
+    /// The `.fai` holds the offsets of the sequences; the record starts are
+    /// derived from the line layout, assuming records follow each other
+    /// without blank lines, as in any file samtools can index.
+    pub fn from_fai<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read index {}", path.display()))?;
+        let mut format = None;
+        let mut entries = Vec::new();
+        let mut start = 0;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let numbers: Option<Vec<u64>> = fields[1..].iter().map(|f| f.parse().ok()).collect();
+            let (line_format, numbers) = match (fields.len(), numbers) {
+                (5, Some(numbers)) => (Format::Fasta, numbers),
+                (6, Some(numbers)) => (Format::Fastq, numbers),
+                _ => bail!("Malformed line in index {}: {}", path.display(), line),
+            };
+            if *format.get_or_insert(line_format) != line_format {
+                bail!("Index {} mixes FASTA and FASTQ entries", path.display());
+            }
+            let [length, offset, line_bases, line_width, ..] = numbers[..] else {
+                unreachable!()
+            };
+            entries.push((fields[0].as_bytes().to_vec(), start));
+            let last_field = if line_format == Format::Fastq {
+                numbers[4]
+            } else {
+                offset
+            };
+            start = last_field + stored_len(length, line_bases, line_width);
+        }
+        Ok(Self::new(format.unwrap_or(Format::Fasta), entries))
+    }
+
+    /// Writes the index as a format line followed by one `id<TAB>offset` line per record
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut out = BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+        );
+        writeln!(out, "#{}", self.format.extension())?;
+        for (id, offset) in &self.entries {
+            out.write_all(id)?;
+            writeln!(out, "\t{}", offset)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads an index written by [`save`](Self::save)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut lines = BufReader::new(
+            File::open(path).with_context(|| format!("Failed to read index {}", path.display()))?,
+        )
+        .split(b'\n');
+        let format = match lines.next().transpose()?.as_deref() {
+            Some(b"#fasta") => Format::Fasta,
+            Some(b"#fastq") => Format::Fastq,
+            _ => bail!("{} is not a record index", path.display()),
+        };
+        let mut entries = Vec::new();
+        for line in lines {
+            let line = line?;
+            let parsed = line.iter().rposition(|&b| b == b'\t').and_then(|tab| {
+                let offset = std::str::from_utf8(&line[tab + 1..]).ok()?.parse().ok()?;
+                Some((line[..tab].to_vec(), offset))
+            });
+            match parsed {
+                Some(entry) => entries.push(entry),
+                None => bail!("Malformed line in index {}", path.display()),
+            }
+        }
+        Ok(Self::new(format, entries))
+    }
+}
+
+/// Bytes taken by a sequence of `length` bases wrapped into lines of `line_bases`
+fn stored_len(length: u64, line_bases: u64, line_width: u64) -> u64 {
+    if length == 0 || line_bases == 0 {
+        return 0;
+    }
+    length + length.div_ceil(line_bases) * (line_width - line_bases)
+}
+
+/// Record read through an [`Index`]
+#[derive(Debug, Clone)]
+pub enum IndexedRecord {
+    Fasta(fasta::OwnedRecord),
+    Fastq(fastq::OwnedRecord),
+}
+
+impl MinimalRefRecord<'_> for IndexedRecord {
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        match self {
+            Self::Fasta(record) => record.ref_id(),
+            Self::Fastq(record) => record.ref_id(),
+        }
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        match self {
+            Self::Fasta(record) => record.ref_head(),
+            Self::Fastq(record) => record.ref_head(),
+        }
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        match self {
+            Self::Fasta(record) => record.ref_seq(),
+            Self::Fastq(record) => record.ref_seq(),
+        }
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        match self {
+            Self::Fasta(record) => record.ref_full_seq(),
+            Self::Fastq(record) => record.ref_full_seq(),
+        }
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        match self {
+            Self::Fasta(record) => record.ref_qual(),
+            Self::Fastq(record) => record.ref_qual(),
+        }
+    }
+
+    fn to_record(&self) -> Record<'_> {
+        match self {
+            Self::Fasta(record) => record.to_record(),
+            Self::Fastq(record) => record.to_record(),
+        }
+    }
+}
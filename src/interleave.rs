@@ -0,0 +1,172 @@
+//! Conversion between separate mate files and interleaved files
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::process::Command;
+
+use crate::chunk::{RawChunk, RawChunkReader};
+use crate::config::ParallelConfig;
+use crate::fastx::{open_path, Format};
+use crate::macro_impl::{run_parallel, WorkerHooks};
+use crate::paired::{PairedReader, DEFAULT_PAIRED_BATCH_SIZE};
+use crate::processor::PairedParallelProcessor;
+use crate::reader::PairedParallelReader;
+use crate::validate::mate_id;
+use crate::writer::ParallelWriter;
+use crate::MinimalRefRecord;
+
+/// Writes both mates of every pair back to back
+#[derive(Clone)]
+struct Interleaver {
+    out: ParallelWriter,
+}
+
+impl PairedParallelProcessor for Interleaver {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        self.out.write_record(record_set_idx, &record1)?;
+        self.out.write_record(record_set_idx, &record2)?;
+        Ok((record1, record2))
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.out.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.out.discard_batch();
+        Ok(())
+    }
+}
+
+/// Merges (possibly compressed) R1/R2 files into one interleaved file at `output`
+///
+/// Pairs are written in input order, R1 before R2.
+pub fn interleave_parallel<P1, P2, P3>(r1: P1, r2: P2, output: P3, num_threads: usize) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let out = ParallelWriter::from_path(output)?;
+    let interleaver = Interleaver { out: out.clone() };
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(interleaver, num_threads)?;
+    out.finish()
+}
+
+/// Feeds the pairs of (possibly compressed) R1/R2 files, interleaved, to the stdin of `command`
+///
+/// Pairs are written in input order, R1 before R2, e.g. for `bwa mem -p ref.fa -`.
+/// Fails if the command exits unsuccessfully, with the end of its stderr.
+pub fn interleave_to_command<P1, P2>(
+    r1: P1,
+    r2: P2,
+    command: Command,
+    num_threads: usize,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let out = ParallelWriter::spawn(command)?;
+    let interleaver = Interleaver { out: out.clone() };
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(interleaver, num_threads)?;
+    out.finish()
+}
+
+/// Writes the first mate of every pair to one output and the second to the other
+#[derive(Clone)]
+struct Deinterleaver {
+    r1: ParallelWriter,
+    r2: ParallelWriter,
+}
+
+impl WorkerHooks for Deinterleaver {
+    fn set_thread_id(&mut self, _thread_id: usize) {}
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.r1.finish_batch()?;
+        self.r2.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.r1.discard_batch();
+        self.r2.discard_batch();
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Splits the pairs of a chunk, checking that adjacent records are mates
+fn deinterleave_chunk(
+    format: Format,
+    chunk: &RawChunk,
+    outputs: &mut Deinterleaver,
+    global_idx: usize,
+) -> Result<()> {
+    macro_rules! split_pairs {
+        ($parser:ty) => {{
+            let mut parser = <$parser>::new(&chunk.data[..]);
+            while let Some(record1) = parser.next() {
+                let record1 = record1?;
+                outputs.r1.write_record(global_idx, &record1)?;
+                let id1 = record1.ref_id()?.to_owned();
+                let Some(record2) = parser.next() else {
+                    bail!("Interleaved input ends with the unpaired record {}", id1);
+                };
+                let record2 = record2?;
+                let id2 = record2.ref_id()?;
+                if mate_id(&id1) != mate_id(id2) {
+                    bail!("Adjacent records {} and {} are not mates", id1, id2);
+                }
+                outputs.r2.write_record(global_idx, &record2)?;
+            }
+        }};
+    }
+
+    match format {
+        Format::Fastq => split_pairs!(seq_io::fastq::Reader<&[u8]>),
+        Format::Fasta => split_pairs!(seq_io::fasta::Reader<&[u8]>),
+    }
+    Ok(())
+}
+
+/// Splits a (possibly compressed) interleaved file into R1/R2 files
+///
+/// Adjacent records must be mates (equal IDs up to a `/1` or `/2` suffix).
+/// Outputs are compressed on the worker threads according to their extension.
+pub fn deinterleave_parallel<P1, P2, P3>(
+    input: P1,
+    r1: P2,
+    r2: P3,
+    num_threads: usize,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let (handle, format) = open_path(input)?;
+    let reader = RawChunkReader::new(handle, format);
+    let outputs = Deinterleaver {
+        r1: ParallelWriter::from_path(r1)?,
+        r2: ParallelWriter::from_path(r2)?,
+    };
+    run_parallel::<_, _, RawChunk, _, _, _>(
+        reader,
+        outputs.clone(),
+        &ParallelConfig::new(num_threads),
+        // An even number of records keeps pairs within a batch
+        |reader, chunk| reader.read_records(chunk, 2 * DEFAULT_PAIRED_BATCH_SIZE),
+        |chunk, outputs, global_idx| deinterleave_chunk(format, chunk, outputs, global_idx),
+    )?;
+    outputs.r1.finish()?;
+    outputs.r2.finish()
+}
@@ -0,0 +1,189 @@
+//! FASTA batches with their sequences joined on the reader thread
+//!
+//! The sequence of a multi-line FASTA record is stored with its line breaks,
+//! so `ref_full_seq()` allocates and copies it on every call, in every
+//! worker that asks. [`JoinedFastaReader`] instead joins the lines of each
+//! batch once, on the reader thread, into a buffer kept next to the record
+//! set. `ref_full_seq()` of its records is then a borrow. Single-line
+//! records are not copied at all.
+use anyhow::{bail, Result};
+use seq_io::{fasta, policy};
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, BoxedReader, Format};
+use crate::macro_impl::run_parallel;
+use crate::record::{Record, RecordSetLike};
+use crate::stats::RunStats;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// FASTA record set with the joined sequences of its multi-line records
+#[derive(Default)]
+pub struct JoinedRecordSet {
+    set: fasta::RecordSet,
+    seqs: Vec<u8>,
+    /// Range of every record in `seqs`, `None` for single-line records
+    spans: Vec<Option<(usize, usize)>>,
+}
+
+impl JoinedRecordSet {
+    /// Joins the sequence lines of every multi-line record
+    fn join(&mut self) {
+        self.seqs.clear();
+        self.spans.clear();
+        for record in &self.set {
+            if record.num_seq_lines() <= 1 {
+                self.spans.push(None);
+                continue;
+            }
+            let start = self.seqs.len();
+            for line in record.seq_lines() {
+                self.seqs.extend_from_slice(line);
+            }
+            self.spans.push(Some((start, self.seqs.len())));
+        }
+    }
+}
+
+/// Record of a [`JoinedRecordSet`]
+#[derive(Debug, Clone)]
+pub struct JoinedRecord<'a> {
+    record: fasta::RefRecord<'a>,
+    /// Joined sequence of a multi-line record
+    joined: Option<&'a [u8]>,
+}
+
+impl<'a> JoinedRecord<'a> {
+    /// The underlying seq_io record
+    pub fn record(&self) -> &fasta::RefRecord<'a> {
+        &self.record
+    }
+
+    /// Sequence without line breaks
+    pub fn full_seq(&self) -> &[u8] {
+        self.joined.unwrap_or_else(|| self.record.ref_seq())
+    }
+}
+
+impl MinimalRefRecord<'_> for JoinedRecord<'_> {
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        self.record.ref_id()
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        self.record.ref_head()
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        self.record.ref_seq()
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.full_seq())
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        &[]
+    }
+
+    fn to_record(&self) -> Record<'_> {
+        self.record.to_record()
+    }
+}
+
+/// Iterator over the records of a [`JoinedRecordSet`]
+pub struct JoinedRecordSetIter<'a> {
+    records: fasta::RecordSetIter<'a>,
+    spans: std::slice::Iter<'a, Option<(usize, usize)>>,
+    seqs: &'a [u8],
+}
+
+impl<'a> Iterator for JoinedRecordSetIter<'a> {
+    type Item = JoinedRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        let joined = self
+            .spans
+            .next()
+            .copied()
+            .flatten()
+            .map(|(start, end)| &self.seqs[start..end]);
+        Some(JoinedRecord { record, joined })
+    }
+}
+
+impl RecordSetLike for JoinedRecordSet {
+    type Record<'a> = JoinedRecord<'a>;
+    type Iter<'a> = JoinedRecordSetIter<'a>;
+
+    fn records(&self) -> Self::Iter<'_> {
+        JoinedRecordSetIter {
+            records: self.set.into_iter(),
+            spans: self.spans.iter(),
+            seqs: &self.seqs,
+        }
+    }
+}
+
+/// FASTA reader that joins the sequence lines of each batch before dispatching it
+pub struct JoinedFastaReader<R: io::Read = BoxedReader> {
+    reader: fasta::Reader<R>,
+}
+
+impl JoinedFastaReader<BoxedReader> {
+    /// Opens a (possibly compressed) FASTA file
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let (handle, format) = open_path(path)?;
+        if format != Format::Fasta {
+            bail!("{} is not a FASTA file", path.display());
+        }
+        Ok(Self::new(handle))
+    }
+}
+
+impl<R: io::Read> JoinedFastaReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: fasta::Reader::new(reader),
+        }
+    }
+
+    /// Reads the next batch and joins its sequences
+    pub fn read_record_set(&mut self, record_set: &mut JoinedRecordSet) -> Option<Result<()>> {
+        match self.reader.read_record_set(&mut record_set.set)? {
+            Ok(()) => {
+                record_set.join();
+                Some(Ok(()))
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl<R> ParallelReader<R, policy::StdPolicy> for JoinedFastaReader<R>
+where
+    R: io::Read + Send,
+{
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        run_parallel::<D, _, JoinedRecordSet, _, _, _>(
+            self,
+            processor,
+            &config,
+            |reader, record_set| reader.read_record_set(record_set),
+            |record_set, processor, global_idx| record_set.process(processor, global_idx),
+        )
+    }
+}
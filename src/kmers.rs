@@ -0,0 +1,176 @@
+//! Canonical k-mer and minimizer iterators
+//!
+//! The iterators take a record's `ref_seq()` as is: line breaks of wrapped
+//! FASTA sequences are skipped, so k-mers span lines without joining the
+//! sequence first, and any other non-ACGT byte (e.g. `N`) ends the current
+//! run of k-mers. K-mers are 2-bit encoded (`A`=0, `C`=1, `G`=2, `T`=3, case
+//! insensitive) into a `u64`, so `k` is at most 32. The canonical k-mer is the
+//! smaller of the k-mer and its reverse complement. Positions count bases,
+//! not bytes, from the start of the sequence.
+use std::collections::VecDeque;
+
+/// Largest k that fits a 2-bit encoded `u64`
+pub const MAX_K: usize = 32;
+
+/// Canonical k-mer and the base position it starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kmer {
+    pub pos: usize,
+    pub value: u64,
+}
+
+fn encode(base: u8) -> Option<u64> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// Iterator over the canonical k-mers of a sequence
+pub struct CanonicalKmers<'a> {
+    seq: std::slice::Iter<'a, u8>,
+    k: usize,
+    mask: u64,
+    shift: usize,
+    forward: u64,
+    reverse: u64,
+    /// Valid bases at the end of the current k-mer
+    run: usize,
+    /// Bases consumed so far
+    pos: usize,
+}
+
+/// Canonical k-mers of `seq`, skipping line breaks and breaking at non-ACGT bytes
+///
+/// Panics if `k` is 0 or larger than [`MAX_K`].
+pub fn canonical_kmers(seq: &[u8], k: usize) -> CanonicalKmers<'_> {
+    assert!(
+        (1..=MAX_K).contains(&k),
+        "k must be between 1 and {}",
+        MAX_K
+    );
+    CanonicalKmers {
+        seq: seq.iter(),
+        k,
+        mask: if k == MAX_K {
+            u64::MAX
+        } else {
+            (1 << (2 * k)) - 1
+        },
+        shift: 2 * (k - 1),
+        forward: 0,
+        reverse: 0,
+        run: 0,
+        pos: 0,
+    }
+}
+
+impl Iterator for CanonicalKmers<'_> {
+    type Item = Kmer;
+
+    fn next(&mut self) -> Option<Kmer> {
+        for &byte in self.seq.by_ref() {
+            if byte == b'\n' || byte == b'\r' {
+                continue;
+            }
+            self.pos += 1;
+            let Some(base) = encode(byte) else {
+                self.run = 0;
+                continue;
+            };
+            self.forward = ((self.forward << 2) | base) & self.mask;
+            self.reverse = (self.reverse >> 2) | ((3 - base) << self.shift);
+            self.run += 1;
+            if self.run >= self.k {
+                return Some(Kmer {
+                    pos: self.pos - self.k,
+                    value: self.forward.min(self.reverse),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the minimizers of a sequence
+///
+/// A minimizer is the canonical k-mer with the smallest hash among `w`
+/// consecutive k-mers, the leftmost one on ties. Each minimizer is yielded once, when it is first
+/// selected, so consecutive windows sharing it yield it a single time.
+/// Windows never span a non-ACGT byte.
+pub struct Minimizers<'a> {
+    kmers: CanonicalKmers<'a>,
+    w: usize,
+    /// Candidates of the current window, increasing in hash
+    window: VecDeque<(u64, Kmer)>,
+    /// K-mers in the current run of consecutive k-mers
+    run: usize,
+    last_pos: Option<usize>,
+    last_yielded: Option<usize>,
+}
+
+/// Minimizers of `seq` over windows of `w` k-mers
+///
+/// K-mers are ordered by a hash of their canonical value rather than by the
+/// value itself, which would favour poly-A k-mers. Panics if `w` is 0 or `k`
+/// is out of range.
+pub fn minimizers(seq: &[u8], k: usize, w: usize) -> Minimizers<'_> {
+    assert!(w > 0, "w must be at least 1");
+    Minimizers {
+        kmers: canonical_kmers(seq, k),
+        w,
+        window: VecDeque::with_capacity(w),
+        run: 0,
+        last_pos: None,
+        last_yielded: None,
+    }
+}
+
+impl Iterator for Minimizers<'_> {
+    type Item = Kmer;
+
+    fn next(&mut self) -> Option<Kmer> {
+        for kmer in self.kmers.by_ref() {
+            if self.last_pos.is_some_and(|last| kmer.pos != last + 1) {
+                self.window.clear();
+                self.run = 0;
+            }
+            self.last_pos = Some(kmer.pos);
+            self.run += 1;
+
+            let hash = kmer_hash(kmer.value);
+            while self.window.back().is_some_and(|&(h, _)| h > hash) {
+                self.window.pop_back();
+            }
+            self.window.push_back((hash, kmer));
+            while self
+                .window
+                .front()
+                .is_some_and(|(_, m)| m.pos + self.w <= kmer.pos)
+            {
+                self.window.pop_front();
+            }
+
+            if self.run < self.w {
+                continue;
+            }
+            let (_, minimizer) = self.window[0];
+            if self.last_yielded != Some(minimizer.pos) {
+                self.last_yielded = Some(minimizer.pos);
+                return Some(minimizer);
+            }
+        }
+        None
+    }
+}
+
+/// Hash ordering k-mers for minimizer selection
+pub fn kmer_hash(value: u64) -> u64 {
+    let mut x = value;
+    x = (x ^ (x >> 31)).wrapping_mul(0x7fb5_d329_728e_a185);
+    x = (x ^ (x >> 27)).wrapping_mul(0x81da_def4_bc2d_d44d);
+    x ^ (x >> 33)
+}
@@ -1,10 +1,161 @@
+pub mod adapter;
+#[cfg(feature = "parallel")]
+mod affinity;
+pub mod archive;
+#[cfg(feature = "arrow")]
+pub mod arrow_batch;
+pub mod autotune;
+pub mod base_batch;
+mod batch;
+pub mod bgzf;
+pub mod checkpoint;
+pub mod chunk;
+#[cfg(feature = "checksum")]
+pub mod checksum;
+mod command;
+pub mod compression;
+pub mod config;
+pub mod count;
+pub mod dedup;
+pub mod demux;
+pub mod diff;
+pub mod dispatch;
+pub mod fasta_qual;
+pub mod fastx;
+pub mod fetch;
+pub mod filter;
+pub mod group;
+#[cfg(feature = "parallel")]
+pub mod handle;
+pub mod header;
+pub mod histogram;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod id_filter;
+pub mod index;
+pub mod interleave;
+pub mod joined;
+pub mod kmers;
+pub mod long_read;
 mod macro_impl;
+pub mod manifest;
+pub mod mapper;
+pub mod merge;
+pub mod metrics;
+pub mod multi;
+#[cfg(feature = "needletail")]
+pub mod needletail_reader;
+#[cfg(feature = "object_store")]
+pub mod object_reader;
+mod output_index;
+pub mod offload;
+pub mod paired;
+#[cfg(any(feature = "http", feature = "object_store"))]
+mod prefetch;
+pub mod report;
+#[cfg(feature = "parallel")]
+mod scaling;
+pub mod schedule;
+#[cfg(feature = "zstd")]
+pub mod seekable_zstd;
+pub mod soa;
 pub mod processor;
+pub mod processors;
+pub mod progress;
+pub mod qc;
+pub mod quality;
 pub mod reader;
+pub mod retry;
 pub mod record;
+pub mod regions;
+pub mod sort;
+pub mod source;
+pub mod stats;
+pub mod subsample;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+pub mod trim;
+pub mod umi;
+pub mod validate;
+pub mod window;
+pub mod writer;
 
-pub use processor::ParallelProcessor;
-pub use reader::ParallelReader;
-pub use record::MinimalRefRecord;
+pub use adapter::AdapterTrimmer;
+pub use archive::TarReader;
+#[cfg(feature = "arrow")]
+pub use arrow_batch::{record_set_to_arrow, sequence_schema};
+pub use autotune::{autotune, Autotune};
+pub use base_batch::BaseBatchReader;
+pub use bgzf::{BgzfBlocks, BgzfReader};
+pub use checkpoint::Checkpoint;
+#[cfg(feature = "checksum")]
+pub use checksum::{ChecksumAlgorithm, ChecksumLayer, Checksums, InputChecksum};
+pub use chunk::{ByteRange, RawChunkReader};
+pub use compression::Compression;
+pub use config::{Backpressure, EmptyBatches, ParallelConfig};
+pub use count::count_records_parallel;
+pub use dedup::{
+    dedup_paired_parallel, dedup_parallel, duplicate_stats_paired_parallel,
+    duplicate_stats_parallel, BatchInsertions, DedupKey, DedupWriter, DuplicateSet,
+    DuplicateStats, PairedDedupWriter,
+};
+pub use diff::{diff_parallel, diff_parallel_with_options, DiffOptions, DiffReport, Difference};
+pub use dispatch::{DefaultDispatcher, Dispatcher};
+pub use fasta_qual::FastaQualReader;
+pub use fastx::{FastxReader, FastxRecord, Format};
+pub use fetch::{fetch_records, fetch_records_with_config};
+pub use filter::RecordFilter;
+pub use group::GroupedReader;
+#[cfg(feature = "parallel")]
+pub use handle::RunHandle;
+pub use histogram::{length_histogram_parallel, LengthHistogram};
+#[cfg(feature = "http")]
+pub use http::{open_url, process_url_sharded, HttpReader};
+pub use id_filter::{filter_ids_paired_parallel, filter_ids_parallel, IdFilter, IdFilterMode};
+pub use index::{Index, IndexBuilder, IndexedRecord};
+pub use interleave::{deinterleave_parallel, interleave_parallel, interleave_to_command};
+pub use joined::JoinedFastaReader;
+pub use long_read::LongReadReader;
+pub use manifest::{Manifest, SampleInfo, SampleResult};
+pub use mapper::{MapWriter, Mates, PairedMapWriter, PairedMapper, ParallelMapper};
+pub use merge::{merge_pairs_parallel, MergeStats, MergeWriter, PairMerger};
+pub use metrics::Metrics;
+pub use multi::{
+    process_files_parallel, process_files_parallel_with_sources, process_files_with_factory,
+    SourceInfo,
+};
+#[cfg(feature = "needletail")]
+pub use needletail_reader::NeedletailReader;
+#[cfg(feature = "object_store")]
+pub use object_reader::{open_object, ObjectReader};
+pub use offload::BatchCompletion;
+pub use paired::{MateCountMismatch, PairedReader};
+pub use processor::{
+    BatchProcessor, Borrowed, DynParallelProcessor, ErrorAction, GroupProcessor, MemberProcessor,
+    OffloadProcessor, PairedParallelProcessor, ParallelProcessor, ProcessorFactory, Shared,
+    SharedParallelProcessor, SourceProcessor, WindowProcessor,
+};
+pub use progress::{CountingReader, InputProgress};
+pub use qc::{
+    overrepresented_qc_parallel, profile_qc_parallel, tile_qc_parallel, MultiqcFormat,
+    MultiqcReport, OverrepresentedQc, OverrepresentedReport, ProfileQc, ProfileReport,
+    QualityTable, TileQc, TileReport,
+};
+pub use quality::QualityEncoding;
+pub use reader::{PairedParallelReader, ParallelReader};
+pub use retry::{RetryPolicy, RetryReader, Retryable};
+pub use record::{MinimalRefRecord, Record, RecordSetLike, SliceRecord};
+pub use regions::{fetch_regions, process_regions, write_regions, FaiIndex, Region};
+pub use schedule::Scheduler;
+pub use soa::SoaBatch;
+pub use sort::{ExternalSorter, SortKey, SortStats};
+pub use source::{process_source_parallel, RecordSetSource};
+pub use stats::{RunStats, WorkerTiming};
+pub use subsample::Subsampler;
+pub use trim::QualityTrimmer;
+pub use umi::{extract_umis_paired_parallel, UmiExtractor, UmiPattern, UmiWriter};
+pub use validate::{validate_paired_parallel, validate_parallel};
+pub use window::{Window, WindowedReader};
+pub use writer::ParallelWriter;
 
 pub use seq_io::{fasta, fastq, policy};
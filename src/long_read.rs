@@ -0,0 +1,173 @@
+//! Reading tuned for long reads (ONT, PacBio)
+//!
+//! A single long read can take as long to process as thousands of short
+//! reads, so filling a buffer-sized record set leaves most workers idle.
+//! [`LongReadReader`] instead dispatches a few records per batch (one by
+//! default), reads with a large initial buffer, and caps the buffer growth so
+//! that a corrupt or unexpectedly huge record fails with a clear error
+//! instead of exhausting memory.
+use anyhow::{anyhow, Result};
+use seq_io::policy::DoubleUntilLimited;
+use seq_io::{fasta, fastq, policy};
+use std::io;
+use std::path::Path;
+
+use crate::batch::RecordBuffer;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, BoxedReader, Format};
+use crate::macro_impl::run_parallel;
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Default initial buffer size
+pub const DEFAULT_LONG_READ_BUFFER: usize = 8 << 20;
+
+/// Default maximum size of a single record (header, sequence and qualities)
+pub const DEFAULT_MAX_RECORD_LEN: usize = 1 << 30;
+
+/// Default number of records per batch
+pub const DEFAULT_LONG_READS_PER_BATCH: usize = 1;
+
+enum LongReadInner<R: io::Read> {
+    Fasta(fasta::Reader<R, DoubleUntilLimited>),
+    Fastq(fastq::Reader<R, DoubleUntilLimited>),
+}
+
+/// Reader dispatching long reads in small batches
+pub struct LongReadReader<R: io::Read = BoxedReader> {
+    inner: LongReadInner<R>,
+    records_per_batch: usize,
+    max_record_len: usize,
+    /// Records read so far, for error messages
+    num_read: usize,
+}
+
+impl LongReadReader<BoxedReader> {
+    /// Opens a (possibly compressed) file and detects its format
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let (handle, format) = open_path(path)?;
+        Ok(Self::new(handle, format))
+    }
+}
+
+impl<R: io::Read> LongReadReader<R> {
+    pub fn new(reader: R, format: Format) -> Self {
+        let policy = Self::policy(DEFAULT_MAX_RECORD_LEN);
+        let inner = match format {
+            Format::Fasta => LongReadInner::Fasta(fasta::Reader::with_cap_and_policy(
+                reader,
+                DEFAULT_LONG_READ_BUFFER,
+                policy,
+            )),
+            Format::Fastq => LongReadInner::Fastq(fastq::Reader::with_cap_and_policy(
+                reader,
+                DEFAULT_LONG_READ_BUFFER,
+                policy,
+            )),
+        };
+        Self {
+            inner,
+            records_per_batch: DEFAULT_LONG_READS_PER_BATCH,
+            max_record_len: DEFAULT_MAX_RECORD_LEN,
+            num_read: 0,
+        }
+    }
+
+    /// Buffer policy doubling up to 64 MiB, then growing linearly up to `max_record_len`
+    fn policy(max_record_len: usize) -> DoubleUntilLimited {
+        DoubleUntilLimited::new(64 << 20, max_record_len.max(DEFAULT_LONG_READ_BUFFER))
+    }
+
+    pub fn with_records_per_batch(mut self, records_per_batch: usize) -> Self {
+        self.records_per_batch = records_per_batch.max(1);
+        self
+    }
+
+    /// Sets the largest record (in bytes, including header and qualities) the reader accepts
+    pub fn with_max_record_len(mut self, max_record_len: usize) -> Self {
+        let policy = Self::policy(max_record_len);
+        self.inner = match self.inner {
+            LongReadInner::Fasta(reader) => LongReadInner::Fasta(reader.set_policy(policy)),
+            LongReadInner::Fastq(reader) => LongReadInner::Fastq(reader.set_policy(policy)),
+        };
+        self.max_record_len = max_record_len;
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        match self.inner {
+            LongReadInner::Fasta(_) => Format::Fasta,
+            LongReadInner::Fastq(_) => Format::Fastq,
+        }
+    }
+
+    /// Fills `records` with the next records, copying joined sequences
+    fn read_records(&mut self, records: &mut RecordBuffer) -> Option<Result<()>> {
+        records.clear();
+
+        macro_rules! fill {
+            ($reader:expr, $module:ident) => {
+                while records.len() < self.records_per_batch {
+                    match $reader.next() {
+                        Some(Ok(record)) => {
+                            let seq = record.ref_full_seq();
+                            records.push(record.ref_head(), &seq, record.ref_qual());
+                            self.num_read += 1;
+                        }
+                        Some(Err($module::Error::BufferLimit)) => {
+                            return Some(Err(record_too_large(
+                                self.num_read + 1,
+                                self.max_record_len,
+                            )))
+                        }
+                        Some(Err(e)) => return Some(Err(e.into())),
+                        None => break,
+                    }
+                }
+            };
+        }
+
+        match &mut self.inner {
+            LongReadInner::Fasta(reader) => fill!(reader, fasta),
+            LongReadInner::Fastq(reader) => fill!(reader, fastq),
+        }
+        if records.is_empty() {
+            return None;
+        }
+        Some(Ok(()))
+    }
+}
+
+/// Error for a record exceeding the buffer limit
+fn record_too_large(record_number: usize, max_record_len: usize) -> anyhow::Error {
+    anyhow!(
+        "Record {} is larger than the maximum record length of {} bytes; raise it with `with_max_record_len`",
+        record_number,
+        max_record_len
+    )
+}
+
+impl<R> ParallelReader<R, policy::DoubleUntilLimited> for LongReadReader<R>
+where
+    R: io::Read + Send,
+{
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        run_parallel::<D, _, RecordBuffer, _, _, _>(
+            self,
+            processor,
+            &config,
+            |reader, records| reader.read_records(records),
+            |records, processor, global_idx| records.process(processor, global_idx),
+        )
+    }
+}
@@ -1,162 +1,1363 @@
-use anyhow::Result;
-use crossbeam_channel::{bounded, Receiver, Sender};
+use anyhow::{bail, Result};
+use flate2::Crc;
 use parking_lot::Mutex;
+#[cfg(feature = "parallel")]
+use parking_lot::{Condvar, MutexGuard};
 use seq_io::policy;
-use std::{io, sync::Arc, thread};
+#[cfg(feature = "parallel")]
+use std::{collections::BTreeSet, sync::atomic::AtomicBool};
+use std::{
+    fs::File,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
+#[cfg(feature = "parallel")]
+use crate::affinity;
+use crate::batch::BatchSize;
+use crate::checkpoint::CheckpointTracker;
+use crate::chunk::ByteRange;
+#[cfg(not(feature = "parallel"))]
+use crate::config::ProgressHook;
+use crate::config::{Backpressure, EmptyBatches, ParallelConfig, Throttle};
+use crate::dispatch::Dispatcher;
+#[cfg(feature = "parallel")]
+use crate::dispatch::{BatchReceiver, BatchSender};
+#[cfg(feature = "parallel")]
+use crate::handle::{RunControl, CONTROL_INTERVAL};
+use crate::metrics::Metrics;
+use crate::offload::InFlight;
+use crate::progress::InputProgress;
+use crate::report::write_report;
+use crate::retry::{RetryPolicy, Retryable};
+#[cfg(feature = "parallel")]
+use crate::scaling::{WorkerGate, SCALING_INTERVAL};
+#[cfg(feature = "parallel")]
+use crate::schedule::{BatchInfo, Scheduler};
+use crate::soa::SoaBatch;
+use crate::source::process_source_parallel;
+use crate::stats::{ErrorBudget, RunStats, WorkerTiming};
+use crate::processor::{
+    commit_batch_errors, discard_batch_errors, set_current_batch, set_error_budget,
+    take_counted_errors,
+    BatchProcessor, GroupProcessor, MemberProcessor, OffloadProcessor, PairedParallelProcessor,
+    SourceProcessor, WindowProcessor,
+};
 use crate::{ParallelProcessor, ParallelReader};
 
+#[cfg(feature = "parallel")]
 type RecordSets<T> = Arc<Vec<Mutex<T>>>;
-type ProcessorChannels = (Sender<Option<(usize, usize)>>, Receiver<Option<(usize, usize)>>);
+#[cfg(feature = "parallel")]
+type BatchMessage = Option<(usize, usize)>;
+
+/// Thread-level hooks shared by the single-end and paired processor traits
+pub(crate) trait WorkerHooks: Send + Clone {
+    fn set_thread_id(&mut self, thread_id: usize);
+    fn on_batch_complete(&mut self) -> Result<()>;
+    /// Undoes a failed attempt at a batch before it is processed again
+    fn on_batch_retry(&mut self) -> Result<()>;
+    fn on_thread_complete(&mut self) -> Result<()>;
+}
+
+impl<T: ParallelProcessor> WorkerHooks for T {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        ParallelProcessor::set_thread_id(self, thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        ParallelProcessor::on_batch_complete(self)
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        ParallelProcessor::on_batch_retry(self)
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        ParallelProcessor::on_thread_complete(self)
+    }
+}
+
+/// Adapter that passes the byte range of each batch to the processor
+#[derive(Clone)]
+pub(crate) struct RangeWorker<T> {
+    pub(crate) processor: T,
+    pub(crate) range: Option<ByteRange>,
+}
+
+impl<T> RangeWorker<T> {
+    pub(crate) fn new(processor: T) -> Self {
+        Self {
+            processor,
+            range: None,
+        }
+    }
+}
+
+impl<T: ParallelProcessor> WorkerHooks for RangeWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.processor.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        match self.range.take() {
+            Some(range) => self.processor.on_batch_complete_with_range(&range),
+            None => self.processor.on_batch_complete(),
+        }
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.processor.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.processor.on_thread_complete()
+    }
+}
+
+/// Adapter that keeps a handle on the input per worker, for random access
+pub(crate) struct FetchWorker<T> {
+    pub(crate) processor: T,
+    pub(crate) file: Option<File>,
+}
+
+impl<T> FetchWorker<T> {
+    pub(crate) fn new(processor: T) -> Self {
+        Self {
+            processor,
+            file: None,
+        }
+    }
+}
+
+/// Clones start without a file, so that every worker opens its own
+impl<T: Clone> Clone for FetchWorker<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.processor.clone())
+    }
+}
+
+impl<T: ParallelProcessor> WorkerHooks for FetchWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.processor.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.processor.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.processor.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.processor.on_thread_complete()
+    }
+}
+
+/// Adapter that runs a paired processor on the worker pool
+#[derive(Clone)]
+pub(crate) struct PairedWorker<T>(pub T);
+
+impl<T: PairedParallelProcessor> WorkerHooks for PairedWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.0.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.0.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.0.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.0.on_thread_complete()
+    }
+}
+
+/// Adapter that runs a group processor on the worker pool
+#[derive(Clone)]
+pub(crate) struct GroupWorker<T>(pub T);
+
+impl<T: GroupProcessor> WorkerHooks for GroupWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.0.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.0.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.0.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.0.on_thread_complete()
+    }
+}
+
+/// Adapter that runs an archive member processor on the worker pool
+#[derive(Clone)]
+pub(crate) struct MemberWorker<T>(pub T);
+
+impl<T: MemberProcessor> WorkerHooks for MemberWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.0.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.0.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.0.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.0.on_thread_complete()
+    }
+}
+
+/// Adapter that runs a source processor on the worker pool
+#[derive(Clone)]
+pub(crate) struct SourceWorker<T>(pub T);
+
+impl<T: SourceProcessor> WorkerHooks for SourceWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.0.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.0.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.0.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.0.on_thread_complete()
+    }
+}
+
+/// Adapter that runs a batch processor on the worker pool, with its batch buffers
+#[derive(Clone)]
+pub(crate) struct BatchWorker<T> {
+    pub(crate) processor: T,
+    pub(crate) batch: SoaBatch,
+}
+
+impl<T> BatchWorker<T> {
+    pub(crate) fn new(processor: T) -> Self {
+        Self {
+            processor,
+            batch: SoaBatch::new(),
+        }
+    }
+}
+
+impl<T: BatchProcessor> WorkerHooks for BatchWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.processor.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.processor.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.processor.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.processor.on_thread_complete()
+    }
+}
+
+/// Adapter that runs an offload processor on the worker pool, with the batches in flight
+#[derive(Clone)]
+pub(crate) struct OffloadWorker<T> {
+    pub(crate) processor: T,
+    pub(crate) in_flight: Arc<InFlight>,
+}
+
+impl<T: OffloadProcessor> WorkerHooks for OffloadWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.processor.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.processor.on_thread_complete()
+    }
+}
+
+/// Adapter that runs a window processor on the worker pool
+#[derive(Clone)]
+pub(crate) struct WindowWorker<T>(pub T);
+
+impl<T: WindowProcessor> WorkerHooks for WindowWorker<T> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.0.set_thread_id(thread_id)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.0.on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.0.on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.0.on_thread_complete()
+    }
+}
 
 /// Creates a collection of record sets
-///
-/// Note: The number of record sets is twice the number of threads
-/// to allow for double buffering
-fn create_record_sets<T: Default>(num_threads: usize) -> RecordSets<T> {
-    let record_sets = (0..num_threads * 2)
-        .map(|_| Mutex::new(T::default()))
-        .collect();
+#[cfg(feature = "parallel")]
+fn create_record_sets<T: Default>(num_sets: usize) -> RecordSets<T> {
+    let record_sets = (0..num_sets).map(|_| Mutex::new(T::default())).collect();
     Arc::new(record_sets)
 }
 
 /// Creates a pair of channels for communication between reader and worker threads
-fn create_channels(buffer_size: usize) -> ProcessorChannels {
-    bounded(buffer_size)
+#[cfg(feature = "parallel")]
+fn create_channels<D: Dispatcher>(
+    buffer_size: usize,
+) -> (D::Sender<BatchMessage>, D::Receiver<BatchMessage>) {
+    D::bounded(buffer_size)
+}
+
+/// Creates the free list of record set slots, initially holding every slot
+#[cfg(feature = "parallel")]
+fn create_free_slots<D: Dispatcher>(
+    num_sets: usize,
+) -> Result<(D::Sender<usize>, D::Receiver<usize>)> {
+    let (free_tx, free_rx) = D::bounded(num_sets);
+    for idx in 0..num_sets {
+        free_tx.send(idx)?;
+    }
+    Ok((free_tx, free_rx))
+}
+
+/// Hands batches from the reader threads to the workers
+#[cfg(feature = "parallel")]
+enum BatchQueue<'a, S, Rx> {
+    /// Dispatcher channel, read by whichever worker is idle
+    Channel(S, Rx),
+    /// Scheduler set in the run configuration
+    Scheduled(&'a dyn Scheduler),
+}
+
+#[cfg(feature = "parallel")]
+impl<S: Clone, Rx: Clone> Clone for BatchQueue<'_, S, Rx> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Channel(tx, rx) => Self::Channel(tx.clone(), rx.clone()),
+            Self::Scheduled(scheduler) => Self::Scheduled(*scheduler),
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl<S, Rx> BatchQueue<'_, S, Rx>
+where
+    S: BatchSender<BatchMessage>,
+    Rx: BatchReceiver<BatchMessage>,
+{
+    fn push(&self, batch: BatchInfo) -> Result<()> {
+        match self {
+            Self::Channel(tx, _) => tx.send(Some((batch.slot, batch.batch_idx))),
+            Self::Scheduled(scheduler) => {
+                scheduler.push(batch);
+                Ok(())
+            }
+        }
+    }
+
+    /// Next `(slot, batch_idx)` for worker `thread_id`
+    fn pop(&self, thread_id: usize) -> Option<(usize, usize)> {
+        match self {
+            Self::Channel(_, rx) => rx.recv().flatten(),
+            Self::Scheduled(scheduler) => scheduler
+                .pop(thread_id)
+                .map(|batch| (batch.slot, batch.batch_idx)),
+        }
+    }
+
+    /// Signals the `num_threads` workers that no more batches follow
+    fn close(&self, num_threads: usize) -> Result<()> {
+        match self {
+            Self::Channel(tx, _) => (0..num_threads).try_for_each(|_| tx.send(None)),
+            Self::Scheduled(scheduler) => {
+                scheduler.close();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// State shared between all reader threads of a run
+struct ReaderState {
+    /// Next batch index handed out across all readers
+    next_batch: AtomicUsize,
+    /// Set once any thread failed so that readers stop early
+    #[cfg(feature = "parallel")]
+    abort: AtomicBool,
+    /// Rate limit shared across readers, if any
+    throttle: Option<Mutex<Throttle>>,
+    /// Counters behind the returned `RunStats`
+    stats: Metrics,
+    /// Counters supplied by the user, if any
+    metrics: Option<Arc<Metrics>>,
+    /// Leading batches still to be skipped when resuming
+    skip_batches: AtomicUsize,
+    checkpoint: Option<CheckpointTracker>,
+    /// Parks and wakes workers with the queue occupancy, if enabled
+    #[cfg(feature = "parallel")]
+    gate: Option<WorkerGate>,
+    /// Wait and busy time of every worker, by thread id
+    timers: Vec<WorkerTimer>,
+    /// Bounds how far dispatched batches run ahead of unfinished ones, if set
+    #[cfg(feature = "parallel")]
+    window: Option<ReorderWindow>,
+    #[cfg(feature = "parallel")]
+    backpressure: Backpressure,
+    empty_batches: EmptyBatches,
+    /// Processing errors tolerated before aborting
+    errors: Arc<ErrorBudget>,
+    batch_retry: Option<RetryPolicy>,
+    input_progress: Option<InputProgress>,
+    /// Handle of a spawned run, if any
+    #[cfg(feature = "parallel")]
+    control: Option<Arc<RunControl>>,
+    /// Index and checksum of every dispatched batch, if enabled
+    batch_checksums: Option<Mutex<Vec<(usize, u32)>>>,
+    #[cfg(feature = "checksum")]
+    checksums: Option<crate::checksum::Checksums>,
+}
+
+/// Whether the target has a clock; `Instant::now` panics on `wasm32-unknown-unknown`
+pub(crate) const HAS_CLOCK: bool = !cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+
+/// Start of a timed span, which reads as zero on targets without a clock
+#[derive(Clone, Copy)]
+struct Stopwatch(Option<Instant>);
+
+impl Stopwatch {
+    fn start() -> Self {
+        Self(HAS_CLOCK.then(Instant::now))
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.0.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+/// Running wait and busy time of a worker, in nanoseconds
+#[derive(Default)]
+struct WorkerTimer {
+    wait: AtomicU64,
+    busy: AtomicU64,
+}
+
+impl WorkerTimer {
+    fn add(counter: &AtomicU64, since: Stopwatch) {
+        counter.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn timing(&self, thread_id: usize) -> WorkerTiming {
+        WorkerTiming {
+            thread_id,
+            wait: Duration::from_nanos(self.wait.load(Ordering::Relaxed)),
+            busy: Duration::from_nanos(self.busy.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl ReaderState {
+    /// Internal and user-supplied counters
+    fn metrics(&self) -> impl Iterator<Item = &Metrics> {
+        std::iter::once(&self.stats).chain(self.metrics.as_deref())
+    }
+
+    /// Whether reading goes on past an empty batch, which is never dispatched
+    fn read_past_empty(&self) -> bool {
+        self.empty_batches == EmptyBatches::Skip
+    }
+
+    /// Whether the batch just read was covered by the resumed checkpoint
+    fn skip_batch(&self) -> bool {
+        self.skip_batches
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
+/// Keeps the reader from dispatching batches too far past the oldest unfinished batch
+#[cfg(feature = "parallel")]
+struct ReorderWindow {
+    size: usize,
+    state: Mutex<WindowState>,
+    advanced: Condvar,
+}
+
+#[cfg(feature = "parallel")]
+struct WindowState {
+    /// Oldest batch not finished yet
+    oldest: usize,
+    /// Batches finished after `oldest`
+    finished: BTreeSet<usize>,
+}
+
+#[cfg(feature = "parallel")]
+impl ReorderWindow {
+    fn new(size: usize, first_batch: usize) -> Self {
+        Self {
+            size,
+            state: Mutex::new(WindowState {
+                oldest: first_batch,
+                finished: BTreeSet::new(),
+            }),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `batch_idx` is within the window or the run is aborted
+    fn wait_turn(&self, batch_idx: usize, abort: &AtomicBool) {
+        let mut state = self.state.lock();
+        while batch_idx >= state.oldest + self.size && !abort.load(Ordering::Relaxed) {
+            self.advanced.wait_for(&mut state, Duration::from_millis(100));
+        }
+    }
+
+    /// Marks a batch as finished, whether it succeeded or not
+    fn on_batch_done(&self, batch_idx: usize) {
+        let mut state = self.state.lock();
+        state.finished.insert(batch_idx);
+        let mut advanced = false;
+        while state.finished.first() == Some(&state.oldest) {
+            state.finished.pop_first();
+            state.oldest += 1;
+            advanced = true;
+        }
+        if advanced {
+            self.advanced.notify_all();
+        }
+    }
+}
+
+/// Wakes the monitor thread until the run is done
+#[cfg(feature = "parallel")]
+#[derive(Default)]
+struct Monitor {
+    done: Mutex<bool>,
+    wake: Condvar,
+}
+
+#[cfg(feature = "parallel")]
+impl Monitor {
+    /// Calls `tick` every `interval` until `stop` is called
+    fn run(&self, interval: Duration, tick: impl Fn()) {
+        let mut done = self.done.lock();
+        let mut next = Instant::now() + interval;
+        while !*done {
+            if self.wake.wait_until(&mut done, next).timed_out() {
+                MutexGuard::unlocked(&mut done, &tick);
+                next += interval;
+            }
+        }
+    }
+
+    fn stop(&self) {
+        *self.done.lock() = true;
+        self.wake.notify_all();
+    }
+}
+
+/// Stops the monitor thread when the run returns, including on early errors
+#[cfg(feature = "parallel")]
+struct StopMonitor<'a>(&'a Monitor);
+
+#[cfg(feature = "parallel")]
+impl Drop for StopMonitor<'_> {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+/// Totals of a run so far
+fn run_stats(state: &ReaderState, num_threads: usize, start: Stopwatch) -> RunStats {
+    let snapshot = state.stats.snapshot();
+    RunStats {
+        num_threads,
+        records: snapshot.records_processed,
+        bytes: snapshot.bytes_read,
+        batches: snapshot.batches_processed,
+        dropped_records: snapshot.records_dropped,
+        dropped_batches: snapshot.batches_dropped,
+        retried_batches: snapshot.batches_retried,
+        errors: snapshot.errors,
+        error_messages: state.errors.messages(),
+        batch_checksums: state
+            .batch_checksums
+            .as_ref()
+            .map_or_else(Vec::new, |checksums| {
+                let mut checksums = checksums.lock().clone();
+                checksums.sort_unstable();
+                checksums.into_iter().map(|(_, checksum)| checksum).collect()
+            }),
+        #[cfg(feature = "checksum")]
+        checksums: state
+            .checksums
+            .as_ref()
+            .map_or_else(Vec::new, |checksums| checksums.values()),
+        input_bytes_read: state
+            .input_progress
+            .as_ref()
+            .map_or(0, InputProgress::bytes_read),
+        input_bytes_decompressed: state
+            .input_progress
+            .as_ref()
+            .map_or(0, InputProgress::bytes_decompressed),
+        input_bytes_total: state
+            .input_progress
+            .as_ref()
+            .map_or(0, InputProgress::total_bytes),
+        elapsed: start.elapsed(),
+        workers: state
+            .timers
+            .iter()
+            .enumerate()
+            .map(|(thread_id, timer)| timer.timing(thread_id))
+            .collect(),
+    }
+}
+
+/// Counts a batch just read, waits out the rate limit and returns the batch's global index
+fn register_batch<T: BatchSize>(state: &ReaderState, record_set: &T) -> usize {
+    let (num_records, num_bytes) = (record_set.num_records(), record_set.num_bytes());
+    state
+        .metrics()
+        .for_each(|m| m.on_batch_read(num_records, num_bytes));
+    let delay = state
+        .throttle
+        .as_ref()
+        .map(|t| t.lock().delay(num_records, num_bytes));
+    if let Some(delay) = delay {
+        thread::sleep(delay);
+    }
+    let global_idx = state.next_batch.fetch_add(1, Ordering::Relaxed);
+    if let Some(checksums) = &state.batch_checksums {
+        let mut crc = Crc::new();
+        crc.update(&(num_records as u64).to_le_bytes());
+        record_set.checksum(&mut crc);
+        checksums.lock().push((global_idx, crc.sum()));
+    }
+    global_idx
+}
+
+/// Runs `process_fn` on a batch, repeating it while it fails with a retryable error and retries are left
+///
+/// Before every retry the processor undoes the failed attempt in its
+/// `on_batch_retry` hook, and the errors the attempt tolerated or counted go
+/// back to the run's budget. The errors of the final attempt are kept.
+fn process_with_retries<T, P, F>(
+    record_set: &T,
+    processor: &mut P,
+    global_idx: usize,
+    state: &ReaderState,
+    process_fn: &F,
+) -> Result<()>
+where
+    P: WorkerHooks,
+    F: Fn(&T, &mut P, usize) -> Result<()>,
+{
+    let mut attempt = 0;
+    let result = loop {
+        match process_fn(record_set, processor, global_idx) {
+            Err(e) if Retryable::is_retryable(&e) => match &state.batch_retry {
+                Some(policy) if attempt < policy.max_retries() => {
+                    discard_batch_errors();
+                    if let Err(e) = processor.on_batch_retry() {
+                        break Err(e);
+                    }
+                    state.metrics().for_each(Metrics::on_batch_retried);
+                    if HAS_CLOCK {
+                        thread::sleep(policy.backoff(attempt));
+                    }
+                    attempt += 1;
+                }
+                _ => break Err(e),
+            },
+            result => break result,
+        }
+    };
+    commit_batch_errors();
+    result
+}
+
+/// Processes one batch and runs the batch hooks, tolerating errors within the run's budget
+fn process_batch<T, P, F>(
+    record_set: &T,
+    processor: &mut P,
+    global_idx: usize,
+    state: &ReaderState,
+    process_fn: &F,
+) -> Result<()>
+where
+    T: BatchSize,
+    P: WorkerHooks,
+    F: Fn(&T, &mut P, usize) -> Result<()>,
+{
+    set_current_batch(Some(global_idx));
+    let result = match process_with_retries(record_set, processor, global_idx, state, process_fn) {
+        Err(e) if state.errors.tolerate(&e) => {
+            state.metrics().for_each(Metrics::on_error);
+            Ok(())
+        }
+        result => result,
+    };
+    let result = result
+        .and_then(|_| processor.on_batch_complete())
+        .and_then(|_| match &state.checkpoint {
+            Some(checkpoint) => checkpoint.on_batch_done(
+                global_idx,
+                record_set.num_records(),
+                record_set.num_bytes(),
+            ),
+            None => Ok(()),
+        });
+    set_current_batch(None);
+    let counted_errors = take_counted_errors();
+    if counted_errors > 0 {
+        state
+            .metrics()
+            .for_each(|m| m.on_record_errors(counted_errors));
+    }
+    match &result {
+        Ok(()) => {
+            let num_records = record_set.num_records();
+            state
+                .metrics()
+                .for_each(|m| m.on_batch_processed(num_records));
+        }
+        Err(_) => state.metrics().for_each(Metrics::on_error),
+    }
+    result
 }
 
 /// Internal processing of reader thread
-fn run_reader_thread<R, T, F>(
+///
+/// A record set slot is only refilled after a worker handed it back through
+/// the free list, so slots in flight are never overwritten. Unless the
+/// backpressure policy blocks, batches read while no slot is free go to a
+/// spare record set and are either dropped or swapped into the next free slot.
+#[cfg(feature = "parallel")]
+fn run_reader_thread<R, T, S, Rx, FS, FR, F>(
     mut reader: R,
     record_sets: RecordSets<T>,
-    tx: Sender<Option<(usize, usize)>>,
-    num_threads: usize,
+    queue: BatchQueue<'_, S, Rx>,
+    free_tx: FS,
+    free_rx: FR,
+    state: &ReaderState,
     read_fn: F,
 ) -> Result<()>
 where
+    T: BatchSize + Default,
+    S: BatchSender<BatchMessage>,
+    Rx: BatchReceiver<BatchMessage>,
+    FS: BatchSender<usize>,
+    FR: BatchReceiver<usize>,
     F: Fn(&mut R, &mut T) -> Option<Result<()>>,
 {
-    let mut current_idx = 0;
-    let mut global_idx = 0;
-    
-    loop {
+    let dispatch = |slot: usize, num_records: usize, num_bytes: usize| -> Result<()> {
+        let global_idx = register_batch(state, &*record_sets[slot].lock());
+        if let Some(window) = &state.window {
+            window.wait_turn(global_idx, &state.abort);
+        }
+        if let Some(gate) = &state.gate {
+            gate.on_push();
+        }
+        queue.push(BatchInfo {
+            slot,
+            batch_idx: global_idx,
+            num_records,
+            num_bytes,
+        })
+    };
+    let fail = |e| {
+        state.abort.store(true, Ordering::Relaxed);
+        state.metrics().for_each(Metrics::on_error);
+        Err(e)
+    };
+
+    // Read into while every slot is in use, unless the policy is to block
+    let mut spare = T::default();
+    let mut pressured_batches = 0;
+    while !state.abort.load(Ordering::Relaxed) {
+        if state.control.as_ref().is_some_and(|c| c.is_cancelled()) {
+            state.abort.store(true, Ordering::Relaxed);
+            bail!("Run was cancelled");
+        }
+        let free = match state.backpressure {
+            Backpressure::Block => free_rx.recv(),
+            _ => free_rx.try_recv(),
+        };
+        let Some(current_idx) = free else {
+            if state.backpressure == Backpressure::Block {
+                break;
+            }
+            match read_fn(&mut reader, &mut spare) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => return fail(e),
+                None => break,
+            }
+            if spare.is_empty_batch() {
+                if state.read_past_empty() {
+                    continue;
+                }
+                break;
+            }
+            let (num_records, num_bytes) = (spare.num_records(), spare.num_bytes());
+            let keep = match state.backpressure {
+                Backpressure::Sample(n) => pressured_batches % n == 0,
+                _ => false,
+            };
+            pressured_batches += 1;
+            if !keep {
+                state
+                    .metrics()
+                    .for_each(|m| m.on_batch_dropped(num_records, num_bytes));
+                continue;
+            }
+            let Some(current_idx) = free_rx.recv() else {
+                break;
+            };
+            std::mem::swap(&mut *record_sets[current_idx].lock(), &mut spare);
+            dispatch(current_idx, num_records, num_bytes)?;
+            continue;
+        };
         let mut record_set = record_sets[current_idx].lock();
 
         if let Some(result) = read_fn(&mut reader, &mut record_set) {
-            result?;
+            if let Err(e) = result {
+                return fail(e);
+            }
+            if record_set.is_empty_batch() {
+                drop(record_set);
+                free_tx.send(current_idx)?;
+                if state.read_past_empty() {
+                    continue;
+                }
+                break;
+            }
+            if state.skip_batch() {
+                drop(record_set);
+                free_tx.send(current_idx)?;
+                continue;
+            }
 
+            let (num_records, num_bytes) = (record_set.num_records(), record_set.num_bytes());
             drop(record_set);
-            tx.send(Some((current_idx, global_idx))).unwrap();
-            current_idx = (current_idx + 1) % record_sets.len();
-            global_idx += 1;
+            dispatch(current_idx, num_records, num_bytes)?;
         } else {
+            drop(record_set);
+            free_tx.send(current_idx)?;
             break;
         }
     }
 
-    // Signal completion
-    for _ in 0..num_threads {
-        tx.send(None).unwrap();
-    }
-
     Ok(())
 }
 
 /// Internal processing of worker threads
-fn run_worker_thread<T, P, F>(
+///
+/// After a processing error the worker keeps draining the queue (without
+/// processing) so that readers never block on a slot that is not returned.
+#[cfg(feature = "parallel")]
+fn run_worker_thread<T, P, S, Rx, FS, F>(
     record_sets: RecordSets<T>,
-    rx: Receiver<Option<(usize, usize)>>,
+    queue: BatchQueue<'_, S, Rx>,
+    free_tx: FS,
     mut processor: P,
     thread_id: usize,
+    state: &ReaderState,
     process_fn: F,
 ) -> Result<()>
 where
-    P: ParallelProcessor,
+    T: BatchSize,
+    P: WorkerHooks,
+    S: BatchSender<BatchMessage>,
+    Rx: BatchReceiver<BatchMessage>,
+    FS: BatchSender<usize>,
     F: Fn(&T, &mut P, usize) -> Result<()>,
 {
     processor.set_thread_id(thread_id);
-    while let Ok(Some((idx, global_idx))) = rx.recv() {
-        let record_set = record_sets[idx].lock();
-        process_fn(&record_set, &mut processor, global_idx)?;
-        processor.on_batch_complete()?;
+    take_counted_errors();
+    set_error_budget(Some(Arc::clone(&state.errors)), state.batch_retry.is_some());
+    let timer = &state.timers[thread_id];
+    let mut failure = None;
+    loop {
+        if let Some(gate) = &state.gate {
+            gate.wait_turn(thread_id);
+        }
+        let waiting = Stopwatch::start();
+        let popped = queue.pop(thread_id);
+        WorkerTimer::add(&timer.wait, waiting);
+        let Some((idx, global_idx)) = popped else {
+            break;
+        };
+        if let Some(gate) = &state.gate {
+            gate.on_pop();
+        }
+        if failure.is_none() {
+            let busy = Stopwatch::start();
+            let record_set = record_sets[idx].lock();
+            let result = process_batch(&*record_set, &mut processor, global_idx, state, &process_fn);
+            drop(record_set);
+            WorkerTimer::add(&timer.busy, busy);
+            if let Err(e) = result {
+                state.abort.store(true, Ordering::Relaxed);
+                failure = Some(e);
+            }
+        }
+        if let Some(window) = &state.window {
+            window.on_batch_done(global_idx);
+        }
+        free_tx.send(idx)?;
+    }
+    if let Some(e) = failure {
+        return Err(e);
     }
     processor.on_thread_complete()?;
     Ok(())
 }
 
+/// Fails a completed run that did not read the number of records it was configured to expect
+fn check_expected_records<D: Dispatcher>(config: &ParallelConfig<D>, stats: &RunStats) -> Result<()> {
+    let Some(expected) = config.options.expected_records else {
+        return Ok(());
+    };
+    let resumed = config.resumed_checkpoint().map_or(0, |checkpoint| checkpoint.records);
+    let read = stats.records + stats.dropped_records + resumed;
+    if read != expected {
+        bail!(
+            "Read {} records but {} were expected; the input may be truncated",
+            read,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Runs a reader thread and `num_threads` worker threads over a shared pool of record sets
+///
+/// `read_fn` fills a record set on the reader thread and `process_fn` consumes
+/// one on a worker thread.
+pub(crate) fn run_parallel<D, R, S, T, RF, PF>(
+    reader: R,
+    processor: T,
+    config: &ParallelConfig<D>,
+    read_fn: RF,
+    process_fn: PF,
+) -> Result<RunStats>
+where
+    D: Dispatcher,
+    R: Send,
+    S: BatchSize + Default + Send,
+    T: WorkerHooks,
+    RF: Fn(&mut R, &mut S) -> Option<Result<()>> + Sync,
+    PF: Fn(&S, &mut T, usize) -> Result<()> + Sync,
+{
+    run_parallel_multi::<D, R, S, T, RF, PF>(vec![reader], processor, config, read_fn, process_fn)
+}
+
+/// Runs one reader thread per entry of `readers`, all feeding the same worker pool
+///
+/// Batch indices are assigned in the order batches are read, across all
+/// readers. Without the `parallel` feature the readers are read one after
+/// the other and every batch is processed on the calling thread.
+pub(crate) fn run_parallel_multi<D, R, S, T, RF, PF>(
+    readers: Vec<R>,
+    processor: T,
+    config: &ParallelConfig<D>,
+    read_fn: RF,
+    process_fn: PF,
+) -> Result<RunStats>
+where
+    D: Dispatcher,
+    R: Send,
+    S: BatchSize + Default + Send,
+    T: WorkerHooks,
+    RF: Fn(&mut R, &mut S) -> Option<Result<()>> + Sync,
+    PF: Fn(&S, &mut T, usize) -> Result<()> + Sync,
+{
+    // A sequential run has a single worker, whatever the configuration asks for
+    let num_threads = if cfg!(feature = "parallel") {
+        config.options.num_threads
+    } else {
+        1
+    };
+    let checkpoint = config.options.checkpoint.clone().map(CheckpointTracker::new);
+    if checkpoint.is_some() && readers.len() > 1 {
+        bail!("Checkpointing requires a single reader");
+    }
+    if config.options.min_threads.is_some() && config.options.scheduler.is_some() {
+        bail!("Worker scaling requires the default queue, not a custom scheduler");
+    }
+    if checkpoint.is_some() && config.options.backpressure != Backpressure::Block {
+        bail!("Checkpointing requires the blocking backpressure policy");
+    }
+    if !cfg!(feature = "parallel") && config.options.min_threads.is_some() {
+        bail!("Worker scaling requires the `parallel` feature");
+    }
+    if !HAS_CLOCK && config.is_throttled() {
+        bail!("Rate limits require a clock, which this target does not have");
+    }
+    let resume_batches = checkpoint.as_ref().map_or(0, CheckpointTracker::resume_batches);
+
+    let state = ReaderState {
+        next_batch: AtomicUsize::new(resume_batches),
+        #[cfg(feature = "parallel")]
+        abort: AtomicBool::new(false),
+        throttle: config.is_throttled().then(|| Mutex::new(Throttle::new(config))),
+        stats: Metrics::new(),
+        metrics: config.options.metrics.clone(),
+        skip_batches: AtomicUsize::new(resume_batches),
+        checkpoint,
+        #[cfg(feature = "parallel")]
+        gate: config
+            .options
+            .min_threads
+            .map(|min_threads| WorkerGate::new(min_threads, num_threads)),
+        timers: (0..num_threads).map(|_| WorkerTimer::default()).collect(),
+        #[cfg(feature = "parallel")]
+        window: config
+            .options
+            .reorder_window
+            .map(|size| ReorderWindow::new(size, resume_batches)),
+        #[cfg(feature = "parallel")]
+        backpressure: config.options.backpressure,
+        empty_batches: config.options.empty_batches,
+        errors: Arc::new(ErrorBudget::new(config.options.max_errors)),
+        batch_retry: config.options.batch_retry,
+        input_progress: config.options.input_progress.clone(),
+        #[cfg(feature = "parallel")]
+        control: config.options.control.clone(),
+        batch_checksums: config.options.batch_checksums.then(|| Mutex::new(Vec::new())),
+        #[cfg(feature = "checksum")]
+        checksums: config.options.checksums.clone(),
+    };
+    let start = Stopwatch::start();
+
+    #[cfg(feature = "parallel")]
+    let result = run_threads(readers, processor, config, &state, start, &read_fn, &process_fn);
+    #[cfg(not(feature = "parallel"))]
+    let result = run_sequential(
+        readers,
+        processor,
+        config.options.progress.as_ref(),
+        &state,
+        start,
+        &read_fn,
+        &process_fn,
+    );
+
+    let stats = run_stats(&state, num_threads, start);
+    let result = result.and_then(|_| check_expected_records(config, &stats));
+    #[cfg(feature = "parallel")]
+    if let Some(control) = &state.control {
+        control.update(stats.clone());
+    }
+    if let Some(checkpoint) = &state.checkpoint {
+        checkpoint.finish()?;
+    }
+    if let Some(report) = &config.options.report {
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        write_report(&report.path, &report.inputs, &stats, error.as_deref())?;
+    }
+    result.map(|_| stats)
+}
+
+/// Spawns the reader, worker and monitor threads of a run and waits for them
+#[cfg(feature = "parallel")]
+fn run_threads<D, R, S, T, RF, PF>(
+    readers: Vec<R>,
+    processor: T,
+    config: &ParallelConfig<D>,
+    state: &ReaderState,
+    start: Stopwatch,
+    read_fn: &RF,
+    process_fn: &PF,
+) -> Result<()>
+where
+    D: Dispatcher,
+    R: Send,
+    S: BatchSize + Default + Send,
+    T: WorkerHooks,
+    RF: Fn(&mut R, &mut S) -> Option<Result<()>> + Sync,
+    PF: Fn(&S, &mut T, usize) -> Result<()> + Sync,
+{
+    let num_threads = config.options.num_threads;
+    // Two sets per thread by default allow for double buffering, plus one slot per reader
+    let num_sets = num_threads * config.options.record_sets_per_thread + readers.len();
+    let record_sets = create_record_sets::<S>(num_sets);
+    let scheduler = config.options.scheduler.as_ref().map(|f| f.create(num_threads));
+    let queue = match scheduler.as_deref() {
+        Some(scheduler) => BatchQueue::Scheduled(scheduler),
+        None => {
+            let (tx, rx) = create_channels::<D>(num_sets);
+            BatchQueue::Channel(tx, rx)
+        }
+    };
+    let (free_tx, free_rx) = create_free_slots::<D>(num_sets)?;
+    let (reader_core, high_priority_reader) = (
+        config.options.reader_core,
+        config.options.high_priority_reader,
+    );
+    let monitor = Monitor::default();
+    let options = &config.options;
+
+    thread::scope(|scope| -> Result<()> {
+        // Spawn the monitor thread for periodic progress reports
+        let _stop_monitor = StopMonitor(&monitor);
+        if let Some(progress) = &options.progress {
+            let monitor = &monitor;
+            options.thread_builder("progress").spawn_scoped(scope, move || {
+                monitor.run(progress.interval, || {
+                    (progress.callback)(&run_stats(state, num_threads, start))
+                })
+            })?;
+        }
+
+        // Spawn the thread refreshing the statistics of a run handle
+        if let Some(control) = &state.control {
+            let monitor = &monitor;
+            options.thread_builder("control").spawn_scoped(scope, move || {
+                monitor.run(CONTROL_INTERVAL, || {
+                    control.update(run_stats(state, num_threads, start))
+                })
+            })?;
+        }
+
+        // Spawn the controller thread for worker scaling
+        if let Some(gate) = &state.gate {
+            let monitor = &monitor;
+            options
+                .thread_builder("scaling")
+                .spawn_scoped(scope, move || monitor.run(SCALING_INTERVAL, || gate.adjust()))?;
+        }
+
+        // Spawn worker threads first, so that a failed spawn can still shut down the running ones
+        let mut handles = Vec::new();
+        let mut spawned = Ok(());
+        for thread_id in 0..num_threads {
+            let worker_sets = Arc::clone(&record_sets);
+            let worker_queue = queue.clone();
+            let worker_free_tx = free_tx.clone();
+            let worker_processor = processor.clone();
+
+            let spawn = options.worker_builder(thread_id).spawn_scoped(scope, move || {
+                // Best effort: a worker on the reader's core only costs throughput
+                if let Some(core) = reader_core {
+                    affinity::avoid_core(core).ok();
+                }
+                if high_priority_reader {
+                    affinity::lower_priority().ok();
+                }
+                run_worker_thread(
+                    worker_sets,
+                    worker_queue,
+                    worker_free_tx,
+                    worker_processor,
+                    thread_id,
+                    state,
+                    process_fn,
+                )
+            });
+            match spawn {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    spawned = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // Spawn reader threads
+        let mut reader_handles = Vec::new();
+        let num_readers = readers.len();
+        let readers = if spawned.is_ok() { readers } else { Vec::new() };
+        for (reader_id, reader) in readers.into_iter().enumerate() {
+            let reader_sets = Arc::clone(&record_sets);
+            let reader_queue = queue.clone();
+            let reader_free_tx = free_tx.clone();
+            let reader_free_rx = free_rx.clone();
+            let builder = if num_readers == 1 {
+                options.thread_builder("reader")
+            } else {
+                options.thread_builder(&format!("reader-{}", reader_id))
+            };
+            let spawn = builder.spawn_scoped(scope, move || -> Result<()> {
+                if let Some(core) = reader_core {
+                    affinity::pin_to_core(core)?;
+                }
+                run_reader_thread(
+                    reader,
+                    reader_sets,
+                    reader_queue,
+                    reader_free_tx,
+                    reader_free_rx,
+                    state,
+                    read_fn,
+                )
+            });
+            match spawn {
+                Ok(handle) => reader_handles.push(handle),
+                Err(e) => {
+                    spawned = Err(e);
+                    break;
+                }
+            }
+        }
+        drop(free_tx);
+        // Readers already running stop at their next batch
+        if spawned.is_err() {
+            state.abort.store(true, Ordering::Relaxed);
+        }
+
+        // Wait for reader threads
+        let mut result = spawned.map_err(Into::into);
+        for handle in reader_handles {
+            let reader_result = handle.join().unwrap();
+            if result.is_ok() {
+                result = reader_result;
+            }
+        }
+
+        // Signal completion, waking parked workers to drain the queue
+        if let Some(gate) = &state.gate {
+            gate.close();
+        }
+        queue.close(num_threads)?;
+
+        // Wait for worker threads
+        for handle in handles {
+            let worker_result = handle.join().unwrap();
+            if result.is_ok() {
+                result = worker_result;
+            }
+        }
+
+        result
+    })
+}
+
+/// Reads and processes every batch on the calling thread, the readers one after the other
+///
+/// The executor of builds without the `parallel` feature: the processor runs
+/// as worker 0 and receives the batches in the order they are read, and
+/// progress callbacks run between batches.
+#[cfg(not(feature = "parallel"))]
+fn run_sequential<R, S, T, RF, PF>(
+    readers: Vec<R>,
+    mut processor: T,
+    progress: Option<&ProgressHook>,
+    state: &ReaderState,
+    start: Stopwatch,
+    read_fn: &RF,
+    process_fn: &PF,
+) -> Result<()>
+where
+    S: BatchSize + Default,
+    T: WorkerHooks,
+    RF: Fn(&mut R, &mut S) -> Option<Result<()>>,
+    PF: Fn(&S, &mut T, usize) -> Result<()>,
+{
+    processor.set_thread_id(0);
+    take_counted_errors();
+    set_error_budget(Some(Arc::clone(&state.errors)), state.batch_retry.is_some());
+    let timer = &state.timers[0];
+    let mut next_progress = progress.map(|progress| progress.interval);
+    let mut record_set = S::default();
+    for mut reader in readers {
+        loop {
+            let waiting = Stopwatch::start();
+            let read = read_fn(&mut reader, &mut record_set);
+            WorkerTimer::add(&timer.wait, waiting);
+            match read {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    state.metrics().for_each(Metrics::on_error);
+                    return Err(e);
+                }
+                None => break,
+            }
+            if record_set.is_empty_batch() {
+                if state.read_past_empty() {
+                    continue;
+                }
+                break;
+            }
+            if state.skip_batch() {
+                continue;
+            }
+
+            let global_idx = register_batch(state, &record_set);
+            let busy = Stopwatch::start();
+            let result = process_batch(&record_set, &mut processor, global_idx, state, process_fn);
+            WorkerTimer::add(&timer.busy, busy);
+            result?;
+
+            if let (Some(progress), Some(next)) = (progress, next_progress.as_mut()) {
+                if HAS_CLOCK && start.elapsed() >= *next {
+                    (progress.callback)(&run_stats(state, 1, start));
+                    *next = start.elapsed() + progress.interval;
+                }
+            }
+        }
+    }
+    processor.on_thread_complete()
+}
+
 macro_rules! impl_parallel_reader {
-    ($reader:ty, $record_set:ty, $error:ty) => {
+    ($reader:ty) => {
         impl<R, P> ParallelReader<R, P> for $reader
         where
             R: io::Read + Send,
             P: policy::BufPolicy + Send,
         {
-            fn process_parallel<T>(self, processor: T, num_threads: usize) -> Result<()>
+            fn process_parallel_with_config<D, T>(
+                self,
+                processor: T,
+                config: ParallelConfig<D>,
+            ) -> Result<RunStats>
             where
+                D: Dispatcher,
                 T: ParallelProcessor,
             {
-                let record_sets = create_record_sets::<$record_set>(num_threads);
-                let (tx, rx) = create_channels(num_threads * 2);
-
-                thread::scope(|scope| -> Result<()> {
-                    // Spawn reader thread
-                    let reader_sets = Arc::clone(&record_sets);
-                    let reader_handle = scope.spawn(move || -> Result<()> {
-                        run_reader_thread(
-                            self,
-                            reader_sets,
-                            tx,
-                            num_threads,
-                            |reader, record_set| {
-                                reader
-                                    .read_record_set(record_set)
-                                    .map(|result| result.map_err(Into::into))
-                            },
-                        )
-                    });
-
-                    // Spawn worker threads
-                    let mut handles = Vec::new();
-                    for thread_id in 0..num_threads {
-                        let worker_sets = Arc::clone(&record_sets);
-                        let worker_rx = rx.clone();
-                        let worker_processor = processor.clone();
-
-                        let handle = scope.spawn(move || {
-                            run_worker_thread(
-                                worker_sets,
-                                worker_rx,
-                                worker_processor,
-                                thread_id,
-                                |record_set, processor, global_idx| {
-                                    for (record_idx, record) in record_set.into_iter().enumerate() {
-                                        let _record = processor.process_record(record, global_idx, record_idx)?;
-                                    }
-                                    Ok(())
-                                },
-                            )
-                        });
-
-                        handles.push(handle);
-                    }
-
-                    // Wait for reader thread
-                    reader_handle.join().unwrap()?;
-
-                    // Wait for worker threads
-                    for handle in handles {
-                        handle.join().unwrap()?;
-                    }
-
-                    Ok(())
-                })?;
-
-                Ok(())
+                process_source_parallel(self, processor, config)
             }
         }
     };
 }
 
 // Use the macro to implement for both FASTA and FASTQ
-impl_parallel_reader!(seq_io::fasta::Reader<R, P>, seq_io::fasta::RecordSet, seq_io::fasta::Error);
-impl_parallel_reader!(seq_io::fastq::Reader<R, P>, seq_io::fastq::RecordSet, seq_io::fastq::Error);
+impl_parallel_reader!(seq_io::fasta::Reader<R, P>);
+impl_parallel_reader!(seq_io::fastq::Reader<R, P>);
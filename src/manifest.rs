@@ -0,0 +1,430 @@
+//! Manifest-driven processing of many samples
+//!
+//! A manifest lists samples with their read files, one `sample R1 [R2]` line
+//! per sample. All samples are read concurrently, by up to one reader thread
+//! per sample, and feed one shared worker pool. Every sample gets its own
+//! processor from a [`ProcessorFactory`](crate::ProcessorFactory), so per-sample state (outputs,
+//! counters, barcodes) does not have to be routed through a single processor,
+//! and the run returns each sample's processor keyed by sample name.
+use anyhow::{bail, Context, Result};
+use flate2::Crc;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::batch::BatchSize;
+use crate::chunk::RawChunkReader;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, BoxedReader, FastxReader, FastxRecordSet, Format};
+use crate::macro_impl::{run_parallel_multi, PairedWorker, WorkerHooks};
+use crate::paired::{process_paired_chunk, PairedChunk, DEFAULT_PAIRED_BATCH_SIZE};
+use crate::processor::{PairedParallelProcessor, ParallelProcessor, ProcessorFactory};
+use crate::record::RecordSetLike;
+
+/// A sample and its read files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleInfo {
+    pub name: String,
+    pub r1: PathBuf,
+    /// Mate file of paired-end samples
+    pub r2: Option<PathBuf>,
+}
+
+/// Result of one sample of a manifest run
+#[derive(Debug, Clone)]
+pub struct SampleResult<P> {
+    /// The processor created for the sample; clones used by the workers share its state
+    pub processor: P,
+    /// Records (or pairs) read for the sample
+    pub records: u64,
+}
+
+/// Samples to process, as read from a manifest
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    samples: Vec<SampleInfo>,
+}
+
+impl Manifest {
+    /// Reads a TSV or CSV manifest of `sample,R1[,R2]` lines
+    ///
+    /// Blank lines and lines starting with `#` are skipped, as is a header
+    /// line starting with `sample`. Relative paths are taken relative to the
+    /// manifest's directory.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open manifest {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        Self::from_reader(BufReader::new(file), base)
+            .with_context(|| format!("Invalid manifest {}", path.display()))
+    }
+
+    /// Single-end samples of one file each, named by their path
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let mut manifest = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            manifest.add(SampleInfo {
+                name: path.display().to_string(),
+                r1: path.to_path_buf(),
+                r2: None,
+            })?;
+        }
+        Ok(manifest)
+    }
+
+    /// Reads a manifest, resolving relative paths against `base`
+    pub fn from_reader<R: BufRead>(reader: R, base: &Path) -> Result<Self> {
+        let mut manifest = Self::default();
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let separator = if line.contains('\t') { '\t' } else { ',' };
+            let columns: Vec<&str> = line.split(separator).map(str::trim).collect();
+            if manifest.samples.is_empty() && columns[0].eq_ignore_ascii_case("sample") {
+                continue;
+            }
+            let (name, r1, r2) = match columns[..] {
+                [name, r1] => (name, r1, None),
+                [name, r1, ""] => (name, r1, None),
+                [name, r1, r2] => (name, r1, Some(base.join(r2))),
+                _ => bail!("Line {}: expected sample,R1[,R2]", line_idx + 1),
+            };
+            manifest.add(SampleInfo {
+                name: name.to_string(),
+                r1: base.join(r1),
+                r2,
+            })?;
+        }
+        if manifest.samples.is_empty() {
+            bail!("Manifest lists no samples");
+        }
+        Ok(manifest)
+    }
+
+    /// Adds a sample
+    pub fn add(&mut self, sample: SampleInfo) -> Result<()> {
+        if sample.name.is_empty() {
+            bail!("Empty sample name for {}", sample.r1.display());
+        }
+        if self.samples.iter().any(|s| s.name == sample.name) {
+            bail!("Duplicate sample name {:?}", sample.name);
+        }
+        self.samples.push(sample);
+        Ok(())
+    }
+
+    pub fn samples(&self) -> &[SampleInfo] {
+        &self.samples
+    }
+
+    /// Processes single-end samples, with a processor per sample from `factory`
+    pub fn process<D, P, F>(
+        &self,
+        factory: &F,
+        config: ParallelConfig<D>,
+    ) -> Result<BTreeMap<String, SampleResult<P>>>
+    where
+        D: Dispatcher,
+        P: ParallelProcessor,
+        F: ProcessorFactory<P>,
+    {
+        if let Some(sample) = self.samples.iter().find(|s| s.r2.is_some()) {
+            bail!("Sample {:?} is paired; use process_paired", sample.name);
+        }
+        let processors = self.create_processors(factory)?;
+        let workers = processors.to_vec();
+        self.run::<D, P, FastxReader, _, _>(
+            processors,
+            workers,
+            config,
+            |chunk, worker, global_idx| chunk.chunk.process(worker, global_idx),
+        )
+    }
+
+    /// Processes paired-end samples, with a processor per sample from `factory`
+    pub fn process_paired<D, P, F>(
+        &self,
+        factory: &F,
+        config: ParallelConfig<D>,
+    ) -> Result<BTreeMap<String, SampleResult<P>>>
+    where
+        D: Dispatcher,
+        P: PairedParallelProcessor,
+        F: ProcessorFactory<P>,
+    {
+        if let Some(sample) = self.samples.iter().find(|s| s.r2.is_none()) {
+            bail!("Sample {:?} has no R2; use process", sample.name);
+        }
+        let processors = self.create_processors(factory)?;
+        let workers = processors.iter().cloned().map(PairedWorker).collect();
+        self.run::<D, P, MateFiles, _, _>(
+            processors,
+            workers,
+            config,
+            |chunk, worker, global_idx| {
+                process_paired_chunk(chunk.format, &chunk.chunk, &mut worker.0, global_idx)
+            },
+        )
+    }
+
+    fn create_processors<P, F: ProcessorFactory<P>>(&self, factory: &F) -> Result<Vec<P>> {
+        self.samples
+            .iter()
+            .map(|sample| {
+                factory
+                    .create(sample)
+                    .with_context(|| format!("Failed to create the processor of {}", sample.name))
+            })
+            .collect()
+    }
+
+    /// Runs all samples on one worker pool, with `workers[i]` processing sample `i`
+    fn run<D, P, I, W, PF>(
+        &self,
+        processors: Vec<P>,
+        workers: Vec<W>,
+        mut config: ParallelConfig<D>,
+        process_fn: PF,
+    ) -> Result<BTreeMap<String, SampleResult<P>>>
+    where
+        D: Dispatcher,
+        I: SampleInput,
+        W: WorkerHooks,
+        PF: Fn(&SampleChunk<I::Chunk>, &mut W, usize) -> Result<()> + Sync,
+    {
+        if let Some(report) = &mut config.options.report {
+            for sample in &self.samples {
+                report.inputs.push(sample.r1.display().to_string());
+                report
+                    .inputs
+                    .extend(sample.r2.iter().map(|p| p.display().to_string()));
+            }
+        }
+        let samples: Arc<[SampleInfo]> = self.samples.iter().cloned().collect();
+        let records: Arc<[AtomicU64]> = samples.iter().map(|_| AtomicU64::new(0)).collect();
+        let next_sample = Arc::new(AtomicUsize::new(0));
+        let num_readers = samples.len().min(config.options.num_threads).max(1);
+        let readers = (0..num_readers)
+            .map(|_| SampleQueueReader::<I> {
+                samples: Arc::clone(&samples),
+                records: Arc::clone(&records),
+                next_sample: Arc::clone(&next_sample),
+                current: None,
+            })
+            .collect();
+
+        run_parallel_multi::<D, _, SampleChunk<I::Chunk>, _, _, _>(
+            readers,
+            SampleWorkers { workers, last: 0 },
+            &config,
+            |reader, chunk| reader.read_chunk(chunk),
+            |chunk, sample_workers, global_idx| {
+                sample_workers.last = chunk.sample;
+                process_fn(chunk, &mut sample_workers.workers[chunk.sample], global_idx)
+            },
+        )?;
+
+        Ok(samples
+            .iter()
+            .zip(processors)
+            .zip(records.iter())
+            .map(|((sample, processor), records)| {
+                let records = records.load(Ordering::Relaxed);
+                (sample.name.clone(), SampleResult { processor, records })
+            })
+            .collect())
+    }
+}
+
+/// Batch of one sample
+struct SampleChunk<T> {
+    sample: usize,
+    format: Format,
+    chunk: T,
+}
+
+impl<T: Default> Default for SampleChunk<T> {
+    fn default() -> Self {
+        Self {
+            sample: 0,
+            format: Format::Fastq,
+            chunk: T::default(),
+        }
+    }
+}
+
+impl<T: BatchSize> BatchSize for SampleChunk<T> {
+    fn num_records(&self) -> usize {
+        self.chunk.num_records()
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.chunk.num_bytes()
+    }
+
+    fn is_empty_batch(&self) -> bool {
+        self.chunk.is_empty_batch()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        self.chunk.checksum(crc);
+    }
+}
+
+/// Opened read files of a sample
+trait SampleInput: Sized + Send {
+    type Chunk: BatchSize + Default + Send;
+
+    fn open(sample: &SampleInfo) -> Result<Self>;
+
+    fn format(&self) -> Format;
+
+    fn read_chunk(&mut self, chunk: &mut Self::Chunk) -> Option<Result<()>>;
+}
+
+impl SampleInput for FastxReader {
+    type Chunk = FastxRecordSet;
+
+    fn open(sample: &SampleInfo) -> Result<Self> {
+        FastxReader::from_path(&sample.r1)
+    }
+
+    fn format(&self) -> Format {
+        FastxReader::format(self)
+    }
+
+    fn read_chunk(&mut self, chunk: &mut FastxRecordSet) -> Option<Result<()>> {
+        self.read_record_set(chunk)
+    }
+}
+
+/// Both mate files of a sample, read in lockstep by one reader thread
+struct MateFiles {
+    name: String,
+    r1: RawChunkReader<BoxedReader>,
+    r2: RawChunkReader<BoxedReader>,
+    format: Format,
+}
+
+impl SampleInput for MateFiles {
+    type Chunk = PairedChunk;
+
+    fn open(sample: &SampleInfo) -> Result<Self> {
+        let r2 = sample.r2.as_ref().context("Sample has no R2")?;
+        let (handle1, format1) = open_path(&sample.r1)?;
+        let (handle2, format2) = open_path(r2)?;
+        if format1 != format2 {
+            bail!(
+                "Mate files have different formats: {:?} and {:?}",
+                format1,
+                format2
+            );
+        }
+        Ok(Self {
+            name: sample.name.clone(),
+            r1: RawChunkReader::new(handle1, format1),
+            r2: RawChunkReader::new(handle2, format2),
+            format: format1,
+        })
+    }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read_chunk(&mut self, chunk: &mut PairedChunk) -> Option<Result<()>> {
+        let read1 = self
+            .r1
+            .read_records(&mut chunk.r1, DEFAULT_PAIRED_BATCH_SIZE);
+        let read2 = self
+            .r2
+            .read_records(&mut chunk.r2, DEFAULT_PAIRED_BATCH_SIZE);
+        match (read1, read2) {
+            (None, None) => None,
+            (Some(Err(e)), _) | (_, Some(Err(e))) => Some(Err(e)),
+            (Some(Ok(())), Some(Ok(()))) if chunk.r1.num_records == chunk.r2.num_records => {
+                Some(Ok(()))
+            }
+            _ => Some(Err(anyhow::anyhow!(
+                "R1 and R2 of sample {:?} have different numbers of records",
+                self.name
+            ))),
+        }
+    }
+}
+
+/// Reader thread state: the shared sample queue and the sample currently being read
+struct SampleQueueReader<I> {
+    samples: Arc<[SampleInfo]>,
+    records: Arc<[AtomicU64]>,
+    next_sample: Arc<AtomicUsize>,
+    current: Option<(usize, I)>,
+}
+
+impl<I: SampleInput> SampleQueueReader<I> {
+    fn read_chunk(&mut self, chunk: &mut SampleChunk<I::Chunk>) -> Option<Result<()>> {
+        loop {
+            if self.current.is_none() {
+                let sample_idx = self.next_sample.fetch_add(1, Ordering::Relaxed);
+                let sample = self.samples.get(sample_idx)?;
+                match I::open(sample) {
+                    Ok(input) => self.current = Some((sample_idx, input)),
+                    Err(e) => return Some(Err(e.context(format!("Sample {:?}", sample.name)))),
+                }
+            }
+            let (sample_idx, input) = self.current.as_mut()?;
+            match input.read_chunk(&mut chunk.chunk) {
+                Some(Ok(())) => {
+                    chunk.sample = *sample_idx;
+                    chunk.format = input.format();
+                    let num_records = chunk.chunk.num_records() as u64;
+                    self.records[*sample_idx].fetch_add(num_records, Ordering::Relaxed);
+                    return Some(Ok(()));
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+/// One processor per sample, run by every worker thread
+#[derive(Clone)]
+struct SampleWorkers<W> {
+    workers: Vec<W>,
+    /// Sample of the batch processed last
+    last: usize,
+}
+
+impl<W: WorkerHooks> WorkerHooks for SampleWorkers<W> {
+    fn set_thread_id(&mut self, thread_id: usize) {
+        for worker in &mut self.workers {
+            worker.set_thread_id(thread_id);
+        }
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.workers[self.last].on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.workers[self.last].on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.workers
+            .iter_mut()
+            .try_for_each(WorkerHooks::on_thread_complete)
+    }
+}
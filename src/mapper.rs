@@ -0,0 +1,181 @@
+//! Record transformation stages
+//!
+//! A [`ParallelMapper`] turns each record into the record to write (e.g. a
+//! trimmed view of it) or drops it. Mappers run on the worker threads,
+//! chain with [`ParallelMapper::then`] and are written in input order by a
+//! [`MapWriter`], which is an ordinary processor for any reader.
+//!
+//! [`PairedMapper`]s do the same for pairs, where both mates are kept or
+//! dropped together, and are written by a [`PairedMapWriter`]. Single-record
+//! mappers apply to both mates through [`Mates`].
+use anyhow::Result;
+
+use crate::processor::PairedParallelProcessor;
+use crate::record::Record;
+use crate::writer::ParallelWriter;
+use crate::{MinimalRefRecord, ParallelProcessor};
+
+/// Transformation of single records
+pub trait ParallelMapper: Send + Clone {
+    /// Maps a record to the record to keep, or `None` to drop it
+    ///
+    /// The kept record may borrow from the input, e.g. a trimmed slice.
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>>;
+
+    /// Runs `next` on the records this mapper keeps
+    fn then<M: ParallelMapper>(self, next: M) -> Then<Self, M>
+    where
+        Self: Sized,
+    {
+        Then(self, next)
+    }
+}
+
+/// Two mappers run one after the other, see [`ParallelMapper::then`]
+#[derive(Clone)]
+pub struct Then<A, B>(A, B);
+
+impl<A: ParallelMapper, B: ParallelMapper> ParallelMapper for Then<A, B> {
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+        match self.0.map_record(record)? {
+            Some(record) => self.1.map_record(record),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Processor writing the records kept by a mapper, in input order
+#[derive(Clone)]
+pub struct MapWriter<M> {
+    mapper: M,
+    out: ParallelWriter,
+}
+
+impl<M: ParallelMapper> MapWriter<M> {
+    /// Writes the output of `mapper` to `out`; call `out.finish()` after the run
+    pub fn new(mapper: M, out: ParallelWriter) -> Self {
+        Self { mapper, out }
+    }
+}
+
+impl<M: ParallelMapper> ParallelProcessor for MapWriter<M> {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        self.out.skip(record_set_idx);
+        if let Some(mapped) = self.mapper.map_record(record.to_record())? {
+            self.out.write_record(record_set_idx, &mapped)?;
+        }
+        Ok(())
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.out.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.out.discard_batch();
+        Ok(())
+    }
+}
+
+/// Transformation of record pairs
+pub trait PairedMapper: Send + Clone {
+    /// Maps a pair to the pair to keep, or `None` to drop both mates
+    fn map_pair<'r>(
+        &mut self,
+        record1: Record<'r>,
+        record2: Record<'r>,
+    ) -> Result<Option<(Record<'r>, Record<'r>)>>;
+
+    /// Runs `next` on the pairs this mapper keeps
+    fn then_pair<M: PairedMapper>(self, next: M) -> Then<Self, M>
+    where
+        Self: Sized,
+    {
+        Then(self, next)
+    }
+}
+
+impl<A: PairedMapper, B: PairedMapper> PairedMapper for Then<A, B> {
+    fn map_pair<'r>(
+        &mut self,
+        record1: Record<'r>,
+        record2: Record<'r>,
+    ) -> Result<Option<(Record<'r>, Record<'r>)>> {
+        match self.0.map_pair(record1, record2)? {
+            Some((record1, record2)) => self.1.map_pair(record1, record2),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Applies a single-record mapper to both mates, dropping the pair if either mate is dropped
+#[derive(Clone)]
+pub struct Mates<M>(pub M);
+
+impl<M: ParallelMapper> PairedMapper for Mates<M> {
+    fn map_pair<'r>(
+        &mut self,
+        record1: Record<'r>,
+        record2: Record<'r>,
+    ) -> Result<Option<(Record<'r>, Record<'r>)>> {
+        let Some(record1) = self.0.map_record(record1)? else {
+            return Ok(None);
+        };
+        Ok(self
+            .0
+            .map_record(record2)?
+            .map(|record2| (record1, record2)))
+    }
+}
+
+/// Processor writing the pairs kept by a paired mapper to two outputs, in input order
+#[derive(Clone)]
+pub struct PairedMapWriter<M> {
+    mapper: M,
+    out1: ParallelWriter,
+    out2: ParallelWriter,
+}
+
+impl<M: PairedMapper> PairedMapWriter<M> {
+    /// Writes the output of `mapper` to `out1` and `out2`; call `finish()` on both after the run
+    pub fn new(mapper: M, out1: ParallelWriter, out2: ParallelWriter) -> Self {
+        Self { mapper, out1, out2 }
+    }
+}
+
+impl<M: PairedMapper> PairedParallelProcessor for PairedMapWriter<M> {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        self.out1.skip(record_set_idx);
+        self.out2.skip(record_set_idx);
+        if let Some((mapped1, mapped2)) = self
+            .mapper
+            .map_pair(record1.to_record(), record2.to_record())?
+        {
+            self.out1.write_record(record_set_idx, &mapped1)?;
+            self.out2.write_record(record_set_idx, &mapped2)?;
+        }
+        Ok((record1, record2))
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.out1.finish_batch()?;
+        self.out2.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.out1.discard_batch();
+        self.out2.discard_batch();
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+//! Merging of overlapping pairs
+//!
+//! [`PairMerger`] joins R1 and the reverse complement of R2 into a single
+//! read when the end of R1 overlaps the start of the reverse-complemented
+//! R2, as FLASH does. Among the overlaps of at least the minimum length and
+//! within the mismatch rate, the one with the fewest mismatches per base
+//! wins, the longest on ties. In the overlap each position takes the base of
+//! the mate with the higher quality; where the mates disagree it gets the
+//! lower of the two qualities, and where they agree the higher one.
+//!
+//! A [`MergeWriter`] writes the merged reads to one output and the pairs that
+//! could not be merged to two others, all in input order. The merged read
+//! keeps the header of R1. Wrapped FASTA pairs are never merged.
+use anyhow::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::adapter::{complement, single_line_seq};
+use crate::paired::PairedReader;
+use crate::processor::PairedParallelProcessor;
+use crate::reader::PairedParallelReader;
+use crate::record::Record;
+use crate::writer::ParallelWriter;
+use crate::MinimalRefRecord;
+
+/// Default number of bases by which mates must overlap to be merged, as FLASH's `-m`
+pub const DEFAULT_MIN_OVERLAP: usize = 10;
+
+/// Default fraction of mismatching bases in the overlap, as FLASH's `-x`
+pub const DEFAULT_MISMATCH_RATE: f64 = 0.25;
+
+/// Overlap of a pair, see [`PairMerger::overlap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlap {
+    /// Number of overlapping bases
+    pub len: usize,
+    pub mismatches: usize,
+}
+
+/// Merges overlapping mates into single reads, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct PairMerger {
+    min_overlap: usize,
+    mismatch_rate: f64,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl Default for PairMerger {
+    fn default() -> Self {
+        Self {
+            min_overlap: DEFAULT_MIN_OVERLAP,
+            mismatch_rate: DEFAULT_MISMATCH_RATE,
+            seq: Vec::new(),
+            qual: Vec::new(),
+        }
+    }
+}
+
+impl PairMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires mates to overlap by at least `min_overlap` bases
+    pub fn with_min_overlap(mut self, min_overlap: usize) -> Self {
+        self.min_overlap = min_overlap.max(1);
+        self
+    }
+
+    /// Allows `mismatch_rate` mismatches per overlapping base
+    pub fn with_mismatch_rate(mut self, mismatch_rate: f64) -> Self {
+        self.mismatch_rate = mismatch_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Best overlap of the end of `seq1` with the reverse complement of `seq2`
+    pub fn overlap(&self, seq1: &[u8], seq2: &[u8]) -> Option<Overlap> {
+        let longest = seq1.len().min(seq2.len());
+        let mut best: Option<Overlap> = None;
+        for len in (self.min_overlap..=longest).rev() {
+            // The start of the reverse-complemented mate 2 is its end read backwards
+            let mate2 = seq2[seq2.len() - len..]
+                .iter()
+                .rev()
+                .map(|&base| complement(base));
+            let mismatches = seq1[seq1.len() - len..]
+                .iter()
+                .zip(mate2)
+                .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+                .count();
+            if mismatches > (len as f64 * self.mismatch_rate) as usize {
+                continue;
+            }
+            let better = best.is_none_or(|best| {
+                // Fewer mismatches per base, compared without division
+                mismatches * best.len < best.mismatches * len
+            });
+            if better {
+                best = Some(Overlap { len, mismatches });
+            }
+        }
+        best
+    }
+
+    /// Merges a pair, or returns `None` if the mates do not overlap
+    ///
+    /// The merged read borrows from buffers of the merger, so it is only
+    /// valid until the next call.
+    pub fn merge_pair<'s, 'r: 's>(
+        &'s mut self,
+        record1: &Record<'r>,
+        record2: &Record<'r>,
+    ) -> Option<Record<'s>> {
+        let seq1 = single_line_seq(record1)?;
+        let seq2 = single_line_seq(record2)?;
+        let overlap = self.overlap(seq1, seq2)?;
+        let (qual1, qual2) = (record1.ref_qual(), record2.ref_qual());
+        let has_qual = matches!(record1, Record::Fastq { .. })
+            && matches!(record2, Record::Fastq { .. })
+            && qual1.len() == seq1.len()
+            && qual2.len() == seq2.len();
+        let start = seq1.len() - overlap.len;
+        self.seq.clear();
+        self.qual.clear();
+        self.seq.extend_from_slice(&seq1[..start]);
+        if has_qual {
+            self.qual.extend_from_slice(&qual1[..start]);
+        }
+        for i in 0..seq2.len() {
+            // Position i of the reverse-complemented mate 2
+            let j = seq2.len() - 1 - i;
+            let base2 = complement(seq2[j]);
+            let q2 = if has_qual { qual2[j] } else { 0 };
+            if i >= overlap.len {
+                self.seq.push(base2);
+                if has_qual {
+                    self.qual.push(q2);
+                }
+                continue;
+            }
+            let base1 = seq1[start + i];
+            let q1 = if has_qual { qual1[start + i] } else { 0 };
+            let base = if q2 > q1 { base2 } else { base1 };
+            self.seq.push(base);
+            if has_qual {
+                let agree = base1.eq_ignore_ascii_case(&base2);
+                self.qual.push(if agree { q1.max(q2) } else { q1.min(q2) });
+            }
+        }
+        let head = record1.ref_head();
+        Some(if has_qual {
+            Record::Fastq {
+                head,
+                seq: &self.seq,
+                qual: &self.qual,
+            }
+        } else {
+            Record::Fasta {
+                head,
+                seq: &self.seq,
+            }
+        })
+    }
+}
+
+/// Counts of a merging run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    pub pairs: u64,
+    /// Pairs written as a single merged read
+    pub merged: u64,
+}
+
+impl MergeStats {
+    pub fn unmerged(&self) -> u64 {
+        self.pairs - self.merged
+    }
+
+    /// Fraction of the pairs that were merged
+    pub fn merge_rate(&self) -> f64 {
+        if self.pairs == 0 {
+            return 0.0;
+        }
+        self.merged as f64 / self.pairs as f64
+    }
+}
+
+#[derive(Default)]
+struct MergeCounts {
+    pairs: AtomicU64,
+    merged: AtomicU64,
+}
+
+/// Processor writing merged reads to one output and unmerged pairs to two others, in input order
+#[derive(Clone)]
+pub struct MergeWriter {
+    merger: PairMerger,
+    merged: ParallelWriter,
+    unmerged1: ParallelWriter,
+    unmerged2: ParallelWriter,
+    counts: Arc<MergeCounts>,
+}
+
+impl MergeWriter {
+    /// Writes merged reads to `merged` and the other pairs to `unmerged1` and `unmerged2`; call `finish()` on all three after the run
+    pub fn new(
+        merger: PairMerger,
+        merged: ParallelWriter,
+        unmerged1: ParallelWriter,
+        unmerged2: ParallelWriter,
+    ) -> Self {
+        Self {
+            merger,
+            merged,
+            unmerged1,
+            unmerged2,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Counts of all clones so far
+    pub fn stats(&self) -> MergeStats {
+        MergeStats {
+            pairs: self.counts.pairs.load(Ordering::Relaxed),
+            merged: self.counts.merged.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl PairedParallelProcessor for MergeWriter {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        self.merged.skip(record_set_idx);
+        self.unmerged1.skip(record_set_idx);
+        self.unmerged2.skip(record_set_idx);
+        self.counts.pairs.fetch_add(1, Ordering::Relaxed);
+        let (mate1, mate2) = (record1.to_record(), record2.to_record());
+        match self.merger.merge_pair(&mate1, &mate2) {
+            Some(merged) => {
+                self.counts.merged.fetch_add(1, Ordering::Relaxed);
+                self.merged.write_record(record_set_idx, &merged)?;
+            }
+            None => {
+                self.unmerged1.write_record(record_set_idx, &mate1)?;
+                self.unmerged2.write_record(record_set_idx, &mate2)?;
+            }
+        }
+        Ok((record1, record2))
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.merged.finish_batch()?;
+        self.unmerged1.finish_batch()?;
+        self.unmerged2.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.merged.discard_batch();
+        self.unmerged1.discard_batch();
+        self.unmerged2.discard_batch();
+        Ok(())
+    }
+}
+
+/// Merges the overlapping pairs of R1/R2 files into `merged` and writes the others to `unmerged1`/`unmerged2`
+///
+/// Outputs are compressed according to their extension.
+pub fn merge_pairs_parallel<P1, P2, P3, P4, P5>(
+    r1: P1,
+    r2: P2,
+    merged: P3,
+    unmerged1: P4,
+    unmerged2: P5,
+    merger: &PairMerger,
+    num_threads: usize,
+) -> Result<MergeStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    P4: AsRef<Path>,
+    P5: AsRef<Path>,
+{
+    let merged = ParallelWriter::from_path(merged)?;
+    let unmerged1 = ParallelWriter::from_path(unmerged1)?;
+    let unmerged2 = ParallelWriter::from_path(unmerged2)?;
+    let writer = MergeWriter::new(
+        merger.clone(),
+        merged.clone(),
+        unmerged1.clone(),
+        unmerged2.clone(),
+    );
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(writer.clone(), num_threads)?;
+    merged.finish()?;
+    unmerged1.finish()?;
+    unmerged2.finish()?;
+    Ok(writer.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+        seq.iter().rev().map(|&base| complement(base)).collect()
+    }
+
+    #[test]
+    fn ties_go_to_the_longest_overlap() {
+        let merger = PairMerger::new();
+        let mate2 = reverse_complement(b"ACACACACACAC");
+        // Overlaps of 12 and 10 bases both match perfectly
+        assert_eq!(
+            merger.overlap(b"ACACACACACAC", &mate2),
+            Some(Overlap { len: 12, mismatches: 0 })
+        );
+        // A shorter overlap without mismatches beats a longer one with a mismatch
+        assert_eq!(
+            merger.overlap(b"TCACACACACAC", &mate2),
+            Some(Overlap { len: 10, mismatches: 0 })
+        );
+    }
+
+    #[test]
+    fn overlap_takes_the_better_base_and_combines_qualities() {
+        // R1 reads TTTTT + insert, R2 the insert with its fourth base changed + GGGGG
+        let seq1 = b"TTTTTACGTTGCATGCA";
+        let qual1 = b"IIIIIIII#IIIIIIII";
+        let seq2 = reverse_complement(b"ACGATGCATGCAGGGGG");
+        let qual2 = [b'5'; 17];
+        let record1 = Record::Fastq {
+            head: b"r1",
+            seq: seq1,
+            qual: qual1,
+        };
+        let record2 = Record::Fastq {
+            head: b"r2",
+            seq: &seq2,
+            qual: &qual2,
+        };
+        let mut merger = PairMerger::new();
+        assert_eq!(
+            merger.merge_pair(&record1, &record2),
+            Some(Record::Fastq {
+                head: b"r1",
+                seq: b"TTTTTACGATGCATGCAGGGGG",
+                // The mismatch keeps the lower quality, agreeing bases the higher one
+                qual: b"IIIIIIII#IIIIIIII55555",
+            })
+        );
+        // Without qualities R1 wins the mismatch
+        let fasta1 = Record::Fasta { head: b"r1", seq: seq1 };
+        let fasta2 = Record::Fasta { head: b"r2", seq: &seq2 };
+        assert_eq!(
+            merger.merge_pair(&fasta1, &fasta2),
+            Some(Record::Fasta {
+                head: b"r1",
+                seq: b"TTTTTACGTTGCATGCAGGGGG",
+            })
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! Live counters of a running pipeline
+//!
+//! Attach a shared [`Metrics`] to a run with
+//! [`ParallelConfig::with_metrics`](crate::ParallelConfig::with_metrics) and
+//! read it from any thread while the run progresses. With the `prometheus`
+//! feature the counters can be rendered in the Prometheus text format and
+//! served over HTTP.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters updated by the reader and worker threads
+#[derive(Debug, Default)]
+pub struct Metrics {
+    records_read: AtomicU64,
+    bytes_read: AtomicU64,
+    batches_dispatched: AtomicU64,
+    batches_processed: AtomicU64,
+    records_processed: AtomicU64,
+    records_dropped: AtomicU64,
+    batches_dropped: AtomicU64,
+    batches_retried: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Point-in-time copy of the [`Metrics`] counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub records_read: u64,
+    pub bytes_read: u64,
+    pub batches_dispatched: u64,
+    pub batches_processed: u64,
+    pub records_processed: u64,
+    /// Records discarded by the backpressure policy
+    pub records_dropped: u64,
+    pub batches_dropped: u64,
+    /// Batches processed again after a retryable error
+    pub batches_retried: u64,
+    pub errors: u64,
+}
+
+impl MetricsSnapshot {
+    /// Batches dispatched to the workers but not yet processed
+    pub fn queue_depth(&self) -> u64 {
+        self.batches_dispatched.saturating_sub(self.batches_processed)
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            records_read: self.records_read.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            batches_dispatched: self.batches_dispatched.load(Ordering::Relaxed),
+            batches_processed: self.batches_processed.load(Ordering::Relaxed),
+            records_processed: self.records_processed.load(Ordering::Relaxed),
+            records_dropped: self.records_dropped.load(Ordering::Relaxed),
+            batches_dropped: self.batches_dropped.load(Ordering::Relaxed),
+            batches_retried: self.batches_retried.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn on_batch_read(&self, records: usize, bytes: usize) {
+        self.records_read.fetch_add(records as u64, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.batches_dispatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_batch_dropped(&self, records: usize, bytes: usize) {
+        self.records_read.fetch_add(records as u64, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.records_dropped.fetch_add(records as u64, Ordering::Relaxed);
+        self.batches_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_batch_processed(&self, records: usize) {
+        self.records_processed.fetch_add(records as u64, Ordering::Relaxed);
+        self.batches_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_batch_retried(&self) {
+        self.batches_retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record errors the processor chose to count and go on from
+    pub(crate) fn on_record_errors(&self, count: u64) {
+        self.errors.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::serve_prometheus;
+
+#[cfg(feature = "prometheus")]
+mod prometheus {
+    use super::Metrics;
+    use std::fmt::Write as _;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpListener, ToSocketAddrs};
+    use std::sync::Arc;
+    use std::thread;
+
+    impl Metrics {
+        /// Renders the counters in the Prometheus text exposition format
+        pub fn render_prometheus(&self) -> String {
+            let snapshot = self.snapshot();
+            let entries = [
+                ("records_read_total", "counter", "Records read from the input", snapshot.records_read),
+                ("bytes_read_total", "counter", "Header, sequence and quality bytes read", snapshot.bytes_read),
+                ("batches_dispatched_total", "counter", "Batches sent to the workers", snapshot.batches_dispatched),
+                ("batches_processed_total", "counter", "Batches completed by the workers", snapshot.batches_processed),
+                ("records_processed_total", "counter", "Records completed by the workers", snapshot.records_processed),
+                ("records_dropped_total", "counter", "Records discarded by the backpressure policy", snapshot.records_dropped),
+                ("batches_dropped_total", "counter", "Batches discarded by the backpressure policy", snapshot.batches_dropped),
+                ("batches_retried_total", "counter", "Batches processed again after a retryable error", snapshot.batches_retried),
+                ("errors_total", "counter", "Reader and processor errors", snapshot.errors),
+                ("queue_depth", "gauge", "Batches waiting for or being processed by a worker", snapshot.queue_depth()),
+            ];
+            let mut out = String::new();
+            for (name, kind, help, value) in entries {
+                let _ = writeln!(out, "# HELP seq_io_parallel_{name} {help}");
+                let _ = writeln!(out, "# TYPE seq_io_parallel_{name} {kind}");
+                let _ = writeln!(out, "seq_io_parallel_{name} {value}");
+            }
+            out
+        }
+    }
+
+    /// Serves the rendered metrics over HTTP on a background thread
+    ///
+    /// Every request, whatever its path, receives the current counters.
+    pub fn serve_prometheus<A: ToSocketAddrs>(
+        addr: A,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                // Consume the request headers before answering
+                let mut request = BufReader::new(&stream);
+                let mut line = String::new();
+                while request.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let body = metrics.render_prometheus();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        }))
+    }
+}
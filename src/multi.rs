@@ -0,0 +1,257 @@
+//! Concurrent processing of many files
+//!
+//! Several reader threads each take the next unread file from a shared queue
+//! and feed their record sets into one shared worker pool. This keeps the
+//! workers busy on workloads of many small files, where a single reader
+//! thread spends most of its time opening and decompressing.
+//!
+//! With a [`SourceProcessor`] every record arrives with a [`SourceInfo`]
+//! naming the file it was read from and its position in that file, so
+//! outputs and errors can point back to their origin.
+use anyhow::Result;
+use flate2::Crc;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::config::ParallelConfig;
+use crate::batch::BatchSize;
+use crate::dispatch::{DefaultDispatcher, Dispatcher};
+use crate::fastx::{FastxReader, FastxRecordSet};
+use crate::macro_impl::{run_parallel_multi, SourceWorker};
+use crate::manifest::{Manifest, SampleResult};
+use crate::processor::{apply_error_action, ProcessorFactory, SourceProcessor};
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::ParallelProcessor;
+
+/// Origin of a record read from one of several files
+#[derive(Debug, Clone, Copy)]
+pub struct SourceInfo<'a> {
+    /// Index of the file in the list of input paths
+    pub file_idx: usize,
+    pub path: &'a Path,
+    /// Index of the record within its file, counting from 0
+    pub record_idx_in_file: u64,
+}
+
+/// Reader thread state: the shared file queue and the file currently being read
+struct FileQueueReader {
+    paths: Arc<[PathBuf]>,
+    next_file: Arc<AtomicUsize>,
+    current: Option<FastxReader>,
+    /// Index of the file currently being read
+    file_idx: usize,
+    /// Records read from the current file before the last record set
+    records_read: u64,
+}
+
+/// Record set with the file it was read from
+#[derive(Default)]
+struct SourceRecordSet {
+    file_idx: usize,
+    /// Index of the first record within its file
+    first_record: u64,
+    records: FastxRecordSet,
+}
+
+impl BatchSize for SourceRecordSet {
+    fn num_records(&self) -> usize {
+        self.records.num_records()
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.records.num_bytes()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        self.records.checksum(crc);
+    }
+}
+
+impl FileQueueReader {
+    fn read_record_set(&mut self, record_set: &mut FastxRecordSet) -> Option<Result<()>> {
+        loop {
+            if self.current.is_none() {
+                let file_idx = self.next_file.fetch_add(1, Ordering::Relaxed);
+                let path = self.paths.get(file_idx)?;
+                match FastxReader::from_path(path) {
+                    Ok(reader) => self.current = Some(reader),
+                    Err(e) => return Some(Err(e)),
+                }
+                self.file_idx = file_idx;
+                self.records_read = 0;
+            }
+            match self.current.as_mut()?.read_record_set(record_set) {
+                Some(result) => return Some(result),
+                None => self.current = None,
+            }
+        }
+    }
+
+    fn read_source_record_set(&mut self, record_set: &mut SourceRecordSet) -> Option<Result<()>> {
+        let result = self.read_record_set(&mut record_set.records)?;
+        record_set.file_idx = self.file_idx;
+        record_set.first_record = self.records_read;
+        self.records_read += record_set.records.num_records() as u64;
+        Some(result)
+    }
+}
+
+/// Shared file queue and one reader per reader thread, listing the paths as report inputs
+fn file_queue_readers<D, P>(
+    paths: &[P],
+    config: &mut ParallelConfig<D>,
+    num_readers: usize,
+) -> (Arc<[PathBuf]>, Vec<FileQueueReader>)
+where
+    D: Dispatcher,
+    P: AsRef<Path>,
+{
+    let paths: Arc<[PathBuf]> = paths.iter().map(|p| p.as_ref().to_path_buf()).collect();
+    if let Some(report) = &mut config.options.report {
+        report
+            .inputs
+            .extend(paths.iter().map(|p| p.display().to_string()));
+    }
+    let next_file = Arc::new(AtomicUsize::new(0));
+    let readers = (0..num_readers.max(1))
+        .map(|_| FileQueueReader {
+            paths: Arc::clone(&paths),
+            next_file: Arc::clone(&next_file),
+            current: None,
+            file_idx: 0,
+            records_read: 0,
+        })
+        .collect();
+    (paths, readers)
+}
+
+/// Processes many (possibly compressed) FASTA/FASTQ files on a shared worker pool
+///
+/// Uses one reader thread per file, up to `num_threads` reader threads.
+/// Batch indices are unique across all files but do not follow file order.
+pub fn process_files_parallel<P, T>(paths: &[P], processor: T, num_threads: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+{
+    let num_readers = paths.len().min(num_threads);
+    process_files_parallel_with_readers(paths, processor, num_threads, num_readers)
+}
+
+/// Same as [`process_files_parallel`] with an explicit number of reader threads
+pub fn process_files_parallel_with_readers<P, T>(
+    paths: &[P],
+    processor: T,
+    num_threads: usize,
+    num_readers: usize,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+{
+    process_files_parallel_with_dispatcher::<DefaultDispatcher, P, T>(
+        paths,
+        processor,
+        num_threads,
+        num_readers,
+    )
+}
+
+/// Same as [`process_files_parallel_with_readers`] with an explicit channel backend
+pub fn process_files_parallel_with_dispatcher<D, P, T>(
+    paths: &[P],
+    processor: T,
+    num_threads: usize,
+    num_readers: usize,
+) -> Result<()>
+where
+    D: Dispatcher,
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+{
+    let config = ParallelConfig::new(num_threads).with_dispatcher::<D>();
+    process_files_parallel_with_config(paths, processor, config, num_readers).map(|_| ())
+}
+
+/// Same as [`process_files_parallel_with_readers`] with a full run configuration
+pub fn process_files_parallel_with_config<D, P, T>(
+    paths: &[P],
+    processor: T,
+    mut config: ParallelConfig<D>,
+    num_readers: usize,
+) -> Result<RunStats>
+where
+    D: Dispatcher,
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+{
+    let (_, readers) = file_queue_readers(paths, &mut config, num_readers);
+    run_parallel_multi::<D, _, FastxRecordSet, _, _, _>(
+        readers,
+        processor,
+        &config,
+        |reader, record_set| reader.read_record_set(record_set),
+        |record_set, processor, global_idx| record_set.process(processor, global_idx),
+    )
+}
+
+/// Same as [`process_files_parallel_with_config`], passing each record's file and position in it
+pub fn process_files_parallel_with_sources<D, P, T>(
+    paths: &[P],
+    processor: T,
+    mut config: ParallelConfig<D>,
+    num_readers: usize,
+) -> Result<RunStats>
+where
+    D: Dispatcher,
+    P: AsRef<Path>,
+    T: SourceProcessor,
+{
+    let (paths, readers) = file_queue_readers(paths, &mut config, num_readers);
+    run_parallel_multi::<D, _, SourceRecordSet, _, _, _>(
+        readers,
+        SourceWorker(processor),
+        &config,
+        |reader, record_set| reader.read_source_record_set(record_set),
+        |record_set, worker, global_idx| {
+            for (record_idx, record) in record_set.records.records().enumerate() {
+                let source = SourceInfo {
+                    file_idx: record_set.file_idx,
+                    path: &paths[record_set.file_idx],
+                    record_idx_in_file: record_set.first_record + record_idx as u64,
+                };
+                let result = worker
+                    .0
+                    .process_source_record(source, record, global_idx, record_idx);
+                if let Err(err) = result {
+                    let action = worker.0.on_record_error(&err, record_idx);
+                    apply_error_action(err, action)?;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Processes many files on a shared worker pool, with a processor per file from `factory`
+///
+/// Every file is a single-end sample named by its path, and the results are
+/// keyed by that name. See [`Manifest`] for paired samples.
+pub fn process_files_with_factory<D, P, T, F>(
+    paths: &[P],
+    factory: &F,
+    config: ParallelConfig<D>,
+) -> Result<BTreeMap<String, SampleResult<T>>>
+where
+    D: Dispatcher,
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+    F: ProcessorFactory<T>,
+{
+    Manifest::from_files(paths)?.process(factory, config)
+}
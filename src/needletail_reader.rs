@@ -0,0 +1,92 @@
+//! Parallel processing of records parsed by [needletail](https://github.com/onecodex/needletail)
+//!
+//! needletail records borrow the parser's buffer until the next call, so the
+//! reader thread copies every batch into a [`RecordBuffer`] and the workers
+//! receive [`SliceRecord`](crate::SliceRecord)s. FASTA sequences arrive with
+//! their line breaks already removed.
+use anyhow::{anyhow, Result};
+use seq_io::policy;
+use std::path::Path;
+
+use crate::batch::RecordBuffer;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::BoxedReader;
+use crate::macro_impl::run_parallel;
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::{ParallelProcessor, ParallelReader};
+
+/// Default number of records per batch
+pub const DEFAULT_NEEDLETAIL_BATCH_SIZE: usize = 1024;
+
+/// Reader dispatching the records of a needletail parser
+pub struct NeedletailReader<'a> {
+    inner: Box<dyn needletail::FastxReader + 'a>,
+    batch_size: usize,
+}
+
+impl NeedletailReader<'static> {
+    /// Opens a (possibly compressed) file with needletail's format detection
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let inner = needletail::parse_fastx_file(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        Ok(Self::new(inner))
+    }
+}
+
+impl<'a> NeedletailReader<'a> {
+    /// Wraps a parser, e.g. from `needletail::parse_fastx_reader`
+    pub fn new(inner: Box<dyn needletail::FastxReader + 'a>) -> Self {
+        Self {
+            inner,
+            batch_size: DEFAULT_NEEDLETAIL_BATCH_SIZE,
+        }
+    }
+
+    /// Sets the number of records per batch
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Fills `records` with copies of the next records
+    fn read_records(&mut self, records: &mut RecordBuffer) -> Option<Result<()>> {
+        records.clear();
+        while records.len() < self.batch_size {
+            match self.inner.next() {
+                Some(Ok(record)) => {
+                    let qual = record.qual().unwrap_or_default();
+                    records.push(record.id(), &record.seq(), qual);
+                }
+                Some(Err(e)) => return Some(Err(anyhow!("{}", e))),
+                None => break,
+            }
+        }
+        if records.is_empty() {
+            return None;
+        }
+        Some(Ok(()))
+    }
+}
+
+impl ParallelReader<BoxedReader, policy::StdPolicy> for NeedletailReader<'_> {
+    fn process_parallel_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        run_parallel::<D, _, RecordBuffer, _, _, _>(
+            self,
+            processor,
+            &config,
+            |reader, records| reader.read_records(records),
+            |records, processor, global_idx| records.process(processor, global_idx),
+        )
+    }
+}
@@ -0,0 +1,181 @@
+//! Asynchronous batch offloading
+//!
+//! A [`BatchProcessor`](crate::BatchProcessor) is done with a batch when
+//! `process_batch` returns, which keeps a worker waiting while a GPU or other
+//! accelerator runs its kernel. An [`OffloadProcessor`] instead receives each
+//! batch as an owned [`SoaBatch`] together with a [`BatchCompletion`], enqueues
+//! the work and returns; the batch is done once the completion is completed,
+//! typically from the device's callback or polling thread.
+//!
+//! The run bounds the batches in flight: a worker waits before submitting
+//! when the limit is reached, so the reader cannot outrun the device and
+//! memory stays bounded. Completed batches hand their buffers back for the
+//! next submissions. The run returns once every batch is completed, failing
+//! with the first error passed to a completion, and a completion dropped
+//! without being completed counts as an error. Completions must not wait for
+//! later submissions, or the run may stall at the limit.
+use anyhow::{anyhow, Error, Result};
+use parking_lot::{Condvar, Mutex};
+use std::io;
+use std::sync::Arc;
+
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{FastxReader, FastxRecordSet};
+use crate::macro_impl::{run_parallel, OffloadWorker};
+use crate::processor::OffloadProcessor;
+use crate::soa::SoaBatch;
+use crate::stats::RunStats;
+
+#[derive(Default)]
+struct InFlightState {
+    count: usize,
+    /// First error of a completed batch
+    error: Option<Error>,
+    /// Buffers of completed batches, for reuse
+    pool: Vec<SoaBatch>,
+}
+
+/// Batches submitted but not yet completed, shared by the workers and the completions
+pub(crate) struct InFlight {
+    max: usize,
+    state: Mutex<InFlightState>,
+    changed: Condvar,
+}
+
+impl InFlight {
+    fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Waits for a free slot and returns a buffer for the next batch
+    fn acquire(&self) -> Result<SoaBatch> {
+        let mut state = self.state.lock();
+        while state.count >= self.max && state.error.is_none() {
+            self.changed.wait(&mut state);
+        }
+        if let Some(error) = &state.error {
+            return Err(anyhow!("Offloaded batch failed: {:#}", error));
+        }
+        state.count += 1;
+        Ok(state.pool.pop().unwrap_or_default())
+    }
+
+    fn release(&self, batch: Option<SoaBatch>, result: Result<()>) {
+        let mut state = self.state.lock();
+        state.count -= 1;
+        if let Err(error) = result {
+            state.error.get_or_insert(error);
+        }
+        if let Some(batch) = batch {
+            state.pool.push(batch);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Waits until every submitted batch is completed
+    fn wait_idle(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        while state.count > 0 {
+            self.changed.wait(&mut state);
+        }
+        match state.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Handle that marks a submitted batch as done
+pub struct BatchCompletion {
+    in_flight: Arc<InFlight>,
+    record_set_idx: usize,
+    completed: bool,
+}
+
+impl BatchCompletion {
+    /// Global index of the batch
+    pub fn record_set_idx(&self) -> usize {
+        self.record_set_idx
+    }
+
+    /// Marks the batch as done with `result` and hands its buffers back for reuse
+    ///
+    /// An error stops the run.
+    pub fn complete(mut self, batch: SoaBatch, result: Result<()>) {
+        self.completed = true;
+        self.in_flight.release(Some(batch), result);
+    }
+}
+
+impl Drop for BatchCompletion {
+    fn drop(&mut self) {
+        if !self.completed {
+            let error = anyhow!(
+                "Batch {} was dropped without being completed",
+                self.record_set_idx
+            );
+            self.in_flight.release(None, Err(error));
+        }
+    }
+}
+
+impl<R: io::Read + Send> FastxReader<R> {
+    /// Hands every batch to `processor` as a [`SoaBatch`], with at most `max_in_flight` batches not yet completed
+    pub fn process_parallel_offload<T: OffloadProcessor>(
+        self,
+        processor: T,
+        max_in_flight: usize,
+        num_threads: usize,
+    ) -> Result<()> {
+        self.process_parallel_offload_with_config(
+            processor,
+            max_in_flight,
+            ParallelConfig::new(num_threads),
+        )
+        .map(|_| ())
+    }
+
+    /// Same as `process_parallel_offload` but with a full run configuration
+    pub fn process_parallel_offload_with_config<D, T>(
+        self,
+        processor: T,
+        max_in_flight: usize,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: OffloadProcessor,
+    {
+        let in_flight = Arc::new(InFlight::new(max_in_flight));
+        let worker = OffloadWorker {
+            processor,
+            in_flight: Arc::clone(&in_flight),
+        };
+        let result = run_parallel::<D, _, FastxRecordSet, _, _, _>(
+            self,
+            worker,
+            &config,
+            |reader, record_set| reader.read_record_set(record_set),
+            |record_set, worker, global_idx| {
+                let mut batch = worker.in_flight.acquire()?;
+                batch.fill(record_set);
+                let completion = BatchCompletion {
+                    in_flight: Arc::clone(&worker.in_flight),
+                    record_set_idx: global_idx,
+                    completed: false,
+                };
+                worker.processor.submit_batch(batch, global_idx, completion)
+            },
+        );
+        // Completions may still arrive after the workers are done
+        let completed = in_flight.wait_idle();
+        let stats = result?;
+        completed?;
+        Ok(stats)
+    }
+}
@@ -0,0 +1,104 @@
+//! `.gzi` and `.fai` indexes of written outputs
+//!
+//! Offsets are only known once a batch is placed in the output, so every
+//! batch records its index relative to its own start and the shared
+//! [`OutputIndex`] shifts it into place when the batch is written.
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compression::BGZF_BLOCK_SIZE;
+
+/// `.fai` line of a FASTA record, with its offset relative to the batch start
+#[derive(Debug, Clone)]
+pub(crate) struct FaiEntry {
+    pub(crate) name: Vec<u8>,
+    pub(crate) length: usize,
+    pub(crate) offset: usize,
+    pub(crate) line_bases: usize,
+    pub(crate) line_width: usize,
+}
+
+/// Index of a single batch
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BatchIndex {
+    /// Uncompressed size of the batch
+    pub(crate) len: usize,
+    /// Compressed sizes of the BGZF blocks of the batch
+    pub(crate) blocks: Vec<usize>,
+    pub(crate) fai: Vec<FaiEntry>,
+    /// Set if the batch holds FASTQ records, which have no `.fai`
+    pub(crate) has_fastq: bool,
+}
+
+/// Index of the whole output, built as batches are written
+#[derive(Debug, Default)]
+pub(crate) struct OutputIndex {
+    compressed: u64,
+    uncompressed: u64,
+    /// Compressed and uncompressed offset of every block after the first
+    gzi: Vec<(u64, u64)>,
+    fai: Vec<u8>,
+    has_fastq: bool,
+}
+
+impl OutputIndex {
+    pub(crate) fn add_batch(&mut self, batch: &BatchIndex) {
+        for entry in &batch.fai {
+            self.fai.extend_from_slice(&entry.name);
+            let line = format!(
+                "\t{}\t{}\t{}\t{}\n",
+                entry.length,
+                self.uncompressed + entry.offset as u64,
+                entry.line_bases,
+                entry.line_width
+            );
+            self.fai.extend_from_slice(line.as_bytes());
+        }
+        self.has_fastq |= batch.has_fastq;
+
+        // Every block but the last of a batch holds exactly BGZF_BLOCK_SIZE bytes
+        let mut remaining = batch.len;
+        for &block in &batch.blocks {
+            let block_len = remaining.min(BGZF_BLOCK_SIZE);
+            remaining -= block_len;
+            self.compressed += block as u64;
+            self.uncompressed += block_len as u64;
+            self.gzi.push((self.compressed, self.uncompressed));
+        }
+        self.uncompressed += remaining as u64;
+    }
+
+    /// Writes `<path>.gzi` for BGZF outputs and `<path>.fai` for FASTA outputs
+    pub(crate) fn write(&self, path: &Path, bgzf: bool) -> Result<()> {
+        if bgzf {
+            let mut out = create(&with_suffix(path, ".gzi"))?;
+            out.write_all(&(self.gzi.len() as u64).to_le_bytes())?;
+            for (compressed, uncompressed) in &self.gzi {
+                out.write_all(&compressed.to_le_bytes())?;
+                out.write_all(&uncompressed.to_le_bytes())?;
+            }
+            out.flush()?;
+        }
+        if !self.has_fastq && !self.fai.is_empty() {
+            let mut out = create(&with_suffix(path, ".fai"))?;
+            out.write_all(&self.fai)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends `suffix` to the file name of `path`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
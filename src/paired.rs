@@ -0,0 +1,605 @@
+//! Paired-end reading
+//!
+//! R1 and R2 are read on two dedicated mate threads so that decompression of
+//! both files runs concurrently. Each mate thread reads batches of a fixed
+//! number of records, which keeps the two files in lockstep without a shared
+//! parser. The batches are then paired and dispatched to the worker pool,
+//! where both halves are parsed and handed to the processor pair by pair.
+//! Without the `parallel` feature the mates are read in turn instead.
+use anyhow::{anyhow, bail, Result};
+#[cfg(feature = "parallel")]
+use crossbeam_channel::{bounded, Receiver, Sender};
+use flate2::Crc;
+use std::io;
+use std::path::Path;
+#[cfg(feature = "parallel")]
+use std::thread;
+
+use crate::batch::BatchSize;
+use crate::chunk::{RawChunk, RawChunkReader};
+use crate::config::{Options, ParallelConfig};
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, open_path_with_retry, BoxedReader, Format};
+use crate::macro_impl::{run_parallel, PairedWorker, WorkerHooks};
+use crate::processor::{apply_error_action, PairedParallelProcessor};
+use crate::reader::PairedParallelReader;
+use crate::retry::RetryPolicy;
+use crate::stats::RunStats;
+use crate::MinimalRefRecord;
+
+/// Default number of record pairs per batch
+pub const DEFAULT_PAIRED_BATCH_SIZE: usize = 1024;
+
+/// Number of batches each mate thread may read ahead
+#[cfg(feature = "parallel")]
+const MATE_READAHEAD: usize = 2;
+
+pub(crate) type MateMessage = Option<Result<RawChunk>>;
+
+/// Reads the next batch of one mate file into a chunk
+pub(crate) type MateReader<'r> = Box<dyn FnMut(&mut RawChunk) -> Option<Result<()>> + Send + 'r>;
+
+/// Reads batches of `batch_size` records of `reader`
+pub(crate) fn mate_reader<'r, R: io::Read + Send + 'r>(
+    mut reader: RawChunkReader<R>,
+    batch_size: usize,
+) -> MateReader<'r> {
+    Box::new(move |chunk| reader.read_records(chunk, batch_size))
+}
+
+/// Reader over two synchronized mate files
+pub struct PairedReader<R1: io::Read, R2: io::Read> {
+    r1: RawChunkReader<R1>,
+    r2: RawChunkReader<R2>,
+    format: Format,
+    batch_size: usize,
+}
+
+impl PairedReader<BoxedReader, BoxedReader> {
+    /// Opens two (possibly compressed) mate files
+    pub fn from_paths<P1: AsRef<Path>, P2: AsRef<Path>>(path1: P1, path2: P2) -> Result<Self> {
+        let (handle1, format1) = open_path(path1)?;
+        let (handle2, format2) = open_path(path2)?;
+        if format1 != format2 {
+            bail!("Mate files have different formats: {:?} and {:?}", format1, format2);
+        }
+        Ok(Self::new(handle1, handle2, format1))
+    }
+
+    /// Opens both mate files like [`from_paths`](Self::from_paths), retrying transient read errors
+    pub fn from_paths_with_retry<P1: AsRef<Path>, P2: AsRef<Path>>(
+        path1: P1,
+        path2: P2,
+        policy: RetryPolicy,
+    ) -> Result<Self> {
+        let (handle1, format1) = open_path_with_retry(path1, policy)?;
+        let (handle2, format2) = open_path_with_retry(path2, policy)?;
+        if format1 != format2 {
+            bail!("Mate files have different formats: {:?} and {:?}", format1, format2);
+        }
+        Ok(Self::new(handle1, handle2, format1))
+    }
+}
+
+impl<R1: io::Read, R2: io::Read> PairedReader<R1, R2> {
+    pub fn new(r1: R1, r2: R2, format: Format) -> Self {
+        Self {
+            r1: RawChunkReader::new(r1, format),
+            r2: RawChunkReader::new(r2, format),
+            format,
+            batch_size: DEFAULT_PAIRED_BATCH_SIZE,
+        }
+    }
+
+    pub fn fastq(r1: R1, r2: R2) -> Self {
+        Self::new(r1, r2, Format::Fastq)
+    }
+
+    pub fn fasta(r1: R1, r2: R2) -> Self {
+        Self::new(r1, r2, Format::Fasta)
+    }
+
+    /// Sets the number of record pairs per batch
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+/// Raw R1 and R2 halves of a batch
+#[derive(Default)]
+pub struct PairedChunk {
+    pub r1: RawChunk,
+    pub r2: RawChunk,
+}
+
+impl BatchSize for PairedChunk {
+    fn num_records(&self) -> usize {
+        self.r1.num_records
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.r1.data.len() + self.r2.data.len()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        self.r1.checksum(crc);
+        self.r2.checksum(crc);
+    }
+}
+
+/// Reads fixed-size batches of one mate file on its own thread
+#[cfg(feature = "parallel")]
+fn run_mate_reader_thread(
+    mut read: MateReader<'_>,
+    tx: Sender<MateMessage>,
+    recycle_rx: Receiver<RawChunk>,
+) {
+    loop {
+        let mut chunk = recycle_rx.try_recv().unwrap_or_default();
+        let message = read(&mut chunk).map(|r| r.map(|_| chunk));
+        let done = !matches!(message, Some(Ok(_)));
+        if tx.send(message).is_err() || done {
+            break;
+        }
+    }
+}
+
+/// Error of a run whose mate files hold different numbers of records
+///
+/// Once the shortest file ends, the remaining records of the longer files
+/// are still read and counted, so the error tells how far the files were out
+/// of sync. Returned through `anyhow` and available with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MateCountMismatch {
+    /// Mate file that ended first
+    pub ended: &'static str,
+    /// Records in the file that ended first
+    pub records: u64,
+    /// Every mate file with records left over, and their number
+    pub leftover: Vec<(&'static str, u64)>,
+}
+
+impl std::fmt::Display for MateCountMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ended after {} records", self.ended, self.records)?;
+        for (name, count) in &self.leftover {
+            write!(f, "; {} has {} more", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MateCountMismatch {}
+
+/// Batches of one mate file, as joined by a [`MateJoiner`]
+trait MateSource: Send {
+    /// Next batch, `None` once the file ends
+    fn next_batch(&mut self) -> MateMessage;
+
+    /// Hands back a joined chunk for reuse
+    fn recycle(&mut self, chunk: RawChunk);
+}
+
+/// Batches read ahead by a mate thread
+#[cfg(feature = "parallel")]
+struct MateThread {
+    rx: Receiver<MateMessage>,
+    recycle_tx: Sender<RawChunk>,
+}
+
+#[cfg(feature = "parallel")]
+impl MateSource for MateThread {
+    fn next_batch(&mut self) -> MateMessage {
+        self.rx.recv().ok().flatten()
+    }
+
+    fn recycle(&mut self, chunk: RawChunk) {
+        let _ = self.recycle_tx.try_send(chunk);
+    }
+}
+
+/// Batches read on demand, on the thread joining them
+#[cfg(not(feature = "parallel"))]
+struct InlineMate<'r> {
+    read: MateReader<'r>,
+    spare: Option<RawChunk>,
+}
+
+#[cfg(not(feature = "parallel"))]
+impl MateSource for InlineMate<'_> {
+    fn next_batch(&mut self) -> MateMessage {
+        let mut chunk = self.spare.take().unwrap_or_default();
+        (self.read)(&mut chunk).map(|r| r.map(|_| chunk))
+    }
+
+    fn recycle(&mut self, chunk: RawChunk) {
+        self.spare = Some(chunk);
+    }
+}
+
+/// Reads every mate on a thread of its own and runs `run` over the joined batches
+///
+/// Without the `parallel` feature the joiner reads the mates itself, one
+/// batch of each in turn. Mate threads are named `mate-1`, `mate-2` and so
+/// on, and `names` name the mates in error messages.
+pub(crate) fn join_mates<'r, T>(
+    options: &Options,
+    names: Vec<&'static str>,
+    mates: Vec<MateReader<'r>>,
+    run: impl FnOnce(MateJoiner<'_>) -> Result<T>,
+) -> Result<T> {
+    #[cfg(feature = "parallel")]
+    {
+        let (recycle_tx, recycle_rx) = bounded(mates.len() * (MATE_READAHEAD + 1));
+        thread::scope(|scope| {
+            let mut sources: Vec<Box<dyn MateSource>> = Vec::with_capacity(mates.len());
+            for (mate, read) in mates.into_iter().enumerate() {
+                let (tx, rx) = bounded(MATE_READAHEAD);
+                let recycle_rx = recycle_rx.clone();
+                options
+                    .thread_builder(&format!("mate-{}", mate + 1))
+                    .spawn_scoped(scope, move || run_mate_reader_thread(read, tx, recycle_rx))?;
+                sources.push(Box::new(MateThread {
+                    rx,
+                    recycle_tx: recycle_tx.clone(),
+                }));
+            }
+            drop(recycle_rx);
+            run(MateJoiner::new(names, sources))
+        })
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        let _ = options;
+        let sources = mates
+            .into_iter()
+            .map(|read| Box::new(InlineMate { read, spare: None }) as Box<dyn MateSource + 'r>)
+            .collect();
+        run(MateJoiner::new(names, sources))
+    }
+}
+
+/// Joins the batches of the mate files into synchronized chunks
+pub(crate) struct MateJoiner<'r> {
+    names: Vec<&'static str>,
+    sources: Vec<Box<dyn MateSource + 'r>>,
+    /// Records joined so far in every mate
+    num_joined: u64,
+}
+
+impl<'r> MateJoiner<'r> {
+    /// Joins the batches of `sources`, named by `names` in error messages
+    fn new(names: Vec<&'static str>, sources: Vec<Box<dyn MateSource + 'r>>) -> Self {
+        Self {
+            names,
+            sources,
+            num_joined: 0,
+        }
+    }
+
+    pub(crate) fn read_mates(&mut self, mates: &mut [&mut RawChunk]) -> Option<Result<()>> {
+        let received: Vec<MateMessage> = self
+            .sources
+            .iter_mut()
+            .map(|source| source.next_batch())
+            .collect();
+        if received.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let mut chunks = Vec::with_capacity(received.len());
+        for message in received {
+            match message {
+                Some(Ok(chunk)) => chunks.push(Some(chunk)),
+                Some(Err(e)) => return Some(Err(e)),
+                None => chunks.push(None),
+            }
+        }
+        let counts: Vec<usize> = chunks
+            .iter()
+            .map(|chunk| chunk.as_ref().map_or(0, |c| c.num_records))
+            .collect();
+        if counts.iter().any(|&count| count != counts[0]) {
+            return Some(Err(self.mismatch(&counts).into()));
+        }
+
+        self.num_joined += counts[0] as u64;
+        let joined = mates.iter_mut().zip(&mut self.sources).zip(chunks.into_iter().flatten());
+        for ((mate, source), chunk) in joined {
+            source.recycle(std::mem::replace(&mut **mate, chunk));
+        }
+        Some(Ok(()))
+    }
+
+    /// Counts the records left in the longer mates after the batch with `counts` records
+    fn mismatch(&mut self, counts: &[usize]) -> MateCountMismatch {
+        let shortest = counts.iter().copied().min().unwrap_or(0);
+        let ended = counts.iter().position(|&count| count == shortest).unwrap_or(0);
+        let leftover = counts
+            .iter()
+            .zip(&mut self.sources)
+            .zip(&self.names)
+            .filter(|((&count, _), _)| count > shortest)
+            .map(|((&count, source), &name)| {
+                let remaining: usize = std::iter::from_fn(|| source.next_batch())
+                    .map_while(Result::ok)
+                    .map(|chunk| chunk.num_records)
+                    .sum();
+                (name, (count - shortest + remaining) as u64)
+            })
+            .collect();
+        MateCountMismatch {
+            ended: self.names[ended],
+            records: self.num_joined + shortest as u64,
+            leftover,
+        }
+    }
+}
+
+/// Parses both halves of a paired chunk and hands each pair to the processor
+pub(crate) fn process_paired_chunk<T: PairedParallelProcessor>(
+    format: Format,
+    chunk: &PairedChunk,
+    processor: &mut T,
+    global_idx: usize,
+) -> Result<()> {
+    macro_rules! zip_mates {
+        ($parser:ty) => {{
+            let mut parser1 = <$parser>::new(&chunk.r1.data[..]);
+            let mut parser2 = <$parser>::new(&chunk.r2.data[..]);
+            let mut record_idx = 0;
+            loop {
+                match (parser1.next(), parser2.next()) {
+                    (Some(record1), Some(record2)) => {
+                        if let Err(err) =
+                            processor.process_record_pair(record1?, record2?, global_idx, record_idx)
+                        {
+                            let action = processor.on_record_error(&err, record_idx);
+                            apply_error_action(err, action)?;
+                        }
+                        record_idx += 1;
+                    }
+                    (None, None) => break,
+                    _ => bail!(
+                        "R1 and R2 have a different number of records in batch {}",
+                        global_idx
+                    ),
+                }
+            }
+        }};
+    }
+
+    match format {
+        Format::Fastq => zip_mates!(seq_io::fastq::Reader<&[u8]>),
+        Format::Fasta => zip_mates!(seq_io::fasta::Reader<&[u8]>),
+    }
+    Ok(())
+}
+
+impl<R1, R2> PairedParallelReader for PairedReader<R1, R2>
+where
+    R1: io::Read + Send,
+    R2: io::Read + Send,
+{
+    fn process_parallel_paired_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: PairedParallelProcessor,
+    {
+        let Self {
+            r1,
+            r2,
+            format,
+            batch_size,
+        } = self;
+        let mates = vec![mate_reader(r1, batch_size), mate_reader(r2, batch_size)];
+        join_mates(&config.options, vec!["R1", "R2"], mates, |joiner| {
+            run_parallel::<D, _, PairedChunk, _, _, _>(
+                joiner,
+                PairedWorker(processor),
+                &config,
+                |joiner, chunk| joiner.read_mates(&mut [&mut chunk.r1, &mut chunk.r2]),
+                |chunk, worker, global_idx| {
+                    process_paired_chunk(format, chunk, &mut worker.0, global_idx)
+                },
+            )
+        })
+    }
+}
+
+/// Raw chunks of any number of synchronized mate files
+#[derive(Default)]
+pub(crate) struct MateChunks {
+    pub mates: Vec<RawChunk>,
+}
+
+impl BatchSize for MateChunks {
+    fn num_records(&self) -> usize {
+        self.mates.first().map_or(0, |m| m.num_records)
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.mates.iter().map(|m| m.data.len()).sum()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        self.mates.iter().for_each(|m| m.checksum(crc));
+    }
+}
+
+/// Processor receiving one record of every mate file at a time
+pub(crate) trait MateGroupProcessor: WorkerHooks {
+    fn process_mates<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        mates: &[Rf],
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<()>;
+}
+
+/// Reader over any number of synchronized mate files, such as R1, R2, I1 and I2
+pub(crate) struct MateGroupReader {
+    readers: Vec<RawChunkReader<BoxedReader>>,
+    names: Vec<&'static str>,
+    format: Format,
+    batch_size: usize,
+}
+
+impl MateGroupReader {
+    /// Opens one file per mate, named by `names` in error messages
+    pub(crate) fn from_paths<P: AsRef<Path>>(paths: &[P], names: Vec<&'static str>) -> Result<Self> {
+        let mut readers = Vec::with_capacity(paths.len());
+        let mut format = None;
+        for (path, name) in paths.iter().zip(&names) {
+            let (handle, mate_format) = open_path(path)?;
+            if format.is_some_and(|f| f != mate_format) {
+                bail!("{} has a different format than the other mate files", name);
+            }
+            format = Some(mate_format);
+            readers.push(RawChunkReader::new(handle, mate_format));
+        }
+        let Some(format) = format else {
+            bail!("No mate files given");
+        };
+        Ok(Self {
+            readers,
+            names,
+            format,
+            batch_size: DEFAULT_PAIRED_BATCH_SIZE,
+        })
+    }
+
+    pub(crate) fn format(&self) -> Format {
+        self.format
+    }
+
+    pub(crate) fn process<D, T>(self, processor: T, config: &ParallelConfig<D>) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: MateGroupProcessor,
+    {
+        let Self {
+            readers,
+            names,
+            format,
+            batch_size,
+        } = self;
+        let num_mates = readers.len();
+        let mates = readers
+            .into_iter()
+            .map(|reader| mate_reader(reader, batch_size))
+            .collect();
+        join_mates(&config.options, names, mates, |joiner| {
+            run_parallel::<D, _, MateChunks, _, _, _>(
+                joiner,
+                processor,
+                config,
+                |joiner, chunk| {
+                    chunk.mates.resize_with(num_mates, RawChunk::default);
+                    let mut mates: Vec<&mut RawChunk> = chunk.mates.iter_mut().collect();
+                    joiner.read_mates(&mut mates)
+                },
+                |chunk, processor, global_idx| {
+                    process_mate_chunks(format, chunk, processor, global_idx)
+                },
+            )
+        })
+    }
+}
+
+/// Parses all mates of a chunk in lockstep and hands each group to the processor
+fn process_mate_chunks<T: MateGroupProcessor>(
+    format: Format,
+    chunk: &MateChunks,
+    processor: &mut T,
+    global_idx: usize,
+) -> Result<()> {
+    macro_rules! zip_group {
+        ($parser:ty) => {{
+            let mut parsers: Vec<$parser> =
+                chunk.mates.iter().map(|m| <$parser>::new(&m.data[..])).collect();
+            let mut record_idx = 0;
+            loop {
+                let records: Vec<_> = parsers.iter_mut().map(|p| p.next()).collect();
+                if records.iter().all(Option::is_none) {
+                    break;
+                }
+                let records = records
+                    .into_iter()
+                    .map(|record| match record {
+                        Some(record) => record.map_err(Into::into),
+                        None => Err(anyhow!(
+                            "Mate files have a different number of records in batch {}",
+                            global_idx
+                        )),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                processor.process_mates(&records, global_idx, record_idx)?;
+                record_idx += 1;
+            }
+        }};
+    }
+
+    match format {
+        Format::Fastq => zip_group!(seq_io::fastq::Reader<&[u8]>),
+        Format::Fasta => zip_group!(seq_io::fasta::Reader<&[u8]>),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::SyntheticReads;
+
+    #[derive(Clone)]
+    struct PassThrough;
+
+    impl PairedParallelProcessor for PassThrough {
+        fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+            &mut self,
+            record1: Rf,
+            record2: Rf,
+            _record_set_idx: usize,
+            _record_idx: usize,
+        ) -> Result<(Rf, Rf)> {
+            Ok((record1, record2))
+        }
+    }
+
+    fn run_pairs(num_r1: usize, num_r2: usize) -> Result<RunStats> {
+        let r1 = SyntheticReads::fastq(num_r1).with_seed(1).reader();
+        let r2 = SyntheticReads::fastq(num_r2).with_seed(2).reader();
+        PairedReader::fastq(r1, r2)
+            .with_batch_size(100)
+            .process_parallel_paired_with_config(PassThrough, ParallelConfig::new(2))
+    }
+
+    #[test]
+    fn mates_with_equal_counts_are_paired() {
+        assert_eq!(run_pairs(1000, 1000).unwrap().records, 1000);
+    }
+
+    #[test]
+    fn mate_count_mismatch_reports_leftover_records() {
+        let err = run_pairs(1000, 990).unwrap_err();
+        let mismatch = err
+            .downcast_ref::<MateCountMismatch>()
+            .expect("error is a MateCountMismatch");
+        let expected = MateCountMismatch {
+            ended: "R2",
+            records: 990,
+            leftover: vec![("R1", 10)],
+        };
+        assert_eq!(*mismatch, expected);
+    }
+}
@@ -0,0 +1,49 @@
+//! Reading remote inputs ahead on a background thread
+//!
+//! Remote readers fetch their input in blocks on a background thread that
+//! stays a few blocks ahead of the reader thread, so network latency overlaps
+//! with parsing.
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::io::{self, Read};
+use std::thread;
+
+pub(crate) type Block = io::Result<Vec<u8>>;
+
+/// `io::Read` over blocks sent by a background thread
+pub(crate) struct PrefetchReader {
+    blocks: Receiver<Block>,
+    block: io::Cursor<Vec<u8>>,
+}
+
+impl PrefetchReader {
+    /// Runs `fetch` on a background thread, at most `readahead` blocks ahead
+    ///
+    /// `fetch` should stop after sending an error or once sending fails,
+    /// which happens when the reader is dropped.
+    pub(crate) fn spawn<F>(readahead: usize, fetch: F) -> Self
+    where
+        F: FnOnce(Sender<Block>) + Send + 'static,
+    {
+        let (tx, rx) = bounded(readahead);
+        thread::spawn(move || fetch(tx));
+        Self {
+            blocks: rx,
+            block: io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for PrefetchReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.block.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.blocks.recv() {
+                Ok(block) => self.block = io::Cursor::new(block?),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
@@ -1,16 +1,157 @@
+use crate::manifest::SampleInfo;
+use crate::multi::SourceInfo;
+use crate::offload::BatchCompletion;
+use crate::chunk::ByteRange;
+use crate::record::Record;
+use crate::soa::SoaBatch;
+use crate::window::Window;
 use crate::MinimalRefRecord;
-use anyhow::Result;
+use anyhow::{Error, Result};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+
+use crate::retry::Retryable;
+use crate::stats::ErrorBudget;
+
+/// What a run does when processing a single record fails
+///
+/// Returned by the `on_record_error` hook of the processor traits. Errors
+/// while reading or parsing, and errors from the batch and thread hooks,
+/// always stop the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Stops the run with the error
+    #[default]
+    Abort,
+    /// Ignores the error and goes on with the next record
+    Skip,
+    /// Goes on with the next record, counting the error in `RunStats::errors` and the metrics
+    Count,
+}
+
+thread_local! {
+    /// Record errors counted on this thread and not yet added to the run's counters
+    static COUNTED_ERRORS: Cell<u64> = const { Cell::new(0) };
+    /// Errors the run of this worker thread tolerates before aborting
+    static ERROR_BUDGET: RefCell<Option<Arc<ErrorBudget>>> = const { RefCell::new(None) };
+    /// Messages of the errors tolerated in the current batch, kept once the batch is done
+    static PENDING_MESSAGES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// Whether the run retries batches, so retryable errors must reach the worker
+    static RETRY_BATCHES: Cell<bool> = const { Cell::new(false) };
+    /// Index of the batch this worker thread is processing
+    static CURRENT_BATCH: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Applies the action chosen for a failed record, returning the error only on abort
+///
+/// Aborting errors are still tolerated while the run's error budget lasts,
+/// except retryable errors in runs that retry batches, which fail the batch
+/// so it can be processed again.
+pub(crate) fn apply_error_action(err: Error, action: ErrorAction) -> Result<()> {
+    match action {
+        ErrorAction::Abort => {
+            if RETRY_BATCHES.with(Cell::get) && Retryable::is_retryable(&err) {
+                return Err(err);
+            }
+            let tolerated = ERROR_BUDGET.with(|budget| {
+                budget
+                    .borrow()
+                    .as_ref()
+                    .is_some_and(|budget| budget.reserve())
+            });
+            if !tolerated {
+                return Err(err);
+            }
+            PENDING_MESSAGES.with(|messages| messages.borrow_mut().push(format!("{:#}", err)));
+            COUNTED_ERRORS.with(|count| count.set(count.get() + 1));
+            Ok(())
+        }
+        ErrorAction::Skip => Ok(()),
+        ErrorAction::Count => {
+            COUNTED_ERRORS.with(|count| count.set(count.get() + 1));
+            Ok(())
+        }
+    }
+}
+
+/// Sets the error budget of the run and whether it retries batches on the current worker thread
+pub(crate) fn set_error_budget(budget: Option<Arc<ErrorBudget>>, retry_batches: bool) {
+    ERROR_BUDGET.with(|current| *current.borrow_mut() = budget);
+    PENDING_MESSAGES.with(|messages| messages.borrow_mut().clear());
+    RETRY_BATCHES.with(|retry| retry.set(retry_batches));
+}
+
+/// Keeps the messages of the errors tolerated in the batch just done
+pub(crate) fn commit_batch_errors() {
+    let messages = PENDING_MESSAGES.with(|messages| std::mem::take(&mut *messages.borrow_mut()));
+    if let Some(budget) = ERROR_BUDGET.with(|budget| budget.borrow().clone()) {
+        budget.record(messages);
+    }
+}
+
+/// Gives back the errors tolerated and counted in a batch that is processed again
+pub(crate) fn discard_batch_errors() {
+    let messages = PENDING_MESSAGES.with(|messages| std::mem::take(&mut *messages.borrow_mut()));
+    if let Some(budget) = ERROR_BUDGET.with(|budget| budget.borrow().clone()) {
+        budget.release(messages.len() as u64);
+    }
+    take_counted_errors();
+}
+
+/// Sets the index of the batch the current worker thread is processing, `None` between batches
+pub(crate) fn set_current_batch(batch_idx: Option<usize>) {
+    CURRENT_BATCH.with(|current| current.set(batch_idx));
+}
+
+/// Index of the batch the current worker thread is processing
+///
+/// Known even before the batch's first record, e.g. in `on_batch_complete`
+/// of a batch that failed right away.
+pub(crate) fn current_batch() -> Option<usize> {
+    CURRENT_BATCH.with(Cell::get)
+}
+
+/// Takes the record errors counted on this thread since the last call
+pub(crate) fn take_counted_errors() -> u64 {
+    COUNTED_ERRORS.with(|count| count.replace(0))
+}
 
 /// Trait implemented for a type that processes records in parallel
 pub trait ParallelProcessor: Send + Clone {
     /// Called on an individual record with its global index
     fn process_record<'a, Rf: MinimalRefRecord<'a>>(&mut self, record: Rf, record_set_idx: usize, record_idx: usize) -> Result<()>;
 
+    /// Called when processing a record fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
     /// Called when a batch of records is complete
     fn on_batch_complete(&mut self) -> Result<()> {
         Ok(())
     }
 
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// Only called with [`ParallelConfig::with_batch_retry`](crate::ParallelConfig::with_batch_retry).
+    /// The records before the failure were already passed to the processor,
+    /// so this undoes what the failed attempt did to its state, such as
+    /// records buffered for output or counts of the batch's records.
+    /// Processors whose state is updated idempotently need not implement it.
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called instead of `on_batch_complete` by readers that know the input bytes of each batch
+    ///
+    /// Lets index-building processors map their results back to file
+    /// coordinates. [`RawChunkReader`](crate::RawChunkReader) calls it.
+    #[allow(unused_variables)]
+    fn on_batch_complete_with_range(&mut self, range: &ByteRange) -> Result<()> {
+        self.on_batch_complete()
+    }
+
     /// Called when the processing for a thread is complete
     fn on_thread_complete(&mut self) -> Result<()> {
         Ok(())
@@ -28,22 +169,283 @@ pub trait ParallelProcessor: Send + Clone {
     }
 }
 
+/// Object-safe counterpart of [`ParallelProcessor`]
+///
+/// Takes a concrete [`Record`] instead of a generic record type, so
+/// processors can be chosen at runtime and boxed. `Box<dyn DynParallelProcessor>`
+/// implements [`ParallelProcessor`] and runs on any reader.
+pub trait DynParallelProcessor: Send + DynProcessorClone {
+    /// Called on an individual record with its global index
+    fn process_record(&mut self, record: Record<'_>, record_set_idx: usize, record_idx: usize) -> Result<()>;
+
+    /// Called when processing a record fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
+    /// Called when a batch of records is complete
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+
+    /// Gets the thread id for the processor
+    fn get_thread_id(&self) -> usize {
+        unimplemented!("Must be implemented by the processor to be used")
+    }
+}
+
+/// Cloning of boxed processors, implemented for every `Clone` processor
+pub trait DynProcessorClone {
+    fn clone_box(&self) -> Box<dyn DynParallelProcessor>;
+}
+
+impl<T: DynParallelProcessor + Clone + 'static> DynProcessorClone for T {
+    fn clone_box(&self) -> Box<dyn DynParallelProcessor> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn DynParallelProcessor> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl ParallelProcessor for Box<dyn DynParallelProcessor> {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(&mut self, record: Rf, record_set_idx: usize, record_idx: usize) -> Result<()> {
+        (**self).process_record(record.to_record(), record_set_idx, record_idx)
+    }
+
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        (**self).on_record_error(err, record_idx)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        (**self).on_batch_complete()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        (**self).on_batch_retry()
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        (**self).on_thread_complete()
+    }
+
+    fn set_thread_id(&mut self, thread_id: usize) {
+        (**self).set_thread_id(thread_id)
+    }
+
+    fn get_thread_id(&self) -> usize {
+        (**self).get_thread_id()
+    }
+}
+
+/// Trait implemented for a processor shared by all threads instead of cloned per thread
+///
+/// For stateless or internally synchronized processors. Any per-thread state
+/// can be kept by `thread_id`, which ranges from 0 to the number of threads.
+pub trait SharedParallelProcessor: Send + Sync {
+    /// Called on an individual record with its global index
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(&self, record: Rf, record_set_idx: usize, record_idx: usize) -> Result<()>;
+
+    /// Called when processing a record fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&self, err: &Error, record_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
+    /// Called when a thread completes a batch of records
+    #[allow(unused_variables)]
+    fn on_batch_complete(&self, thread_id: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a thread processes a batch that failed with a [`Retryable`] error again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    #[allow(unused_variables)]
+    fn on_batch_retry(&self, thread_id: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    #[allow(unused_variables)]
+    fn on_thread_complete(&self, thread_id: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a [`SharedParallelProcessor`] as a [`ParallelProcessor`]
+///
+/// Cloning only clones the `Arc`, so every thread uses the same processor.
+pub struct Shared<T> {
+    processor: Arc<T>,
+    thread_id: usize,
+}
+
+impl<T> Shared<T> {
+    pub fn new(processor: Arc<T>) -> Self {
+        Self {
+            processor,
+            thread_id: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &Arc<T> {
+        &self.processor
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self {
+            processor: Arc::clone(&self.processor),
+            thread_id: self.thread_id,
+        }
+    }
+}
+
+impl<T: SharedParallelProcessor> ParallelProcessor for Shared<T> {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(&mut self, record: Rf, record_set_idx: usize, record_idx: usize) -> Result<()> {
+        self.processor.process_record(record, record_set_idx, record_idx)
+    }
+
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        self.processor.on_record_error(err, record_idx)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.processor.on_batch_complete(self.thread_id)
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.processor.on_batch_retry(self.thread_id)
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.processor.on_thread_complete(self.thread_id)
+    }
+
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.thread_id = thread_id;
+    }
+
+    fn get_thread_id(&self) -> usize {
+        self.thread_id
+    }
+}
+
+/// Runs a borrowed [`SharedParallelProcessor`] as a [`ParallelProcessor`]
+///
+/// Workers run in a thread scope that ends with the run, so a processor
+/// owned by the caller can be lent to all threads without an `Arc`, along
+/// with any indexes or references it borrows itself.
+pub struct Borrowed<'p, T> {
+    processor: &'p T,
+    thread_id: usize,
+}
+
+impl<'p, T> Borrowed<'p, T> {
+    pub fn new(processor: &'p T) -> Self {
+        Self {
+            processor,
+            thread_id: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &'p T {
+        self.processor
+    }
+}
+
+impl<T> Clone for Borrowed<'_, T> {
+    fn clone(&self) -> Self {
+        Self {
+            processor: self.processor,
+            thread_id: self.thread_id,
+        }
+    }
+}
+
+impl<T: SharedParallelProcessor> ParallelProcessor for Borrowed<'_, T> {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(&mut self, record: Rf, record_set_idx: usize, record_idx: usize) -> Result<()> {
+        self.processor.process_record(record, record_set_idx, record_idx)
+    }
+
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        self.processor.on_record_error(err, record_idx)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.processor.on_batch_complete(self.thread_id)
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.processor.on_batch_retry(self.thread_id)
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.processor.on_thread_complete(self.thread_id)
+    }
+
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.thread_id = thread_id;
+    }
+
+    fn get_thread_id(&self) -> usize {
+        self.thread_id
+    }
+}
+
 /// Trait implemented for a type that processes pairs of records in parallel
 pub trait PairedParallelProcessor: Send + Clone {
-    /// Called on a pair of records with their indices
+    /// Called on a pair of records with their global index
     fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
         &mut self,
         record1: Rf,
         record2: Rf,
-        index1: usize,
-        index2: usize,
+        record_set_idx: usize,
+        record_idx: usize,
     ) -> Result<(Rf, Rf)>;
 
+    /// Called when processing a pair fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
     /// Called when a batch of pairs is complete
     fn on_batch_complete(&mut self) -> Result<()> {
         Ok(())
     }
 
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Called when the processing for a thread is complete
     fn on_thread_complete(&mut self) -> Result<()> {
         Ok(())
@@ -60,3 +462,226 @@ pub trait PairedParallelProcessor: Send + Clone {
         unimplemented!("Must be implemented by the processor to be used")
     }
 }
+
+/// Trait implemented for a type that processes groups of records sharing a key
+pub trait GroupProcessor: Send + Clone {
+    /// Called on a group of consecutive records with the same key
+    fn process_group<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        records: &[Rf],
+        record_set_idx: usize,
+        group_idx: usize,
+    ) -> Result<()>;
+
+    /// Called when processing a group fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, group_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
+    /// Called when a batch of groups is complete
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+}
+
+/// Creates a processor per sample in multi-sample modes
+///
+/// Lets per-sample state (output paths, barcodes, counters) be built by the
+/// framework for each sample instead of being routed through the clones of a
+/// single processor. Implemented for closures taking a `&SampleInfo`.
+pub trait ProcessorFactory<P> {
+    fn create(&self, sample: &SampleInfo) -> Result<P>;
+}
+
+impl<P, F> ProcessorFactory<P> for F
+where
+    F: Fn(&SampleInfo) -> Result<P>,
+{
+    fn create(&self, sample: &SampleInfo) -> Result<P> {
+        self(sample)
+    }
+}
+
+/// Trait implemented for a type that processes the records of archive members
+pub trait MemberProcessor: Send + Clone {
+    /// Called on an individual record with the name of the member it was read from
+    fn process_member_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        member: &str,
+        record: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<()>;
+
+    /// Called when processing a record fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
+    /// Called when a batch of records is complete
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+}
+
+/// Trait implemented for a type that processes records of many files with their origin
+pub trait SourceProcessor: Send + Clone {
+    /// Called on an individual record with the file it was read from and its index in that file
+    fn process_source_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        source: SourceInfo<'_>,
+        record: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<()>;
+
+    /// Called when processing a record fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, record_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
+    /// Called when a batch of records is complete
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+}
+
+/// Trait implemented for a type that processes whole batches in parallel
+pub trait BatchProcessor: Send + Clone {
+    /// Called on every batch with its global index
+    fn process_batch(&mut self, batch: &SoaBatch, record_set_idx: usize) -> Result<()>;
+
+    /// Called when a batch is complete
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+}
+
+/// Trait implemented for a type that hands whole batches to an accelerator and completes them asynchronously
+pub trait OffloadProcessor: Send + Clone {
+    /// Called on every batch with its global index, to enqueue it
+    ///
+    /// The batch is done once `completion` is completed, from any thread.
+    fn submit_batch(&mut self, batch: SoaBatch, record_set_idx: usize, completion: BatchCompletion) -> Result<()>;
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+}
+
+/// Trait implemented for a type that processes windows of long sequences in parallel
+pub trait WindowProcessor: Send + Clone {
+    /// Called on an individual window with its batch index
+    fn process_window(&mut self, window: Window<'_>, record_set_idx: usize, window_idx: usize) -> Result<()>;
+
+    /// Called when processing a window fails, to decide whether the run goes on
+    #[allow(unused_variables)]
+    fn on_record_error(&mut self, err: &Error, window_idx: usize) -> ErrorAction {
+        ErrorAction::Abort
+    }
+
+    /// Called when a batch of windows is complete
+    fn on_batch_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a batch that failed with a [`Retryable`] error is processed again
+    ///
+    /// See [`ParallelProcessor::on_batch_retry`].
+    fn on_batch_retry(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the processing for a thread is complete
+    fn on_thread_complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the thread id for the processor
+    #[allow(unused_variables)]
+    fn set_thread_id(&mut self, thread_id: usize) {
+        // Default implementation does nothing
+    }
+}
@@ -0,0 +1,6 @@
+//! Building blocks for writing processors
+//!
+//! Utilities for state that processors share across worker threads, which is
+//! easy to get subtly wrong when every worker runs its own clone.
+pub mod rng;
+pub mod seen;
@@ -0,0 +1,71 @@
+//! Random numbers that do not depend on the thread count
+//!
+//! Stochastic processors (subsampling, read simulation, augmentation) that
+//! keep one random generator per clone draw different numbers for a record
+//! depending on which worker got its batch and what that worker processed
+//! before, so their results change with the number of threads. A
+//! [`RecordRng`] is instead derived from a run seed and the record itself,
+//! either its position in the input or a key such as its name, and draws the
+//! same numbers for the same record in every run.
+//!
+//! Positions are batch-relative, so generators seeded with
+//! [`for_record`](RecordRng::for_record) are only reproducible between runs
+//! that cut the input into the same batches, which holds for any thread
+//! count with the same reader and batch size. Generators seeded with
+//! [`from_key`](RecordRng::from_key) do not depend on batching at all.
+
+/// SplitMix64 generator, small and reproducible across platforms
+#[derive(Debug, Clone)]
+pub struct RecordRng(u64);
+
+impl RecordRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Generator of the record at `record_idx` within batch `record_set_idx`, as passed to `process_record`
+    pub fn for_record(seed: u64, record_set_idx: usize, record_idx: usize) -> Self {
+        Self(mix(
+            mix(seed ^ mix(record_set_idx as u64)) ^ record_idx as u64
+        ))
+    }
+
+    /// Generator of the record identified by `key`, e.g. its id
+    pub fn from_key(seed: u64, key: &[u8]) -> Self {
+        // FNV-1a, stable across platforms and Rust versions
+        let hash = key.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        Self(mix(seed ^ mix(hash)))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.0)
+    }
+
+    /// Uniform over `min..=max`
+    pub fn range(&mut self, min: usize, max: usize) -> usize {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min + 1) as u64) as usize
+    }
+
+    /// Uniform over `[0, 1)`
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// `true` with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+}
+
+/// SplitMix64 output function
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,149 @@
+//! Approximate "seen before" sets shared by all workers
+//!
+//! Processors asking "have we seen this k-mer/read before?" usually keep a
+//! set per clone, which answers per worker thread instead of per run, or
+//! merge the sets when a thread completes, which is too late for the
+//! question. A [`SeenSet`] is a Bloom filter over atomic words instead:
+//! clones share one filter, so every worker sees the items inserted by the
+//! others as soon as they are inserted, and nothing needs merging at batch or
+//! thread completion.
+//!
+//! All bits of an item fall into a single 64-bit word, set with one atomic
+//! `fetch_or`. When several workers insert the same item at once, exactly
+//! one of them is told that it is new. Like any Bloom filter the set has
+//! false positives (items reported as seen although they were not) at about
+//! the configured rate, and no false negatives.
+use anyhow::{bail, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Most bits set per item, as each takes 6 bits of the item's hash
+const MAX_HASHES: u32 = 10;
+
+/// Extra space over a classic Bloom filter, for the single-word layout
+const BLOCKED_OVERHEAD: f64 = 1.5;
+
+struct Filter {
+    words: Box<[AtomicU64]>,
+    num_hashes: u32,
+}
+
+/// Concurrent approximate set of items, shared by its clones
+#[derive(Clone)]
+pub struct SeenSet {
+    filter: Arc<Filter>,
+}
+
+impl SeenSet {
+    /// Sized for `expected_items` at a false-positive rate of about `false_positive_rate`
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let bits_per_item = -rate.ln() / std::f64::consts::LN_2.powi(2) * BLOCKED_OVERHEAD;
+        let num_bits = (expected_items.max(1) as f64 * bits_per_item).ceil() as usize;
+        let num_hashes = (-rate.log2()).round() as u32;
+        Self::with_size(num_bits.div_ceil(64), num_hashes)
+    }
+
+    /// Uses `num_words` 64-bit words and sets `num_hashes` bits per item
+    pub fn with_size(num_words: usize, num_hashes: u32) -> Self {
+        let words = (0..num_words.max(1)).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            filter: Arc::new(Filter {
+                words,
+                num_hashes: num_hashes.clamp(1, MAX_HASHES),
+            }),
+        }
+    }
+
+    /// Inserts `item` and returns whether it was new, like `HashSet::insert`
+    pub fn insert(&self, item: &[u8]) -> bool {
+        self.insert_hash(hash_bytes(item))
+    }
+
+    /// Whether `item` was (probably) inserted before
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.contains_hash(hash_bytes(item))
+    }
+
+    /// Inserts an item given as a 64-bit value, e.g. a 2-bit encoded k-mer
+    pub fn insert_u64(&self, item: u64) -> bool {
+        self.insert_hash(mix(item))
+    }
+
+    pub fn contains_u64(&self, item: u64) -> bool {
+        self.contains_hash(mix(item))
+    }
+
+    fn insert_hash(&self, hash: u64) -> bool {
+        let (word, mask) = self.locate(hash);
+        let previous = self.filter.words[word].fetch_or(mask, Ordering::Relaxed);
+        previous & mask != mask
+    }
+
+    fn contains_hash(&self, hash: u64) -> bool {
+        let (word, mask) = self.locate(hash);
+        self.filter.words[word].load(Ordering::Relaxed) & mask == mask
+    }
+
+    /// Word of the item and the bits it sets in that word
+    fn locate(&self, hash: u64) -> (usize, u64) {
+        let num_words = self.filter.words.len() as u128;
+        let word = ((u128::from(hash) * num_words) >> 64) as usize;
+        let mut bits = mix(hash);
+        let mut mask = 0;
+        for _ in 0..self.filter.num_hashes {
+            mask |= 1 << (bits & 63);
+            bits >>= 6;
+        }
+        (word, mask)
+    }
+
+    /// Adds all items of `other`, e.g. a set filled by an earlier run
+    ///
+    /// Both sets must have the same size and number of bits per item.
+    pub fn merge(&self, other: &SeenSet) -> Result<()> {
+        if self.filter.words.len() != other.filter.words.len()
+            || self.filter.num_hashes != other.filter.num_hashes
+        {
+            bail!("Cannot merge seen sets of different sizes");
+        }
+        for (word, other) in self.filter.words.iter().zip(other.filter.words.iter()) {
+            word.fetch_or(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Estimated number of distinct items inserted, from the fraction of bits set
+    pub fn estimated_len(&self) -> u64 {
+        let num_bits = self.filter.words.len() as f64 * 64.0;
+        let set: u64 = self
+            .filter
+            .words
+            .iter()
+            .map(|word| u64::from(word.load(Ordering::Relaxed).count_ones()))
+            .sum();
+        if set as f64 >= num_bits {
+            return u64::MAX;
+        }
+        let k = f64::from(self.filter.num_hashes);
+        (-(num_bits / k) * (1.0 - set as f64 / num_bits).ln()).round() as u64
+    }
+}
+
+fn hash_bytes(item: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// SplitMix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,175 @@
+//! Input position tracking for completion estimates
+//!
+//! Record counts alone can't tell how far a run is, as the number of records
+//! in a file is unknown until it has been read. The position in the file is
+//! known, though, and for compressed inputs the position in the compressed
+//! bytes is what relates to the file size. An [`InputProgress`] collector
+//! counts the bytes read from every file opened through it, below
+//! decompression. Registered with
+//! [`ParallelConfig::with_input_progress`](crate::ParallelConfig::with_input_progress),
+//! the counts are reported in [`RunStats`](crate::RunStats), whose
+//! [`fraction_complete`](crate::RunStats::fraction_complete) and
+//! [`eta`](crate::RunStats::eta) turn them into an estimate for progress
+//! callbacks.
+//!
+//! Inputs opened in other ways, such as network streams or custom sources,
+//! get the same counts by wrapping their raw stream with
+//! [`InputProgress::count_stored`] and, optionally, their decompressed
+//! stream with [`InputProgress::count_decompressed`].
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::fastx::{open_reader_with, BoxedReader, FastxReader, Format};
+use crate::paired::PairedReader;
+
+#[derive(Debug, Default)]
+struct Counters {
+    read: AtomicU64,
+    decompressed: AtomicU64,
+    total: AtomicU64,
+}
+
+/// Counts the bytes read from the inputs opened through it, out of their total size
+///
+/// Clones share the counts, so the collector can be handed to the run
+/// configuration after opening the inputs.
+#[derive(Debug, Clone, Default)]
+pub struct InputProgress {
+    counters: Arc<Counters>,
+}
+
+impl InputProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes read so far from the inputs as stored, i.e. compressed for compressed inputs
+    pub fn bytes_read(&self) -> u64 {
+        self.counters.read.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read so far after decompression, from the streams wrapped with [`count_decompressed`](Self::count_decompressed)
+    pub fn bytes_decompressed(&self) -> u64 {
+        self.counters.decompressed.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the inputs opened so far
+    pub fn total_bytes(&self) -> u64 {
+        self.counters.total.load(Ordering::Relaxed)
+    }
+
+    /// Counts the bytes read from `inner` as stored, adding `total_bytes` to the total size
+    ///
+    /// Wrap the raw stream, below decompression, so the counts relate to
+    /// `total_bytes`. Pass 0 when the size is unknown; the run then reports
+    /// the bytes read but no estimate.
+    pub fn count_stored<R: Read>(&self, inner: R, total_bytes: u64) -> CountingReader<R> {
+        self.counters.total.fetch_add(total_bytes, Ordering::Relaxed);
+        CountingReader {
+            inner,
+            counters: Arc::clone(&self.counters),
+            layer: Layer::Stored,
+        }
+    }
+
+    /// Counts the bytes read from `inner` after decompression
+    pub fn count_decompressed<R: Read>(&self, inner: R) -> CountingReader<R> {
+        CountingReader {
+            inner,
+            counters: Arc::clone(&self.counters),
+            layer: Layer::Decompressed,
+        }
+    }
+
+    /// Opens a (possibly compressed) file and detects its format, counting the bytes read from it
+    pub fn open_path<P: AsRef<Path>>(&self, path: P) -> Result<(BoxedReader, Format)> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("Failed to read the size of {}", path.display()))?
+            .len();
+        let handle = self.count_stored(file, size);
+        open_reader_with(
+            Box::new(BufReader::new(handle)),
+            &path.display().to_string(),
+            |handle| Box::new(self.count_decompressed(handle)),
+        )
+    }
+}
+
+/// Counter a [`CountingReader`] adds to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    Stored,
+    Decompressed,
+}
+
+/// Reader adding the bytes read through it to the counts of an [`InputProgress`]
+///
+/// Created with [`InputProgress::count_stored`] or
+/// [`InputProgress::count_decompressed`].
+#[derive(Debug)]
+pub struct CountingReader<R> {
+    inner: R,
+    counters: Arc<Counters>,
+    layer: Layer,
+}
+
+impl<R> CountingReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let counter = match self.layer {
+            Layer::Stored => &self.counters.read,
+            Layer::Decompressed => &self.counters.decompressed,
+        };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl FastxReader<BoxedReader> {
+    /// Opens a (possibly compressed) file like [`from_path`](Self::from_path), counting its bytes in `progress`
+    pub fn from_path_with_progress<P: AsRef<Path>>(
+        path: P,
+        progress: &InputProgress,
+    ) -> Result<Self> {
+        let (handle, format) = progress.open_path(path)?;
+        Ok(Self::new(handle, format))
+    }
+}
+
+impl PairedReader<BoxedReader, BoxedReader> {
+    /// Opens both mate files like [`from_paths`](Self::from_paths), counting their bytes in `progress`
+    pub fn from_paths_with_progress<P1: AsRef<Path>, P2: AsRef<Path>>(
+        path1: P1,
+        path2: P2,
+        progress: &InputProgress,
+    ) -> Result<Self> {
+        let (handle1, format1) = progress.open_path(path1)?;
+        let (handle2, format2) = progress.open_path(path2)?;
+        if format1 != format2 {
+            bail!(
+                "Mate files have different formats: {:?} and {:?}",
+                format1,
+                format2
+            );
+        }
+        Ok(Self::new(handle1, handle2, format1))
+    }
+}
@@ -0,0 +1,129 @@
+//! Quality control processors
+//!
+//! Every QC processor collects into a report of its own clone, merged into a
+//! report shared by all clones when the thread completes, so workers never
+//! wait on each other while counting. The reports merge exactly: a report of
+//! a run is the same for any thread count or batch size.
+//!
+//! - [`TileQc`]: read counts and qualities per Illumina lane and tile
+//! - [`ProfileQc`]: quality and base composition at every read position
+//! - [`OverrepresentedQc`]: frequent read starts, matched against common adapters
+//!
+//! [`QualityTable`] is the per-position quality distribution the reports
+//! share, with the quantiles of a FastQC "per base sequence quality" table.
+//! Every report also renders as MultiQC custom content, see [`multiqc`].
+pub mod multiqc;
+pub mod overrepresented;
+pub mod profile;
+pub mod tiles;
+
+pub use multiqc::{MultiqcFormat, MultiqcReport, MultiqcSection};
+pub use overrepresented::{
+    overrepresented_qc_parallel, Overrepresented, OverrepresentedQc, OverrepresentedReport,
+    SpaceSaving,
+};
+pub use profile::{profile_qc_parallel, PositionComposition, ProfileQc, ProfileReport};
+pub use tiles::{tile_qc_parallel, LaneStats, TileQc, TileReport, TileStats};
+
+/// Number of distinct Phred scores, the printable range of Phred+33
+pub const NUM_SCORES: usize = 94;
+
+/// Distribution of the quality scores at every read position
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QualityTable {
+    /// Number of bases of every score, per position
+    counts: Vec<[u64; NUM_SCORES]>,
+}
+
+impl QualityTable {
+    /// Adds the scores of a read, starting at its first position
+    pub fn add<I: IntoIterator<Item = u8>>(&mut self, scores: I) {
+        for (position, score) in scores.into_iter().enumerate() {
+            if position == self.counts.len() {
+                self.counts.push([0; NUM_SCORES]);
+            }
+            self.counts[position][usize::from(score).min(NUM_SCORES - 1)] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), [0; NUM_SCORES]);
+        }
+        for (counts, other) in self.counts.iter_mut().zip(&other.counts) {
+            for (count, other) in counts.iter_mut().zip(other) {
+                *count += other;
+            }
+        }
+    }
+
+    /// Number of positions, the length of the longest read
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Number of bases of every score at `position`
+    pub fn counts(&self, position: usize) -> Option<&[u64; NUM_SCORES]> {
+        self.counts.get(position)
+    }
+
+    /// Summary of the scores at `position`
+    pub fn position(&self, position: usize) -> Option<PositionQuality> {
+        let counts = self.counts.get(position)?;
+        let total: u64 = counts.iter().sum();
+        let sum: u64 = counts
+            .iter()
+            .enumerate()
+            .map(|(score, &count)| score as u64 * count)
+            .sum();
+        let quantile = |fraction: f64| {
+            let rank = ((total as f64 * fraction).ceil() as u64).max(1);
+            let mut seen = 0;
+            counts
+                .iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(0) as u8
+        };
+        Some(PositionQuality {
+            position,
+            count: total,
+            mean: if total > 0 {
+                sum as f64 / total as f64
+            } else {
+                0.0
+            },
+            median: quantile(0.5),
+            lower_quartile: quantile(0.25),
+            upper_quartile: quantile(0.75),
+            percentile_10: quantile(0.1),
+            percentile_90: quantile(0.9),
+        })
+    }
+
+    /// Summaries of all positions, as in a FastQC "per base sequence quality" table
+    pub fn rows(&self) -> Vec<PositionQuality> {
+        (0..self.len()).filter_map(|pos| self.position(pos)).collect()
+    }
+}
+
+/// Quality summary of one read position
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionQuality {
+    /// Position in the read, from 0
+    pub position: usize,
+    /// Number of reads reaching the position
+    pub count: u64,
+    pub mean: f64,
+    pub median: u8,
+    pub lower_quartile: u8,
+    pub upper_quartile: u8,
+    pub percentile_10: u8,
+    pub percentile_90: u8,
+}
@@ -0,0 +1,365 @@
+//! MultiQC custom content
+//!
+//! The QC reports render as MultiQC "custom content" sections, so their
+//! results show up in a MultiQC report next to FastQC and other tools
+//! without glue scripts. [`MultiqcReport::write_multiqc`] writes every
+//! section of a report to a `<sample>_<id>_mqc.json` (or `.tsv`) file, which
+//! MultiQC picks up when run on the directory. Sections with the same id
+//! from several samples end up in the same plot or table, one series or
+//! row per sample.
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use super::{OverrepresentedReport, ProfileReport, TileReport};
+use crate::report::json_string;
+
+/// File format of the written sections, both parsed by MultiQC
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MultiqcFormat {
+    #[default]
+    Json,
+    /// Tab-separated values below a YAML header of `#` comments
+    Tsv,
+}
+
+impl MultiqcFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Tsv => "tsv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotType {
+    LineGraph,
+    Table,
+}
+
+impl PlotType {
+    fn name(self) -> &'static str {
+        match self {
+            Self::LineGraph => "linegraph",
+            Self::Table => "table",
+        }
+    }
+}
+
+/// Point of a line graph or cell of a table
+#[derive(Debug, Clone, PartialEq)]
+pub enum MultiqcValue {
+    Number(f64),
+    Text(String),
+}
+
+impl MultiqcValue {
+    fn json(&self) -> String {
+        match self {
+            Self::Number(value) if value.is_finite() => value.to_string(),
+            Self::Number(_) => "null".to_string(),
+            Self::Text(text) => json_string(text),
+        }
+    }
+
+    fn tsv(&self) -> String {
+        match self {
+            Self::Number(value) if value.is_finite() => value.to_string(),
+            Self::Number(_) => String::new(),
+            Self::Text(text) => tsv_field(text),
+        }
+    }
+}
+
+impl From<f64> for MultiqcValue {
+    fn from(value: f64) -> Self {
+        Self::Number(value)
+    }
+}
+
+impl From<u64> for MultiqcValue {
+    fn from(value: u64) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<&str> for MultiqcValue {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+/// One section of a MultiQC report, a line graph or a table
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiqcSection {
+    /// Identifier shared by the sections of all samples
+    pub id: String,
+    pub section_name: String,
+    pub description: String,
+    pub plot_type: PlotType,
+    /// Axis labels of a line graph
+    pub xlab: Option<String>,
+    pub ylab: Option<String>,
+    /// Series of a line graph or rows of a table, each with its points or cells by key
+    pub data: Vec<(String, Vec<(String, MultiqcValue)>)>,
+}
+
+impl MultiqcSection {
+    pub fn line_graph(
+        id: &str,
+        section_name: &str,
+        description: &str,
+        xlab: &str,
+        ylab: &str,
+    ) -> Self {
+        let mut section = Self::table(id, section_name, description);
+        section.plot_type = PlotType::LineGraph;
+        section.xlab = Some(xlab.to_string());
+        section.ylab = Some(ylab.to_string());
+        section
+    }
+
+    pub fn table(id: &str, section_name: &str, description: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            section_name: section_name.to_string(),
+            description: description.to_string(),
+            plot_type: PlotType::Table,
+            xlab: None,
+            ylab: None,
+            data: Vec::new(),
+        }
+    }
+
+    /// Adds a series or row named `name`
+    pub fn push<K, V, I>(&mut self, name: impl Into<String>, values: I)
+    where
+        K: ToString,
+        V: Into<MultiqcValue>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let values = values
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.into()))
+            .collect();
+        self.data.push((name.into(), values));
+    }
+
+    /// Renders the section as MultiQC custom content in `format`
+    pub fn render(&self, format: MultiqcFormat) -> String {
+        match format {
+            MultiqcFormat::Json => self.render_json(),
+            MultiqcFormat::Tsv => self.render_tsv(),
+        }
+    }
+
+    fn render_json(&self) -> String {
+        let mut out = String::from("{\n");
+        let _ = writeln!(out, "  \"id\": {},", json_string(&self.id));
+        let _ = writeln!(out, "  \"section_name\": {},", json_string(&self.section_name));
+        let _ = writeln!(out, "  \"description\": {},", json_string(&self.description));
+        let _ = writeln!(out, "  \"plot_type\": {},", json_string(self.plot_type.name()));
+        let mut pconfig = vec![
+            format!("\"id\": {}", json_string(&format!("{}_plot", self.id))),
+            format!("\"title\": {}", json_string(&self.section_name)),
+        ];
+        if let Some(xlab) = &self.xlab {
+            pconfig.push(format!("\"xlab\": {}", json_string(xlab)));
+        }
+        if let Some(ylab) = &self.ylab {
+            pconfig.push(format!("\"ylab\": {}", json_string(ylab)));
+        }
+        let _ = writeln!(out, "  \"pconfig\": {{{}}},", pconfig.join(", "));
+        out.push_str("  \"data\": {");
+        for (i, (name, values)) in self.data.iter().enumerate() {
+            let values = values
+                .iter()
+                .map(|(key, value)| format!("{}: {}", json_string(key), value.json()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let separator = if i == 0 { "" } else { "," };
+            let _ = write!(out, "{}\n    {}: {{{}}}", separator, json_string(name), values);
+        }
+        if !self.data.is_empty() {
+            out.push_str("\n  ");
+        }
+        out.push_str("}\n}\n");
+        out
+    }
+
+    fn render_tsv(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# id: {}", yaml_string(&self.id));
+        let _ = writeln!(out, "# section_name: {}", yaml_string(&self.section_name));
+        let _ = writeln!(out, "# description: {}", yaml_string(&self.description));
+        let _ = writeln!(out, "# plot_type: {}", yaml_string(self.plot_type.name()));
+        out.push_str("# pconfig:\n");
+        let _ = writeln!(out, "#     id: {}", yaml_string(&format!("{}_plot", self.id)));
+        let _ = writeln!(out, "#     title: {}", yaml_string(&self.section_name));
+        if let Some(xlab) = &self.xlab {
+            let _ = writeln!(out, "#     xlab: {}", yaml_string(xlab));
+        }
+        if let Some(ylab) = &self.ylab {
+            let _ = writeln!(out, "#     ylab: {}", yaml_string(ylab));
+        }
+        // One column per key, in the order the keys first appear
+        let mut keys: Vec<&str> = Vec::new();
+        for (key, _) in self.data.iter().flat_map(|(_, values)| values) {
+            if !keys.contains(&key.as_str()) {
+                keys.push(key);
+            }
+        }
+        out.push_str("Sample");
+        for key in &keys {
+            let _ = write!(out, "\t{}", tsv_field(key));
+        }
+        out.push('\n');
+        for (name, values) in &self.data {
+            out.push_str(&tsv_field(name));
+            for key in &keys {
+                let value = values.iter().find(|(k, _)| k.as_str() == *key);
+                let _ = write!(out, "\t{}", value.map_or_else(String::new, |(_, v)| v.tsv()));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Quotes `value` as a single-quoted YAML scalar
+fn yaml_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''").replace('\n', " "))
+}
+
+/// Replaces the separators of a TSV field with spaces
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Report that renders as MultiQC custom content
+pub trait MultiqcReport {
+    /// Sections of the report, with the series and rows named after `sample`
+    fn multiqc_sections(&self, sample: &str) -> Vec<MultiqcSection>;
+
+    /// Writes every section to `dir` as `<sample>_<id>_mqc.json` or `.tsv`, returning the paths written
+    fn write_multiqc<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        sample: &str,
+        format: MultiqcFormat,
+    ) -> Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        self.multiqc_sections(sample)
+            .iter()
+            .map(|section| {
+                let name = format!("{}_{}_mqc.{}", sample, section.id, format.extension());
+                let path = dir.join(name);
+                std::fs::write(&path, section.render(format))
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                Ok(path)
+            })
+            .collect()
+    }
+}
+
+impl MultiqcReport for TileReport {
+    fn multiqc_sections(&self, sample: &str) -> Vec<MultiqcSection> {
+        let mut quality = MultiqcSection::line_graph(
+            "seq_io_parallel_lane_quality",
+            "Per-lane sequence quality",
+            "Mean quality score at every read position, per Illumina lane.",
+            "Position (bp)",
+            "Mean quality score",
+        );
+        let mut lanes = MultiqcSection::table(
+            "seq_io_parallel_lane_stats",
+            "Per-lane statistics",
+            "Reads, chastity-filtered reads and bases per Illumina lane.",
+        );
+        for (lane, stats) in &self.lanes {
+            let name = format!("{} lane {}", sample, lane);
+            let rows = stats.qualities.rows();
+            quality.push(&*name, rows.iter().map(|row| (row.position + 1, row.mean)));
+            lanes.push(
+                name,
+                [
+                    ("Reads", stats.reads),
+                    ("Filtered reads", stats.filtered),
+                    ("Bases", stats.bases),
+                ],
+            );
+        }
+        let mut tiles = MultiqcSection::table(
+            "seq_io_parallel_tile_stats",
+            "Per-tile statistics",
+            "Reads, bases and mean quality score per Illumina tile.",
+        );
+        for ((lane, tile), stats) in &self.tiles {
+            let mut values = vec![
+                ("Reads", MultiqcValue::from(stats.reads)),
+                ("Bases", MultiqcValue::from(stats.bases)),
+            ];
+            if let Some(mean) = stats.mean_quality() {
+                values.push(("Mean quality", MultiqcValue::from(mean)));
+            }
+            tiles.push(format!("{} {}:{}", sample, lane, tile), values);
+        }
+        vec![quality, lanes, tiles]
+    }
+}
+
+impl MultiqcReport for ProfileReport {
+    fn multiqc_sections(&self, sample: &str) -> Vec<MultiqcSection> {
+        let mut quality = MultiqcSection::line_graph(
+            "seq_io_parallel_per_base_quality",
+            "Per-base sequence quality",
+            "Mean quality score at every read position.",
+            "Position (bp)",
+            "Mean quality score",
+        );
+        let rows = self.qualities.rows();
+        quality.push(sample, rows.iter().map(|row| (row.position + 1, row.mean)));
+        let mut gc = MultiqcSection::line_graph(
+            "seq_io_parallel_per_base_gc",
+            "Per-base GC content",
+            "Percentage of G and C among the called bases at every read position.",
+            "Position (bp)",
+            "% GC",
+        );
+        let compositions = self.compositions();
+        gc.push(
+            sample,
+            compositions
+                .iter()
+                .map(|composition| (composition.position + 1, 100.0 * composition.gc)),
+        );
+        vec![quality, gc]
+    }
+}
+
+impl MultiqcReport for OverrepresentedReport {
+    fn multiqc_sections(&self, sample: &str) -> Vec<MultiqcSection> {
+        let mut table = MultiqcSection::table(
+            "seq_io_parallel_overrepresented",
+            "Overrepresented sequences",
+            "Read starts found in more than the minimum fraction of the reads.",
+        );
+        for seq in &self.sequences {
+            table.push(
+                format!("{} {}", sample, String::from_utf8_lossy(&seq.seq)),
+                [
+                    ("Count", MultiqcValue::from(seq.count)),
+                    ("Percentage", MultiqcValue::from(100.0 * seq.fraction)),
+                    (
+                        "Possible source",
+                        MultiqcValue::from(seq.possible_source.unwrap_or("No Hit")),
+                    ),
+                ],
+            );
+        }
+        vec![table]
+    }
+}
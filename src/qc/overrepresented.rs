@@ -0,0 +1,329 @@
+//! Overrepresented sequences
+//!
+//! [`OverrepresentedQc`] counts the first bases of every read in a
+//! [`SpaceSaving`] sketch, which keeps a bounded number of candidates and
+//! never misses a sequence more frequent than one in `capacity` reads. Each
+//! thread fills a sketch of its own and merges it into the shared one when
+//! it completes; merged counts may overestimate, by at most the reported
+//! error.
+//!
+//! Sequences above a fraction of the reads (0.1% by default, as in FastQC)
+//! are reported, and matched against common adapters so adapter dimers and
+//! read-through stand out.
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::fastx::FastxReader;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Default number of leading bases counted per read, as FastQC for long reads
+pub const DEFAULT_PREFIX_LEN: usize = 50;
+
+/// Default number of candidates kept by the sketch
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Default fraction of the reads above which a sequence is reported
+pub const DEFAULT_MIN_FRACTION: f64 = 0.001;
+
+/// Bases of an adapter that must occur in a sequence for it to be attributed to the adapter
+const ADAPTER_MATCH_LEN: usize = 20;
+
+/// Common adapters checked by [`OverrepresentedQc`]
+pub const ADAPTERS: &[(&str, &[u8])] = &[
+    ("Illumina Universal Adapter", b"AGATCGGAAGAGCACACGTCTGAACTCCAGTCA"),
+    ("Illumina TruSeq Read 2 Adapter", b"AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGT"),
+    ("Illumina Small RNA 3' Adapter", b"TGGAATTCTCGGGTGCCAAGGAACTCCAGTCAC"),
+    ("Nextera Transposase Sequence", b"CTGTCTCTTATACACATCTCCGAGCCCACGAGAC"),
+    ("Nextera Transposase Sequence Read 2", b"CTGTCTCTTATACACATCTGACGCTGCCGACGA"),
+    ("Poly-A", b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+    ("Poly-G", b"GGGGGGGGGGGGGGGGGGGGGGGGGGGGGG"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Counter {
+    key: Box<[u8]>,
+    count: u64,
+    error: u64,
+}
+
+/// Space-saving heavy-hitter sketch over byte strings
+///
+/// Keeps at most `capacity` keys. A new key replaces the least frequent one
+/// and inherits its count as error, so counts never underestimate and
+/// overestimate by at most their error.
+#[derive(Debug, Clone, Default)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: Vec<Counter>,
+    index: HashMap<Box<[u8]>, usize>,
+    /// `(count, counter index)` of all counters, to find the least frequent
+    order: BTreeSet<(u64, usize)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ..Self::default()
+        }
+    }
+
+    /// Counts `key` `n` more times
+    pub fn add(&mut self, key: &[u8], n: u64) {
+        self.add_with_error(key, n, 0);
+    }
+
+    fn add_with_error(&mut self, key: &[u8], n: u64, error: u64) {
+        if let Some(&idx) = self.index.get(key) {
+            let counter = &mut self.counters[idx];
+            self.order.remove(&(counter.count, idx));
+            counter.count += n;
+            counter.error += error;
+            self.order.insert((counter.count, idx));
+            return;
+        }
+        let counter = Counter {
+            key: key.into(),
+            count: n,
+            error,
+        };
+        if self.counters.len() < self.capacity {
+            let idx = self.counters.len();
+            self.index.insert(counter.key.clone(), idx);
+            self.order.insert((counter.count, idx));
+            self.counters.push(counter);
+            return;
+        }
+        let (min, idx) = self.order.pop_first().expect("a full sketch has counters");
+        let old = std::mem::replace(
+            &mut self.counters[idx],
+            Counter {
+                count: min + n,
+                error: min + error,
+                ..counter
+            },
+        );
+        self.index.remove(&old.key);
+        self.index.insert(self.counters[idx].key.clone(), idx);
+        self.order.insert((min + n, idx));
+    }
+
+    /// Count of the least frequent key, the most an untracked key can have occurred
+    pub fn min_count(&self) -> u64 {
+        if self.counters.len() < self.capacity {
+            return 0;
+        }
+        self.order.first().map_or(0, |&(count, _)| count)
+    }
+
+    /// Adds the counts of `other`, as if its keys had been added to this sketch
+    ///
+    /// Keys tracked by only one sketch get the other's minimum count, which
+    /// keeps the guarantees of both (mergeable summaries, Agarwal et al.).
+    pub fn merge(&mut self, other: &Self) {
+        let (min, other_min) = (self.min_count(), other.min_count());
+        let mut merged: HashMap<&[u8], (u64, u64)> = HashMap::new();
+        for counter in &self.counters {
+            merged.insert(&counter.key, (counter.count + other_min, counter.error + other_min));
+        }
+        for counter in &other.counters {
+            let entry = merged.entry(&counter.key).or_insert((min, min));
+            // Replace the other minimum assumed above by the actual count
+            if self.index.contains_key(&counter.key) {
+                entry.0 -= other_min;
+                entry.1 -= other_min;
+            }
+            entry.0 += counter.count;
+            entry.1 += counter.error;
+        }
+        let mut merged: Vec<(&[u8], (u64, u64))> = merged.into_iter().collect();
+        merged.sort_unstable_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(b.0)));
+        merged.truncate(self.capacity);
+        let mut sketch = Self::new(self.capacity);
+        for (key, (count, error)) in merged {
+            sketch.add_with_error(key, count, error);
+        }
+        *self = sketch;
+    }
+
+    /// Tracked keys with their count and error, most frequent first
+    pub fn top(&self) -> Vec<(&[u8], u64, u64)> {
+        self.order
+            .iter()
+            .rev()
+            .map(|&(_, idx)| {
+                let counter = &self.counters[idx];
+                (&counter.key[..], counter.count, counter.error)
+            })
+            .collect()
+    }
+}
+
+/// Sequence found in more than the minimum fraction of the reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overrepresented {
+    pub seq: Vec<u8>,
+    /// Reads starting with the sequence, possibly overestimated by `error`
+    pub count: u64,
+    pub error: u64,
+    /// Fraction of all reads
+    pub fraction: f64,
+    /// Name of the adapter the sequence matches, if any
+    pub possible_source: Option<&'static str>,
+}
+
+/// Overrepresented sequences of a run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OverrepresentedReport {
+    pub reads: u64,
+    /// Most frequent first
+    pub sequences: Vec<Overrepresented>,
+}
+
+impl OverrepresentedReport {
+    /// Whether any sequence matches a known adapter
+    pub fn has_adapter_contamination(&self) -> bool {
+        self.sequences
+            .iter()
+            .any(|seq| seq.possible_source.is_some())
+    }
+}
+
+/// Name of the first adapter sharing [`ADAPTER_MATCH_LEN`] bases with `seq`
+fn adapter_source(seq: &[u8]) -> Option<&'static str> {
+    ADAPTERS.iter().find_map(|&(name, adapter)| {
+        let window = ADAPTER_MATCH_LEN.min(seq.len());
+        let found = window > 0
+            && (adapter
+                .windows(window)
+                .any(|part| memchr::memmem::find(seq, part).is_some()));
+        found.then_some(name)
+    })
+}
+
+#[derive(Default)]
+struct SharedSketch {
+    reads: u64,
+    sketch: SpaceSaving,
+}
+
+/// Processor collecting an [`OverrepresentedReport`], see the [module docs](self)
+///
+/// Clones share the sketch; read the report with [`report`](Self::report)
+/// after the run.
+#[derive(Clone)]
+pub struct OverrepresentedQc {
+    prefix_len: usize,
+    min_fraction: f64,
+    reads: u64,
+    local: SpaceSaving,
+    shared: Arc<Mutex<SharedSketch>>,
+}
+
+impl Default for OverrepresentedQc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OverrepresentedQc {
+    pub fn new() -> Self {
+        Self {
+            prefix_len: DEFAULT_PREFIX_LEN,
+            min_fraction: DEFAULT_MIN_FRACTION,
+            reads: 0,
+            local: SpaceSaving::new(DEFAULT_CAPACITY),
+            shared: Arc::new(Mutex::new(SharedSketch {
+                reads: 0,
+                sketch: SpaceSaving::new(DEFAULT_CAPACITY),
+            })),
+        }
+    }
+
+    /// Counts the first `prefix_len` bases of every read, or all of shorter reads
+    pub fn with_prefix_len(mut self, prefix_len: usize) -> Self {
+        self.prefix_len = prefix_len.max(1);
+        self
+    }
+
+    /// Keeps `capacity` candidates per sketch
+    ///
+    /// Sequences more frequent than one in `capacity` reads are never
+    /// missed. Must be set before the processor is cloned into the workers.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.local = SpaceSaving::new(capacity);
+        self.shared.lock().sketch = SpaceSaving::new(capacity);
+        self
+    }
+
+    /// Reports sequences found in more than `min_fraction` of the reads
+    pub fn with_min_fraction(mut self, min_fraction: f64) -> Self {
+        self.min_fraction = min_fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Report of the threads completed so far
+    pub fn report(&self) -> OverrepresentedReport {
+        let shared = self.shared.lock();
+        let min_count = (shared.reads as f64 * self.min_fraction) as u64;
+        let sequences = shared
+            .sketch
+            .top()
+            .into_iter()
+            .filter(|&(_, count, _)| count > min_count)
+            .map(|(seq, count, error)| Overrepresented {
+                seq: seq.to_vec(),
+                count,
+                error,
+                fraction: count as f64 / shared.reads.max(1) as f64,
+                possible_source: adapter_source(seq),
+            })
+            .collect();
+        OverrepresentedReport {
+            reads: shared.reads,
+            sequences,
+        }
+    }
+}
+
+impl ParallelProcessor for OverrepresentedQc {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        let seq = record.ref_full_seq();
+        let prefix = &seq[..self.prefix_len.min(seq.len())];
+        if prefix.iter().any(u8::is_ascii_lowercase) {
+            self.local.add(&prefix.to_ascii_uppercase(), 1);
+        } else {
+            self.local.add(prefix, 1);
+        }
+        self.reads += 1;
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        let mut shared = self.shared.lock();
+        shared.reads += self.reads;
+        shared.sketch.merge(&self.local);
+        drop(shared);
+        self.reads = 0;
+        self.local = SpaceSaving::new(self.local.capacity);
+        Ok(())
+    }
+}
+
+/// Finds the overrepresented sequences of a (possibly compressed) FASTA/FASTQ file
+pub fn overrepresented_qc_parallel<P: AsRef<Path>>(
+    path: P,
+    num_threads: usize,
+) -> Result<OverrepresentedReport> {
+    let qc = OverrepresentedQc::new();
+    FastxReader::from_path(path)?.process_parallel(qc.clone(), num_threads)?;
+    Ok(qc.report())
+}
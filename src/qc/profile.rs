@@ -0,0 +1,184 @@
+//! Per-position quality and base composition
+//!
+//! [`ProfileQc`] counts the quality scores and the bases at every read
+//! position, as FastQC's "per base sequence quality" and "per base sequence
+//! content" modules do. Reads of any length are counted at the positions
+//! they reach, so every fraction and quantile of a position is relative to
+//! the reads long enough to have it; [`ProfileReport::is_fixed_length`]
+//! tells whether that matters.
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::QualityTable;
+use crate::fastx::FastxReader;
+use crate::quality::QualityEncoding;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Bases told apart in the composition, anything else counts as `N`
+pub const BASES: [u8; 5] = *b"ACGTN";
+
+fn base_index(base: u8) -> usize {
+    match base.to_ascii_uppercase() {
+        b'A' => 0,
+        b'C' => 1,
+        b'G' => 2,
+        b'T' => 3,
+        _ => 4,
+    }
+}
+
+/// Base composition of one read position
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionComposition {
+    /// Position in the read, from 0
+    pub position: usize,
+    /// Number of reads reaching the position
+    pub count: u64,
+    /// Fraction of each of [`BASES`]
+    pub fractions: [f64; 5],
+    /// Fraction of `G` and `C` among the `A`, `C`, `G` and `T` bases
+    pub gc: f64,
+}
+
+/// Per-position qualities and bases of a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileReport {
+    pub reads: u64,
+    pub min_len: usize,
+    pub max_len: usize,
+    /// Qualities of the FASTQ reads
+    pub qualities: QualityTable,
+    /// Number of each of [`BASES`], per position
+    pub bases: Vec<[u64; 5]>,
+}
+
+impl ProfileReport {
+    /// Whether all reads have the same length
+    pub fn is_fixed_length(&self) -> bool {
+        self.min_len == self.max_len
+    }
+
+    /// Base composition at `position`
+    pub fn composition(&self, position: usize) -> Option<PositionComposition> {
+        let counts = self.bases.get(position)?;
+        let count: u64 = counts.iter().sum();
+        let mut fractions = [0.0; 5];
+        if count > 0 {
+            for (fraction, &n) in fractions.iter_mut().zip(counts) {
+                *fraction = n as f64 / count as f64;
+            }
+        }
+        let called = count - counts[4];
+        let gc = if called > 0 {
+            (counts[1] + counts[2]) as f64 / called as f64
+        } else {
+            0.0
+        };
+        Some(PositionComposition {
+            position,
+            count,
+            fractions,
+            gc,
+        })
+    }
+
+    /// Base composition at all positions
+    pub fn compositions(&self) -> Vec<PositionComposition> {
+        (0..self.bases.len())
+            .filter_map(|position| self.composition(position))
+            .collect()
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        if other.reads == 0 {
+            return;
+        }
+        if self.reads == 0 {
+            self.min_len = other.min_len;
+        }
+        self.min_len = self.min_len.min(other.min_len);
+        self.max_len = self.max_len.max(other.max_len);
+        self.reads += other.reads;
+        self.qualities.merge(&other.qualities);
+        if other.bases.len() > self.bases.len() {
+            self.bases.resize(other.bases.len(), [0; 5]);
+        }
+        for (counts, other) in self.bases.iter_mut().zip(&other.bases) {
+            for (count, other) in counts.iter_mut().zip(other) {
+                *count += other;
+            }
+        }
+    }
+
+    fn add<'a, Rf: MinimalRefRecord<'a>>(&mut self, record: &Rf, encoding: QualityEncoding) {
+        let seq = record.ref_full_seq();
+        if self.reads == 0 {
+            self.min_len = seq.len();
+        }
+        self.min_len = self.min_len.min(seq.len());
+        self.max_len = self.max_len.max(seq.len());
+        self.reads += 1;
+        if seq.len() > self.bases.len() {
+            self.bases.resize(seq.len(), [0; 5]);
+        }
+        for (counts, &base) in self.bases.iter_mut().zip(seq.iter()) {
+            counts[base_index(base)] += 1;
+        }
+        self.qualities.add(record.ref_qual_scores_with(encoding));
+    }
+}
+
+/// Processor collecting a [`ProfileReport`], see the [module docs](self)
+///
+/// Clones share the report; read it with [`report`](Self::report) after the
+/// run.
+#[derive(Clone, Default)]
+pub struct ProfileQc {
+    encoding: QualityEncoding,
+    local: ProfileReport,
+    shared: Arc<Mutex<ProfileReport>>,
+}
+
+impl ProfileQc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the encoding of the quality characters, Phred+33 by default
+    pub fn with_encoding(mut self, encoding: QualityEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Report merged from the threads completed so far
+    pub fn report(&self) -> ProfileReport {
+        self.shared.lock().clone()
+    }
+}
+
+impl ParallelProcessor for ProfileQc {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        self.local.add(&record, self.encoding);
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.shared.lock().merge(&self.local);
+        self.local = ProfileReport::default();
+        Ok(())
+    }
+}
+
+/// Collects the per-position qualities and bases of a (possibly compressed) FASTA/FASTQ file
+pub fn profile_qc_parallel<P: AsRef<Path>>(path: P, num_threads: usize) -> Result<ProfileReport> {
+    let qc = ProfileQc::new();
+    FastxReader::from_path(path)?.process_parallel(qc.clone(), num_threads)?;
+    Ok(qc.report())
+}
@@ -0,0 +1,209 @@
+//! Per-lane and per-tile statistics of Illumina reads
+//!
+//! [`TileQc`] parses every header with
+//! [`parse_illumina`](crate::header::parse_illumina) and counts reads, bases
+//! and quality scores per lane and per tile. Lanes keep a full
+//! [`QualityTable`]; tiles, of which a flowcell has thousands, keep the mean
+//! score per position, enough to spot the tiles that fall behind the rest as
+//! FastQC's "per tile sequence quality" does. Reads without an Illumina
+//! header only count towards the overall table.
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::QualityTable;
+use crate::fastx::FastxReader;
+use crate::header::parse_illumina;
+use crate::quality::QualityEncoding;
+use crate::{MinimalRefRecord, ParallelProcessor, ParallelReader};
+
+/// Counts of the reads of one lane
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LaneStats {
+    pub reads: u64,
+    /// Reads that failed the chastity filter, as flagged in CASAVA 1.8+ headers
+    pub filtered: u64,
+    pub bases: u64,
+    pub qualities: QualityTable,
+}
+
+impl LaneStats {
+    fn merge(&mut self, other: &Self) {
+        self.reads += other.reads;
+        self.filtered += other.filtered;
+        self.bases += other.bases;
+        self.qualities.merge(&other.qualities);
+    }
+}
+
+/// Counts of the reads of one tile
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TileStats {
+    pub reads: u64,
+    pub bases: u64,
+    /// Sum of the scores at every position
+    pub score_sums: Vec<u64>,
+    /// Number of scores at every position
+    pub score_counts: Vec<u64>,
+}
+
+impl TileStats {
+    /// Mean score over all bases of the tile
+    pub fn mean_quality(&self) -> Option<f64> {
+        let count: u64 = self.score_counts.iter().sum();
+        (count > 0).then(|| self.score_sums.iter().sum::<u64>() as f64 / count as f64)
+    }
+
+    /// Mean score at every position
+    pub fn position_means(&self) -> Vec<f64> {
+        self.score_sums
+            .iter()
+            .zip(&self.score_counts)
+            .map(|(&sum, &count)| if count > 0 { sum as f64 / count as f64 } else { 0.0 })
+            .collect()
+    }
+
+    fn add<I: IntoIterator<Item = u8>>(&mut self, scores: I) {
+        for (position, score) in scores.into_iter().enumerate() {
+            if position == self.score_sums.len() {
+                self.score_sums.push(0);
+                self.score_counts.push(0);
+            }
+            self.score_sums[position] += u64::from(score);
+            self.score_counts[position] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.reads += other.reads;
+        self.bases += other.bases;
+        let len = self.score_sums.len().max(other.score_sums.len());
+        self.score_sums.resize(len, 0);
+        self.score_counts.resize(len, 0);
+        for (sum, other) in self.score_sums.iter_mut().zip(&other.score_sums) {
+            *sum += other;
+        }
+        for (count, other) in self.score_counts.iter_mut().zip(&other.score_counts) {
+            *count += other;
+        }
+    }
+}
+
+/// Lane and tile statistics of a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TileReport {
+    pub lanes: BTreeMap<u32, LaneStats>,
+    /// Statistics per `(lane, tile)`
+    pub tiles: BTreeMap<(u32, u32), TileStats>,
+    /// Reads whose header is not an Illumina header
+    pub unparsed: u64,
+    /// Qualities of all reads
+    pub qualities: QualityTable,
+}
+
+impl TileReport {
+    pub fn merge(&mut self, other: &Self) {
+        for (lane, stats) in &other.lanes {
+            self.lanes.entry(*lane).or_default().merge(stats);
+        }
+        for (tile, stats) in &other.tiles {
+            self.tiles.entry(*tile).or_default().merge(stats);
+        }
+        self.unparsed += other.unparsed;
+        self.qualities.merge(&other.qualities);
+    }
+
+    /// Mean score of a tile at every position minus the mean of all tiles there
+    ///
+    /// Negative values mark positions where the tile is worse than average,
+    /// as in FastQC's per-tile heatmap.
+    pub fn tile_deviations(&self, lane: u32, tile: u32) -> Option<Vec<f64>> {
+        let stats = self.tiles.get(&(lane, tile))?;
+        let mut all = TileStats::default();
+        for stats in self.tiles.values() {
+            all.merge(stats);
+        }
+        let all = all.position_means();
+        Some(
+            stats
+                .position_means()
+                .iter()
+                .zip(&all)
+                .map(|(mean, all)| mean - all)
+                .collect(),
+        )
+    }
+}
+
+/// Processor collecting a [`TileReport`], see the [module docs](self)
+///
+/// Clones share the report; read it with [`report`](Self::report) after the
+/// run.
+#[derive(Clone, Default)]
+pub struct TileQc {
+    encoding: QualityEncoding,
+    local: TileReport,
+    shared: Arc<Mutex<TileReport>>,
+}
+
+impl TileQc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the encoding of the quality characters, Phred+33 by default
+    pub fn with_encoding(mut self, encoding: QualityEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Report merged from the threads completed so far
+    pub fn report(&self) -> TileReport {
+        self.shared.lock().clone()
+    }
+}
+
+impl ParallelProcessor for TileQc {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        _record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        let bases = record.ref_full_seq().len() as u64;
+        let scores = record.ref_qual_scores_with(self.encoding);
+        let report = &mut self.local;
+        report.qualities.add(scores.clone());
+        let Some(header) = parse_illumina(record.ref_head()) else {
+            report.unparsed += 1;
+            return Ok(());
+        };
+        let lane = report.lanes.entry(header.lane).or_default();
+        lane.reads += 1;
+        lane.bases += bases;
+        if header.filtered == Some(true) {
+            lane.filtered += 1;
+        }
+        lane.qualities.add(scores.clone());
+        let tile = report.tiles.entry((header.lane, header.tile)).or_default();
+        tile.reads += 1;
+        tile.bases += bases;
+        tile.add(scores);
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.shared.lock().merge(&self.local);
+        self.local = TileReport::default();
+        Ok(())
+    }
+}
+
+/// Collects the lane and tile statistics of a (possibly compressed) FASTQ file
+pub fn tile_qc_parallel<P: AsRef<Path>>(path: P, num_threads: usize) -> Result<TileReport> {
+    let qc = TileQc::new();
+    FastxReader::from_path(path)?.process_parallel(qc.clone(), num_threads)?;
+    Ok(qc.report())
+}
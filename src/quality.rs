@@ -0,0 +1,80 @@
+//! Decoding of FASTQ quality characters into Phred scores
+use std::iter::FusedIterator;
+use std::slice;
+
+/// Offset of the quality characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QualityEncoding {
+    /// Sanger and Illumina 1.8+, `!` is score 0
+    #[default]
+    Phred33,
+    /// Illumina 1.3 to 1.7, `@` is score 0
+    Phred64,
+}
+
+impl QualityEncoding {
+    pub fn offset(self) -> u8 {
+        match self {
+            Self::Phred33 => 33,
+            Self::Phred64 => 64,
+        }
+    }
+
+    /// Guesses the encoding from a sample of quality strings
+    ///
+    /// Characters below `;` only occur in Phred+33, while Phred+64 files use
+    /// no characters below `@`. Returns `None` if the sample fits both.
+    pub fn detect<'q, I: IntoIterator<Item = &'q [u8]>>(quals: I) -> Option<Self> {
+        let min = quals.into_iter().flatten().copied().min()?;
+        match min {
+            0..=58 => Some(Self::Phred33),
+            64.. => Some(Self::Phred64),
+            _ => None,
+        }
+    }
+}
+
+/// Iterator over the Phred scores of a quality string
+///
+/// Characters below the encoding offset decode to 0.
+#[derive(Debug, Clone)]
+pub struct QualScores<'q> {
+    quals: slice::Iter<'q, u8>,
+    offset: u8,
+}
+
+impl<'q> QualScores<'q> {
+    pub fn new(quals: &'q [u8], encoding: QualityEncoding) -> Self {
+        Self {
+            quals: quals.iter(),
+            offset: encoding.offset(),
+        }
+    }
+}
+
+impl Iterator for QualScores<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        self.quals.next().map(|q| q.saturating_sub(self.offset))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.quals.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for QualScores<'_> {
+    fn next_back(&mut self) -> Option<u8> {
+        self.quals.next_back().map(|q| q.saturating_sub(self.offset))
+    }
+}
+
+impl ExactSizeIterator for QualScores<'_> {}
+
+impl FusedIterator for QualScores<'_> {}
+
+/// Probability that a base call with the given Phred score is wrong
+pub fn error_probability(score: u8) -> f64 {
+    10f64.powf(-(score as f64) / 10.0)
+}
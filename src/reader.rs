@@ -1,15 +1,120 @@
 use anyhow::Result;
 use seq_io::policy;
 use std::io;
+use std::sync::Arc;
 
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+#[cfg(feature = "parallel")]
+use crate::handle::RunHandle;
+use crate::processor::{Borrowed, PairedParallelProcessor, Shared, SharedParallelProcessor};
+use crate::stats::RunStats;
 use crate::ParallelProcessor;
 
-pub trait ParallelReader<R, P>
+pub trait ParallelReader<R, P>: Sized
 where
     R: io::Read + Send,
     P: policy::BufPolicy + Send,
 {
     fn process_parallel<T>(self, processor: T, num_threads: usize) -> Result<()>
     where
+        T: ParallelProcessor,
+    {
+        self.process_parallel_with_config(processor, ParallelConfig::new(num_threads))
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel` but with one processor shared by all threads
+    fn process_parallel_shared<T>(self, processor: Arc<T>, num_threads: usize) -> Result<()>
+    where
+        T: SharedParallelProcessor,
+    {
+        self.process_parallel(Shared::new(processor), num_threads)
+    }
+
+    /// Same as `process_parallel_shared` but with a processor borrowed for the duration of the run
+    fn process_parallel_borrowed<T>(self, processor: &T, num_threads: usize) -> Result<()>
+    where
+        T: SharedParallelProcessor,
+    {
+        self.process_parallel(Borrowed::new(processor), num_threads)
+    }
+
+    /// Same as `process_parallel` but with an explicit channel backend
+    fn process_parallel_with_dispatcher<D, T>(self, processor: T, num_threads: usize) -> Result<()>
+    where
+        D: Dispatcher,
+        T: ParallelProcessor,
+    {
+        let config = ParallelConfig::new(num_threads).with_dispatcher::<D>();
+        self.process_parallel_with_config(processor, config)
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel` but with a full run configuration
+    fn process_parallel_with_config<D, T>(self, processor: T, config: ParallelConfig<D>) -> Result<RunStats>
+    where
+        D: Dispatcher,
         T: ParallelProcessor;
+
+    /// Starts `process_parallel_with_config` on a new thread and returns a handle to the run
+    #[cfg(feature = "parallel")]
+    fn spawn_process_parallel<D, T>(self, processor: T, config: ParallelConfig<D>) -> Result<RunHandle>
+    where
+        Self: Send + 'static,
+        D: Dispatcher + Send + 'static,
+        T: ParallelProcessor + 'static,
+    {
+        RunHandle::spawn(config, move |config| {
+            self.process_parallel_with_config(processor, config)
+        })
+    }
+}
+
+pub trait PairedParallelReader: Sized {
+    fn process_parallel_paired<T>(self, processor: T, num_threads: usize) -> Result<()>
+    where
+        T: PairedParallelProcessor,
+    {
+        self.process_parallel_paired_with_config(processor, ParallelConfig::new(num_threads))
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel_paired` but with an explicit channel backend
+    fn process_parallel_paired_with_dispatcher<D, T>(
+        self,
+        processor: T,
+        num_threads: usize,
+    ) -> Result<()>
+    where
+        D: Dispatcher,
+        T: PairedParallelProcessor,
+    {
+        let config = ParallelConfig::new(num_threads).with_dispatcher::<D>();
+        self.process_parallel_paired_with_config(processor, config)
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel_paired` but with a full run configuration
+    fn process_parallel_paired_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: PairedParallelProcessor;
+
+    /// Starts `process_parallel_paired_with_config` on a new thread and returns a handle to the run
+    #[cfg(feature = "parallel")]
+    fn spawn_process_parallel_paired<D, T>(self, processor: T, config: ParallelConfig<D>) -> Result<RunHandle>
+    where
+        Self: Send + 'static,
+        D: Dispatcher + Send + 'static,
+        T: PairedParallelProcessor + 'static,
+    {
+        RunHandle::spawn(config, move |config| {
+            self.process_parallel_paired_with_config(processor, config)
+        })
+    }
 }
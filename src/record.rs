@@ -1,5 +1,10 @@
+use anyhow::Result;
 use std::borrow::Cow;
 
+use crate::processor::apply_error_action;
+use crate::quality::{QualScores, QualityEncoding};
+use crate::ParallelProcessor;
+
 pub trait MinimalRefRecord<'a> {
     fn ref_id(&self) -> Result<&str, std::str::Utf8Error>;
 
@@ -10,6 +15,67 @@ pub trait MinimalRefRecord<'a> {
     fn ref_full_seq(&self) -> Cow<[u8]>;
 
     fn ref_qual(&self) -> &[u8];
+
+    /// Bases `start..end` of the sequence, or `None` if out of bounds
+    ///
+    /// Coordinates ignore the line breaks of wrapped FASTA. The slice is
+    /// borrowed unless the range spans several sequence lines.
+    fn ref_seq_range(&self, start: usize, end: usize) -> Option<Cow<[u8]>> {
+        if start > end {
+            return None;
+        }
+        let seq = self.ref_seq();
+        if memchr::memchr(b'\n', seq).is_none() {
+            return seq.get(start..end).map(Cow::Borrowed);
+        }
+
+        let mut line_start = 0;
+        let mut joined = Vec::new();
+        for line in seq.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let line_end = line_start + line.len();
+            if start >= line_start && end <= line_end {
+                return Some(Cow::Borrowed(&line[start - line_start..end - line_start]));
+            }
+            if start < line_end && end > line_start {
+                let from = start.max(line_start) - line_start;
+                let to = end.min(line_end) - line_start;
+                joined.extend_from_slice(&line[from..to]);
+                if end <= line_end {
+                    return Some(Cow::Owned(joined));
+                }
+            }
+            line_start = line_end;
+        }
+        None
+    }
+
+    /// Qualities `start..end`, or `None` if out of bounds
+    fn ref_qual_range(&self, start: usize, end: usize) -> Option<&[u8]> {
+        self.ref_qual().get(start..end)
+    }
+
+    /// Phred scores of the qualities, assuming the Phred+33 encoding
+    fn ref_qual_scores(&self) -> QualScores<'_> {
+        QualScores::new(self.ref_qual(), QualityEncoding::Phred33)
+    }
+
+    /// Phred scores of the qualities in the given encoding
+    fn ref_qual_scores_with(&self, encoding: QualityEncoding) -> QualScores<'_> {
+        QualScores::new(self.ref_qual(), encoding)
+    }
+
+    /// Concrete view of the record, as passed to object-safe processors
+    ///
+    /// The default treats records with qualities or an empty sequence as FASTQ.
+    fn to_record(&self) -> Record<'_> {
+        let (head, seq, qual) = (self.ref_head(), self.ref_seq(), self.ref_qual());
+        if qual.is_empty() && !seq.is_empty() {
+            Record::Fasta { head, seq }
+        } else {
+            Record::Fastq { head, seq, qual }
+        }
+    }
 }
 
 impl MinimalRefRecord<'_> for seq_io::fastq::RefRecord<'_> {
@@ -33,6 +99,14 @@ impl MinimalRefRecord<'_> for seq_io::fastq::RefRecord<'_> {
     fn ref_qual(&self) -> &[u8] {
         <Self as seq_io::fastq::Record>::qual(self)
     }
+
+    fn to_record(&self) -> Record<'_> {
+        Record::Fastq {
+            head: self.ref_head(),
+            seq: self.ref_seq(),
+            qual: self.ref_qual(),
+        }
+    }
 }
 
 impl MinimalRefRecord<'_> for seq_io::fasta::RefRecord<'_> {
@@ -56,4 +130,212 @@ impl MinimalRefRecord<'_> for seq_io::fasta::RefRecord<'_> {
     fn ref_qual(&self) -> &[u8] {
         &[]
     }
+
+    fn to_record(&self) -> Record<'_> {
+        Record::Fasta {
+            head: self.ref_head(),
+            seq: self.ref_seq(),
+        }
+    }
+}
+
+impl MinimalRefRecord<'_> for seq_io::fastq::OwnedRecord {
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        <Self as seq_io::fastq::Record>::id(self)
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        &self.head
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.seq)
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        &self.qual
+    }
+
+    fn to_record(&self) -> Record<'_> {
+        Record::Fastq {
+            head: &self.head,
+            seq: &self.seq,
+            qual: &self.qual,
+        }
+    }
+}
+
+/// Owned FASTA records hold their sequence without line breaks
+impl MinimalRefRecord<'_> for seq_io::fasta::OwnedRecord {
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        <Self as seq_io::fasta::Record>::id(self)
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        &self.head
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.seq)
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        &[]
+    }
+
+    fn to_record(&self) -> Record<'_> {
+        Record::Fasta {
+            head: &self.head,
+            seq: &self.seq,
+        }
+    }
+}
+
+/// Record borrowing its fields from a batch buffer
+///
+/// Used by batches that are not parsed by seq_io on the worker. The sequence
+/// holds no line breaks and the quality is empty for FASTA records.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceRecord<'a> {
+    pub head: &'a [u8],
+    pub seq: &'a [u8],
+    pub qual: &'a [u8],
+}
+
+impl MinimalRefRecord<'_> for SliceRecord<'_> {
+
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        let id = self.head.split(|&b| b == b' ').next().unwrap_or_default();
+        std::str::from_utf8(id)
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        self.head
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        self.seq
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.seq)
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        self.qual
+    }
+}
+
+/// Record of either format with borrowed fields
+///
+/// FASTA sequences keep their line breaks, as with `ref_seq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record<'r> {
+    Fasta { head: &'r [u8], seq: &'r [u8] },
+    Fastq { head: &'r [u8], seq: &'r [u8], qual: &'r [u8] },
+}
+
+impl MinimalRefRecord<'_> for Record<'_> {
+
+    fn ref_id(&self) -> Result<&str, std::str::Utf8Error> {
+        let id = self.ref_head().split(|&b| b == b' ').next().unwrap_or_default();
+        std::str::from_utf8(id)
+    }
+
+    fn ref_head(&self) -> &[u8] {
+        match self {
+            Self::Fasta { head, .. } | Self::Fastq { head, .. } => head,
+        }
+    }
+
+    fn ref_seq(&self) -> &[u8] {
+        match self {
+            Self::Fasta { seq, .. } | Self::Fastq { seq, .. } => seq,
+        }
+    }
+
+    fn ref_full_seq(&self) -> Cow<[u8]> {
+        let seq = self.ref_seq();
+        if memchr::memchr(b'\n', seq).is_none() {
+            return Cow::Borrowed(seq);
+        }
+        Cow::Owned(seq.iter().copied().filter(|&b| b != b'\n' && b != b'\r').collect())
+    }
+
+    fn ref_qual(&self) -> &[u8] {
+        match self {
+            Self::Fasta { .. } => &[],
+            Self::Fastq { qual, .. } => qual,
+        }
+    }
+
+    fn to_record(&self) -> Record<'_> {
+        *self
+    }
+}
+
+/// Batch of records that can be iterated, independent of the parser that filled it
+///
+/// Implemented for the seq_io record sets, so code that consumes batches
+/// (workers, writers, custom sources) is written once for all of them.
+pub trait RecordSetLike {
+    type Record<'a>: MinimalRefRecord<'a>
+    where
+        Self: 'a;
+
+    type Iter<'a>: Iterator<Item = Self::Record<'a>>
+    where
+        Self: 'a;
+
+    fn records(&self) -> Self::Iter<'_>;
+
+    /// Number of records in the set
+    fn len(&self) -> usize {
+        self.records().count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records().next().is_none()
+    }
+
+    /// Hands every record of the set to the processor
+    fn process<T: ParallelProcessor>(
+        &self,
+        processor: &mut T,
+        record_set_idx: usize,
+    ) -> Result<()> {
+        for (record_idx, record) in self.records().enumerate() {
+            if let Err(err) = processor.process_record(record, record_set_idx, record_idx) {
+                let action = processor.on_record_error(&err, record_idx);
+                apply_error_action(err, action)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RecordSetLike for seq_io::fasta::RecordSet {
+    type Record<'a> = seq_io::fasta::RefRecord<'a>;
+    type Iter<'a> = seq_io::fasta::RecordSetIter<'a>;
+
+    fn records(&self) -> Self::Iter<'_> {
+        self.into_iter()
+    }
+}
+
+impl RecordSetLike for seq_io::fastq::RecordSet {
+    type Record<'a> = seq_io::fastq::RefRecord<'a>;
+    type Iter<'a> = seq_io::fastq::RecordSetIter<'a>;
+
+    fn records(&self) -> Self::Iter<'_> {
+        self.into_iter()
+    }
 }
@@ -0,0 +1,346 @@
+//! Region queries on indexed FASTA files
+//!
+//! Extracts `chr:start-end` regions like `samtools faidx`, using the line
+//! layout recorded in the `.fai` to seek straight to the bytes of a region.
+//! [`process_regions`] spreads the regions over the worker pool, each worker
+//! reading through its own handle on the file, so thousands of regions are
+//! extracted in parallel. Regions are handed to the processor as FASTA
+//! records named after the region, in batches that follow the order of the
+//! region list.
+use anyhow::{anyhow, bail, Context, Result};
+use seq_io::fasta;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::batch::BatchSize;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::macro_impl::{run_parallel, FetchWorker};
+use crate::processor::apply_error_action;
+use crate::record::Record;
+use crate::stats::RunStats;
+use crate::writer::ParallelWriter;
+use crate::{MinimalRefRecord, ParallelProcessor};
+
+/// Number of regions extracted per batch
+pub const REGION_BATCH_SIZE: usize = 64;
+
+/// Part of a sequence, with 0-based half-open coordinates
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub name: String,
+    pub start: u64,
+    /// `None` to extract up to the end of the sequence
+    pub end: Option<u64>,
+}
+
+impl Region {
+    pub fn new(name: impl Into<String>, start: u64, end: Option<u64>) -> Self {
+        Self {
+            name: name.into(),
+            start,
+            end,
+        }
+    }
+
+    /// Whole sequence
+    pub fn sequence(name: impl Into<String>) -> Self {
+        Self::new(name, 0, None)
+    }
+}
+
+/// Parses the samtools syntax: `chr`, `chr:start` or `chr:start-end`,
+/// 1-based and inclusive, with optional thousands separators
+impl FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, range)) = s.rsplit_once(':') else {
+            return Ok(Self::sequence(s));
+        };
+        let range = range.replace(',', "");
+        let parse = |pos: &str| {
+            pos.parse::<u64>()
+                .map_err(|_| anyhow!("Invalid region {}", s))
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse(start)?, Some(parse(end)?)),
+            None => (parse(&range)?, None),
+        };
+        if start == 0 || end.is_some_and(|end| end < start) {
+            bail!("Invalid region {}", s);
+        }
+        Ok(Self::new(name, start - 1, end))
+    }
+}
+
+/// Formats the region back in the samtools syntax, as used for record names
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}:{}-{}", self.name, self.start + 1, end),
+            None if self.start > 0 => write!(f, "{}:{}", self.name, self.start + 1),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// Entry of a `.fai` index
+#[derive(Debug, Clone)]
+struct FaiEntry {
+    length: u64,
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+}
+
+impl FaiEntry {
+    /// Byte offset of the base at 0-based `pos`
+    fn byte(&self, pos: u64) -> u64 {
+        self.offset + pos / self.line_bases * self.line_width + pos % self.line_bases
+    }
+}
+
+/// `samtools faidx` index of a FASTA file
+#[derive(Debug, Clone)]
+pub struct FaiIndex {
+    entries: HashMap<String, FaiEntry>,
+}
+
+impl FaiIndex {
+    /// Reads the `.fai` at `path`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read index {}", path.display()))?;
+        let mut entries = HashMap::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let numbers: Option<Vec<u64>> = fields[1..].iter().map(|f| f.parse().ok()).collect();
+            match (fields.len(), numbers.as_deref()) {
+                (5, Some(&[length, offset, line_bases, line_width])) => {
+                    entries.insert(
+                        fields[0].to_string(),
+                        FaiEntry {
+                            length,
+                            offset,
+                            line_bases,
+                            line_width,
+                        },
+                    );
+                }
+                _ => bail!("Malformed line in FASTA index {}: {}", path.display(), line),
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    /// Reads the index of the FASTA file at `path`, `<path>.fai`
+    pub fn for_fasta<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut fai = PathBuf::from(path.as_ref()).into_os_string();
+        fai.push(".fai");
+        Self::from_path(fai)
+    }
+
+    /// Length of the named sequence
+    pub fn sequence_length(&self, name: &str) -> Option<u64> {
+        self.entries.get(name).map(|entry| entry.length)
+    }
+
+    /// Reads the bases of `region` into `seq`, clipping it to the sequence
+    pub(crate) fn read_region<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        region: &Region,
+        seq: &mut Vec<u8>,
+    ) -> Result<()> {
+        let entry = self
+            .entries
+            .get(&region.name)
+            .ok_or_else(|| anyhow!("Sequence {} is not in the index", region.name))?;
+        seq.clear();
+        let end = region.end.unwrap_or(entry.length).min(entry.length);
+        if region.start >= end {
+            return Ok(());
+        }
+        let first = entry.byte(region.start);
+        let last = entry.byte(end - 1) + 1;
+        seq.resize((last - first) as usize, 0);
+        reader.seek(SeekFrom::Start(first))?;
+        reader.read_exact(seq)?;
+        seq.retain(|&b| b != b'\n' && b != b'\r');
+        Ok(())
+    }
+}
+
+/// Indices into the region list of the regions of a batch
+#[derive(Debug, Default)]
+struct RegionBatch {
+    regions: Range<usize>,
+}
+
+impl BatchSize for RegionBatch {
+    fn num_records(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Not known before the regions are read
+    fn num_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Hands every region of the FASTA file at `path` to the processor
+///
+/// Region `i` is record `i % REGION_BATCH_SIZE` of batch `i / REGION_BATCH_SIZE`.
+/// Regions reaching past the end of their sequence are clipped; unknown
+/// sequence names fail the run.
+pub fn process_regions<P, T, D>(
+    path: P,
+    index: &FaiIndex,
+    regions: &[Region],
+    processor: T,
+    config: ParallelConfig<D>,
+) -> Result<RunStats>
+where
+    P: AsRef<Path>,
+    T: ParallelProcessor,
+    D: Dispatcher,
+{
+    let path = path.as_ref();
+    run_parallel::<D, _, RegionBatch, _, _, _>(
+        0,
+        FetchWorker::new(processor),
+        &config,
+        |next, batch| {
+            if *next >= regions.len() {
+                return None;
+            }
+            let end = (*next + REGION_BATCH_SIZE).min(regions.len());
+            batch.regions = *next..end;
+            *next = end;
+            Some(Ok(()))
+        },
+        |batch, worker, global_idx| {
+            let file = match &mut worker.file {
+                Some(file) => file,
+                None => worker.file.insert(File::open(path)?),
+            };
+            let mut seq = Vec::new();
+            for (record_idx, region) in regions[batch.regions.clone()].iter().enumerate() {
+                index.read_region(file, region, &mut seq)?;
+                let head = region.to_string();
+                let record = Record::Fasta {
+                    head: head.as_bytes(),
+                    seq: &seq,
+                };
+                if let Err(err) = worker
+                    .processor
+                    .process_record(record, global_idx, record_idx)
+                {
+                    let action = worker.processor.on_record_error(&err, record_idx);
+                    apply_error_action(err, action)?;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Extracts the regions of the FASTA file at `path`, in the order of `regions`
+pub fn fetch_regions<P: AsRef<Path>>(
+    path: P,
+    index: &FaiIndex,
+    regions: &[Region],
+    num_threads: usize,
+) -> Result<Vec<fasta::OwnedRecord>> {
+    let records = Arc::new(Mutex::new(vec![None; regions.len()]));
+    let collector = RegionCollector {
+        records: records.clone(),
+    };
+    process_regions(
+        path,
+        index,
+        regions,
+        collector,
+        ParallelConfig::new(num_threads),
+    )?;
+    let records = std::mem::take(&mut *records.lock());
+    Ok(records.into_iter().flatten().collect())
+}
+
+/// Writes the regions of the FASTA file at `path` to `out`, in the order of `regions`
+///
+/// `out` is not finished, so more can be written to it afterwards.
+pub fn write_regions<P: AsRef<Path>>(
+    path: P,
+    index: &FaiIndex,
+    regions: &[Region],
+    num_threads: usize,
+    out: &ParallelWriter,
+) -> Result<()> {
+    let writer = RegionWriter { out: out.clone() };
+    process_regions(
+        path,
+        index,
+        regions,
+        writer,
+        ParallelConfig::new(num_threads),
+    )?;
+    Ok(())
+}
+
+/// Stores every region at its position in the region list
+#[derive(Clone)]
+struct RegionCollector {
+    records: Arc<Mutex<Vec<Option<fasta::OwnedRecord>>>>,
+}
+
+impl ParallelProcessor for RegionCollector {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<()> {
+        let record = fasta::OwnedRecord {
+            head: record.ref_head().to_vec(),
+            seq: record.ref_seq().to_vec(),
+        };
+        self.records.lock()[record_set_idx * REGION_BATCH_SIZE + record_idx] = Some(record);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct RegionWriter {
+    out: ParallelWriter,
+}
+
+impl ParallelProcessor for RegionWriter {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<()> {
+        self.out.write_record(record_set_idx, &record)
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.out.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.out.discard_batch();
+        Ok(())
+    }
+}
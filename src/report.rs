@@ -0,0 +1,141 @@
+//! Machine-readable JSON report of a run
+//!
+//! Written at the end of a run (successful or not) when requested with
+//! [`ParallelConfig::with_report`](crate::ParallelConfig::with_report), for
+//! provenance tracking in pipeline frameworks.
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::stats::RunStats;
+
+/// Where to write the report and which inputs to list in it
+#[derive(Debug, Clone, Default)]
+pub struct ReportOptions {
+    pub path: PathBuf,
+    pub inputs: Vec<String>,
+}
+
+/// Escapes a string for inclusion in a JSON document
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Renders the report of a run as JSON
+pub fn render_report(inputs: &[String], stats: &RunStats, error: Option<&str>) -> String {
+    let finished_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let inputs = inputs
+        .iter()
+        .map(|input| json_string(input))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{{");
+    let _ = writeln!(out, "  \"tool\": {},", json_string(env!("CARGO_PKG_NAME")));
+    let _ = writeln!(out, "  \"version\": {},", json_string(env!("CARGO_PKG_VERSION")));
+    let _ = writeln!(out, "  \"status\": {},", json_string(if error.is_some() { "failed" } else { "ok" }));
+    if let Some(error) = error {
+        let _ = writeln!(out, "  \"error\": {},", json_string(error));
+    }
+    let _ = writeln!(out, "  \"inputs\": [{}],", inputs);
+    let _ = writeln!(out, "  \"num_threads\": {},", stats.num_threads);
+    let _ = writeln!(out, "  \"records\": {},", stats.records);
+    let _ = writeln!(out, "  \"bytes\": {},", stats.bytes);
+    if stats.input_bytes_total > 0 {
+        let _ = writeln!(out, "  \"input_bytes_read\": {},", stats.input_bytes_read);
+        let _ = writeln!(out, "  \"input_bytes_total\": {},", stats.input_bytes_total);
+    }
+    if stats.input_bytes_decompressed > 0 {
+        let _ = writeln!(out, "  \"input_bytes_decompressed\": {},", stats.input_bytes_decompressed);
+    }
+    let _ = writeln!(out, "  \"batches\": {},", stats.batches);
+    let _ = writeln!(out, "  \"dropped_records\": {},", stats.dropped_records);
+    let _ = writeln!(out, "  \"dropped_batches\": {},", stats.dropped_batches);
+    let _ = writeln!(out, "  \"retried_batches\": {},", stats.retried_batches);
+    let _ = writeln!(out, "  \"errors\": {},", stats.errors);
+    let error_messages = stats
+        .error_messages
+        .iter()
+        .map(|message| json_string(message))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let _ = writeln!(out, "  \"error_messages\": [{}],", error_messages);
+    if !stats.batch_checksums.is_empty() {
+        let batch_checksums = stats
+            .batch_checksums
+            .iter()
+            .map(|checksum| format!("\"{:08x}\"", checksum))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = writeln!(out, "  \"batch_checksums\": [{}],", batch_checksums);
+    }
+    #[cfg(feature = "checksum")]
+    {
+        let checksums = stats
+            .checksums
+            .iter()
+            .map(|checksum| {
+                format!(
+                    "{{\"input\": {}, \"algorithm\": {}, \"layer\": {}, \"value\": {}}}",
+                    json_string(&checksum.input),
+                    json_string(checksum.algorithm.name()),
+                    json_string(checksum.layer.name()),
+                    json_string(&checksum.value)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = writeln!(out, "  \"checksums\": [{}],", checksums);
+    }
+    let _ = writeln!(out, "  \"elapsed_secs\": {:.6},", stats.elapsed.as_secs_f64());
+    let _ = writeln!(out, "  \"records_per_sec\": {:.2},", stats.records_per_sec());
+    let workers = stats
+        .workers
+        .iter()
+        .map(|w| {
+            format!(
+                "{{\"thread_id\": {}, \"wait_secs\": {:.6}, \"busy_secs\": {:.6}}}",
+                w.thread_id,
+                w.wait.as_secs_f64(),
+                w.busy.as_secs_f64()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let _ = writeln!(out, "  \"busy_fraction\": {:.4},", stats.busy_fraction());
+    let _ = writeln!(out, "  \"workers\": [{}],", workers);
+    let _ = writeln!(out, "  \"finished_at_unix\": {}", finished_at);
+    let _ = writeln!(out, "}}");
+    out
+}
+
+/// Writes the report of a run to `path`
+pub(crate) fn write_report(
+    path: &Path,
+    inputs: &[String],
+    stats: &RunStats,
+    error: Option<&str>,
+) -> Result<()> {
+    std::fs::write(path, render_report(inputs, stats, error))
+        .with_context(|| format!("Failed to write run report to {}", path.display()))
+}
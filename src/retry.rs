@@ -0,0 +1,277 @@
+//! Retries of transient I/O errors
+//!
+//! Network and cluster file systems occasionally fail a read with an error
+//! that goes away when the read is repeated (an NFS server that is briefly
+//! unreachable, a timed-out socket). Such an error would otherwise fail the
+//! reader thread and with it the whole run. A [`RetryReader`] wraps the raw
+//! input, below decompression and parsing, and repeats failed reads with an
+//! exponential backoff. A failed read consumes no input, so a repeated read
+//! continues exactly where the failed one started.
+//!
+//! Processors calling flaky external services can fail a batch with a
+//! [`Retryable`] error instead. With
+//! [`ParallelConfig::with_batch_retry`](crate::ParallelConfig::with_batch_retry)
+//! the worker then processes the whole batch again after the backoff, and
+//! only fails the run once the retries are spent.
+use anyhow::Error;
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::macro_impl::HAS_CLOCK;
+
+/// Default number of consecutive failed reads retried before giving up
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Default delay before the first retry
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Default upper bound on the delay between retries
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How often and how patiently transient read errors are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retries up to `max_retries` consecutive failed reads with the default backoff
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..Self::default()
+        }
+    }
+
+    /// Waits `initial` before the first retry, doubling the delay up to `max` after each further failure
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Whether a read failing with `error` is worth repeating
+    ///
+    /// `Interrupted` reads are repeated right away and without limit, as
+    /// the standard library does; timeouts and `WouldBlock` count as
+    /// retries and are repeated after the backoff.
+    pub fn is_transient(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        )
+    }
+
+    pub(crate) fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Delay before retry number `attempt`, counting from 0
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Reader repeating reads that fail with a transient error, see [`RetryPolicy`]
+#[derive(Debug)]
+pub struct RetryReader<R> {
+    inner: R,
+    policy: RetryPolicy,
+}
+
+impl<R: io::Read> RetryReader<R> {
+    pub fn new(inner: R, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: io::Read> io::Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if RetryPolicy::is_transient(&e) && attempt < self.policy.max_retries => {
+                    if HAS_CLOCK {
+                        thread::sleep(self.policy.backoff(attempt));
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Processor error marking its batch as worth processing again
+///
+/// Displays as the wrapped error, so messages and reports read the same.
+/// Context added on top of a `Retryable` keeps the batch retryable.
+#[derive(Debug)]
+pub struct Retryable(Error);
+
+impl Retryable {
+    pub fn new(error: impl Into<Error>) -> Self {
+        Self(error.into())
+    }
+
+    pub fn into_inner(self) -> Error {
+        self.0
+    }
+
+    /// Whether `error` or any of its causes is a `Retryable`
+    pub fn is_retryable(error: &Error) -> bool {
+        error.chain().any(|cause| cause.is::<Self>())
+    }
+}
+
+impl fmt::Display for Retryable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for Retryable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastx::Format;
+    use crate::mapper::{MapWriter, ParallelMapper};
+    use crate::record::Record;
+    use crate::stats::RunStats;
+    use crate::testutil::SyntheticReads;
+    use crate::{
+        FastxReader, MinimalRefRecord, ParallelConfig, ParallelProcessor, ParallelReader,
+        ParallelWriter,
+    };
+    use anyhow::{anyhow, bail, Result};
+    use parking_lot::Mutex;
+    use std::io::{Cursor, Write};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn no_backoff(max_retries: u32) -> RetryPolicy {
+        RetryPolicy::new(max_retries).with_backoff(Duration::ZERO, Duration::ZERO)
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Keeps every record, failing once with a retryable error at the `fail_at`-th record seen
+    #[derive(Clone)]
+    struct FlakyMapper {
+        seen: Arc<AtomicUsize>,
+        fail_at: Option<usize>,
+    }
+
+    impl ParallelMapper for FlakyMapper {
+        fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+            if Some(self.seen.fetch_add(1, Ordering::Relaxed)) == self.fail_at {
+                return Err(Retryable::new(anyhow!("service unavailable")).into());
+            }
+            Ok(Some(record))
+        }
+    }
+
+    fn map_through(data: &[u8], fail_at: Option<usize>) -> (Vec<u8>, RunStats) {
+        let buffer = SharedBuffer::default();
+        let out = ParallelWriter::new(buffer.clone());
+        let mapper = FlakyMapper {
+            seen: Arc::default(),
+            fail_at,
+        };
+        let config = ParallelConfig::new(4).with_batch_retry(no_backoff(1));
+        let stats = FastxReader::new(Cursor::new(data.to_vec()), Format::Fastq)
+            .process_parallel_with_config(MapWriter::new(mapper, out.clone()), config)
+            .unwrap();
+        out.finish().unwrap();
+        let output = buffer.0.lock().clone();
+        (output, stats)
+    }
+
+    #[test]
+    fn retried_batch_is_written_once() {
+        let data = SyntheticReads::fastq(2000).to_bytes();
+        let (expected, _) = map_through(&data, None);
+        let (output, stats) = map_through(&data, Some(10));
+        assert_eq!(stats.retried_batches, 1);
+        assert_eq!(output, expected);
+    }
+
+    /// Fails record 3 of the first batch for good and record 7 once with a retryable error
+    #[derive(Clone)]
+    struct FlakyProcessor {
+        retried: Arc<AtomicBool>,
+    }
+
+    impl ParallelProcessor for FlakyProcessor {
+        fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+            &mut self,
+            _record: Rf,
+            record_set_idx: usize,
+            record_idx: usize,
+        ) -> Result<()> {
+            match (record_set_idx, record_idx) {
+                (0, 3) => bail!("malformed record"),
+                (0, 7) if !self.retried.swap(true, Ordering::Relaxed) => {
+                    Err(Retryable::new(anyhow!("service unavailable")).into())
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn retryable_record_error_retries_and_releases_budget() {
+        let data = SyntheticReads::fastq(100).to_bytes();
+        let processor = FlakyProcessor {
+            retried: Arc::default(),
+        };
+        // The budget covers the malformed record once, not once per attempt
+        let config = ParallelConfig::new(2)
+            .with_max_errors(1)
+            .with_batch_retry(no_backoff(1));
+        let stats = FastxReader::new(Cursor::new(data), Format::Fastq)
+            .process_parallel_with_config(processor, config)
+            .unwrap();
+        assert_eq!(stats.retried_batches, 1);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.error_messages, ["malformed record"]);
+    }
+}
@@ -0,0 +1,92 @@
+//! Parking and waking workers with the queue occupancy
+//!
+//! A run that is bound by its reader (e.g. gzip decompression) leaves most
+//! workers spinning on an empty queue. With worker scaling, a controller
+//! samples the number of queued batches at a fixed interval: an empty queue
+//! over several samples parks the highest-numbered active worker, a backlog
+//! wakes a parked one. Workers only park between batches.
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Interval at which the queue occupancy is sampled
+pub(crate) const SCALING_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Consecutive samples with an empty queue before a worker is parked
+const IDLE_SAMPLES: usize = 3;
+
+/// Active worker limit of a run
+struct Limit {
+    active: usize,
+    /// Set once the queue is closed, which releases all workers
+    closed: bool,
+    idle_samples: usize,
+}
+
+/// Gate that workers pass before taking their next batch
+pub(crate) struct WorkerGate {
+    limit: Mutex<Limit>,
+    wake: Condvar,
+    min_threads: usize,
+    max_threads: usize,
+    /// Batches dispatched but not yet taken by a worker
+    queued: AtomicUsize,
+}
+
+impl WorkerGate {
+    /// Starts with all `max_threads` workers active
+    pub(crate) fn new(min_threads: usize, max_threads: usize) -> Self {
+        Self {
+            limit: Mutex::new(Limit {
+                active: max_threads,
+                closed: false,
+                idle_samples: 0,
+            }),
+            wake: Condvar::new(),
+            min_threads: min_threads.clamp(1, max_threads),
+            max_threads,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Blocks worker `thread_id` while it is parked
+    pub(crate) fn wait_turn(&self, thread_id: usize) {
+        let mut limit = self.limit.lock();
+        while thread_id >= limit.active && !limit.closed {
+            self.wake.wait(&mut limit);
+        }
+    }
+
+    pub(crate) fn on_push(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_pop(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Samples the queue and parks or wakes a worker
+    pub(crate) fn adjust(&self) {
+        let queued = self.queued.load(Ordering::Relaxed);
+        let mut limit = self.limit.lock();
+        if queued == 0 {
+            limit.idle_samples += 1;
+            if limit.idle_samples >= IDLE_SAMPLES && limit.active > self.min_threads {
+                limit.active -= 1;
+                limit.idle_samples = 0;
+            }
+        } else {
+            limit.idle_samples = 0;
+            if queued > 1 && limit.active < self.max_threads {
+                limit.active += 1;
+                self.wake.notify_all();
+            }
+        }
+    }
+
+    /// Releases all workers so they can drain the queue and exit
+    pub(crate) fn close(&self) {
+        self.limit.lock().closed = true;
+        self.wake.notify_all();
+    }
+}
@@ -0,0 +1,279 @@
+//! Assignment of batches to workers
+//!
+//! By default every batch goes through the dispatcher channel and is taken by
+//! whichever worker is idle first. A [`Scheduler`] set with
+//! [`ParallelConfig::with_scheduler`](crate::ParallelConfig::with_scheduler)
+//! replaces that channel and decides which worker gets which batch:
+//!
+//! - [`FifoScheduler`]: batches in read order, like the default channel
+//! - [`LifoScheduler`]: the most recently read batch first, which is more
+//!   likely to still be in cache
+//! - [`RoundRobinScheduler`]: batch `i` always goes to worker `i % num_workers`
+//! - [`DedicatedScheduler`]: batches dealt in turn to a queue per worker, so
+//!   workers never contend for a shared queue
+//! - [`SizeAwareScheduler`]: the largest waiting batch (in bytes) first
+//!
+//! Batches in flight are bounded by the record set pool, so schedulers do not
+//! need to apply backpressure.
+use parking_lot::{Condvar, Mutex};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+/// A batch waiting to be processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchInfo {
+    /// Record set slot holding the batch
+    pub slot: usize,
+    /// Global index of the batch, in read order
+    pub batch_idx: usize,
+    pub num_records: usize,
+    pub num_bytes: usize,
+}
+
+/// Queue between the reader threads and the workers
+///
+/// Every pushed batch must be returned by `pop` exactly once, otherwise its
+/// record set slot is never freed and the readers stall.
+pub trait Scheduler: Send + Sync {
+    /// Queues a batch, called from the reader threads
+    fn push(&self, batch: BatchInfo);
+
+    /// Takes the next batch for worker `thread_id`, blocking until one is available
+    ///
+    /// Returns `None` once the scheduler is closed and no batch is left for this worker.
+    fn pop(&self, thread_id: usize) -> Option<BatchInfo>;
+
+    /// Signals that all readers are done
+    fn close(&self);
+}
+
+/// Queue state behind a mutex, with a condvar to wake waiting workers
+struct Blocking<Q> {
+    state: Mutex<(Q, bool)>,
+    ready: Condvar,
+}
+
+impl<Q> Blocking<Q> {
+    fn new(queue: Q) -> Self {
+        Self {
+            state: Mutex::new((queue, false)),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn push(&self, push_fn: impl FnOnce(&mut Q), wake_all: bool) {
+        push_fn(&mut self.state.lock().0);
+        if wake_all {
+            self.ready.notify_all();
+        } else {
+            self.ready.notify_one();
+        }
+    }
+
+    fn pop(&self, mut pop_fn: impl FnMut(&mut Q) -> Option<BatchInfo>) -> Option<BatchInfo> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(batch) = pop_fn(&mut state.0) {
+                return Some(batch);
+            }
+            if state.1 {
+                return None;
+            }
+            self.ready.wait(&mut state);
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().1 = true;
+        self.ready.notify_all();
+    }
+}
+
+/// Hands out batches in read order
+pub struct FifoScheduler(Blocking<VecDeque<BatchInfo>>);
+
+impl FifoScheduler {
+    pub fn new() -> Self {
+        Self(Blocking::new(VecDeque::new()))
+    }
+}
+
+impl Default for FifoScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for FifoScheduler {
+    fn push(&self, batch: BatchInfo) {
+        self.0.push(|queue| queue.push_back(batch), false);
+    }
+
+    fn pop(&self, _thread_id: usize) -> Option<BatchInfo> {
+        self.0.pop(VecDeque::pop_front)
+    }
+
+    fn close(&self) {
+        self.0.close();
+    }
+}
+
+/// Hands out the most recently read batch first
+///
+/// Its records were just written by the reader, so they are more likely to
+/// still be in a shared cache. Batches are no longer processed in read order.
+pub struct LifoScheduler(Blocking<Vec<BatchInfo>>);
+
+impl LifoScheduler {
+    pub fn new() -> Self {
+        Self(Blocking::new(Vec::new()))
+    }
+}
+
+impl Default for LifoScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for LifoScheduler {
+    fn push(&self, batch: BatchInfo) {
+        self.0.push(|stack| stack.push(batch), false);
+    }
+
+    fn pop(&self, _thread_id: usize) -> Option<BatchInfo> {
+        self.0.pop(Vec::pop)
+    }
+
+    fn close(&self) {
+        self.0.close();
+    }
+}
+
+/// Assigns batch `i` to worker `i % num_workers`
+///
+/// Gives every worker a deterministic share of the input, at the cost of
+/// idle workers when batches differ a lot in processing time.
+pub struct RoundRobinScheduler(Blocking<Vec<VecDeque<BatchInfo>>>);
+
+impl RoundRobinScheduler {
+    pub fn new(num_workers: usize) -> Self {
+        let queues = (0..num_workers.max(1)).map(|_| VecDeque::new()).collect();
+        Self(Blocking::new(queues))
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn push(&self, batch: BatchInfo) {
+        // The condvar is shared by all workers, so wake all to reach the right one
+        self.0.push(
+            |queues| {
+                let worker = batch.batch_idx % queues.len();
+                queues[worker].push_back(batch);
+            },
+            true,
+        );
+    }
+
+    fn pop(&self, thread_id: usize) -> Option<BatchInfo> {
+        self.0.pop(|queues| {
+            let worker = thread_id % queues.len();
+            queues[worker].pop_front()
+        })
+    }
+
+    fn close(&self) {
+        self.0.close();
+    }
+}
+
+/// Deals batches in turn to a dedicated queue per worker
+///
+/// Every queue has its own lock and is only shared between the readers and
+/// one worker, so workers neither contend for a shared queue nor wake each
+/// other up. Each batch is read by the reader and then touched by a single
+/// worker only. Like [`RoundRobinScheduler`], a slow batch holds up the
+/// batches queued behind it on the same worker.
+pub struct DedicatedScheduler {
+    queues: Vec<Blocking<VecDeque<BatchInfo>>>,
+    next: AtomicUsize,
+}
+
+impl DedicatedScheduler {
+    pub fn new(num_workers: usize) -> Self {
+        let queues = (0..num_workers.max(1))
+            .map(|_| Blocking::new(VecDeque::new()))
+            .collect();
+        Self {
+            queues,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Scheduler for DedicatedScheduler {
+    fn push(&self, batch: BatchInfo) {
+        let worker = self.next.fetch_add(1, AtomicOrdering::Relaxed) % self.queues.len();
+        self.queues[worker].push(|queue| queue.push_back(batch), false);
+    }
+
+    fn pop(&self, thread_id: usize) -> Option<BatchInfo> {
+        self.queues[thread_id % self.queues.len()].pop(VecDeque::pop_front)
+    }
+
+    fn close(&self) {
+        for queue in &self.queues {
+            queue.close();
+        }
+    }
+}
+
+/// Orders batches by size, largest first, then by read order
+#[derive(PartialEq, Eq)]
+struct BySize(BatchInfo);
+
+impl Ord for BySize {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.num_bytes, Reverse(self.0.batch_idx))
+            .cmp(&(other.0.num_bytes, Reverse(other.0.batch_idx)))
+    }
+}
+
+impl PartialOrd for BySize {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Hands out the largest waiting batch (in bytes) first
+///
+/// Starting long batches early shortens the tail of a run when batch sizes
+/// vary, e.g. with long reads or grouped input.
+pub struct SizeAwareScheduler(Blocking<BinaryHeap<BySize>>);
+
+impl SizeAwareScheduler {
+    pub fn new() -> Self {
+        Self(Blocking::new(BinaryHeap::new()))
+    }
+}
+
+impl Default for SizeAwareScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for SizeAwareScheduler {
+    fn push(&self, batch: BatchInfo) {
+        self.0.push(|heap| heap.push(BySize(batch)), false);
+    }
+
+    fn pop(&self, _thread_id: usize) -> Option<BatchInfo> {
+        self.0.pop(|heap| heap.pop().map(|batch| batch.0))
+    }
+
+    fn close(&self) {
+        self.0.close();
+    }
+}
@@ -0,0 +1,158 @@
+//! Random access into files in the zstd seekable format
+//!
+//! A seekable zstd file is a series of independent zstd frames followed by a
+//! seek table (stored in a skippable frame) listing the compressed and
+//! decompressed size of every frame. [`SeekableZstdReader`] uses that table to
+//! implement `io::Seek` over the decompressed stream, decompressing only the
+//! frame that holds the requested position.
+use std::io::{self, Read, Seek, SeekFrom};
+
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const FOOTER_SIZE: usize = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+
+/// Location of one frame in the compressed and decompressed streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameEntry {
+    pub compressed_offset: u64,
+    pub compressed_size: u32,
+    pub decompressed_offset: u64,
+    pub decompressed_size: u32,
+}
+
+/// `Read + Seek` adapter over a seekable zstd file
+pub struct SeekableZstdReader<R> {
+    inner: R,
+    frames: Vec<FrameEntry>,
+    total_size: u64,
+    pos: u64,
+    /// Index and decompressed contents of the last frame read
+    current: Option<(usize, Vec<u8>)>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+impl<R: Read + Seek> SeekableZstdReader<R> {
+    /// Reads the seek table at the end of `inner`
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut footer = [0u8; FOOTER_SIZE];
+        inner.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        inner.read_exact(&mut footer)?;
+        if read_u32(&footer[5..]) != SEEKABLE_MAGIC {
+            return Err(invalid("Missing zstd seek table"));
+        }
+        let num_frames = read_u32(&footer[..4]) as usize;
+        let entry_size = if footer[4] & CHECKSUM_FLAG != 0 { 12 } else { 8 };
+
+        // Skippable frame header + entries + footer
+        let table_size = 8 + num_frames * entry_size + FOOTER_SIZE;
+        let mut table = vec![0u8; num_frames * entry_size + 8];
+        inner.seek(SeekFrom::End(-(table_size as i64)))?;
+        inner.read_exact(&mut table)?;
+        if read_u32(&table[..4]) != SKIPPABLE_MAGIC {
+            return Err(invalid("Malformed zstd seek table"));
+        }
+
+        let mut frames = Vec::with_capacity(num_frames);
+        let (mut compressed_offset, mut decompressed_offset) = (0u64, 0u64);
+        for entry in table[8..].chunks_exact(entry_size) {
+            let frame = FrameEntry {
+                compressed_offset,
+                compressed_size: read_u32(&entry[..4]),
+                decompressed_offset,
+                decompressed_size: read_u32(&entry[4..8]),
+            };
+            compressed_offset += frame.compressed_size as u64;
+            decompressed_offset += frame.decompressed_size as u64;
+            frames.push(frame);
+        }
+
+        Ok(Self {
+            inner,
+            frames,
+            total_size: decompressed_offset,
+            pos: 0,
+            current: None,
+        })
+    }
+
+    /// Frames of the file, in order
+    ///
+    /// Frame boundaries are natural split points for sharded reading.
+    pub fn frames(&self) -> &[FrameEntry] {
+        &self.frames
+    }
+
+    /// Size of the decompressed stream
+    pub fn decompressed_size(&self) -> u64 {
+        self.total_size
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Decompresses frame `idx` unless it is already loaded
+    fn load_frame(&mut self, idx: usize) -> io::Result<()> {
+        if matches!(self.current, Some((current, _)) if current == idx) {
+            return Ok(());
+        }
+        let frame = self.frames[idx];
+        let mut compressed = vec![0u8; frame.compressed_size as usize];
+        self.inner.seek(SeekFrom::Start(frame.compressed_offset))?;
+        self.inner.read_exact(&mut compressed)?;
+        let data = zstd::bulk::decompress(&compressed, frame.decompressed_size as usize)?;
+        if data.len() != frame.decompressed_size as usize {
+            return Err(invalid("zstd frame size does not match its seek table entry"));
+        }
+        self.current = Some((idx, data));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.total_size || buf.is_empty() {
+            return Ok(0);
+        }
+        let idx = self
+            .frames
+            .partition_point(|f| f.decompressed_offset + f.decompressed_size as u64 <= self.pos);
+        self.load_frame(idx)?;
+
+        let frame = self.frames[idx];
+        let data = &self.current.as_ref().expect("frame was just loaded").1;
+        let start = (self.pos - frame.decompressed_offset) as usize;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableZstdReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.total_size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )),
+        }
+    }
+}
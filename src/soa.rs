@@ -0,0 +1,168 @@
+//! Structure-of-arrays view of whole batches
+//!
+//! Record-at-a-time processing hands out one slice per field and record,
+//! which gets in the way of SIMD kernels and of GPU offloading, both of
+//! which want all sequences of a batch in one buffer. A [`SoaBatch`] holds
+//! the sequences, qualities and headers of a batch concatenated into one
+//! buffer each, with the offsets of every record. A [`BatchProcessor`]
+//! receives every batch in this layout; the buffers are filled on the worker
+//! and reused from batch to batch, so they only allocate while growing.
+use anyhow::Result;
+use std::io;
+
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{FastxReader, FastxRecordSet};
+use crate::macro_impl::{run_parallel, BatchWorker};
+use crate::processor::BatchProcessor;
+use crate::record::{Record, RecordSetLike};
+use crate::stats::RunStats;
+use crate::MinimalRefRecord;
+
+/// Records of a batch with every field concatenated into one buffer
+///
+/// Sequences are stored without the line breaks of wrapped FASTA, and the
+/// qualities of FASTQ records share the offsets of the sequences.
+#[derive(Debug, Clone)]
+pub struct SoaBatch {
+    pub(crate) seqs: Vec<u8>,
+    pub(crate) quals: Vec<u8>,
+    /// Start of every sequence in `seqs`, plus the end of the last one
+    pub(crate) offsets: Vec<usize>,
+    pub(crate) heads: Vec<u8>,
+    pub(crate) head_offsets: Vec<usize>,
+    pub(crate) has_quals: bool,
+}
+
+impl Default for SoaBatch {
+    fn default() -> Self {
+        Self {
+            seqs: Vec::new(),
+            quals: Vec::new(),
+            offsets: vec![0],
+            heads: Vec::new(),
+            head_offsets: vec![0],
+            has_quals: false,
+        }
+    }
+}
+
+impl SoaBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the content with the records of `record_set`
+    pub fn fill<S: RecordSetLike>(&mut self, record_set: &S) {
+        self.clear();
+        for record in record_set.records() {
+            self.push(&record);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.seqs.clear();
+        self.quals.clear();
+        self.offsets.truncate(1);
+        self.heads.clear();
+        self.head_offsets.truncate(1);
+        self.has_quals = false;
+    }
+
+    /// Appends a record
+    pub fn push<'a, Rf: MinimalRefRecord<'a>>(&mut self, record: &Rf) {
+        if let Record::Fastq { qual, .. } = record.to_record() {
+            self.quals.extend_from_slice(qual);
+            self.has_quals = true;
+        }
+        self.seqs.extend_from_slice(&record.ref_full_seq());
+        self.offsets.push(self.seqs.len());
+        self.heads.extend_from_slice(record.ref_head());
+        self.head_offsets.push(self.heads.len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All sequences, one after the other
+    pub fn seqs(&self) -> &[u8] {
+        &self.seqs
+    }
+
+    /// All qualities, aligned with [`seqs`](Self::seqs), or `None` for FASTA batches
+    pub fn quals(&self) -> Option<&[u8]> {
+        self.has_quals.then_some(self.quals.as_slice())
+    }
+
+    /// Start of every record in [`seqs`](Self::seqs), followed by the total length
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// Sequence length of every record
+    pub fn lengths(&self) -> impl Iterator<Item = usize> + '_ {
+        self.offsets.windows(2).map(|w| w[1] - w[0])
+    }
+
+    /// All headers, one after the other
+    pub fn heads(&self) -> &[u8] {
+        &self.heads
+    }
+
+    /// Start of every record in [`heads`](Self::heads), followed by the total length
+    pub fn head_offsets(&self) -> &[usize] {
+        &self.head_offsets
+    }
+
+    pub fn seq(&self, idx: usize) -> &[u8] {
+        &self.seqs[self.offsets[idx]..self.offsets[idx + 1]]
+    }
+
+    pub fn qual(&self, idx: usize) -> Option<&[u8]> {
+        self.quals()
+            .map(|quals| &quals[self.offsets[idx]..self.offsets[idx + 1]])
+    }
+
+    pub fn head(&self, idx: usize) -> &[u8] {
+        &self.heads[self.head_offsets[idx]..self.head_offsets[idx + 1]]
+    }
+}
+
+impl<R: io::Read + Send> FastxReader<R> {
+    /// Processes every batch as a whole, in the layout of a [`SoaBatch`]
+    pub fn process_parallel_batches<T: BatchProcessor>(
+        self,
+        processor: T,
+        num_threads: usize,
+    ) -> Result<()> {
+        self.process_parallel_batches_with_config(processor, ParallelConfig::new(num_threads))
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel_batches` but with a full run configuration
+    pub fn process_parallel_batches_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        D: Dispatcher,
+        T: BatchProcessor,
+    {
+        run_parallel::<D, _, FastxRecordSet, _, _, _>(
+            self,
+            BatchWorker::new(processor),
+            &config,
+            |reader, record_set| reader.read_record_set(record_set),
+            |record_set, worker, global_idx| {
+                worker.batch.fill(record_set);
+                worker.processor.process_batch(&worker.batch, global_idx)
+            },
+        )
+    }
+}
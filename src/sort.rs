@@ -0,0 +1,656 @@
+//! External sorting by name or length
+//!
+//! [`ExternalSorter`] sorts files larger than memory. Every worker collects
+//! the records of its batches until its share of the memory limit is used,
+//! sorts them and spills them to a temporary file as a sorted run. Once the
+//! input is read, the runs are merged on the calling thread and written to
+//! the output. If there are more runs than can be open at once, they are
+//! first merged in groups into fewer, longer runs. Ties are broken by input order, so the output doesn't depend
+//! on the thread count or the batching.
+//!
+//! Files that are already sorted, e.g. per lane, are combined with
+//! [`ExternalSorter::merge_parallel`], which decompresses and parses every
+//! input on a thread of its own and checks their order while merging. It
+//! requires the `parallel` feature.
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "parallel")]
+use crossbeam_channel::{bounded, Receiver, Sender};
+use parking_lot::Mutex;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use std::thread;
+
+use crate::fastx::FastxReader;
+#[cfg(feature = "parallel")]
+use crate::fastx::FastxRecordSet;
+use crate::header::split_id;
+use crate::reader::ParallelReader;
+use crate::record::Record;
+#[cfg(feature = "parallel")]
+use crate::record::RecordSetLike;
+use crate::writer::ParallelWriter;
+use crate::{MinimalRefRecord, ParallelProcessor};
+
+/// Memory used for the records held by all workers, unless set
+const DEFAULT_MEMORY_LIMIT: usize = 1 << 30;
+
+/// Records written per output batch while merging
+const MERGE_BATCH_SIZE: usize = 1024;
+
+/// Sorted runs open at once in one merge pass
+const MAX_MERGE_RUNS: usize = 64;
+
+/// Estimated bookkeeping per held record, on top of its bytes
+const RECORD_OVERHEAD: usize = 96;
+
+/// Batches parsed ahead by the reader of every merged input
+#[cfg(feature = "parallel")]
+const MERGE_READAHEAD: usize = 4;
+
+/// Order of a sort
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortKey {
+    /// Byte-wise by ID, the header up to the first space
+    #[default]
+    Name,
+    /// By sequence length, shortest first
+    Length,
+}
+
+/// Counts of a sort
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SortStats {
+    pub records: u64,
+    /// Sorted runs spilled to disk and merged
+    pub runs: usize,
+}
+
+/// Sorts FASTA/FASTQ files with bounded memory
+#[derive(Debug, Clone)]
+pub struct ExternalSorter {
+    key: SortKey,
+    reverse: bool,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+}
+
+impl ExternalSorter {
+    /// Sorter spilling to the system temporary directory, with a 1 GiB memory limit
+    pub fn new(key: SortKey) -> Self {
+        Self {
+            key,
+            reverse: false,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+
+    /// Sorts in descending order, e.g. longest reads first; ties stay in input order
+    pub fn with_reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// Bytes of records held in memory by all workers together before they spill
+    ///
+    /// The merge keeps one record per run in memory, so fewer, larger runs
+    /// also make the merge cheaper.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Directory of the sorted runs, which take about as much space as the uncompressed input
+    pub fn with_temp_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.temp_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Sorts a (possibly compressed) FASTA/FASTQ file into `output`
+    ///
+    /// The output is compressed according to its extension. Wrapped FASTA
+    /// sequences are written on a single line.
+    pub fn sort_parallel<P1, P2>(
+        &self,
+        input: P1,
+        output: P2,
+        num_threads: usize,
+    ) -> Result<SortStats>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let out = ParallelWriter::from_path(output)?;
+        let stats = self.sort_to(input, &out, num_threads)?;
+        out.finish()?;
+        Ok(stats)
+    }
+
+    /// Sorts a (possibly compressed) FASTA/FASTQ file into `out`, which is not finished
+    pub fn sort_to<P: AsRef<Path>>(
+        &self,
+        input: P,
+        out: &ParallelWriter,
+        num_threads: usize,
+    ) -> Result<SortStats> {
+        let runs = Arc::new(SpilledRuns::new(&self.temp_dir));
+        let spiller = Spiller {
+            sorter: self.clone(),
+            budget: self.memory_limit / num_threads.max(1),
+            runs: Arc::clone(&runs),
+            records: Vec::new(),
+            bytes: 0,
+        };
+        FastxReader::from_path(input)?.process_parallel(spiller, num_threads)?;
+        let spilled = runs.paths.lock().len();
+        let paths = self.reduce_runs(&runs)?;
+        let records = self.merge(open_runs(&paths)?, out.clone())?;
+        Ok(SortStats {
+            records,
+            runs: spilled,
+        })
+    }
+
+    /// Merges the runs in groups until at most [`MAX_MERGE_RUNS`] are left and returns them
+    ///
+    /// The merged runs keep the input positions of their records, so ties
+    /// still come out in input order.
+    fn reduce_runs(&self, runs: &SpilledRuns) -> Result<Vec<PathBuf>> {
+        let mut paths = runs.paths.lock().clone();
+        while paths.len() > MAX_MERGE_RUNS {
+            let mut merged = Vec::new();
+            for group in paths.chunks(MAX_MERGE_RUNS) {
+                let (path, file) = runs.create()?;
+                let mut out = BufWriter::new(file);
+                self.merge_with(open_runs(group)?, |record| Ok(record.write_to(&mut out)?))?;
+                out.flush()
+                    .with_context(|| format!("Failed to write sorted run {}", path.display()))?;
+                group.iter().for_each(|run| runs.remove(run));
+                merged.push(path);
+            }
+            paths = merged;
+        }
+        Ok(paths)
+    }
+
+    /// Merges (possibly compressed) files sorted in this sorter's order into `output`
+    ///
+    /// Every input is read on a thread of its own. Records with equal keys
+    /// are written in the order of the inputs. Fails as soon as an input is
+    /// found out of order, leaving a partial output.
+    #[cfg(feature = "parallel")]
+    pub fn merge_parallel<P1, P2>(&self, inputs: &[P1], output: P2) -> Result<SortStats>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let out = ParallelWriter::from_path(output)?;
+        let stats = self.merge_to(inputs, &out)?;
+        out.finish()?;
+        Ok(stats)
+    }
+
+    /// Merges sorted files into `out`, which is not finished
+    #[cfg(feature = "parallel")]
+    pub fn merge_to<P: AsRef<Path>>(
+        &self,
+        inputs: &[P],
+        out: &ParallelWriter,
+    ) -> Result<SortStats> {
+        thread::scope(|scope| {
+            let mut sources = Vec::with_capacity(inputs.len());
+            for (input, path) in inputs.iter().enumerate() {
+                let path = path.as_ref();
+                let (tx, rx) = bounded(MERGE_READAHEAD);
+                thread::Builder::new()
+                    .name(format!("seqio-merge-{}", input))
+                    .spawn_scoped(scope, move || {
+                        if let Err(err) = read_input(path, input, &tx) {
+                            let _ = tx.send(Err(err));
+                        }
+                    })?;
+                sources.push(MergeInput {
+                    path,
+                    rx,
+                    records: Vec::new().into_iter(),
+                });
+            }
+            let records = self.merge(sources, out.clone())?;
+            Ok(SortStats {
+                records,
+                runs: inputs.len(),
+            })
+        })
+    }
+
+    fn compare_keys(&self, a: &SortRecord, b: &SortRecord) -> Ordering {
+        let order = match self.key {
+            SortKey::Name => split_id(&a.head).0.cmp(split_id(&b.head).0),
+            SortKey::Length => a.seq.len().cmp(&b.seq.len()),
+        };
+        if self.reverse {
+            order.reverse()
+        } else {
+            order
+        }
+    }
+
+    fn compare(&self, a: &SortRecord, b: &SortRecord) -> Ordering {
+        self.compare_keys(a, b).then(a.input_idx.cmp(&b.input_idx))
+    }
+
+    /// Merges sorted sources into `out` and returns the number of records
+    fn merge<S: SortedSource>(&self, sources: Vec<S>, mut out: ParallelWriter) -> Result<u64> {
+        let mut records = 0;
+        self.merge_with(sources, |record| {
+            let batch_idx = (records / MERGE_BATCH_SIZE as u64) as usize;
+            out.write_record(batch_idx, &record.as_record())?;
+            records += 1;
+            if records % MERGE_BATCH_SIZE as u64 == 0 {
+                out.finish_batch()?;
+            }
+            Ok(())
+        })?;
+        out.finish_batch()?;
+        Ok(records)
+    }
+
+    /// Merges sorted sources, passing their records to `emit` in order
+    fn merge_with<S, F>(&self, mut sources: Vec<S>, mut emit: F) -> Result<()>
+    where
+        S: SortedSource,
+        F: FnMut(&SortRecord) -> Result<()>,
+    {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (source, reader) in sources.iter_mut().enumerate() {
+            if let Some(record) = reader.next_record()? {
+                heap.push(MergeEntry {
+                    record,
+                    source,
+                    sorter: self,
+                });
+            }
+        }
+        while let Some(MergeEntry { record, source, .. }) = heap.pop() {
+            emit(&record)?;
+            if let Some(next) = sources[source].next_record()? {
+                if self.compare_keys(&record, &next) == Ordering::Greater {
+                    bail!(
+                        "{} is not sorted: {} follows {}",
+                        sources[source].name(),
+                        String::from_utf8_lossy(split_id(&next.head).0),
+                        String::from_utf8_lossy(split_id(&record.head).0)
+                    );
+                }
+                heap.push(MergeEntry {
+                    record: next,
+                    source,
+                    sorter: self,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Opens sorted runs for a merge
+fn open_runs(paths: &[PathBuf]) -> Result<Vec<RunFile<'_>>> {
+    paths
+        .iter()
+        .map(|path| {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open sorted run {}", path.display()))?;
+            Ok(RunFile {
+                path,
+                reader: BufReader::new(file),
+            })
+        })
+        .collect()
+}
+
+/// Parses a merged input into batches of owned records
+#[cfg(feature = "parallel")]
+fn read_input(path: &Path, input: usize, tx: &Sender<Result<Vec<SortRecord>>>) -> Result<()> {
+    let mut reader = FastxReader::from_path(path)?;
+    let mut record_set = FastxRecordSet::default();
+    let mut next_idx = (input as u64) << 40;
+    while let Some(result) = reader.read_record_set(&mut record_set) {
+        result?;
+        let batch = record_set
+            .records()
+            .map(|record| {
+                next_idx += 1;
+                SortRecord::new(&record, next_idx)
+            })
+            .collect();
+        if tx.send(Ok(batch)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Sorted stream of records to merge
+trait SortedSource {
+    fn next_record(&mut self) -> Result<Option<SortRecord>>;
+
+    /// Name in error messages
+    fn name(&self) -> String;
+}
+
+/// Sorted run spilled by a worker
+struct RunFile<'p> {
+    path: &'p Path,
+    reader: BufReader<File>,
+}
+
+impl SortedSource for RunFile<'_> {
+    fn next_record(&mut self) -> Result<Option<SortRecord>> {
+        Ok(SortRecord::read_from(&mut self.reader)?)
+    }
+
+    fn name(&self) -> String {
+        format!("Sorted run {}", self.path.display())
+    }
+}
+
+/// Input of [`ExternalSorter::merge_parallel`], parsed by its own thread
+#[cfg(feature = "parallel")]
+struct MergeInput<'p> {
+    path: &'p Path,
+    rx: Receiver<Result<Vec<SortRecord>>>,
+    records: std::vec::IntoIter<SortRecord>,
+}
+
+#[cfg(feature = "parallel")]
+impl SortedSource for MergeInput<'_> {
+    fn next_record(&mut self) -> Result<Option<SortRecord>> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Ok(Some(record));
+            }
+            match self.rx.recv() {
+                Ok(batch) => self.records = batch?.into_iter(),
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("Input {}", self.path.display())
+    }
+}
+
+/// Owned record with its position in the input
+#[derive(Debug, Clone)]
+struct SortRecord {
+    input_idx: u64,
+    fasta: bool,
+    head: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl SortRecord {
+    fn new<'a, Rf: MinimalRefRecord<'a>>(record: &Rf, input_idx: u64) -> Self {
+        Self {
+            input_idx,
+            fasta: matches!(record.to_record(), Record::Fasta { .. }),
+            head: record.ref_head().to_vec(),
+            seq: record.ref_full_seq().into_owned(),
+            qual: record.ref_qual().to_vec(),
+        }
+    }
+
+    fn as_record(&self) -> Record<'_> {
+        if self.fasta {
+            Record::Fasta {
+                head: &self.head,
+                seq: &self.seq,
+            }
+        } else {
+            Record::Fastq {
+                head: &self.head,
+                seq: &self.seq,
+                qual: &self.qual,
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.head.len() + self.seq.len() + self.qual.len() + RECORD_OVERHEAD
+    }
+
+    /// Appends the record to a run: index, FASTA flag and the three length-prefixed fields
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.input_idx.to_le_bytes())?;
+        out.write_all(&[u8::from(self.fasta)])?;
+        for field in [&self.head, &self.seq, &self.qual] {
+            out.write_all(&(field.len() as u64).to_le_bytes())?;
+            out.write_all(field)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next record of a run, `None` at its end
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut input_idx = [0u8; 8];
+        match reader.read_exact(&mut input_idx) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut fasta = [0u8];
+        reader.read_exact(&mut fasta)?;
+        let mut field = || -> io::Result<Vec<u8>> {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            let mut data = vec![0; u64::from_le_bytes(len) as usize];
+            reader.read_exact(&mut data)?;
+            Ok(data)
+        };
+        Ok(Some(Self {
+            input_idx: u64::from_le_bytes(input_idx),
+            fasta: fasta[0] != 0,
+            head: field()?,
+            seq: field()?,
+            qual: field()?,
+        }))
+    }
+}
+
+/// Head of a source in the merge, ordered so the max-heap pops the smallest record first
+struct MergeEntry<'s> {
+    record: SortRecord,
+    source: usize,
+    sorter: &'s ExternalSorter,
+}
+
+impl PartialEq for MergeEntry<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeEntry<'_> {}
+
+impl PartialOrd for MergeEntry<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeEntry<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sorter.compare(&other.record, &self.record)
+    }
+}
+
+/// Temporary files of the sorted runs, removed when dropped
+struct SpilledRuns {
+    prefix: PathBuf,
+    next: AtomicUsize,
+    paths: Mutex<Vec<PathBuf>>,
+}
+
+impl SpilledRuns {
+    fn new(dir: &Path) -> Self {
+        let prefix = dir.join(format!("seqio-sort-{}", std::process::id()));
+        Self {
+            prefix,
+            next: AtomicUsize::new(0),
+            paths: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates the file of a new run
+    fn create(&self) -> Result<(PathBuf, File)> {
+        let idx = self.next.fetch_add(1, AtomicOrdering::Relaxed);
+        let mut path = self.prefix.clone().into_os_string();
+        path.push(format!("-{:p}-{}.run", self, idx));
+        let path = PathBuf::from(path);
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create sorted run {}", path.display()))?;
+        self.paths.lock().push(path.clone());
+        Ok((path, file))
+    }
+
+    /// Removes a run once it is merged into another
+    fn remove(&self, path: &Path) {
+        self.paths.lock().retain(|run| run != path);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+impl Drop for SpilledRuns {
+    fn drop(&mut self) {
+        for path in self.paths.get_mut().drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Collects the records of a worker and spills them as sorted runs
+#[derive(Clone)]
+struct Spiller {
+    sorter: ExternalSorter,
+    /// Bytes held by this worker before it spills
+    budget: usize,
+    runs: Arc<SpilledRuns>,
+    records: Vec<SortRecord>,
+    bytes: usize,
+}
+
+impl Spiller {
+    fn spill(&mut self) -> Result<()> {
+        if self.records.is_empty() {
+            return Ok(());
+        }
+        let sorter = &self.sorter;
+        self.records.sort_unstable_by(|a, b| sorter.compare(a, b));
+        let (path, file) = self.runs.create()?;
+        let mut out = BufWriter::new(file);
+        for record in self.records.drain(..) {
+            record.write_to(&mut out)?;
+        }
+        out.flush()
+            .with_context(|| format!("Failed to write sorted run {}", path.display()))?;
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+impl ParallelProcessor for Spiller {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<()> {
+        let input_idx = ((record_set_idx as u64) << 32) | record_idx as u64;
+        let record = SortRecord::new(&record, input_idx);
+        self.bytes += record.size();
+        self.records.push(record);
+        Ok(())
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        if self.bytes >= self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn on_thread_complete(&mut self) -> Result<()> {
+        self.spill()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{LengthDist, SyntheticReads};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sort_{}_{}.fq", name, std::process::id()))
+    }
+
+    /// Splits FASTQ data into its records, four lines each
+    fn fastq_records(data: &[u8]) -> Vec<Vec<u8>> {
+        let lines: Vec<&[u8]> = data.split_inclusive(|&b| b == b'\n').collect();
+        lines.chunks(4).map(|record| record.concat()).collect()
+    }
+
+    #[test]
+    fn ties_keep_input_order_across_spilled_runs() {
+        let input = temp_path("ties_input");
+        let output = temp_path("ties_output");
+        let data = SyntheticReads::fastq(5000)
+            .with_lengths(LengthDist::Uniform { min: 50, max: 53 })
+            .to_bytes();
+        std::fs::write(&input, &data).unwrap();
+        let stats = ExternalSorter::new(SortKey::Length)
+            .with_memory_limit(1)
+            .sort_parallel(&input, &output, 4)
+            .unwrap();
+        let sorted = std::fs::read(&output).unwrap();
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert!(stats.runs > 1);
+        // A stable sort by the length of the sequence line
+        let mut expected = fastq_records(&data);
+        expected.sort_by_key(|record| record.split(|&b| b == b'\n').nth(1).unwrap().len());
+        assert!(fastq_records(&sorted) == expected);
+    }
+
+    #[test]
+    fn many_runs_are_merged_in_passes() {
+        let input = temp_path("many_runs_input");
+        std::fs::write(&input, SyntheticReads::fastq(30_000).to_bytes()).unwrap();
+        let in_memory = temp_path("many_runs_memory");
+        let spilled = temp_path("many_runs_spilled");
+        let sorter = ExternalSorter::new(SortKey::Name);
+        let one_run = sorter.sort_parallel(&input, &in_memory, 1).unwrap();
+        // Every batch spills a run of its own
+        let many_runs = sorter
+            .clone()
+            .with_memory_limit(1)
+            .sort_parallel(&input, &spilled, 4)
+            .unwrap();
+        let expected = std::fs::read(&in_memory).unwrap();
+        let output = std::fs::read(&spilled).unwrap();
+        for path in [&input, &in_memory, &spilled] {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(one_run.runs, 1);
+        assert!(many_runs.runs > MAX_MERGE_RUNS, "{} runs", many_runs.runs);
+        assert_eq!(many_runs.records, 30_000);
+        assert!(output == expected);
+    }
+}
@@ -0,0 +1,125 @@
+//! Plugging third-party readers into the parallel executor
+//!
+//! The executor only needs two operations from a reader: filling a reusable
+//! record set on the reader thread, and handing the records of a filled set
+//! to a processor on a worker thread. [`RecordSetSource`] exposes exactly
+//! these, so parsers from other crates (needletail, GFA parsers, custom
+//! binary formats) get the same slot recycling, dispatching, throttling,
+//! metrics and checkpoints as the seq_io readers.
+use anyhow::Result;
+use seq_io::{fasta, fastq, policy};
+use std::io;
+
+use crate::batch::BatchSize;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::macro_impl::run_parallel;
+use crate::record::RecordSetLike;
+use crate::stats::RunStats;
+use crate::ParallelProcessor;
+
+/// Reader producing batches of records for the parallel executor
+pub trait RecordSetSource: Send {
+    /// Reusable batch of records, allocated once per slot and refilled
+    type RecordSet: Default + Send;
+
+    /// Fills `record_set` with the next batch on the reader thread
+    ///
+    /// Returns `None` once the input is exhausted. A batch left without
+    /// records is not dispatched and reading goes on, unless the run is
+    /// configured with [`EmptyBatches::Stop`](crate::EmptyBatches::Stop).
+    fn read_record_set(&mut self, record_set: &mut Self::RecordSet) -> Option<Result<()>>;
+
+    /// Hands every record of `record_set` to `processor` on a worker thread
+    fn process_record_set<T: ParallelProcessor>(
+        record_set: &Self::RecordSet,
+        processor: &mut T,
+        record_set_idx: usize,
+    ) -> Result<()>;
+
+    /// Number of records in `record_set`, for metrics and checkpoints
+    fn num_records(record_set: &Self::RecordSet) -> usize;
+
+    /// Bytes of headers, sequences and qualities in `record_set`, for metrics and throttling
+    #[allow(unused_variables)]
+    fn num_bytes(record_set: &Self::RecordSet) -> usize {
+        0
+    }
+}
+
+/// Record set of a [`RecordSetSource`] with the executor's size accounting
+struct SourceSet<S: RecordSetSource>(S::RecordSet);
+
+impl<S: RecordSetSource> Default for SourceSet<S> {
+    fn default() -> Self {
+        Self(S::RecordSet::default())
+    }
+}
+
+impl<S: RecordSetSource> BatchSize for SourceSet<S> {
+    fn num_records(&self) -> usize {
+        S::num_records(&self.0)
+    }
+
+    fn num_bytes(&self) -> usize {
+        S::num_bytes(&self.0)
+    }
+}
+
+/// Processes all records of `source` in parallel
+pub fn process_source_parallel<S, D, T>(
+    source: S,
+    processor: T,
+    config: ParallelConfig<D>,
+) -> Result<RunStats>
+where
+    S: RecordSetSource,
+    D: Dispatcher,
+    T: ParallelProcessor,
+{
+    run_parallel::<D, _, SourceSet<S>, _, _, _>(
+        source,
+        processor,
+        &config,
+        |source, record_set| source.read_record_set(&mut record_set.0),
+        |record_set, processor, record_set_idx| {
+            S::process_record_set(&record_set.0, processor, record_set_idx)
+        },
+    )
+}
+
+macro_rules! impl_record_set_source {
+    ($reader:ty, $record_set:ty) => {
+        impl<R, P> RecordSetSource for $reader
+        where
+            R: io::Read + Send,
+            P: policy::BufPolicy + Send,
+        {
+            type RecordSet = $record_set;
+
+            fn read_record_set(&mut self, record_set: &mut Self::RecordSet) -> Option<Result<()>> {
+                self.read_record_set(record_set)
+                    .map(|result| result.map_err(Into::into))
+            }
+
+            fn process_record_set<T: ParallelProcessor>(
+                record_set: &Self::RecordSet,
+                processor: &mut T,
+                record_set_idx: usize,
+            ) -> Result<()> {
+                record_set.process(processor, record_set_idx)
+            }
+
+            fn num_records(record_set: &Self::RecordSet) -> usize {
+                record_set.len()
+            }
+
+            fn num_bytes(record_set: &Self::RecordSet) -> usize {
+                record_set.num_bytes()
+            }
+        }
+    };
+}
+
+impl_record_set_source!(fasta::Reader<R, P>, fasta::RecordSet);
+impl_record_set_source!(fastq::Reader<R, P>, fastq::RecordSet);
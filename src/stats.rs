@@ -0,0 +1,154 @@
+//! Summary statistics of a completed run
+use anyhow::Error;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Totals collected over a run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunStats {
+    pub num_threads: usize,
+    /// Records processed by the workers
+    pub records: u64,
+    /// Header, sequence and quality bytes read
+    pub bytes: u64,
+    /// Batches processed by the workers
+    pub batches: u64,
+    /// Records discarded by the backpressure policy
+    pub dropped_records: u64,
+    pub dropped_batches: u64,
+    /// Batches processed again after a retryable error, with [`ParallelConfig::with_batch_retry`](crate::ParallelConfig::with_batch_retry)
+    pub retried_batches: u64,
+    /// Reader and processor errors
+    pub errors: u64,
+    /// Errors tolerated with [`ParallelConfig::with_max_errors`](crate::ParallelConfig::with_max_errors)
+    pub error_messages: Vec<String>,
+    /// Checksums of the inputs, with [`ParallelConfig::with_checksums`](crate::ParallelConfig::with_checksums)
+    #[cfg(feature = "checksum")]
+    pub checksums: Vec<crate::checksum::InputChecksum>,
+    /// CRC-32 of every batch in batch order, with [`ParallelConfig::with_batch_checksums`](crate::ParallelConfig::with_batch_checksums)
+    pub batch_checksums: Vec<u32>,
+    /// Bytes read from the inputs as stored, with [`ParallelConfig::with_input_progress`](crate::ParallelConfig::with_input_progress)
+    pub input_bytes_read: u64,
+    /// Bytes read from the inputs after decompression, with [`ParallelConfig::with_input_progress`](crate::ParallelConfig::with_input_progress)
+    pub input_bytes_decompressed: u64,
+    /// Total size of the inputs as stored, or 0 when not tracked
+    pub input_bytes_total: u64,
+    /// Wall-clock time of the run
+    pub elapsed: Duration,
+    /// Wait and busy time of every worker, by thread id
+    pub workers: Vec<WorkerTiming>,
+}
+
+/// Time a worker spent waiting for batches versus processing them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WorkerTiming {
+    pub thread_id: usize,
+    /// Time blocked on the batch queue
+    pub wait: Duration,
+    /// Time spent processing batches
+    pub busy: Duration,
+}
+
+impl WorkerTiming {
+    /// Fraction of the worker's time spent processing
+    pub fn busy_fraction(&self) -> f64 {
+        busy_fraction(self.wait, self.busy)
+    }
+}
+
+fn busy_fraction(wait: Duration, busy: Duration) -> f64 {
+    let total = (wait + busy).as_secs_f64();
+    if total > 0.0 {
+        busy.as_secs_f64() / total
+    } else {
+        0.0
+    }
+}
+
+impl RunStats {
+    pub fn records_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.records as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Fraction of the input read so far, if the input position is tracked
+    pub fn fraction_complete(&self) -> Option<f64> {
+        (self.input_bytes_total > 0)
+            .then(|| (self.input_bytes_read as f64 / self.input_bytes_total as f64).min(1.0))
+    }
+
+    /// Estimated time until the input is read, at the average pace so far
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction_complete()?;
+        if fraction <= 0.0 {
+            return None;
+        }
+        Some(self.elapsed.mul_f64((1.0 - fraction) / fraction))
+    }
+
+    /// Fraction of the workers' time spent processing rather than waiting for batches
+    ///
+    /// Close to 1 the run is CPU-bound and more threads help; far below it
+    /// the workers starve and the run is bound by reading or decompression.
+    pub fn busy_fraction(&self) -> f64 {
+        let wait = self.workers.iter().map(|w| w.wait).sum();
+        let busy = self.workers.iter().map(|w| w.busy).sum();
+        busy_fraction(wait, busy)
+    }
+}
+
+/// Errors a run tolerates before aborting, shared by its worker threads
+#[derive(Debug, Default)]
+pub(crate) struct ErrorBudget {
+    max_errors: u64,
+    used: AtomicU64,
+    messages: Mutex<Vec<String>>,
+}
+
+impl ErrorBudget {
+    pub(crate) fn new(max_errors: u64) -> Self {
+        Self {
+            max_errors,
+            ..Self::default()
+        }
+    }
+
+    /// Takes `err` out of the budget and keeps its message, or returns false once the budget is spent
+    pub(crate) fn tolerate(&self, err: &Error) -> bool {
+        let taken = self.reserve();
+        if taken {
+            self.record(vec![format!("{:#}", err)]);
+        }
+        taken
+    }
+
+    /// Takes one error out of the budget without keeping a message, or returns false once the budget is spent
+    pub(crate) fn reserve(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used < self.max_errors).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    /// Gives back `count` reserved errors, e.g. those of a batch that is processed again
+    pub(crate) fn release(&self, count: u64) {
+        self.used.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    /// Keeps the messages of reserved errors
+    pub(crate) fn record(&self, messages: Vec<String>) {
+        if !messages.is_empty() {
+            self.messages.lock().extend(messages);
+        }
+    }
+
+    pub(crate) fn messages(&self) -> Vec<String> {
+        self.messages.lock().clone()
+    }
+}
@@ -0,0 +1,63 @@
+//! Reproducible subsampling of reads and pairs
+//!
+//! [`Subsampler`] keeps a fraction of the records, deciding for each record
+//! by a hash of its ID rather than by drawing from a generator. The decision
+//! thus doesn't depend on the thread count or the batching, and mates agree
+//! as long as their IDs do up to a `/1` or `/2` suffix, also when R1 and R2
+//! are subsampled in separate runs. Used as a [`PairedMapper`] it decides by
+//! the first mate alone, so pairs always stay together.
+use anyhow::Result;
+
+use crate::header::split_id;
+use crate::mapper::{PairedMapper, ParallelMapper};
+use crate::processors::rng::RecordRng;
+use crate::record::Record;
+use crate::MinimalRefRecord;
+
+/// Keeps each record (or pair) with probability `fraction`, decided by its ID
+#[derive(Debug, Clone)]
+pub struct Subsampler {
+    fraction: f64,
+    seed: u64,
+}
+
+impl Subsampler {
+    pub fn new(fraction: f64) -> Self {
+        Self {
+            fraction: fraction.clamp(0.0, 1.0),
+            seed: 0,
+        }
+    }
+
+    /// Draws a different subsample; runs with the same seed keep the same records
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Whether the record, and any mate of it, is kept
+    pub fn keeps(&self, record: &Record<'_>) -> bool {
+        let (id, _) = split_id(record.ref_head());
+        let key = id
+            .strip_suffix(b"/1")
+            .or_else(|| id.strip_suffix(b"/2"))
+            .unwrap_or(id);
+        RecordRng::from_key(self.seed, key).chance(self.fraction)
+    }
+}
+
+impl ParallelMapper for Subsampler {
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+        Ok(self.keeps(&record).then_some(record))
+    }
+}
+
+impl PairedMapper for Subsampler {
+    fn map_pair<'r>(
+        &mut self,
+        record1: Record<'r>,
+        record2: Record<'r>,
+    ) -> Result<Option<(Record<'r>, Record<'r>)>> {
+        Ok(self.keeps(&record1).then_some((record1, record2)))
+    }
+}
@@ -0,0 +1,379 @@
+//! Deterministic synthetic reads for tests and benchmarks
+//!
+//! [`SyntheticReads`] describes a FASTA or FASTQ file (record count, length
+//! distribution, quality profile, optional gzip) and generates it lazily as
+//! an `io::Read`, so processor tests and benchmarks need no fixture files.
+//! The same seed always yields the same bytes.
+//!
+//! [`check_parallel_consistency`] runs a processor over such input once
+//! sequentially and several times in parallel and compares the results, which
+//! catches processors whose merged state depends on thread scheduling.
+use anyhow::{bail, Result};
+use std::fmt::Debug;
+use std::io::{self, Read};
+
+use crate::fastx::{append_record, FastxReader, FastxRecordSet, Format};
+use crate::processors::rng::RecordRng;
+use crate::record::RecordSetLike;
+use crate::{ParallelProcessor, ParallelReader};
+
+/// Number of parallel runs compared against the sequential run
+const PARALLEL_RUNS: usize = 3;
+
+/// Distribution of generated sequence lengths
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthDist {
+    Fixed(usize),
+    /// Uniform over `min..=max`
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    /// Normal, rounded and clamped to at least 1
+    Normal {
+        mean: f64,
+        sd: f64,
+    },
+}
+
+/// Profile of generated Phred scores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityProfile {
+    Constant(u8),
+    /// Uniform over `min..=max`
+    Uniform {
+        min: u8,
+        max: u8,
+    },
+    /// Falls linearly from `start` to `end` along the read with +-`noise`, as on Illumina runs
+    Decaying {
+        start: u8,
+        end: u8,
+        noise: u8,
+    },
+}
+
+/// Description of a synthetic FASTA/FASTQ file
+#[derive(Debug, Clone)]
+pub struct SyntheticReads {
+    format: Format,
+    num_records: usize,
+    lengths: LengthDist,
+    quality: QualityProfile,
+    line_width: Option<usize>,
+    seed: u64,
+    gzip: bool,
+}
+
+impl SyntheticReads {
+    /// `num_records` FASTQ records of 150 bases with decaying qualities
+    pub fn fastq(num_records: usize) -> Self {
+        Self {
+            format: Format::Fastq,
+            num_records,
+            lengths: LengthDist::Fixed(150),
+            quality: QualityProfile::Decaying {
+                start: 38,
+                end: 25,
+                noise: 3,
+            },
+            line_width: None,
+            seed: 0,
+            gzip: false,
+        }
+    }
+
+    /// `num_records` FASTA records of 150 bases
+    pub fn fasta(num_records: usize) -> Self {
+        Self {
+            format: Format::Fasta,
+            ..Self::fastq(num_records)
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_lengths(mut self, lengths: LengthDist) -> Self {
+        self.lengths = lengths;
+        self
+    }
+
+    pub fn with_quality(mut self, quality: QualityProfile) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    /// Wraps FASTA sequences at `line_width` columns
+    pub fn with_line_width(mut self, line_width: usize) -> Self {
+        self.line_width = Some(line_width.max(1));
+        self
+    }
+
+    /// Compresses the output with gzip
+    pub fn gzip(mut self) -> Self {
+        self.gzip = true;
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Lazily generated file contents
+    pub fn reader(&self) -> Box<dyn Read + Send> {
+        let reader = SyntheticReader {
+            spec: self.clone(),
+            rng: RecordRng::new(self.seed),
+            next_record: 0,
+            buffer: Vec::new(),
+            pos: 0,
+        };
+        if self.gzip {
+            Box::new(flate2::read::GzEncoder::new(
+                reader,
+                flate2::Compression::new(1),
+            ))
+        } else {
+            Box::new(reader)
+        }
+    }
+
+    /// Whole file contents
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.reader()
+            .read_to_end(&mut data)
+            .expect("generating reads does not fail");
+        data
+    }
+}
+
+/// Generator behind [`SyntheticReads::reader`], producing one record at a time
+struct SyntheticReader {
+    spec: SyntheticReads,
+    rng: RecordRng,
+    next_record: usize,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl SyntheticReader {
+    fn length(&mut self) -> usize {
+        match self.spec.lengths {
+            LengthDist::Fixed(len) => len,
+            LengthDist::Uniform { min, max } => self.rng.range(min, max),
+            LengthDist::Normal { mean, sd } => {
+                // Box-Muller transform
+                let (u1, u2) = (1.0 - self.rng.unit(), self.rng.unit());
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                (mean + sd * z).round().max(1.0) as usize
+            }
+        }
+    }
+
+    fn quality(&mut self, pos: usize, len: usize) -> u8 {
+        let score = match self.spec.quality {
+            QualityProfile::Constant(score) => score,
+            QualityProfile::Uniform { min, max } => {
+                self.rng.range(min as usize, max as usize) as u8
+            }
+            QualityProfile::Decaying { start, end, noise } => {
+                let frac = pos as f64 / len.saturating_sub(1).max(1) as f64;
+                let base = start as f64 + (end as f64 - start as f64) * frac;
+                let jitter = self.rng.range(0, 2 * noise as usize) as f64 - noise as f64;
+                (base + jitter).round().clamp(0.0, 93.0) as u8
+            }
+        };
+        score.min(93) + 33
+    }
+
+    /// Appends the next record to the buffer
+    fn generate(&mut self) {
+        let len = self.length();
+        let head = format!("read{} len={}", self.next_record, len);
+        let seq: Vec<u8> = (0..len)
+            .map(|_| b"ACGT"[(self.rng.next_u64() >> 62) as usize])
+            .collect();
+        self.next_record += 1;
+        match (self.spec.format, self.spec.line_width) {
+            (Format::Fastq, _) => {
+                let qual: Vec<u8> = (0..len).map(|pos| self.quality(pos, len)).collect();
+                append_record(
+                    &mut self.buffer,
+                    Format::Fastq,
+                    head.as_bytes(),
+                    &seq,
+                    &qual,
+                );
+            }
+            (Format::Fasta, Some(width)) => {
+                self.buffer.push(b'>');
+                self.buffer.extend_from_slice(head.as_bytes());
+                self.buffer.push(b'\n');
+                for line in seq.chunks(width) {
+                    self.buffer.extend_from_slice(line);
+                    self.buffer.push(b'\n');
+                }
+            }
+            (Format::Fasta, None) => {
+                append_record(&mut self.buffer, Format::Fasta, head.as_bytes(), &seq, &[])
+            }
+        }
+    }
+}
+
+impl Read for SyntheticReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() {
+            self.buffer.clear();
+            self.pos = 0;
+            if self.next_record == self.spec.num_records {
+                return Ok(0);
+            }
+            self.generate();
+        }
+        let n = buf.len().min(self.buffer.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Runs processors from `make` over `reads` sequentially and with `num_threads` threads and compares the results
+///
+/// `make` creates a processor with fresh shared state and `finish` extracts
+/// the merged result from it once the run is done. The sequential run feeds
+/// all batches, in order, to a single processor on the calling thread and
+/// serves as the reference. Fails on the first parallel run whose result
+/// differs.
+pub fn check_parallel_consistency<T, O, M, F>(
+    reads: &SyntheticReads,
+    num_threads: usize,
+    make: M,
+    finish: F,
+) -> Result<()>
+where
+    T: ParallelProcessor,
+    O: PartialEq + Debug,
+    M: Fn() -> T,
+    F: Fn(T) -> O,
+{
+    let open = || -> Result<FastxReader> {
+        let (input, _compression) = niffler::send::get_reader(reads.reader())?;
+        Ok(FastxReader::new(input, reads.format()))
+    };
+
+    let mut reader = open()?;
+    let mut processor = make();
+    processor.set_thread_id(0);
+    let mut record_set = FastxRecordSet::default();
+    let mut record_set_idx = 0;
+    while let Some(result) = reader.read_record_set(&mut record_set) {
+        result?;
+        record_set.process(&mut processor, record_set_idx)?;
+        processor.on_batch_complete()?;
+        record_set_idx += 1;
+    }
+    processor.on_thread_complete()?;
+    let expected = finish(processor);
+
+    for run in 0..PARALLEL_RUNS {
+        let processor = make();
+        open()?.process_parallel(processor.clone(), num_threads)?;
+        let result = finish(processor);
+        if result != expected {
+            bail!(
+                "Run {} with {} threads differs from the sequential run:\n  sequential: {:?}\n  parallel:   {:?}",
+                run + 1,
+                num_threads,
+                expected,
+                result
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Panicking form of [`check_parallel_consistency`] for use in tests
+pub fn assert_parallel_consistent<T, O, M, F>(
+    reads: &SyntheticReads,
+    num_threads: usize,
+    make: M,
+    finish: F,
+) where
+    T: ParallelProcessor,
+    O: PartialEq + Debug,
+    M: Fn() -> T,
+    F: Fn(T) -> O,
+{
+    if let Err(e) = check_parallel_consistency(reads, num_threads, make, finish) {
+        panic!("{:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MinimalRefRecord;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Counts records per thread, merging the counts correctly or not
+    #[derive(Clone, Default)]
+    struct ThreadCounts {
+        count: usize,
+        counts: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl ThreadCounts {
+        fn counts(&self) -> Vec<usize> {
+            self.counts.lock().clone()
+        }
+    }
+
+    impl ParallelProcessor for ThreadCounts {
+        fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+            &mut self,
+            _record: Rf,
+            _record_set_idx: usize,
+            _record_idx: usize,
+        ) -> Result<()> {
+            self.count += 1;
+            Ok(())
+        }
+
+        fn on_thread_complete(&mut self) -> Result<()> {
+            self.counts.lock().push(self.count);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn merged_results_are_consistent() {
+        let reads = SyntheticReads::fastq(5000).gzip();
+        assert_parallel_consistent(&reads, 4, ThreadCounts::default, |processor| {
+            processor.counts().iter().sum::<usize>()
+        });
+    }
+
+    #[test]
+    fn unmerged_per_thread_results_are_caught() {
+        let reads = SyntheticReads::fastq(5000);
+        let err =
+            check_parallel_consistency(&reads, 4, ThreadCounts::default, |processor| {
+                processor.counts()
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("differs from the sequential run"));
+    }
+
+    #[test]
+    fn same_seed_gives_same_bytes() {
+        let reads = SyntheticReads::fasta(100).with_line_width(60).with_seed(7);
+        assert_eq!(reads.to_bytes(), reads.clone().to_bytes());
+        assert_ne!(reads.to_bytes(), reads.with_seed(8).to_bytes());
+    }
+}
@@ -0,0 +1,143 @@
+//! Quality trimming
+//!
+//! [`QualityTrimmer`] is a [`ParallelMapper`] with the usual Trimmomatic
+//! steps, applied in this order: `LEADING`, `TRAILING`, `SLIDINGWINDOW` and
+//! `MINLEN`. Trimmed records borrow from the input, so trimming copies
+//! nothing until the record is written. FASTA records have no qualities and
+//! are only subject to the minimum length.
+use anyhow::{bail, Result};
+
+use crate::mapper::ParallelMapper;
+use crate::quality::QualityEncoding;
+use crate::record::Record;
+
+/// Trimmomatic-style quality trimmer
+#[derive(Debug, Clone, Default)]
+pub struct QualityTrimmer {
+    leading: Option<u8>,
+    trailing: Option<u8>,
+    window: Option<(usize, u8)>,
+    min_len: usize,
+    encoding: QualityEncoding,
+}
+
+impl QualityTrimmer {
+    /// Trimmer without any step, to be configured with the `with_*` methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes leading bases below quality `min_qual` (`LEADING`)
+    pub fn with_leading(mut self, min_qual: u8) -> Self {
+        self.leading = Some(min_qual);
+        self
+    }
+
+    /// Removes trailing bases below quality `min_qual` (`TRAILING`)
+    pub fn with_trailing(mut self, min_qual: u8) -> Self {
+        self.trailing = Some(min_qual);
+        self
+    }
+
+    /// Cuts the read at the first window of `size` bases with a mean quality below `min_qual` (`SLIDINGWINDOW`)
+    ///
+    /// The bases of that window that reach `min_qual` on their own before
+    /// the first one that doesn't are kept, as in Trimmomatic.
+    pub fn with_sliding_window(mut self, size: usize, min_qual: u8) -> Self {
+        self.window = Some((size.max(1), min_qual));
+        self
+    }
+
+    /// Drops reads shorter than `min_len` after trimming (`MINLEN`)
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+
+    /// Sets the encoding of the quality characters, Phred+33 by default
+    pub fn with_encoding(mut self, encoding: QualityEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Range of `qual` kept by the trimming steps
+    pub fn trim_range(&self, qual: &[u8]) -> (usize, usize) {
+        let offset = self.encoding.offset();
+        let score = |q: u8| q.saturating_sub(offset);
+        let (mut start, mut end) = (0, qual.len());
+        if let Some(min_qual) = self.leading {
+            while start < end && score(qual[start]) < min_qual {
+                start += 1;
+            }
+        }
+        if let Some(min_qual) = self.trailing {
+            while end > start && score(qual[end - 1]) < min_qual {
+                end -= 1;
+            }
+        }
+        if let Some((size, min_qual)) = self.window {
+            end = start + sliding_window_len(&qual[start..end], size, min_qual, offset);
+        }
+        (start, end)
+    }
+}
+
+/// Length kept by a sliding window cut of `qual`
+fn sliding_window_len(qual: &[u8], size: usize, min_qual: u8, offset: u8) -> usize {
+    let score = |q: u8| u32::from(q.saturating_sub(offset));
+    if qual.len() < size {
+        // A read shorter than the window is checked as one window
+        let total: u32 = qual.iter().map(|&q| score(q)).sum();
+        if qual.is_empty() || total >= u32::from(min_qual) * qual.len() as u32 {
+            return qual.len();
+        }
+        return qual
+            .iter()
+            .take_while(|&&q| score(q) >= u32::from(min_qual))
+            .count();
+    }
+    let required = u32::from(min_qual) * size as u32;
+    let mut total: u32 = qual[..size].iter().map(|&q| score(q)).sum();
+    for start in 0..=qual.len() - size {
+        if start > 0 {
+            total = total - score(qual[start - 1]) + score(qual[start + size - 1]);
+        }
+        if total < required {
+            let kept = qual[start..start + size]
+                .iter()
+                .take_while(|&&q| score(q) >= u32::from(min_qual))
+                .count();
+            return start + kept;
+        }
+    }
+    qual.len()
+}
+
+impl ParallelMapper for QualityTrimmer {
+    fn map_record<'r>(&mut self, record: Record<'r>) -> Result<Option<Record<'r>>> {
+        let record = match record {
+            Record::Fastq { head, seq, qual } => {
+                if seq.len() != qual.len() {
+                    bail!(
+                        "Sequence {} has {} bases but {} quality scores",
+                        String::from_utf8_lossy(head),
+                        seq.len(),
+                        qual.len()
+                    );
+                }
+                let (start, end) = self.trim_range(qual);
+                Record::Fastq {
+                    head,
+                    seq: &seq[start..end],
+                    qual: &qual[start..end],
+                }
+            }
+            record => record,
+        };
+        let len = match record {
+            Record::Fastq { seq, .. } => seq.len(),
+            Record::Fasta { seq, .. } => seq.iter().filter(|&&b| b != b'\n' && b != b'\r').count(),
+        };
+        Ok((len >= self.min_len).then_some(record))
+    }
+}
@@ -0,0 +1,280 @@
+//! UMI and cell barcode extraction
+//!
+//! [`UmiExtractor`] clips the UMI and cell barcode bases from the start of R1
+//! and/or R2 and appends them to the read names, as `umi_tools extract` does
+//! with string patterns: every `N` of a [`UmiPattern`] is a UMI base, every
+//! `C` a cell barcode base and every `X` a base that stays in the read. Both
+//! mates are renamed to `<id>_<cell>_<umi>`, with the UMI and barcode bases
+//! of R1 followed by those of R2, and keep their description.
+//!
+//! The names are rewritten, so the extractor is not a
+//! [`PairedMapper`](crate::mapper::PairedMapper) but runs in a
+//! [`UmiWriter`], which writes the pairs to two outputs in input order.
+//! Pairs with a mate shorter than its pattern are dropped.
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use crate::header::split_id;
+use crate::paired::PairedReader;
+use crate::processor::PairedParallelProcessor;
+use crate::reader::PairedParallelReader;
+use crate::record::Record;
+use crate::writer::ParallelWriter;
+use crate::MinimalRefRecord;
+
+/// Bases at the start of a read, one of `N` (UMI), `C` (cell barcode) or `X` (kept)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UmiPattern(Vec<u8>);
+
+impl UmiPattern {
+    /// Parses a umi_tools string pattern such as `NNNNNNNN` or `CCCCCCCCNNNNNNNN`
+    pub fn new(pattern: &str) -> Result<Self> {
+        let pattern = pattern.as_bytes().to_ascii_uppercase();
+        if let Some(&base) = pattern.iter().find(|b| !matches!(b, b'N' | b'C' | b'X')) {
+            bail!(
+                "Invalid base {:?} in UMI pattern, expected N, C or X",
+                base as char
+            );
+        }
+        Ok(Self(pattern))
+    }
+
+    /// UMI of the first `len` bases
+    pub fn umi(len: usize) -> Self {
+        Self(vec![b'N'; len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn has_cell(&self) -> bool {
+        self.0.contains(&b'C')
+    }
+}
+
+/// Mate with its UMI and barcode bases clipped
+#[derive(Debug, Clone, Default)]
+struct ClippedMate {
+    head: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl ClippedMate {
+    fn record(&self) -> Record<'_> {
+        Record::Fastq {
+            head: &self.head,
+            seq: &self.seq,
+            qual: &self.qual,
+        }
+    }
+}
+
+/// Clips UMIs and cell barcodes into the read names, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct UmiExtractor {
+    pattern1: Option<UmiPattern>,
+    pattern2: Option<UmiPattern>,
+    separator: u8,
+    umi: Vec<u8>,
+    cell: Vec<u8>,
+    mates: [ClippedMate; 2],
+}
+
+impl Default for UmiExtractor {
+    fn default() -> Self {
+        Self {
+            pattern1: None,
+            pattern2: None,
+            separator: b'_',
+            umi: Vec::new(),
+            cell: Vec::new(),
+            mates: Default::default(),
+        }
+    }
+}
+
+impl UmiExtractor {
+    /// Extractor without any pattern, to be configured with the `with_*` methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clips `pattern` from the start of R1
+    pub fn with_r1_pattern(mut self, pattern: UmiPattern) -> Self {
+        self.pattern1 = Some(pattern);
+        self
+    }
+
+    /// Clips `pattern` from the start of R2
+    pub fn with_r2_pattern(mut self, pattern: UmiPattern) -> Self {
+        self.pattern2 = Some(pattern);
+        self
+    }
+
+    /// Separates the ID, barcode and UMI with `separator` instead of `_`
+    pub fn with_separator(mut self, separator: u8) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Clips both mates and renames them, or returns `None` if a mate is shorter than its pattern
+    ///
+    /// The returned records borrow from buffers of the extractor, so they
+    /// are only valid until the next call.
+    pub fn extract_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: &Rf,
+        record2: &Rf,
+    ) -> Result<Option<(Record<'_>, Record<'_>)>> {
+        self.umi.clear();
+        self.cell.clear();
+        let patterns = [&self.pattern1, &self.pattern2];
+        for ((record, pattern), mate) in [record1, record2]
+            .into_iter()
+            .zip(patterns)
+            .zip(&mut self.mates)
+        {
+            if !clip(record, pattern.as_ref(), &mut self.umi, &mut self.cell, mate)? {
+                return Ok(None);
+            }
+        }
+        let has_cell = [&self.pattern1, &self.pattern2]
+            .into_iter()
+            .flatten()
+            .any(UmiPattern::has_cell);
+        for (record, mate) in [record1, record2].into_iter().zip(&mut self.mates) {
+            let (id, description) = split_id(record.ref_head());
+            mate.head.clear();
+            mate.head.extend_from_slice(id);
+            if has_cell {
+                mate.head.push(self.separator);
+                mate.head.extend_from_slice(&self.cell);
+            }
+            mate.head.push(self.separator);
+            mate.head.extend_from_slice(&self.umi);
+            if let Some(description) = description {
+                mate.head.push(b' ');
+                mate.head.extend_from_slice(description);
+            }
+        }
+        let [mate1, mate2] = &self.mates;
+        Ok(Some((mate1.record(), mate2.record())))
+    }
+}
+
+/// Moves the bases of `record` covered by `pattern` to `umi` and `cell` and the rest to `mate`
+///
+/// Returns `false` if the record is shorter than the pattern.
+fn clip<'a, Rf: MinimalRefRecord<'a>>(
+    record: &Rf,
+    pattern: Option<&UmiPattern>,
+    umi: &mut Vec<u8>,
+    cell: &mut Vec<u8>,
+    mate: &mut ClippedMate,
+) -> Result<bool> {
+    let (seq, qual) = match record.to_record() {
+        Record::Fastq { seq, qual, .. } => (seq, qual),
+        Record::Fasta { head, .. } => bail!(
+            "Cannot extract the UMI of FASTA record {}",
+            String::from_utf8_lossy(head)
+        ),
+    };
+    let pattern = pattern.map_or(&[][..], |pattern| &pattern.0[..]);
+    if seq.len() < pattern.len() || qual.len() != seq.len() {
+        return Ok(false);
+    }
+    mate.seq.clear();
+    mate.qual.clear();
+    for (i, &kind) in pattern.iter().enumerate() {
+        match kind {
+            b'N' => umi.push(seq[i]),
+            b'C' => cell.push(seq[i]),
+            _ => {
+                mate.seq.push(seq[i]);
+                mate.qual.push(qual[i]);
+            }
+        }
+    }
+    mate.seq.extend_from_slice(&seq[pattern.len()..]);
+    mate.qual.extend_from_slice(&qual[pattern.len()..]);
+    Ok(true)
+}
+
+/// Processor writing the pairs renamed by a [`UmiExtractor`] to two outputs, in input order
+#[derive(Clone)]
+pub struct UmiWriter {
+    extractor: UmiExtractor,
+    out1: ParallelWriter,
+    out2: ParallelWriter,
+}
+
+impl UmiWriter {
+    /// Writes the pairs renamed by `extractor` to `out1` and `out2`; call `finish()` on both after the run
+    pub fn new(extractor: UmiExtractor, out1: ParallelWriter, out2: ParallelWriter) -> Self {
+        Self {
+            extractor,
+            out1,
+            out2,
+        }
+    }
+}
+
+impl PairedParallelProcessor for UmiWriter {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        _record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        self.out1.skip(record_set_idx);
+        self.out2.skip(record_set_idx);
+        if let Some((mate1, mate2)) = self.extractor.extract_pair(&record1, &record2)? {
+            self.out1.write_record(record_set_idx, &mate1)?;
+            self.out2.write_record(record_set_idx, &mate2)?;
+        }
+        Ok((record1, record2))
+    }
+
+    fn on_batch_complete(&mut self) -> Result<()> {
+        self.out1.finish_batch()?;
+        self.out2.finish_batch()
+    }
+
+    fn on_batch_retry(&mut self) -> Result<()> {
+        self.out1.discard_batch();
+        self.out2.discard_batch();
+        Ok(())
+    }
+}
+
+/// Writes the pairs of R1/R2 files renamed by `extractor` to `out1`/`out2`, in input order
+///
+/// Outputs are compressed according to their extension.
+pub fn extract_umis_paired_parallel<P1, P2, P3, P4>(
+    r1: P1,
+    r2: P2,
+    out1: P3,
+    out2: P4,
+    extractor: &UmiExtractor,
+    num_threads: usize,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    P4: AsRef<Path>,
+{
+    let out1 = ParallelWriter::from_path(out1)?;
+    let out2 = ParallelWriter::from_path(out2)?;
+    let writer = UmiWriter::new(extractor.clone(), out1.clone(), out2.clone());
+    PairedReader::from_paths(r1, r2)?.process_parallel_paired(writer, num_threads)?;
+    out1.finish()?;
+    out2.finish()
+}
@@ -0,0 +1,233 @@
+//! Validation of FASTA/FASTQ files without a user processor
+//!
+//! The records are parsed by the usual reader machinery and checked on the
+//! workers: sequence characters, quality characters and, for FASTQ, equal
+//! sequence and quality lengths. Paired inputs are additionally checked for
+//! mates with matching IDs and files with the same number of records. The run
+//! stops at the first invalid record.
+use anyhow::{anyhow, Result};
+use parking_lot::Mutex;
+use std::fmt;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::fastx::{FastxReader, Format};
+use crate::paired::PairedReader;
+use crate::processor::{PairedParallelProcessor, ParallelProcessor};
+use crate::reader::{PairedParallelReader, ParallelReader};
+use crate::MinimalRefRecord;
+
+/// Outcome of a validation run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Records (or pairs) that passed validation
+    pub records: u64,
+    /// Sequence bases of those records
+    pub bases: u64,
+    pub first_error: Option<ValidationError>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.first_error.is_none()
+    }
+}
+
+/// Location and cause of the first invalid record
+///
+/// Parse errors are raised by the reader and carry their position in the
+/// message instead of record indices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub record_set_idx: Option<usize>,
+    pub record_idx: Option<usize>,
+    pub record_id: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(set), Some(idx)) = (self.record_set_idx, self.record_idx) {
+            write!(f, "record set {}, record {}", set, idx)?;
+            if let Some(id) = &self.record_id {
+                write!(f, " ({})", id)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Counters and first error shared by all validator clones
+#[derive(Default)]
+struct ValidationState {
+    records: AtomicU64,
+    bases: AtomicU64,
+    first_error: Mutex<Option<ValidationError>>,
+}
+
+impl ValidationState {
+    /// Keeps the error with the lowest indices, so the result does not depend on thread timing
+    fn record_error(&self, error: ValidationError) {
+        let mut first = self.first_error.lock();
+        let key = |e: &ValidationError| (e.record_set_idx, e.record_idx);
+        if first.as_ref().is_none_or(|f| key(&error) < key(f)) {
+            *first = Some(error);
+        }
+    }
+
+    fn into_report(self, result: Result<()>) -> ValidationReport {
+        let first_error = self.first_error.into_inner().or_else(|| {
+            result.err().map(|e| ValidationError {
+                record_set_idx: None,
+                record_idx: None,
+                record_id: None,
+                message: format!("{:#}", e),
+            })
+        });
+        ValidationReport {
+            records: self.records.into_inner(),
+            bases: self.bases.into_inner(),
+            first_error,
+        }
+    }
+}
+
+/// Checks a single record, returning the number of sequence bases
+fn check_record<'a, Rf: MinimalRefRecord<'a>>(record: &Rf, format: Format) -> Result<u64, String> {
+    let mut bases = 0;
+    for &b in record.ref_seq() {
+        match b {
+            b'\n' | b'\r' => {}
+            b if b.is_ascii_alphabetic() || matches!(b, b'*' | b'-' | b'.') => bases += 1,
+            b => return Err(format!("invalid sequence character {:?}", b as char)),
+        }
+    }
+    let qual = record.ref_qual();
+    if format == Format::Fastq {
+        if qual.len() as u64 != bases {
+            return Err(format!(
+                "sequence and quality lengths differ ({} vs {})",
+                bases,
+                qual.len()
+            ));
+        }
+        if let Some(&q) = qual.iter().find(|&&q| !(b'!'..=b'~').contains(&q)) {
+            return Err(format!("invalid quality character {:?}", q as char));
+        }
+    }
+    Ok(bases)
+}
+
+/// Mate ID without a trailing `/1` or `/2`
+pub(crate) fn mate_id(id: &str) -> &str {
+    id.strip_suffix("/1")
+        .or_else(|| id.strip_suffix("/2"))
+        .unwrap_or(id)
+}
+
+#[derive(Clone)]
+struct Validator {
+    format: Format,
+    state: Arc<ValidationState>,
+}
+
+impl Validator {
+    fn new(format: Format) -> Self {
+        Self {
+            format,
+            state: Arc::default(),
+        }
+    }
+
+    fn fail<'a, Rf: MinimalRefRecord<'a>>(
+        &self,
+        record: &Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+        message: String,
+    ) -> anyhow::Error {
+        let error = ValidationError {
+            record_set_idx: Some(record_set_idx),
+            record_idx: Some(record_idx),
+            record_id: record.ref_id().ok().map(str::to_string),
+            message,
+        };
+        let err = anyhow!("{}", error);
+        self.state.record_error(error);
+        err
+    }
+}
+
+impl ParallelProcessor for Validator {
+    fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<()> {
+        let bases = check_record(&record, self.format)
+            .map_err(|msg| self.fail(&record, record_set_idx, record_idx, msg))?;
+        self.state.records.fetch_add(1, Ordering::Relaxed);
+        self.state.bases.fetch_add(bases, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl PairedParallelProcessor for Validator {
+    fn process_record_pair<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+        record_set_idx: usize,
+        record_idx: usize,
+    ) -> Result<(Rf, Rf)> {
+        let bases1 = check_record(&record1, self.format)
+            .map_err(|msg| self.fail(&record1, record_set_idx, record_idx, format!("R1: {}", msg)))?;
+        let bases2 = check_record(&record2, self.format)
+            .map_err(|msg| self.fail(&record2, record_set_idx, record_idx, format!("R2: {}", msg)))?;
+        let (id1, id2) = (record1.ref_id().unwrap_or(""), record2.ref_id().unwrap_or(""));
+        if mate_id(id1) != mate_id(id2) {
+            let msg = format!("mate IDs differ ({} vs {})", id1, id2);
+            return Err(self.fail(&record1, record_set_idx, record_idx, msg));
+        }
+        self.state.records.fetch_add(1, Ordering::Relaxed);
+        self.state.bases.fetch_add(bases1 + bases2, Ordering::Relaxed);
+        Ok((record1, record2))
+    }
+}
+
+/// Validates a (possibly compressed) FASTA/FASTQ file
+///
+/// Invalid records are reported in the returned [`ValidationReport`]; only a
+/// file that cannot be opened is returned as an error.
+pub fn validate_parallel<P: AsRef<Path>>(path: P, num_threads: usize) -> Result<ValidationReport> {
+    let reader = FastxReader::from_path(path)?;
+    let validator = Validator::new(reader.format());
+    let result = reader.process_parallel(validator.clone(), num_threads);
+    Ok(unwrap_state(validator).into_report(result))
+}
+
+/// Validates two mate files, including their pair synchronization
+pub fn validate_paired_parallel<P1, P2>(
+    path1: P1,
+    path2: P2,
+    num_threads: usize,
+) -> Result<ValidationReport>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let reader = PairedReader::from_paths(path1, path2)?;
+    let validator = Validator::new(reader.format());
+    let result = reader.process_parallel_paired(validator.clone(), num_threads);
+    Ok(unwrap_state(validator).into_report(result))
+}
+
+/// Takes the shared state back once all worker clones are dropped
+fn unwrap_state(validator: Validator) -> ValidationState {
+    Arc::into_inner(validator.state).expect("workers hold no validator after the run")
+}
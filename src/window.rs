@@ -0,0 +1,281 @@
+//! Windowed processing of long FASTA sequences
+//!
+//! Per-record parallelism gives no speedup on a genome with a handful of
+//! chromosomes. [`WindowedReader`] instead streams every sequence and cuts it
+//! into overlapping windows, which are dispatched in batches to the workers.
+//! Only the bases of the windows in flight are held in memory, never a whole
+//! sequence.
+use anyhow::{bail, Result};
+use flate2::Crc;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::batch::BatchSize;
+use crate::config::ParallelConfig;
+use crate::dispatch::Dispatcher;
+use crate::fastx::{open_path, BoxedReader, Format};
+use crate::macro_impl::{run_parallel, WindowWorker};
+use crate::processor::{apply_error_action, WindowProcessor};
+use crate::stats::RunStats;
+
+/// Default number of windows per batch
+pub const DEFAULT_WINDOWS_PER_BATCH: usize = 64;
+
+/// A window of a sequence, with 0-based half-open coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window<'a> {
+    /// ID of the sequence (header up to the first whitespace)
+    pub name: &'a str,
+    /// Index of the sequence in the file
+    pub record_idx: usize,
+    pub start: usize,
+    pub end: usize,
+    /// Bases of the window, without line breaks
+    pub seq: &'a [u8],
+}
+
+/// Consecutive windows of one sequence
+#[derive(Debug, Default)]
+struct WindowChunk {
+    name: String,
+    record_idx: usize,
+    /// Sequence coordinate of `data[0]`
+    offset: usize,
+    data: Vec<u8>,
+    /// Window ranges, relative to `data`
+    windows: Vec<(usize, usize)>,
+}
+
+impl WindowChunk {
+    fn window(&self, idx: usize) -> Window<'_> {
+        let (start, end) = self.windows[idx];
+        Window {
+            name: &self.name,
+            record_idx: self.record_idx,
+            start: self.offset + start,
+            end: self.offset + end,
+            seq: &self.data[start..end],
+        }
+    }
+}
+
+impl BatchSize for WindowChunk {
+    fn num_records(&self) -> usize {
+        self.windows.len()
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    fn checksum(&self, crc: &mut Crc) {
+        crc.update(self.name.as_bytes());
+        crc.update(&self.offset.to_le_bytes());
+        crc.update(&self.data);
+    }
+}
+
+/// Reader that cuts FASTA sequences into overlapping windows
+pub struct WindowedReader<R: io::Read> {
+    inner: BufReader<R>,
+    window_size: usize,
+    step: usize,
+    windows_per_batch: usize,
+    line: Vec<u8>,
+    /// Header of the next sequence, read while finishing the current one
+    next_header: Option<Vec<u8>>,
+    name: String,
+    record_idx: Option<usize>,
+    record_done: bool,
+    /// Bases not yet dropped, starting at the next window
+    pending: Vec<u8>,
+    pending_start: usize,
+}
+
+impl WindowedReader<BoxedReader> {
+    /// Opens a (possibly compressed) FASTA file
+    pub fn from_path<P: AsRef<Path>>(path: P, window_size: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let (handle, format) = open_path(path)?;
+        if format != Format::Fasta {
+            bail!("{} is not a FASTA file", path.display());
+        }
+        Ok(Self::new(handle, window_size))
+    }
+}
+
+impl<R: io::Read> WindowedReader<R> {
+    /// Cuts the sequences of a FASTA stream into non-overlapping windows of `window_size` bases
+    pub fn new(inner: R, window_size: usize) -> Self {
+        let window_size = window_size.max(1);
+        Self {
+            inner: BufReader::new(inner),
+            window_size,
+            step: window_size,
+            windows_per_batch: DEFAULT_WINDOWS_PER_BATCH,
+            line: Vec::new(),
+            next_header: None,
+            name: String::new(),
+            record_idx: None,
+            record_done: true,
+            pending: Vec::new(),
+            pending_start: 0,
+        }
+    }
+
+    /// Sets the number of bases shared by consecutive windows
+    ///
+    /// Must be smaller than the window size.
+    pub fn with_overlap(mut self, overlap: usize) -> Self {
+        assert!(overlap < self.window_size, "Overlap must be smaller than the window size");
+        self.step = self.window_size - overlap;
+        self
+    }
+
+    pub fn with_windows_per_batch(mut self, windows_per_batch: usize) -> Self {
+        self.windows_per_batch = windows_per_batch.max(1);
+        self
+    }
+
+    /// Reads the next line into `self.line`, returning false at EOF
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        Ok(self.inner.read_until(b'\n', &mut self.line)? > 0)
+    }
+
+    /// Moves to the next sequence, returning false at EOF
+    fn start_record(&mut self) -> Result<bool> {
+        let header = match self.next_header.take() {
+            Some(header) => header,
+            None => loop {
+                if !self.read_line()? {
+                    return Ok(false);
+                }
+                match self.line.first() {
+                    Some(b'>') => break std::mem::take(&mut self.line),
+                    Some(b) if !b.is_ascii_whitespace() => {
+                        bail!("Expected '>' at the start of a FASTA record")
+                    }
+                    _ => {}
+                }
+            },
+        };
+        let id = header[1..]
+            .split(|b| b.is_ascii_whitespace())
+            .next()
+            .unwrap_or_default();
+        self.name = String::from_utf8_lossy(id).into_owned();
+        self.record_idx = Some(self.record_idx.map_or(0, |idx| idx + 1));
+        self.record_done = false;
+        self.pending.clear();
+        self.pending_start = 0;
+        Ok(true)
+    }
+
+    /// Reads sequence lines until `pending` holds `target` bases or the sequence ends
+    fn fill_pending(&mut self, target: usize) -> Result<()> {
+        while self.pending.len() < target && !self.record_done {
+            if !self.read_line()? {
+                self.record_done = true;
+            } else if self.line.first() == Some(&b'>') {
+                self.next_header = Some(std::mem::take(&mut self.line));
+                self.record_done = true;
+            } else {
+                let bases = self.line.iter().filter(|b| !b.is_ascii_whitespace());
+                self.pending.extend(bases);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills `chunk` with the next windows of the current sequence
+    fn read_windows(&mut self, chunk: &mut WindowChunk) -> Option<Result<()>> {
+        loop {
+            if self.record_done && self.pending.is_empty() {
+                match self.start_record() {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let target = self.window_size + (self.windows_per_batch - 1) * self.step;
+            if let Err(e) = self.fill_pending(target) {
+                return Some(Err(e));
+            }
+
+            chunk.windows.clear();
+            let overlap = self.window_size - self.step;
+            let mut start = 0;
+            while chunk.windows.len() < self.windows_per_batch && start < self.pending.len() {
+                let end = start + self.window_size;
+                if end <= self.pending.len() {
+                    chunk.windows.push((start, end));
+                } else if self.record_done {
+                    // The last window is shorter; skip it if the previous one covered it
+                    if self.pending_start + start == 0 || self.pending.len() - start > overlap {
+                        chunk.windows.push((start, self.pending.len()));
+                    }
+                    start = self.pending.len();
+                    break;
+                } else {
+                    break;
+                }
+                start += self.step;
+            }
+
+            let end = chunk.windows.last().map_or(0, |&(_, end)| end);
+            chunk.name.clone_from(&self.name);
+            chunk.record_idx = self.record_idx.unwrap_or_default();
+            chunk.offset = self.pending_start;
+            chunk.data.clear();
+            chunk.data.extend_from_slice(&self.pending[..end]);
+
+            let consumed = start.min(self.pending.len());
+            self.pending.drain(..consumed);
+            self.pending_start += consumed;
+
+            if !chunk.windows.is_empty() {
+                return Some(Ok(()));
+            }
+        }
+    }
+
+    /// Processes the windows in parallel
+    pub fn process_parallel_windows<T>(self, processor: T, num_threads: usize) -> Result<()>
+    where
+        R: Send,
+        T: WindowProcessor,
+    {
+        self.process_parallel_windows_with_config(processor, ParallelConfig::new(num_threads))
+            .map(|_| ())
+    }
+
+    /// Same as `process_parallel_windows` but with a full run configuration
+    pub fn process_parallel_windows_with_config<D, T>(
+        self,
+        processor: T,
+        config: ParallelConfig<D>,
+    ) -> Result<RunStats>
+    where
+        R: Send,
+        D: Dispatcher,
+        T: WindowProcessor,
+    {
+        run_parallel::<D, _, WindowChunk, _, _, _>(
+            self,
+            WindowWorker(processor),
+            &config,
+            |reader, chunk| reader.read_windows(chunk),
+            |chunk, worker, global_idx| {
+                for window_idx in 0..chunk.windows.len() {
+                    let window = chunk.window(window_idx);
+                    if let Err(err) = worker.0.process_window(window, global_idx, window_idx) {
+                        let action = worker.0.on_record_error(&err, window_idx);
+                        apply_error_action(err, action)?;
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+}
@@ -0,0 +1,803 @@
+//! Writing records from the worker threads
+//!
+//! A [`ParallelWriter`] is cloned into every worker like a processor. Each
+//! clone formats its records into a private buffer and hands the buffer to
+//! the shared output once the batch is done, so the output lock is taken
+//! once per batch rather than once per record. By default batches are
+//! written in the order they were read, which keeps the output in input
+//! order: a batch finished early waits until all earlier batches are written.
+//!
+//! Outputs are compressed according to their extension, as niffler does on
+//! the read side, or as set with
+//! [`with_compression`](ParallelWriter::with_compression). Every batch is
+//! compressed on its worker as independent gzip members, BGZF blocks or zstd
+//! frames, and the concatenated batches form a valid compressed file.
+//!
+//! Every record of a batch has to pass through
+//! [`write_record`](ParallelWriter::write_record) or
+//! [`skip`](ParallelWriter::skip) so the writer learns the batch index, even
+//! if the batch writes nothing, and
+//! [`finish_batch`](ParallelWriter::finish_batch) is called from
+//! `on_batch_complete`.
+//!
+//! With [`with_thread_parts`](ParallelWriter::with_thread_parts) every
+//! worker writes its batches to a part file of its own instead, and
+//! [`finish`](ParallelWriter::finish) merges the parts into the output.
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::command::CommandSink;
+use crate::compression::{bgzf_block_sizes, Compression, CompressionOptions, BGZF_EOF};
+use crate::fastx::{append_record, Format};
+use crate::output_index::{BatchIndex, FaiEntry, OutputIndex};
+use crate::processor::current_batch;
+use crate::record::{Record, RecordSetLike};
+use crate::MinimalRefRecord;
+
+/// Buffer size of the stdout sink
+const STDOUT_BUFFER_SIZE: usize = 1 << 20;
+
+/// Output shared by all clones of a writer
+struct WriterState {
+    out: Box<dyn Write + Send>,
+    ordered: bool,
+    /// Index of the next batch to write, in ordered mode
+    next_batch: usize,
+    /// Finished batches waiting for earlier ones, in ordered mode
+    pending: BTreeMap<usize, (Vec<u8>, BatchIndex)>,
+    /// Error of a failed write, which fails all later batches
+    failed: Option<String>,
+    /// Temporary file and final path of an atomic output that is not yet committed
+    atomic: Option<(PathBuf, PathBuf)>,
+    /// Final path of a file output
+    path: Option<PathBuf>,
+    /// Index built as batches are written, if requested
+    index: Option<OutputIndex>,
+    /// Child process reading the output, if any
+    command: Option<CommandSink>,
+    /// Path prefix of the part files, if workers write to parts
+    part_prefix: Option<PathBuf>,
+    /// Number of part files created so far
+    num_parts: usize,
+    /// Part files of the handles dropped so far, waiting to be merged
+    parts: Vec<Part>,
+}
+
+/// Batch written to a part file
+struct PartBatch {
+    batch_idx: usize,
+    offset: u64,
+    len: usize,
+    index: BatchIndex,
+}
+
+/// Part file written by a single handle
+struct Part {
+    path: PathBuf,
+    batches: Vec<PartBatch>,
+}
+
+/// Part file a handle is writing to
+struct PartWriter {
+    out: BufWriter<File>,
+    len: u64,
+    part: Part,
+}
+
+impl Drop for WriterState {
+    /// Removes the temporary file of an atomic output that was never committed
+    /// and stops a child process whose input was never finished
+    fn drop(&mut self) {
+        if let Some((tmp, _)) = self.atomic.take() {
+            self.out = Box::new(io::sink());
+            let _ = std::fs::remove_file(tmp);
+        }
+        if let Some(command) = self.command.take() {
+            self.out = Box::new(io::sink());
+            command.kill();
+        }
+        for part in self.parts.drain(..) {
+            let _ = std::fs::remove_file(part.path);
+        }
+    }
+}
+
+impl WriterState {
+    fn write_batch(&mut self, batch_idx: usize, data: Vec<u8>, index: BatchIndex) -> Result<()> {
+        if let Some(error) = &self.failed {
+            bail!("Output failed on an earlier batch: {}", error);
+        }
+        let result = if self.ordered {
+            self.pending.insert(batch_idx, (data, index));
+            self.write_ready()
+        } else {
+            self.write_data(&data, &index)
+        };
+        let Err(e) = result else {
+            return Ok(());
+        };
+        // A closed pipe usually means the child failed, so report its error
+        let error = match self.command.take() {
+            Some(command) => {
+                self.out = Box::new(io::sink());
+                command.wait().err().unwrap_or_else(|| e.into())
+            }
+            None => e.into(),
+        };
+        self.failed = Some(format!("{:#}", error));
+        Err(error.context("Failed to write output"))
+    }
+
+    /// Writes pending batches as long as they continue the output
+    fn write_ready(&mut self) -> io::Result<()> {
+        while let Some((data, index)) = self.pending.remove(&self.next_batch) {
+            self.write_data(&data, &index)?;
+            self.next_batch += 1;
+        }
+        Ok(())
+    }
+
+    fn create_part(&mut self) -> Result<PartWriter> {
+        let mut path = self.part_prefix.clone().unwrap_or_default().into_os_string();
+        path.push(format!(".part{}", self.num_parts));
+        let path = PathBuf::from(path);
+        self.num_parts += 1;
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(PartWriter {
+            out: BufWriter::new(file),
+            len: 0,
+            part: Part {
+                path,
+                batches: Vec::new(),
+            },
+        })
+    }
+
+    /// Writes the batches of all part files to the output and removes the parts
+    ///
+    /// Ordered outputs take the batches across parts by index, unordered
+    /// ones copy one part after the other.
+    fn merge_parts(&mut self) -> Result<()> {
+        let parts = std::mem::take(&mut self.parts);
+        let result = self.copy_parts(&parts);
+        for part in parts {
+            let _ = std::fs::remove_file(part.path);
+        }
+        result
+    }
+
+    fn copy_parts(&mut self, parts: &[Part]) -> Result<()> {
+        let mut files = parts
+            .iter()
+            .map(|part| {
+                File::open(&part.path)
+                    .with_context(|| format!("Failed to open {}", part.path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut batches: Vec<(usize, &PartBatch)> = parts
+            .iter()
+            .enumerate()
+            .flat_map(|(part_idx, part)| part.batches.iter().map(move |batch| (part_idx, batch)))
+            .collect();
+        if self.ordered {
+            batches.sort_by_key(|(_, batch)| batch.batch_idx);
+        }
+        let mut data = Vec::new();
+        for (part_idx, batch) in batches {
+            if self.ordered {
+                if batch.batch_idx != self.next_batch {
+                    bail!(
+                        "Batch {} was never finished, so batches from {} on were not written",
+                        self.next_batch,
+                        batch.batch_idx
+                    );
+                }
+                self.next_batch += 1;
+            }
+            let file = &mut files[part_idx];
+            data.resize(batch.len, 0);
+            file.seek(SeekFrom::Start(batch.offset))
+                .and_then(|_| file.read_exact(&mut data))
+                .with_context(|| format!("Failed to read {}", parts[part_idx].path.display()))?;
+            self.write_data(&data, &batch.index)
+                .context("Failed to write output")?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8], index: &BatchIndex) -> io::Result<()> {
+        self.out.write_all(data)?;
+        if let Some(output_index) = &mut self.index {
+            output_index.add_batch(index);
+        }
+        Ok(())
+    }
+}
+
+/// Appends a FASTA record with its sequence wrapped at `width` columns
+fn append_wrapped_fasta(out: &mut Vec<u8>, head: &[u8], seq: &[u8], width: usize) {
+    out.push(b'>');
+    out.extend_from_slice(head);
+    out.push(b'\n');
+    for line in seq.chunks(width) {
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+    if seq.is_empty() {
+        out.push(b'\n');
+    }
+}
+
+/// `.fai` entry of a FASTA record about to be appended to `buffer`
+fn fai_entry(buffer: &[u8], head: &[u8], seq: &[u8], layout: LineLayout) -> FaiEntry {
+    let name = head.split(u8::is_ascii_whitespace).next().unwrap_or(head);
+    let line_bases = match layout {
+        LineLayout::Wrapped(width) => width,
+        _ => seq.len(),
+    };
+    FaiEntry {
+        name: name.to_vec(),
+        length: seq.len(),
+        offset: buffer.len() + head.len() + 2,
+        line_bases,
+        line_width: line_bases + 1,
+    }
+}
+
+/// Letter case of written sequences
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Upper,
+    Lower,
+}
+
+/// Line layout of written FASTA sequences
+#[derive(Debug, Clone, Copy, Default)]
+enum LineLayout {
+    /// Line breaks as in the input
+    #[default]
+    Input,
+    SingleLine,
+    Wrapped(usize),
+}
+
+type HeaderFn = Arc<dyn for<'h> Fn(&'h [u8]) -> Cow<'h, [u8]> + Send + Sync>;
+
+/// How records are formatted, copied into every handle
+#[derive(Clone, Default)]
+struct WriterOptions {
+    /// Output format, or the format of each record if unset
+    format: Option<Format>,
+    layout: LineLayout,
+    case: Option<Case>,
+    header_fn: Option<HeaderFn>,
+    compression: CompressionOptions,
+    indexed: bool,
+    thread_parts: bool,
+}
+
+/// Writer of FASTA/FASTQ records shared by the workers of a run
+pub struct ParallelWriter {
+    shared: Arc<Mutex<WriterState>>,
+    /// Records of the current batch
+    buffer: Vec<u8>,
+    /// Index of the current batch, if the output is indexed
+    index: BatchIndex,
+    batch_idx: Option<usize>,
+    options: WriterOptions,
+    /// Part file of this handle, created with its first batch
+    part: Option<PartWriter>,
+}
+
+impl ParallelWriter {
+    /// Writes batches to `out` in input order
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        let state = WriterState {
+            out: Box::new(out),
+            ordered: true,
+            next_batch: 0,
+            pending: BTreeMap::new(),
+            failed: None,
+            atomic: None,
+            path: None,
+            index: None,
+            command: None,
+            part_prefix: None,
+            num_parts: 0,
+            parts: Vec::new(),
+        };
+        Self {
+            shared: Arc::new(Mutex::new(state)),
+            buffer: Vec::new(),
+            index: BatchIndex::default(),
+            batch_idx: None,
+            options: WriterOptions::default(),
+            part: None,
+        }
+    }
+
+    /// Writes to standard output
+    ///
+    /// All handles share one buffered stdout and write whole batches under
+    /// the output lock, in input order unless `unordered` is set, so records
+    /// are never interleaved between workers.
+    pub fn stdout() -> Self {
+        Self::new(BufWriter::with_capacity(STDOUT_BUFFER_SIZE, io::stdout()))
+    }
+
+    /// Creates (or truncates) the output file at `path`, or writes to stdout if `path` is `-`
+    ///
+    /// The compression is picked from the extension (see
+    /// [`Compression::from_path`]) and can be overridden with
+    /// [`with_compression`](Self::with_compression).
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if path.as_os_str() == "-" {
+            return Ok(Self::stdout());
+        }
+        Ok(Self::create(path)?.with_compression(Compression::from_path(path)))
+    }
+
+    /// Creates the output file at `path` without compression
+    fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let writer = Self::new(BufWriter::new(file));
+        writer.shared.lock().path = Some(path.to_path_buf());
+        Ok(writer)
+    }
+
+    /// Writes to `<path>.tmp` and renames it to `path` only when [`finish`](Self::finish) succeeds
+    ///
+    /// If the writer is dropped without a successful `finish`, e.g. because
+    /// the run failed, the temporary file is removed, so an interrupted run
+    /// never leaves a truncated output behind.
+    pub fn from_path_atomic<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if path.as_os_str() == "-" {
+            return Ok(Self::stdout());
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let writer = Self::create(&tmp)?.with_compression(Compression::from_path(path));
+        let mut state = writer.shared.lock();
+        state.atomic = Some((tmp, path.to_path_buf()));
+        state.path = Some(path.to_path_buf());
+        drop(state);
+        Ok(writer)
+    }
+
+    /// Feeds the records to the stdin of `command`, e.g. `bwa mem -p ref.fa -`
+    ///
+    /// The child's stdout is left as configured on `command` and its stderr
+    /// is passed through. [`finish`](Self::finish) closes the child's stdin,
+    /// waits for it, and fails with the end of its stderr if it exits
+    /// unsuccessfully. A writer dropped without `finish` kills the child.
+    pub fn spawn(command: Command) -> Result<Self> {
+        let (command, stdin) = CommandSink::spawn(command)?;
+        let writer = Self::new(BufWriter::new(stdin));
+        writer.shared.lock().command = Some(command);
+        Ok(writer)
+    }
+
+    /// Writes batches as soon as they are finished instead of in input order
+    ///
+    /// Must be set before the writer is cloned into the workers.
+    pub fn unordered(self) -> Self {
+        self.shared.lock().ordered = false;
+        self
+    }
+
+    /// Lets every worker write its batches to a part file of its own, merged into the output by [`finish`](Self::finish)
+    ///
+    /// Workers never wait for the output lock or, in ordered mode, hold
+    /// batches in memory until earlier ones are written, which pays off with
+    /// many threads and large outputs. In exchange every byte is written
+    /// twice. Parts are written next to file outputs as `<path>.part<N>`,
+    /// and to the system temporary directory for other outputs; they are
+    /// removed by `finish` or when the writer is dropped. `finish` must be
+    /// called after the workers' handles are dropped, i.e. after the run.
+    /// Must be set before the writer is cloned into the workers.
+    pub fn with_thread_parts(mut self) -> Self {
+        self.options.thread_parts = true;
+        let mut state = self.shared.lock();
+        let prefix = match &state.path {
+            Some(path) => path.clone(),
+            None => std::env::temp_dir().join(format!(
+                "seqio-output-{}-{}",
+                std::process::id(),
+                Arc::as_ptr(&self.shared) as usize
+            )),
+        };
+        state.part_prefix = Some(prefix);
+        drop(state);
+        self
+    }
+
+    /// Compresses every batch on its worker before it is written
+    ///
+    /// Overrides the compression picked from the file extension. Must be set
+    /// before the writer is cloned into the workers.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.options.compression.compression = compression;
+        self
+    }
+
+    /// Sets the compression level (1-9)
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.options.compression.level = Some(level);
+        self
+    }
+
+    /// Compresses each batch with `threads` threads on top of the worker threads
+    ///
+    /// Useful when few workers produce large batches, e.g. when the
+    /// processing is cheap and compression is the bottleneck.
+    pub fn with_compression_threads(mut self, threads: usize) -> Self {
+        self.options.compression.threads = threads.max(1);
+        self
+    }
+
+    /// Writes `<path>.gzi` for BGZF outputs and `<path>.fai` for FASTA outputs in [`finish`](Self::finish)
+    ///
+    /// The indexes match those of `bgzip -i` and `samtools faidx`, so the
+    /// output is random-accessible by htslib-based tools right away. As
+    /// `.fai` needs evenly wrapped sequences, FASTA records are written on a
+    /// single line unless [`with_line_width`](Self::with_line_width) is set.
+    /// Only file outputs without compression or with BGZF can be indexed.
+    /// Must be set before the writer is cloned into the workers.
+    pub fn with_index(mut self) -> Self {
+        self.options.indexed = true;
+        self.shared.lock().index = Some(OutputIndex::default());
+        self
+    }
+
+    /// Writes all records in `format`
+    ///
+    /// Qualities are dropped when writing FASTA. Writing a FASTA record as
+    /// FASTQ fails.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.options.format = Some(format);
+        self
+    }
+
+    /// Wraps FASTA sequences at `line_width` columns
+    pub fn with_line_width(mut self, line_width: usize) -> Self {
+        self.options.layout = LineLayout::Wrapped(line_width.max(1));
+        self
+    }
+
+    /// Writes every FASTA sequence on a single line
+    pub fn with_single_line(mut self) -> Self {
+        self.options.layout = LineLayout::SingleLine;
+        self
+    }
+
+    /// Converts sequences to upper or lower case
+    pub fn with_case(mut self, case: Case) -> Self {
+        self.options.case = Some(case);
+        self
+    }
+
+    /// Rewrites every header with `header_fn`, which receives the header without `>` or `@`
+    pub fn with_header_fn<F>(mut self, header_fn: F) -> Self
+    where
+        F: for<'h> Fn(&'h [u8]) -> Cow<'h, [u8]> + Send + Sync + 'static,
+    {
+        self.options.header_fn = Some(Arc::new(header_fn));
+        self
+    }
+
+    /// Sets the index of the first batch, e.g. the batches covered by a resumed checkpoint
+    pub fn with_first_batch(self, batch_idx: usize) -> Self {
+        self.shared.lock().next_batch = batch_idx;
+        self
+    }
+
+    /// Marks the current batch without writing anything
+    pub fn skip(&mut self, record_set_idx: usize) {
+        self.batch_idx = Some(record_set_idx);
+    }
+
+    /// Drops the records appended to the current batch, keeping the batch marked
+    ///
+    /// Call from `on_batch_retry`, so a batch processed again is not written twice.
+    pub fn discard_batch(&mut self) {
+        self.buffer.clear();
+        self.index = BatchIndex::default();
+    }
+
+    /// Appends a record to the current batch
+    pub fn write_record<'a, Rf: MinimalRefRecord<'a>>(
+        &mut self,
+        record_set_idx: usize,
+        record: &Rf,
+    ) -> Result<()> {
+        self.skip(record_set_idx);
+        let record = record.to_record();
+        let is_fasta = matches!(record, Record::Fasta { .. });
+        let format = self.options.format.unwrap_or(if is_fasta {
+            Format::Fasta
+        } else {
+            Format::Fastq
+        });
+        let head = match &self.options.header_fn {
+            Some(header_fn) => header_fn(record.ref_head()),
+            None => Cow::Borrowed(record.ref_head()),
+        };
+        let layout = match self.options.layout {
+            LineLayout::Input if self.options.indexed => LineLayout::SingleLine,
+            layout => layout,
+        };
+        let seq = match (format, layout) {
+            (Format::Fasta, LineLayout::Input) => Cow::Borrowed(record.ref_seq()),
+            _ => record.ref_full_seq(),
+        };
+        let seq = match self.options.case {
+            Some(Case::Upper) if seq.iter().any(u8::is_ascii_lowercase) => {
+                Cow::Owned(seq.to_ascii_uppercase())
+            }
+            Some(Case::Lower) if seq.iter().any(u8::is_ascii_uppercase) => {
+                Cow::Owned(seq.to_ascii_lowercase())
+            }
+            _ => seq,
+        };
+        if self.options.indexed {
+            match format {
+                Format::Fasta => self
+                    .index
+                    .fai
+                    .push(fai_entry(&self.buffer, &head, &seq, layout)),
+                Format::Fastq => self.index.has_fastq = true,
+            }
+        }
+        match (format, layout) {
+            (Format::Fastq, _) => {
+                if is_fasta {
+                    bail!(
+                        "Cannot write FASTA record {} as FASTQ",
+                        String::from_utf8_lossy(&head)
+                    );
+                }
+                append_record(&mut self.buffer, format, &head, &seq, record.ref_qual());
+            }
+            (Format::Fasta, LineLayout::Wrapped(width)) => {
+                append_wrapped_fasta(&mut self.buffer, &head, &seq, width)
+            }
+            (Format::Fasta, _) => append_record(&mut self.buffer, format, &head, &seq, &[]),
+        }
+        Ok(())
+    }
+
+    /// Appends every record of `record_set` to the current batch
+    pub fn write_record_set<S: RecordSetLike>(
+        &mut self,
+        record_set_idx: usize,
+        record_set: &S,
+    ) -> Result<()> {
+        self.skip(record_set_idx);
+        for record in record_set.records() {
+            self.write_record(record_set_idx, &record)?;
+        }
+        Ok(())
+    }
+
+    /// Hands the current batch to the output
+    ///
+    /// Call from `on_batch_complete`. Inside a run the batch index comes
+    /// from the executor, so a batch that failed before its first record
+    /// still moves the ordered output on; outside a run it is the one given
+    /// to [`skip`](Self::skip) or [`write_record`](Self::write_record).
+    pub fn finish_batch(&mut self) -> Result<()> {
+        let started = self.batch_idx.take();
+        let Some(batch_idx) = current_batch().or(started) else {
+            return Ok(());
+        };
+        self.index.len = self.buffer.len();
+        let data = match self.options.compression.compression {
+            Compression::None => std::mem::take(&mut self.buffer),
+            compression => {
+                let data = self.options.compression.compress(&self.buffer)?;
+                self.buffer.clear();
+                if self.options.indexed && compression == Compression::Bgzf {
+                    self.index.blocks = bgzf_block_sizes(&data);
+                }
+                data
+            }
+        };
+        let index = std::mem::take(&mut self.index);
+        if self.options.thread_parts {
+            return self.write_part(batch_idx, data, index);
+        }
+        self.shared.lock().write_batch(batch_idx, data, index)
+    }
+
+    /// Appends a batch to the part file of this handle
+    fn write_part(&mut self, batch_idx: usize, data: Vec<u8>, index: BatchIndex) -> Result<()> {
+        let part = match self.part.take() {
+            Some(part) => part,
+            None => self.shared.lock().create_part()?,
+        };
+        let part = self.part.insert(part);
+        part.out
+            .write_all(&data)
+            .with_context(|| format!("Failed to write {}", part.part.path.display()))?;
+        part.part.batches.push(PartBatch {
+            batch_idx,
+            offset: part.len,
+            len: data.len(),
+            index,
+        });
+        part.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Hands the part file of this handle to the output, to be merged by `finish`
+    fn release_part(&mut self) {
+        let Some(mut part) = self.part.take() else {
+            return;
+        };
+        let mut state = self.shared.lock();
+        match part.out.flush() {
+            Ok(()) => state.parts.push(part.part),
+            Err(e) => {
+                let error = format!("Failed to write {}: {}", part.part.path.display(), e);
+                state.failed = Some(error);
+                let _ = std::fs::remove_file(&part.part.path);
+            }
+        }
+    }
+
+    /// Flushes the output once the run is done
+    ///
+    /// Fails if batches are still waiting for an earlier batch that was never
+    /// finished. BGZF outputs get their end-of-file block, atomic outputs are
+    /// renamed to their final path, indexes are written next to it, and child
+    /// processes are waited for.
+    pub fn finish(mut self) -> Result<()> {
+        self.release_part();
+        let mut guard = self.shared.lock();
+        let state = &mut *guard;
+        if let Some(error) = &state.failed {
+            bail!("Output failed on an earlier batch: {}", error);
+        }
+        if !state.parts.is_empty() {
+            state.merge_parts()?;
+        }
+        if let Some(&batch_idx) = state.pending.keys().next() {
+            bail!(
+                "Batch {} was never finished, so batches from {} on were not written",
+                state.next_batch,
+                batch_idx
+            );
+        }
+        let compression = self.options.compression.compression;
+        if state.index.is_some() {
+            if !matches!(compression, Compression::None | Compression::Bgzf) {
+                bail!("Only uncompressed and BGZF outputs can be indexed");
+            }
+            if state.path.is_none() {
+                bail!("Only file outputs can be indexed");
+            }
+        }
+        if compression == Compression::Bgzf {
+            state.out.write_all(&BGZF_EOF)?;
+        }
+        state.out.flush().context("Failed to flush output")?;
+        if let Some(command) = state.command.take() {
+            // Close the child's stdin so it sees the end of its input
+            state.out = Box::new(io::sink());
+            command.wait()?;
+        }
+        if let Some((tmp, path)) = &state.atomic {
+            // Close the file before renaming it
+            state.out = Box::new(io::sink());
+            std::fs::rename(tmp, path).with_context(|| {
+                format!("Failed to rename {} to {}", tmp.display(), path.display())
+            })?;
+            state.atomic = None;
+        }
+        if let (Some(index), Some(path)) = (&state.index, &state.path) {
+            index.write(path, compression == Compression::Bgzf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Clone for ParallelWriter {
+    /// Creates another handle with its own batch buffer
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            buffer: Vec::new(),
+            index: BatchIndex::default(),
+            batch_idx: None,
+            options: self.options.clone(),
+            part: None,
+        }
+    }
+}
+
+impl Drop for ParallelWriter {
+    fn drop(&mut self) {
+        self.release_part();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::SyntheticReads;
+    use crate::{FastxReader, ParallelConfig, ParallelProcessor, ParallelReader};
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes every record except those of the first batch, which never reach the writer
+    #[derive(Clone)]
+    struct DropFirstBatch {
+        out: ParallelWriter,
+        dropped: Arc<AtomicUsize>,
+    }
+
+    impl ParallelProcessor for DropFirstBatch {
+        fn process_record<'a, Rf: MinimalRefRecord<'a>>(
+            &mut self,
+            record: Rf,
+            record_set_idx: usize,
+            _record_idx: usize,
+        ) -> Result<()> {
+            if record_set_idx == 0 {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            self.out.write_record(record_set_idx, &record)
+        }
+
+        fn on_batch_complete(&mut self) -> Result<()> {
+            self.out.finish_batch()
+        }
+    }
+
+    #[test]
+    fn batch_without_records_still_advances_output() {
+        let data = SyntheticReads::fastq(2000).to_bytes();
+        let buffer = SharedBuffer::default();
+        let processor = DropFirstBatch {
+            out: ParallelWriter::new(buffer.clone()),
+            dropped: Arc::default(),
+        };
+        let out = processor.out.clone();
+        let dropped = Arc::clone(&processor.dropped);
+        FastxReader::new(Cursor::new(data.clone()), Format::Fastq)
+            .process_parallel_with_config(processor, ParallelConfig::new(4))
+            .unwrap();
+        out.finish().unwrap();
+        let output = buffer.0.lock().clone();
+        assert!((1..2000).contains(&dropped.load(Ordering::Relaxed)));
+        assert!(!output.is_empty());
+        assert!(data.ends_with(&output));
+    }
+}